@@ -0,0 +1,591 @@
+/// This is where the command line is read. The recorder is
+/// one of several commands, the others look at devices and
+/// at what storage has written, so they run without
+/// starting the pipeline and print to stdout. Running the
+/// binary with no command records, as it always has. Every
+/// command reads the same configuration file, so list,
+/// export, clip and verify look where storage writes, and
+/// control finds the socket the recorder answers on
+use crate::config::Config;
+use crate::control::socket::{self, Request};
+use crate::hardware;
+use crate::recordings;
+use crate::time::{self, Timestamp};
+
+use anyhow::{anyhow, bail, Result};
+
+use std::string::{String, ToString};
+use std::vec::Vec;
+use std::{format, println};
+
+pub const USAGE: &str = "\
+usage: camera [--config FILE] [COMMAND] [OPTIONS]
+
+commands:
+  record      capture audio and video until stopped, the default
+                --no-audio           leave the microphone off
+                --no-video           leave the cameras off
+                --audio-device NAME  record the input whose name has NAME
+  devices     list the audio inputs and cameras that can be recorded
+  list        list the recordings in storage
+                --from TIME --to TIME  only those playing in this range
+                --directory DIR        look in DIR, not the configured one
+  export      write the audio from a range of time to one file
+                --from TIME --to TIME --output FILE [--directory DIR]
+  clip        cut the audio and video from a range of time into one
+              AVI file, with a manifest of what it was cut from
+                --from TIME --to TIME --output FILE [--directory DIR]
+  verify      check that recordings can be played to their end
+                [FILE...]        these files, or every recording
+                --directory DIR  look in DIR, not the configured one
+  control     ask the running recorder, over its control socket
+                status           what it records, where, and its queues
+                start, stop      write what is captured, or stop writing
+                segment          start new files now
+                storage DIR      record to DIR from now on
+                mark [NOTE...]   bookmark now, keeping the recording around it
+                log-level FILTER replace the log filter, as in CAMERA_LOG
+                --socket PATH    talk to PATH, not the configured socket
+
+--config FILE reads FILE, not $CAMERA_CONFIG or camera.toml
+TIME is a date, 2023-07-27, or a date and time, 2023-07-27T23:31:46Z
+";
+
+/// what the command line asked for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cli {
+    /// the configuration file given with --config
+    pub config: Option<String>,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Record(RecordOptions),
+    Devices,
+    List {
+        directory: Option<String>,
+        from: Option<u64>,
+        to: Option<u64>,
+    },
+    Export {
+        directory: Option<String>,
+        from: u64,
+        to: u64,
+        output: String,
+    },
+    Clip {
+        directory: Option<String>,
+        from: u64,
+        to: u64,
+        output: String,
+    },
+    Verify {
+        directory: Option<String>,
+        paths: Vec<String>,
+    },
+    Control {
+        socket: Option<String>,
+        request: Request,
+    },
+    Help,
+}
+
+/// the sources the record command turns on
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordOptions {
+    pub audio: bool,
+    pub video: bool,
+    /// replaces [audio] device from the configuration
+    pub audio_device: Option<String>,
+}
+
+impl Default for RecordOptions {
+    fn default() -> RecordOptions {
+        RecordOptions {
+            audio: true,
+            video: true,
+            audio_device: None,
+        }
+    }
+}
+
+impl RecordOptions {
+    /// this function applies the options to the configuration
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(device) = self.audio_device.as_ref() {
+            config.audio.device = Some(device.clone());
+        }
+    }
+}
+
+/// this function reads the arguments after the program name
+pub fn parse<I>(args: I) -> Result<Cli>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = Args {
+        remaining: args.into_iter().collect(),
+    };
+    let config = args.value("--config")?;
+    if args.flag("--help") || args.flag("-h") {
+        return Ok(Cli {
+            config,
+            command: Command::Help,
+        });
+    }
+    let name = match args.remaining.first() {
+        Some(first) if !first.starts_with('-') => args.remaining.remove(0),
+        _ => "record".to_string(),
+    };
+    let command = match name.as_str() {
+        "record" => Command::Record(RecordOptions {
+            audio: !args.flag("--no-audio"),
+            video: !args.flag("--no-video"),
+            audio_device: args.value("--audio-device")?,
+        }),
+        "devices" => Command::Devices,
+        "list" => Command::List {
+            directory: args.value("--directory")?,
+            from: args.time("--from")?,
+            to: args.time("--to")?,
+        },
+        "export" => Command::Export {
+            directory: args.value("--directory")?,
+            from: args.time("--from")?.ok_or(anyhow!("export needs --from"))?,
+            to: args.time("--to")?.ok_or(anyhow!("export needs --to"))?,
+            output: args
+                .value("--output")?
+                .ok_or(anyhow!("export needs --output"))?,
+        },
+        "clip" => Command::Clip {
+            directory: args.value("--directory")?,
+            from: args.time("--from")?.ok_or(anyhow!("clip needs --from"))?,
+            to: args.time("--to")?.ok_or(anyhow!("clip needs --to"))?,
+            output: args
+                .value("--output")?
+                .ok_or(anyhow!("clip needs --output"))?,
+        },
+        "verify" => Command::Verify {
+            directory: args.value("--directory")?,
+            paths: args.words(),
+        },
+        "control" => Command::Control {
+            socket: args.value("--socket")?,
+            request: control_request(args.words())?,
+        },
+        "help" => Command::Help,
+        other => bail!("unknown command {}", other),
+    };
+    if let Some(unused) = args.remaining.first() {
+        bail!("{} does not take {}", name, unused);
+    }
+    Ok(Cli { config, command })
+}
+
+/// the arguments not yet taken by a flag
+struct Args {
+    remaining: Vec<String>,
+}
+
+impl Args {
+    /// this function takes `name` if it was given
+    fn flag(&mut self, name: &str) -> bool {
+        let before = self.remaining.len();
+        self.remaining.retain(|arg| arg != name);
+        self.remaining.len() != before
+    }
+
+    /// this function takes `name VALUE` or `name=VALUE`
+    fn value(&mut self, name: &str) -> Result<Option<String>> {
+        let prefix = format!("{}=", name);
+        for index in 0..self.remaining.len() {
+            if self.remaining[index] == name {
+                if index + 1 == self.remaining.len() {
+                    bail!("{} needs a value", name);
+                }
+                let value = self.remaining.remove(index + 1);
+                self.remaining.remove(index);
+                return Ok(Some(value));
+            }
+            if let Some(value) = self.remaining[index].strip_prefix(&prefix) {
+                let value = value.to_string();
+                self.remaining.remove(index);
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// this function takes every argument that is not a flag
+    fn words(&mut self) -> Vec<String> {
+        let (flags, words) = core::mem::take(&mut self.remaining)
+            .into_iter()
+            .partition(|arg| arg.starts_with('-'));
+        self.remaining = flags;
+        words
+    }
+
+    fn time(&mut self, name: &str) -> Result<Option<u64>> {
+        match self.value(name)? {
+            Some(value) => time::parse_wall(&value)
+                .map(Some)
+                .map_err(|err| anyhow!("{} {}", name, err)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// this function reads what follows `control`
+fn control_request(words: Vec<String>) -> Result<Request> {
+    let mut words = words.into_iter();
+    let request = match words.next().as_deref() {
+        Some("status") => Request::Status,
+        Some("start") => Request::Start,
+        Some("stop") => Request::Stop,
+        Some("segment") => Request::NewSegment,
+        Some("storage") => Request::SwitchStorage {
+            directory: words
+                .next()
+                .ok_or(anyhow!("control storage needs a directory"))?,
+        },
+        Some("mark") => {
+            return Ok(Request::Mark {
+                note: words.collect::<Vec<_>>().join(" "),
+            })
+        }
+        Some("log-level") => Request::LogLevel {
+            filter: words
+                .next()
+                .ok_or(anyhow!("control log-level needs a filter"))?,
+        },
+        Some(other) => bail!("unknown control command {}", other),
+        None => bail!("control needs a command, see --help"),
+    };
+    if let Some(unused) = words.next() {
+        bail!("control does not take {}", unused);
+    }
+    Ok(request)
+}
+
+/// this function runs every command but record, returning
+/// the status the process should exit with
+pub fn run(command: Command, config: &Config) -> Result<i32> {
+    match command {
+        Command::Record(_) => {
+            bail!("record runs the pipeline, it is started from main")
+        }
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+        Command::Devices => devices(config),
+        Command::List {
+            directory,
+            from,
+            to,
+        } => list(
+            directory.as_deref().unwrap_or(&config.storage.directory),
+            from,
+            to,
+        ),
+        Command::Export {
+            directory,
+            from,
+            to,
+            output,
+        } => {
+            let directory =
+                directory.as_deref().unwrap_or(&config.storage.directory);
+            let exported = recordings::export(
+                recordings::scan(directory)?,
+                from,
+                to,
+                &output,
+            )?;
+            println!(
+                "wrote {:.1}s from {} recordings, starting {}, to {}",
+                exported.seconds(exported.frames),
+                exported.sources,
+                wall(exported.start),
+                output
+            );
+            if exported.silent_frames > 0 {
+                println!(
+                    "{:.1}s of that is silence where nothing was recorded",
+                    exported.seconds(exported.silent_frames)
+                );
+            }
+            Ok(0)
+        }
+        Command::Clip {
+            directory,
+            from,
+            to,
+            output,
+        } => {
+            let directory =
+                directory.as_deref().unwrap_or(&config.storage.directory);
+            let clip = recordings::clip::export(directory, from, to, &output)?;
+            println!(
+                "wrote {:.1}s from {} recordings, starting {}, to {}",
+                clip.seconds(),
+                clip.sources.len(),
+                wall(clip.start),
+                output
+            );
+            if clip.held_frames > 0 {
+                println!(
+                    "{} of {} frames hold the one before, the camera had \
+                     nothing new",
+                    clip.held_frames, clip.video_frames
+                );
+            }
+            if clip.silent_frames > 0 {
+                println!(
+                    "{:.1}s of audio is silence where nothing was recorded",
+                    clip.silent_frames as f64 / clip.sample_rate as f64
+                );
+            }
+            println!("sha256 {}, listed in {}", clip.sha256, clip.manifest);
+            Ok(0)
+        }
+        Command::Verify { directory, paths } => {
+            let paths = if paths.is_empty() {
+                let directory =
+                    directory.as_deref().unwrap_or(&config.storage.directory);
+                recordings::scan(directory)?
+                    .into_iter()
+                    .map(|recording| recording.path)
+                    .collect()
+            } else {
+                paths
+            };
+            verify(paths)
+        }
+        Command::Control { socket, request } => {
+            let socket = socket.unwrap_or(config.control.socket.clone());
+            if socket.is_empty() {
+                bail!(
+                    "the control socket is turned off, name one with --socket"
+                );
+            }
+            let answer = socket::send(&socket, &request)?;
+            println!("{}", answer);
+            Ok(if answer.starts_with("{\"ok\":true") { 0 } else { 1 })
+        }
+    }
+}
+
+fn devices(config: &Config) -> Result<i32> {
+    let (inputs, default) = hardware::audio::devices();
+    println!("audio inputs:");
+    for input in inputs.iter() {
+        let marker = if Some(input) == default.as_ref() {
+            "*"
+        } else {
+            " "
+        };
+        println!("  {} {}", marker, input);
+    }
+    if inputs.is_empty() {
+        println!("    none found");
+    }
+    match config.audio.device.as_ref() {
+        Some(wanted) => println!(
+            "configured audio device: {} ({})",
+            wanted,
+            if inputs.iter().any(|input| input.contains(wanted.as_str())) {
+                "found"
+            } else {
+                "missing"
+            }
+        ),
+        None => println!("configured audio device: the default"),
+    }
+
+    let cameras = hardware::camera::devices();
+    println!("cameras:");
+    for camera in cameras.iter() {
+        println!("    {}", camera);
+    }
+    if cameras.is_empty() {
+        println!("    none found");
+    }
+    for camera in config.cameras.iter() {
+        println!(
+            "configured camera {}: {} at {}x{} {}fps ({})",
+            camera.name,
+            camera.device,
+            camera.width,
+            camera.height,
+            camera.fps,
+            if cameras.contains(&camera.device) {
+                "found"
+            } else {
+                "missing"
+            }
+        );
+    }
+    Ok(0)
+}
+
+fn list(directory: &str, from: Option<u64>, to: Option<u64>) -> Result<i32> {
+    let found = recordings::between(recordings::scan(directory)?, from, to);
+    for (recording, format) in found.iter() {
+        match format {
+            Ok(format) => println!(
+                "{}  {:>9.1}s  {}ch {}Hz  {}",
+                wall(recording.start),
+                format.duration().as_secs_f64(),
+                format.channels,
+                format.sample_rate,
+                recording.path
+            ),
+            Err(err) => {
+                println!("{}  unreadable, {}", wall(recording.start), err)
+            }
+        }
+    }
+    if found.is_empty() {
+        println!("no recordings in {}", directory);
+    }
+    Ok(0)
+}
+
+fn verify(paths: Vec<String>) -> Result<i32> {
+    let mut failed = 0;
+    for path in paths.iter() {
+        let problems = recordings::verify(path)?;
+        if problems.is_empty() {
+            println!("ok   {}", path);
+        } else {
+            failed += 1;
+            println!("BAD  {}", path);
+            for problem in problems {
+                println!("       {}", problem);
+            }
+        }
+    }
+    println!("{} of {} recordings are damaged", failed, paths.len());
+    Ok(if failed == 0 { 0 } else { 1 })
+}
+
+fn wall(wall_nanos: u64) -> String {
+    Timestamp {
+        wall_nanos,
+        monotonic_nanos: 0,
+    }
+    .rfc3339()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(line: &str) -> Result<Cli> {
+        parse(line.split_whitespace().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn records_when_no_command_is_given() {
+        let cli = parsed("").unwrap();
+        assert_eq!(cli.config, None);
+        assert_eq!(cli.command, Command::Record(RecordOptions::default()));
+
+        let cli = parsed("--no-video --config=porch.toml --audio-device USB")
+            .unwrap();
+        assert_eq!(cli.config.as_deref(), Some("porch.toml"));
+        assert_eq!(
+            cli.command,
+            Command::Record(RecordOptions {
+                audio: true,
+                video: false,
+                audio_device: Some("USB".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn reads_each_command() {
+        assert_eq!(parsed("devices").unwrap().command, Command::Devices);
+        assert_eq!(
+            parsed("list --from 2023-07-27 --directory /media/usb")
+                .unwrap()
+                .command,
+            Command::List {
+                directory: Some("/media/usb".into()),
+                from: Some(1_690_416_000_000_000_000),
+                to: None,
+            }
+        );
+        let export = parsed(
+            "export --from 2023-07-27T23:31:46Z --to 2023-07-27T23:32:46Z \
+             --output clip.wav",
+        )
+        .unwrap()
+        .command;
+        assert_eq!(
+            export,
+            Command::Export {
+                directory: None,
+                from: 1_690_500_706_000_000_000,
+                to: 1_690_500_766_000_000_000,
+                output: "clip.wav".into(),
+            }
+        );
+        assert_eq!(
+            parsed("clip --from 2023-07-27 --to 2023-07-28 --output a.avi")
+                .unwrap()
+                .command,
+            Command::Clip {
+                directory: None,
+                from: 1_690_416_000_000_000_000,
+                to: 1_690_502_400_000_000_000,
+                output: "a.avi".into(),
+            }
+        );
+        assert_eq!(
+            parsed("verify a.wav b.wav --config c.toml").unwrap(),
+            Cli {
+                config: Some("c.toml".into()),
+                command: Command::Verify {
+                    directory: None,
+                    paths: std::vec!["a.wav".into(), "b.wav".into()],
+                },
+            }
+        );
+        assert_eq!(
+            parsed("control mark door opened --socket /run/camera.sock")
+                .unwrap()
+                .command,
+            Command::Control {
+                socket: Some("/run/camera.sock".into()),
+                request: Request::Mark {
+                    note: "door opened".into(),
+                },
+            }
+        );
+        assert_eq!(parsed("list -h").unwrap().command, Command::Help);
+    }
+
+    #[test]
+    fn names_what_is_wrong() {
+        let error = |line: &str| parsed(line).unwrap_err().to_string();
+        assert_eq!(error("play"), "unknown command play");
+        assert_eq!(
+            error("control storage"),
+            "control storage needs a directory"
+        );
+        assert_eq!(error("export --from 2023-07-27"), "export needs --to");
+        assert_eq!(error("list --to"), "--to needs a value");
+        assert_eq!(
+            error("devices --no-audio"),
+            "devices does not take --no-audio"
+        );
+        assert_eq!(
+            error("list --from monday"),
+            "--from monday is not a date or a date and time"
+        );
+    }
+}
@@ -1,19 +1,65 @@
 use crate::log::{Job, LogPipe};
+use crate::pipeline::{Stage, StageFuture};
 use crate::queue::{AudioUpdate, Receiver, Sender};
-use pasts::prelude::*;
 
-use std::format;
 /// this function is where we perform our audio
 /// post processing to gain more human voice signal
 /// frma amidst the background noise
 pub async fn start(
     mut audio_from_microphone: Receiver<AudioUpdate>,
-    mut audio_to_storage: Sender<AudioUpdate>,
+    audio_to_storage: Sender<AudioUpdate>,
     audio_compute_log: LogPipe,
 ) {
-    audio_compute_log.info("started audio processing", Job::AudioCompute);
-    for update in audio_from_microphone.try_dequeue() {
-        audio_compute_log.info("successfully received frame from microphone task", Job::AudioCompute);
-        audio_compute_log.info(&format!("audio compute update {{timestamp: {:?}, name: {:?} }}", update.timestamp, update.name), Job::AudioCompute);
+    crate::info!(
+        audio_compute_log,
+        Job::AudioCompute,
+        "started audio processing"
+    );
+    while let Ok(update) = audio_from_microphone.dequeue().await {
+        crate::trace!(
+            audio_compute_log,
+            Job::AudioCompute,
+            "audio compute update {{timestamp: {:?}, name: {:?} }}",
+            update.timestamp,
+            update.name
+        );
+        // denoising and normalizing will happen here,
+        // for now the frame passes straight through
+        if let Err((_, err)) = audio_to_storage.enqueue_waiting(update).await {
+            crate::error!(
+                audio_compute_log,
+                Job::AudioCompute,
+                "failed to pass audio update on from compute: {:?}",
+                err
+            );
+        }
+    }
+    crate::warn!(
+        audio_compute_log,
+        Job::AudioCompute,
+        "audio compute queue closed"
+    );
+}
+
+/// this is the audio compute step of the pipeline
+#[derive(Clone)]
+pub struct AudioCompute;
+
+impl Stage for AudioCompute {
+    type Input = AudioUpdate;
+    type Output = AudioUpdate;
+    const NAME: &'static str = "audio compute";
+
+    fn job(&self) -> Job {
+        Job::AudioCompute
+    }
+
+    fn run(
+        self,
+        input: Receiver<AudioUpdate>,
+        output: Sender<AudioUpdate>,
+        log: LogPipe,
+    ) -> StageFuture {
+        std::boxed::Box::pin(start(input, output, log))
     }
 }
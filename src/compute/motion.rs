@@ -0,0 +1,86 @@
+/// This is where frames are compared for movement. Each
+/// frame is shared with storage and the viewfinder, so
+/// motion detection only reads the frame buffer and never
+/// copies it, keeping the previous frame alive just long
+/// enough to compare the next one against it. Until the
+/// frames are decoded this compares the raw bytes, which
+/// is enough to tell a still scene from a busy one
+use crate::config::ComputeConfig;
+use crate::control::Setting;
+use crate::log::{Job, LogPipe};
+use crate::queue::{Receiver, VideoUpdate};
+
+use std::sync::Arc;
+
+/// this function returns the mean absolute difference
+/// between the bytes two frames have in common
+pub fn difference(previous: &[u8], current: &[u8]) -> f32 {
+    let len = previous.len().min(current.len());
+    if len == 0 {
+        return 0.0;
+    }
+    let total: u64 = previous
+        .iter()
+        .zip(current.iter())
+        .map(|(a, b)| a.abs_diff(*b) as u64)
+        .sum();
+    total as f32 / len as f32
+}
+
+/// this function takes every frame shared with motion
+/// detection and logs when movement starts and stops,
+/// a difference over the configured threshold is movement,
+/// a new threshold is used from the next frame
+pub async fn start(
+    mut frames: Receiver<Arc<VideoUpdate>>,
+    motion_log: LogPipe,
+    mut config: Setting<ComputeConfig>,
+) {
+    crate::info!(motion_log, Job::VideoCompute, "started motion detection");
+    let mut threshold = config.current().motion_threshold;
+    let mut previous: Option<Arc<VideoUpdate>> = None;
+    let mut moving = false;
+    while let Ok(frame) = frames.dequeue().await {
+        if let Some(changed) = config.try_changed() {
+            if changed.motion_threshold != threshold {
+                threshold = changed.motion_threshold;
+                crate::info!(
+                    motion_log,
+                    Job::VideoCompute,
+                    "motion threshold is now {:.1}",
+                    threshold
+                );
+            }
+        }
+        if let Some(previous) = previous.as_ref() {
+            let difference = difference(&previous.data, &frame.data);
+            if (difference > threshold) != moving {
+                moving = !moving;
+                crate::info!(
+                    motion_log,
+                    Job::VideoCompute,
+                    "motion {} at frame {}, difference {:.1}",
+                    if moving { "started" } else { "stopped" },
+                    frame.sequence,
+                    difference
+                );
+            }
+        }
+        previous = Some(frame);
+    }
+    crate::warn!(motion_log, Job::VideoCompute, "motion queue closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difference_is_mean_per_byte() {
+        assert_eq!(difference(&[10, 10, 10, 10], &[10, 10, 10, 10]), 0.0);
+        assert_eq!(difference(&[0, 0, 0, 0], &[0, 20, 0, 20]), 10.0);
+        // only the bytes both frames have are compared
+        assert_eq!(difference(&[255, 0], &[0]), 255.0);
+        assert_eq!(difference(&[], &[1, 2, 3]), 0.0);
+    }
+}
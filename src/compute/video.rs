@@ -1,4 +1,5 @@
 use crate::log::{Job, LogPipe};
+use crate::pipeline::{Stage, StageFuture};
 use crate::queue::{Receiver, Sender, VideoUpdate};
 
 /// this function receives video frames from
@@ -7,9 +8,53 @@ use crate::queue::{Receiver, Sender, VideoUpdate};
 /// to noise ratio and make movement
 /// more perceptable
 pub async fn start(
-    video_from_camera: Receiver<VideoUpdate>,
+    mut video_from_camera: Receiver<VideoUpdate>,
     video_to_storage: Sender<VideoUpdate>,
     video_compute_log: LogPipe,
 ) {
-    video_compute_log.info("started processing video", Job::VideoCompute);
+    crate::info!(
+        video_compute_log,
+        Job::VideoCompute,
+        "started processing video"
+    );
+    while let Ok(update) = video_from_camera.dequeue().await {
+        // denoising, sharpening and overexposing will
+        // happen here, for now the frame passes through
+        if let Err((_, err)) = video_to_storage.enqueue_waiting(update).await {
+            crate::error!(
+                video_compute_log,
+                Job::VideoCompute,
+                "failed to pass video update on from compute: {:?}",
+                err
+            );
+        }
+    }
+    crate::warn!(
+        video_compute_log,
+        Job::VideoCompute,
+        "video compute queue closed"
+    );
+}
+
+/// this is the video compute step of the pipeline
+#[derive(Clone)]
+pub struct VideoCompute;
+
+impl Stage for VideoCompute {
+    type Input = VideoUpdate;
+    type Output = VideoUpdate;
+    const NAME: &'static str = "video compute";
+
+    fn job(&self) -> Job {
+        Job::VideoCompute
+    }
+
+    fn run(
+        self,
+        input: Receiver<VideoUpdate>,
+        output: Sender<VideoUpdate>,
+        log: LogPipe,
+    ) -> StageFuture {
+        std::boxed::Box::pin(start(input, output, log))
+    }
 }
@@ -0,0 +1,875 @@
+/// This is where the recorder's settings come from. They
+/// are read from a TOML file, camera.toml unless the
+/// CAMERA_CONFIG environment variable names another, with
+/// a section for each part of the recorder: [audio],
+/// [[cameras]], [compute], [storage], [log], [ui],
+/// [control] and [http]. Every
+/// key has a default, so a missing file, section or key
+/// leaves the recorder as it was before there was a file,
+/// but a key that is unknown, of the wrong type, or out of
+/// range stops the recorder before it starts, with the
+/// file, line and key named in the error. Each section is
+/// read into its own struct, which main hands to the start
+/// function of the part it describes. While recording, the
+/// file is watched, and a file that changes and still reads
+/// is handed to control::Settings, a file that no longer
+/// reads is reported and the running settings are kept
+pub mod toml;
+
+use self::toml::{Entry, Table, Value};
+use crate::log::filter::LogFilter;
+use crate::log::LogFormat;
+use crate::queue::QueueSpec;
+
+use anyhow::{anyhow, bail, Result};
+
+use std::format;
+use std::string::{String, ToString};
+use std::time::SystemTime;
+use std::vec::Vec;
+
+/// the file read when CAMERA_CONFIG is not set
+pub const DEFAULT_PATH: &str = "camera.toml";
+
+/// every setting of the recorder
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub audio: AudioConfig,
+    pub cameras: Vec<CameraConfig>,
+    pub compute: ComputeConfig,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+    pub ui: UiConfig,
+    pub control: ControlConfig,
+    pub http: HttpConfig,
+}
+
+/// the [audio] section, for the microphone
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioConfig {
+    /// part of the name of the input device to record
+    /// from, None for the sound server's default
+    pub device: Option<String>,
+    /// how many chunks of audio each audio queue holds
+    pub queue_size: usize,
+}
+
+/// one [[cameras]] entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraConfig {
+    /// the name the camera is logged and recorded under
+    pub name: String,
+    /// the device node, e.g. /dev/video0
+    pub device: String,
+    pub width: u32,
+    pub height: u32,
+    /// frames per second asked of the camera
+    pub fps: u32,
+}
+
+/// the [compute] section, for the video and motion steps
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComputeConfig {
+    /// the mean difference per byte, out of 255, that
+    /// counts as movement between two frames
+    pub motion_threshold: f32,
+    /// how many frames each video queue holds
+    pub video_queue_size: usize,
+}
+
+/// the [storage] section, for where recordings go
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageConfig {
+    /// the directory recordings are written to on
+    /// the main disk
+    pub directory: String,
+    /// the hours of recording the main disk must have
+    /// room for before the UI shows it as nearly full
+    pub main_reserve_hours: u32,
+    /// the same for removable storage
+    pub removable_reserve_hours: u32,
+    /// each recording is cut into files this many minutes
+    /// long, starting on the hour
+    pub segment_minutes: u32,
+    /// recordings older than this are deleted, zero keeps
+    /// every recording until the disk is full
+    pub retention_hours: u32,
+    /// the seconds before a bookmark that are kept for it,
+    /// and held in memory while recording is stopped
+    pub pre_roll_seconds: u32,
+    /// the seconds after a bookmark that are kept for it,
+    /// and recorded even while recording is stopped
+    pub post_roll_seconds: u32,
+    /// the table of mounted filesystems, read for
+    /// removable storage
+    pub mount_table: String,
+    /// removable storage is mounted under this directory,
+    /// and recorded to while it is, empty never records
+    /// to removable storage
+    pub removable_root: String,
+}
+
+/// the [log] section
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    /// the text log file
+    pub file: String,
+    /// the JSON lines file, written when the format
+    /// asks for JSON lines
+    pub json_file: String,
+    pub format: LogFormat,
+    /// which updates are kept, see LogFilter::parse
+    pub filter: LogFilter,
+    /// print each step of setting up the log pipe to
+    /// stdout, for when the log itself is what is broken
+    pub trace_setup: bool,
+    /// how many updates each log queue holds
+    pub queue_size: usize,
+}
+
+/// the [ui] section
+#[derive(Debug, Clone, PartialEq)]
+pub struct UiConfig {
+    /// show the viewfinder rather than only the status
+    pub viewfinder: bool,
+    /// how many updates the UI queue holds
+    pub queue_size: usize,
+    pub colors: StatusColors,
+}
+
+/// the [control] section, for the control socket
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlConfig {
+    /// the unix socket the recorder answers on, empty
+    /// for no socket
+    pub socket: String,
+}
+
+/// the [http] section, for the status page and preview
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    /// the address and port the status page is served
+    /// on, e.g. 0.0.0.0:8080, empty for no server
+    pub address: String,
+}
+
+/// the color the screen is filled with in each state,
+/// as 0xRRGGBB, written "#rrggbb" in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusColors {
+    /// recording from the secondary camera
+    pub secondary_camera: u32,
+    /// recording from the webcam, no secondary camera
+    pub main_camera: u32,
+    /// recording to removable storage
+    pub removable: u32,
+    /// removable storage has less than an hour left
+    pub removable_near_full: u32,
+    /// the main disk has less than a day left
+    pub main_near_full: u32,
+    /// a disk we record to is full
+    pub full: u32,
+}
+
+impl Default for StatusColors {
+    fn default() -> StatusColors {
+        StatusColors {
+            secondary_camera: 0x0000ff,
+            main_camera: 0x808080,
+            removable: 0x00ff00,
+            removable_near_full: 0xffff00,
+            main_near_full: 0xffa500,
+            full: 0xff0000,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            audio: AudioConfig {
+                device: None,
+                queue_size: crate::queue::AUDIO_IN.size,
+            },
+            cameras: std::vec![CameraConfig::default()],
+            compute: ComputeConfig {
+                motion_threshold: 12.0,
+                video_queue_size: crate::queue::VIDEO_IN.size,
+            },
+            storage: StorageConfig {
+                directory: ".".to_string(),
+                main_reserve_hours: 24,
+                removable_reserve_hours: 1,
+                segment_minutes: 10,
+                retention_hours: 0,
+                pre_roll_seconds: 30,
+                post_roll_seconds: 60,
+                mount_table: "/proc/self/mounts".to_string(),
+                removable_root: "/media".to_string(),
+            },
+            log: LogConfig {
+                file: "main_log.txt".to_string(),
+                json_file: "main_log.jsonl".to_string(),
+                format: LogFormat::Text,
+                filter: LogFilter::default(),
+                trace_setup: false,
+                queue_size: crate::queue::LOG_STORAGE.size,
+            },
+            ui: UiConfig {
+                viewfinder: true,
+                queue_size: crate::queue::VIEW_OUT.size,
+                colors: StatusColors::default(),
+            },
+            control: ControlConfig {
+                socket: "camera.sock".to_string(),
+            },
+            http: HttpConfig {
+                address: String::new(),
+            },
+        }
+    }
+}
+
+impl Default for CameraConfig {
+    /// the webcam above the laptop screen
+    fn default() -> CameraConfig {
+        CameraConfig {
+            name: "main".to_string(),
+            device: "/dev/video0".to_string(),
+            width: 1280,
+            height: 720,
+            fps: 30,
+        }
+    }
+}
+
+impl Config {
+    /// this function reads the file named by CAMERA_CONFIG,
+    /// or camera.toml, using the defaults when camera.toml
+    /// does not exist, a file that was asked for by name
+    /// must exist
+    pub fn from_env() -> Result<Config> {
+        match std::env::var("CAMERA_CONFIG") {
+            Ok(path) => Config::load(&path),
+            Err(_) if !std::path::Path::new(DEFAULT_PATH).exists() => {
+                Ok(Config::default())
+            }
+            Err(_) => Config::load(DEFAULT_PATH),
+        }
+    }
+
+    /// this function reads and checks one file
+    pub fn load(path: &str) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("{}: {}", path, err))?;
+        Config::parse(&text).map_err(|err| anyhow!("{}: {}", path, err))
+    }
+
+    /// this function reads and checks the text of a file
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut config = Config::default();
+        let mut cameras = Vec::new();
+        for table in toml::parse(text)? {
+            match (table.name.as_str(), table.repeated) {
+                ("", _) => Section::new(&table).finish()?,
+                ("audio", false) => config.audio.read(&table)?,
+                ("cameras", true) => {
+                    let mut camera = CameraConfig::default();
+                    camera.read(&table)?;
+                    cameras.push(camera);
+                }
+                ("cameras", false) => bail!(
+                    "line {}: cameras is an array of tables, write [[cameras]]",
+                    table.line
+                ),
+                ("compute", false) => config.compute.read(&table)?,
+                ("storage", false) => config.storage.read(&table)?,
+                ("log", false) => config.log.read(&table)?,
+                ("ui", false) => config.ui.read(&table)?,
+                ("control", false) => config.control.read(&table)?,
+                ("http", false) => config.http.read(&table)?,
+                (name, _) => bail!(
+                    "line {}: unknown section [{}], expected one of \
+                     [audio], [[cameras]], [compute], [storage], [log], \
+                     [ui], [control], [http]",
+                    table.line,
+                    name
+                ),
+            }
+        }
+        if !cameras.is_empty() {
+            config.cameras = cameras;
+        }
+        for (index, camera) in config.cameras.iter().enumerate() {
+            if config.cameras[..index]
+                .iter()
+                .any(|earlier| earlier.name == camera.name)
+            {
+                bail!("two cameras are named {}", camera.name);
+            }
+        }
+        Ok(config)
+    }
+
+    /// the audio queues, sized from the [audio] section
+    pub fn audio_queue(&self, spec: QueueSpec) -> QueueSpec {
+        spec.with_size(self.audio.queue_size)
+    }
+
+    /// the video queues, sized from the [compute] section
+    pub fn video_queue(&self, spec: QueueSpec) -> QueueSpec {
+        spec.with_size(self.compute.video_queue_size)
+    }
+
+    /// this function names the file that is read, `given`
+    /// on the command line, else CAMERA_CONFIG, else
+    /// camera.toml
+    pub fn path(given: Option<&str>) -> String {
+        match given {
+            Some(path) => path.to_string(),
+            None => std::env::var("CAMERA_CONFIG")
+                .unwrap_or_else(|_| DEFAULT_PATH.to_string()),
+        }
+    }
+}
+
+/// this notices when the file is written again
+pub struct Watcher {
+    path: String,
+    /// when the file was last changed and how long it was,
+    /// None while it does not exist, the length catches a
+    /// write too soon after the last for the time to move
+    modified: Option<(SystemTime, u64)>,
+}
+
+impl Watcher {
+    /// this function starts watching `path` as it is now,
+    /// which should be the file the running settings came from
+    pub fn new(path: String) -> Watcher {
+        let modified = modified(&path);
+        Watcher { path, modified }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// this function reads the file again if it has changed
+    /// since it was last read, a file that is removed is
+    /// not a change, the settings it gave are kept
+    pub fn changed(&mut self) -> Option<Result<Config>> {
+        let modified = modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        Some(Config::load(&self.path))
+    }
+}
+
+fn modified(path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+impl AudioConfig {
+    fn read(&mut self, table: &Table) -> Result<()> {
+        let mut section = Section::new(table);
+        if let Some(device) = section.string("device")? {
+            self.device = Some(device);
+        }
+        section.queue_size("queue_size", &mut self.queue_size)?;
+        section.finish()
+    }
+}
+
+impl CameraConfig {
+    fn read(&mut self, table: &Table) -> Result<()> {
+        let mut section = Section::new(table);
+        if let Some(name) = section.string("name")? {
+            self.name = name;
+        }
+        if let Some(device) = section.string("device")? {
+            self.device = device;
+        }
+        section.number("width", 1..=7680, &mut self.width)?;
+        section.number("height", 1..=4320, &mut self.height)?;
+        section.number("fps", 1..=240, &mut self.fps)?;
+        section.finish()
+    }
+}
+
+impl ComputeConfig {
+    fn read(&mut self, table: &Table) -> Result<()> {
+        let mut section = Section::new(table);
+        if let Some((threshold, line)) = section.float("motion_threshold")? {
+            if !(0.0..=255.0).contains(&threshold) {
+                bail!(
+                    "line {}: [compute] motion_threshold must be between \
+                     0 and 255, found {}",
+                    line,
+                    threshold
+                );
+            }
+            self.motion_threshold = threshold as f32;
+        }
+        section.queue_size("video_queue_size", &mut self.video_queue_size)?;
+        section.finish()
+    }
+}
+
+impl StorageConfig {
+    fn read(&mut self, table: &Table) -> Result<()> {
+        let mut section = Section::new(table);
+        if let Some(directory) = section.string("directory")? {
+            self.directory = directory;
+        }
+        section.number(
+            "main_reserve_hours",
+            0..=24 * 365,
+            &mut self.main_reserve_hours,
+        )?;
+        section.number(
+            "removable_reserve_hours",
+            0..=24 * 365,
+            &mut self.removable_reserve_hours,
+        )?;
+        section.number(
+            "segment_minutes",
+            1..=24 * 60,
+            &mut self.segment_minutes,
+        )?;
+        section.number(
+            "retention_hours",
+            0..=24 * 365 * 10,
+            &mut self.retention_hours,
+        )?;
+        section.number(
+            "pre_roll_seconds",
+            0..=3600,
+            &mut self.pre_roll_seconds,
+        )?;
+        section.number(
+            "post_roll_seconds",
+            0..=3600,
+            &mut self.post_roll_seconds,
+        )?;
+        if let Some(mount_table) = section.string("mount_table")? {
+            self.mount_table = mount_table;
+        }
+        if let Some(removable_root) = section.string("removable_root")? {
+            self.removable_root = removable_root;
+        }
+        section.finish()
+    }
+}
+
+impl LogConfig {
+    fn read(&mut self, table: &Table) -> Result<()> {
+        let mut section = Section::new(table);
+        if let Some(file) = section.string("file")? {
+            self.file = file;
+        }
+        if let Some(json_file) = section.string("json_file")? {
+            self.json_file = json_file;
+        }
+        if let Some(format) = section.string("format")? {
+            self.format = match format.as_str() {
+                "text" => LogFormat::Text,
+                "jsonl" => LogFormat::TextAndJsonLines,
+                other => bail!(
+                    "line {}: [log] format must be \"text\" or \"jsonl\", \
+                     found {}",
+                    section.line_of("format"),
+                    toml::quote(other)
+                ),
+            };
+        }
+        if let Some(filter) = section.string("filter")? {
+            self.filter = LogFilter::parse(&filter).map_err(|err| {
+                anyhow!(
+                    "line {}: [log] filter: {}",
+                    section.line_of("filter"),
+                    err
+                )
+            })?;
+        }
+        if let Some(trace_setup) = section.boolean("trace_setup")? {
+            self.trace_setup = trace_setup;
+        }
+        section.queue_size("queue_size", &mut self.queue_size)?;
+        section.finish()
+    }
+}
+
+impl UiConfig {
+    fn read(&mut self, table: &Table) -> Result<()> {
+        let mut section = Section::new(table);
+        if let Some(viewfinder) = section.boolean("viewfinder")? {
+            self.viewfinder = viewfinder;
+        }
+        section.queue_size("queue_size", &mut self.queue_size)?;
+        let colors = &mut self.colors;
+        section.color("secondary_camera_color", &mut colors.secondary_camera)?;
+        section.color("main_camera_color", &mut colors.main_camera)?;
+        section.color("removable_color", &mut colors.removable)?;
+        section.color(
+            "removable_near_full_color",
+            &mut colors.removable_near_full,
+        )?;
+        section.color("main_near_full_color", &mut colors.main_near_full)?;
+        section.color("full_color", &mut colors.full)?;
+        section.finish()
+    }
+}
+
+impl ControlConfig {
+    fn read(&mut self, table: &Table) -> Result<()> {
+        let mut section = Section::new(table);
+        if let Some(socket) = section.string("socket")? {
+            self.socket = socket;
+        }
+        section.finish()
+    }
+}
+
+impl HttpConfig {
+    fn read(&mut self, table: &Table) -> Result<()> {
+        let mut section = Section::new(table);
+        if let Some(address) = section.string("address")? {
+            self.address = address;
+        }
+        section.finish()
+    }
+}
+
+/// the most updates any one queue may be configured to hold
+const MAX_QUEUE_SIZE: i64 = 1 << 20;
+
+/// this reads the keys of one table, remembering which
+/// were read, so that any left over can be reported
+struct Section<'table> {
+    table: &'table Table,
+    read: Vec<bool>,
+}
+
+impl<'table> Section<'table> {
+    fn new(table: &'table Table) -> Section<'table> {
+        Section {
+            table,
+            read: std::vec![false; table.entries.len()],
+        }
+    }
+
+    /// how the section is written in error messages
+    fn header(&self) -> String {
+        match (self.table.name.as_str(), self.table.repeated) {
+            ("", _) => "the top of the file".to_string(),
+            (name, true) => format!("[[{}]]", name),
+            (name, false) => format!("[{}]", name),
+        }
+    }
+
+    fn entry(&mut self, key: &str) -> Option<&'table Entry> {
+        let index = self
+            .table
+            .entries
+            .iter()
+            .position(|entry| entry.key == key)?;
+        self.read[index] = true;
+        Some(&self.table.entries[index])
+    }
+
+    fn line_of(&self, key: &str) -> usize {
+        self.table
+            .entries
+            .iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.line)
+            .unwrap_or(self.table.line)
+    }
+
+    fn wrong_type(&self, entry: &Entry, expected: &str) -> anyhow::Error {
+        anyhow!(
+            "line {}: {} {} must be {}, found {}",
+            entry.line,
+            self.header(),
+            entry.key,
+            expected,
+            entry.value.kind()
+        )
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>> {
+        match self.entry(key) {
+            None => Ok(None),
+            Some(Entry {
+                value: Value::String(string),
+                ..
+            }) => Ok(Some(string.clone())),
+            Some(entry) => Err(self.wrong_type(entry, "a string")),
+        }
+    }
+
+    fn boolean(&mut self, key: &str) -> Result<Option<bool>> {
+        match self.entry(key) {
+            None => Ok(None),
+            Some(Entry {
+                value: Value::Boolean(boolean),
+                ..
+            }) => Ok(Some(*boolean)),
+            Some(entry) => Err(self.wrong_type(entry, "true or false")),
+        }
+    }
+
+    /// a float, or an integer written without the point,
+    /// with the line it was on
+    fn float(&mut self, key: &str) -> Result<Option<(f64, usize)>> {
+        match self.entry(key) {
+            None => Ok(None),
+            Some(Entry {
+                value: Value::Float(float),
+                line,
+                ..
+            }) => Ok(Some((*float, *line))),
+            Some(Entry {
+                value: Value::Integer(integer),
+                line,
+                ..
+            }) => Ok(Some((*integer as f64, *line))),
+            Some(entry) => Err(self.wrong_type(entry, "a number")),
+        }
+    }
+
+    /// this function sets `value` from an integer key,
+    /// which must be within `range`
+    fn number<T>(
+        &mut self,
+        key: &str,
+        range: core::ops::RangeInclusive<i64>,
+        value: &mut T,
+    ) -> Result<()>
+    where
+        T: TryFrom<i64>,
+    {
+        let entry = match self.entry(key) {
+            None => return Ok(()),
+            Some(entry) => entry,
+        };
+        let integer = match entry.value {
+            Value::Integer(integer) => integer,
+            _ => return Err(self.wrong_type(entry, "an integer")),
+        };
+        if !range.contains(&integer) {
+            bail!(
+                "line {}: {} {} must be from {} to {}, found {}",
+                entry.line,
+                self.header(),
+                key,
+                range.start(),
+                range.end(),
+                toml::written(&entry.value)
+            );
+        }
+        *value = T::try_from(integer).map_err(|_| {
+            anyhow!("line {}: {} is too large", entry.line, key)
+        })?;
+        Ok(())
+    }
+
+    fn queue_size(&mut self, key: &str, value: &mut usize) -> Result<()> {
+        self.number(key, 1..=MAX_QUEUE_SIZE, value)
+    }
+
+    /// this function sets `value` from a "#rrggbb" string
+    fn color(&mut self, key: &str, value: &mut u32) -> Result<()> {
+        let written = match self.string(key)? {
+            Some(written) => written,
+            None => return Ok(()),
+        };
+        let hex = written.strip_prefix('#').filter(|hex| {
+            hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit())
+        });
+        match hex {
+            Some(hex) => {
+                *value = u32::from_str_radix(hex, 16).expect("six hex digits");
+                Ok(())
+            }
+            None => bail!(
+                "line {}: {} {} must be written \"#rrggbb\", found {}",
+                self.line_of(key),
+                self.header(),
+                key,
+                toml::quote(&written)
+            ),
+        }
+    }
+
+    /// this function fails on the first key that was
+    /// never read, which is a key we do not know
+    fn finish(self) -> Result<()> {
+        match self.read.iter().position(|read| !read) {
+            None => Ok(()),
+            Some(index) => {
+                let entry = &self.table.entries[index];
+                bail!(
+                    "line {}: unknown key {} in {}",
+                    entry.line,
+                    entry.key,
+                    self.header()
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::{Job, Level};
+
+    #[test]
+    fn an_empty_file_is_the_defaults() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert_eq!(Config::parse("# nothing\n").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn the_example_file_is_the_defaults() {
+        let example = include_str!("../camera.example.toml");
+        assert_eq!(Config::parse(example).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn reads_every_section() {
+        let config = Config::parse(
+            "[audio]\n\
+             device = \"USB\"\n\
+             queue_size = 600\n\
+             [[cameras]]\n\
+             name = \"door\"\n\
+             device = \"/dev/video2\"\n\
+             fps = 15\n\
+             [[cameras]]\n\
+             name = \"yard\"\n\
+             [compute]\n\
+             motion_threshold = 20\n\
+             video_queue_size = 900\n\
+             [storage]\n\
+             directory = \"/var/recordings\"\n\
+             main_reserve_hours = 48\n\
+             segment_minutes = 5\n\
+             retention_hours = 72\n\
+             pre_roll_seconds = 10\n\
+             removable_root = \"\"\n\
+             [log]\n\
+             file = \"recorder.log\"\n\
+             format = \"jsonl\"\n\
+             filter = \"warn,AudioInput=debug\"\n\
+             trace_setup = true\n\
+             [ui]\n\
+             viewfinder = false\n\
+             full_color = \"#AA0000\"\n\
+             [control]\n\
+             socket = \"/run/camera.sock\"\n\
+             [http]\n\
+             address = \"0.0.0.0:8080\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.audio.device.as_deref(), Some("USB"));
+        assert_eq!(config.audio.queue_size, 600);
+        assert_eq!(config.cameras.len(), 2);
+        assert_eq!(config.cameras[0].device, "/dev/video2");
+        assert_eq!(config.cameras[0].fps, 15);
+        // keys left out of a camera take the defaults
+        assert_eq!(config.cameras[1].width, 1280);
+        assert_eq!(config.compute.motion_threshold, 20.0);
+        assert_eq!(config.video_queue(crate::queue::VIDEO_STORAGE).size, 900);
+        assert_eq!(config.storage.main_reserve_hours, 48);
+        assert_eq!(config.storage.removable_reserve_hours, 1);
+        assert_eq!(config.storage.segment_minutes, 5);
+        assert_eq!(config.storage.retention_hours, 72);
+        assert_eq!(config.storage.pre_roll_seconds, 10);
+        assert_eq!(config.storage.post_roll_seconds, 60);
+        assert_eq!(config.storage.mount_table, "/proc/self/mounts");
+        assert_eq!(config.storage.removable_root, "");
+        assert_eq!(config.log.file, "recorder.log");
+        assert_eq!(config.log.json_file, "main_log.jsonl");
+        assert_eq!(config.log.format, LogFormat::TextAndJsonLines);
+        assert!(config.log.filter.allows(Level::Debug, &Job::AudioInput));
+        assert!(!config.log.filter.allows(Level::Info, &Job::Main));
+        assert!(config.log.trace_setup);
+        assert!(!config.ui.viewfinder);
+        assert_eq!(config.ui.colors.full, 0xaa0000);
+        assert_eq!(config.ui.colors.removable, 0x00ff00);
+        assert_eq!(config.control.socket, "/run/camera.sock");
+        assert_eq!(config.http.address, "0.0.0.0:8080");
+    }
+
+    #[test]
+    fn the_watcher_reads_a_file_once_it_changes() {
+        let path = std::env::temp_dir()
+            .join(format!("watched_{}.toml", std::process::id()));
+        let path = path.display().to_string();
+        let _ = std::fs::remove_file(&path);
+        let mut watcher = Watcher::new(path.clone());
+        assert!(watcher.changed().is_none());
+
+        std::fs::write(&path, "[compute]\nmotion_threshold = 30\n").unwrap();
+        let reloaded = watcher.changed().unwrap().unwrap();
+        assert_eq!(reloaded.compute.motion_threshold, 30.0);
+        assert!(watcher.changed().is_none());
+
+        std::fs::write(&path, "[compute]\nmotion_threshold = \"x\"\n").unwrap();
+        assert!(watcher.changed().unwrap().is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(watcher.changed().is_none());
+    }
+
+    #[test]
+    fn explains_what_is_wrong_and_where() {
+        let error = |text: &str| Config::parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("[audio]\nqueue = 5"),
+            "line 2: unknown key queue in [audio]"
+        );
+        assert_eq!(
+            error("[audio]\nqueue_size = \"big\""),
+            "line 2: [audio] queue_size must be an integer, found a string"
+        );
+        assert_eq!(
+            error("[ui]\nqueue_size = 0"),
+            "line 2: [ui] queue_size must be from 1 to 1048576, found 0"
+        );
+        assert_eq!(
+            error("[[cameras]]\nname = \"a\"\nfps = 1000"),
+            "line 3: [[cameras]] fps must be from 1 to 240, found 1000"
+        );
+        assert_eq!(
+            error("[video]\n"),
+            "line 1: unknown section [video], expected one of [audio], \
+             [[cameras]], [compute], [storage], [log], [ui], [control], \
+             [http]"
+        );
+        assert_eq!(
+            error("[ui]\nfull_color = \"red\""),
+            "line 2: [ui] full_color must be written \"#rrggbb\", found \"red\""
+        );
+        assert_eq!(
+            error("[log]\n\nformat = \"xml\""),
+            "line 3: [log] format must be \"text\" or \"jsonl\", found \"xml\""
+        );
+        assert_eq!(
+            error("[log]\nfilter = \"loud\""),
+            "line 2: [log] filter: unknown log level: loud"
+        );
+        assert_eq!(
+            error("[[cameras]]\n[[cameras]]\n"),
+            "two cameras are named main"
+        );
+        assert_eq!(
+            error("[cameras]\n"),
+            "line 1: cameras is an array of tables, write [[cameras]]"
+        );
+    }
+}
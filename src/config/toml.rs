@@ -0,0 +1,410 @@
+/// This is where the configuration file is read. Only the
+/// part of TOML the configuration needs is understood:
+/// [tables], [[arrays of tables]], and keys set to a
+/// string, an integer, a float, a boolean, or an array of
+/// those on one line, with # comments anywhere outside a
+/// string. Anything else is an error naming the line it is
+/// on, rather than something skipped, so a typo can never
+/// quietly leave a default in place
+use anyhow::{anyhow, bail, Result};
+
+use std::format;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// one value on the right of an `=`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// what the value is, for error messages
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// one `key = value` line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    /// the line it was on, counting from one
+    pub line: usize,
+}
+
+/// the keys under one [table] or [[table]] header,
+/// the keys before any header are in a table named ""
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub name: String,
+    /// true for a [[table]], which may be repeated
+    pub repeated: bool,
+    /// the line of the header, zero for the top table
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+/// this function reads a whole file into its tables,
+/// in the order they were written
+pub fn parse(text: &str) -> Result<Vec<Table>> {
+    let mut tables = std::vec![Table {
+        name: String::new(),
+        repeated: false,
+        line: 0,
+        entries: Vec::new(),
+    }];
+    for (index, raw) in text.lines().enumerate() {
+        let line = index + 1;
+        parse_line(raw, line, &mut tables)
+            .map_err(|err| anyhow!("line {}: {}", line, err))?;
+    }
+    Ok(tables)
+}
+
+fn parse_line(raw: &str, line: usize, tables: &mut Vec<Table>) -> Result<()> {
+    let mut parser = Parser {
+        chars: raw.chars().collect(),
+        position: 0,
+    };
+    parser.skip_blank();
+    match parser.peek() {
+        None | Some('#') => return Ok(()),
+        Some('[') => {
+            parser.position += 1;
+            let repeated = parser.peek() == Some('[');
+            if repeated {
+                parser.position += 1;
+            }
+            parser.skip_blank();
+            let name = parser.bare_key()?;
+            parser.skip_blank();
+            parser.expect(']')?;
+            if repeated {
+                parser.expect(']')?;
+            }
+            parser.end()?;
+            if let Some(earlier) =
+                tables.iter().find(|table| table.name == name)
+            {
+                if !(repeated && earlier.repeated) {
+                    bail!(
+                        "[{}] was already given on line {}",
+                        name,
+                        earlier.line
+                    );
+                }
+            }
+            tables.push(Table {
+                name,
+                repeated,
+                line,
+                entries: Vec::new(),
+            });
+        }
+        Some(_) => {
+            let key = parser.bare_key()?;
+            parser.skip_blank();
+            if parser.peek() == Some('.') {
+                bail!("dotted keys are not supported, use a [table]");
+            }
+            parser.expect('=')?;
+            parser.skip_blank();
+            let value = parser.value()?;
+            parser.end()?;
+            let table = tables.last_mut().expect("there is always a table");
+            if let Some(earlier) =
+                table.entries.iter().find(|entry| entry.key == key)
+            {
+                bail!("{} was already set on line {}", key, earlier.line);
+            }
+            table.entries.push(Entry { key, value, line });
+        }
+    }
+    Ok(())
+}
+
+/// this walks the characters of one line
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let next = self.peek().ok_or(anyhow!("the line ended early"))?;
+        self.position += 1;
+        Ok(next)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(found) if found == expected => {
+                self.position += 1;
+                Ok(())
+            }
+            Some(found) => {
+                bail!("expected '{}' but found '{}'", expected, found)
+            }
+            None => bail!("expected '{}' but the line ended", expected),
+        }
+    }
+
+    fn skip_blank(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.position += 1;
+        }
+    }
+
+    /// only blanks or a comment may follow a value or header
+    fn end(&mut self) -> Result<()> {
+        self.skip_blank();
+        match self.peek() {
+            None | Some('#') => Ok(()),
+            Some(found) => bail!("unexpected '{}' after the value", found),
+        }
+    }
+
+    fn bare_key(&mut self) -> Result<String> {
+        let start = self.position;
+        while matches!(
+            self.peek(),
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-'
+        ) {
+            self.position += 1;
+        }
+        if start == self.position {
+            match self.peek() {
+                Some(found) => bail!("expected a name but found '{}'", found),
+                None => bail!("expected a name but the line ended"),
+            }
+        }
+        Ok(self.chars[start..self.position].iter().collect())
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.basic_string()?)),
+            Some('\'') => Ok(Value::String(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let word = self.bare_key()?;
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    other => {
+                        bail!("{} is not a value, strings need quotes", other)
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => {
+                self.number()
+            }
+            Some(found) => bail!("expected a value but found '{}'", found),
+            None => bail!("expected a value but the line ended"),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next().map_err(|_| anyhow!("unterminated string"))? {
+                '"' => return Ok(string),
+                '\\' => match self.next()? {
+                    '"' => string.push('"'),
+                    '\\' => string.push('\\'),
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    other => bail!("unknown escape \\{}", other),
+                },
+                other => string.push(other),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        self.expect('\'')?;
+        let mut string = String::new();
+        loop {
+            match self.next().map_err(|_| anyhow!("unterminated string"))? {
+                '\'' => return Ok(string),
+                other => string.push(other),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value> {
+        let start = self.position;
+        while matches!(
+            self.peek(),
+            Some(c) if c.is_ascii_alphanumeric() || "+-._".contains(c)
+        ) {
+            self.position += 1;
+        }
+        let written: String = self.chars[start..self.position].iter().collect();
+        let digits: String = written.chars().filter(|c| *c != '_').collect();
+        if let Ok(integer) = digits.parse::<i64>() {
+            return Ok(Value::Integer(integer));
+        }
+        match digits.parse::<f64>() {
+            Ok(float) if float.is_finite() => Ok(Value::Float(float)),
+            _ => Err(anyhow!("{} is not a number", written)),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.position += 1;
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank();
+            match self.next().map_err(|_| anyhow!("unterminated array"))? {
+                ',' => continue,
+                ']' => return Ok(Value::Array(values)),
+                other => bail!("expected ',' or ']' but found '{}'", other),
+            }
+        }
+    }
+}
+
+/// this function writes a string the way parse reads it
+pub fn quote(unquoted: &str) -> String {
+    let mut quoted = String::with_capacity(unquoted.len() + 2);
+    quoted.push('"');
+    for character in unquoted.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            other => quoted.push(other),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// this function names a value the way it is written
+pub fn written(value: &Value) -> String {
+    match value {
+        Value::String(string) => quote(string),
+        Value::Integer(integer) => integer.to_string(),
+        Value::Float(float) => format!("{:?}", float),
+        Value::Boolean(boolean) => boolean.to_string(),
+        Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(written).collect();
+            format!("[{}]", values.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_tables_values_and_comments() {
+        let tables = parse(
+            "# the recorder\n\
+             name = 'front door' # trailing comment\n\
+             \n\
+             [audio]\n\
+             device = \"USB # Mic \\\"2\\\"\"\n\
+             queue_size = 3_600\n\
+             gain = -1.5\n\
+             enabled = true\n\
+             [[cameras]]\n\
+             sizes = [640, 1280]\n\
+             [[cameras]]\n",
+        )
+        .unwrap();
+        assert_eq!(tables.len(), 4);
+        assert_eq!(
+            tables[0].entries[0].value,
+            Value::String("front door".into())
+        );
+        let audio = &tables[1];
+        assert_eq!(audio.name, "audio");
+        assert_eq!(audio.line, 4);
+        assert_eq!(
+            audio
+                .entries
+                .iter()
+                .map(|entry| &entry.value)
+                .collect::<Vec<_>>(),
+            std::vec![
+                &Value::String("USB # Mic \"2\"".into()),
+                &Value::Integer(3600),
+                &Value::Float(-1.5),
+                &Value::Boolean(true),
+            ]
+        );
+        assert!(tables[2].repeated && tables[3].repeated);
+        assert_eq!(
+            tables[2].entries[0].value,
+            Value::Array(std::vec![Value::Integer(640), Value::Integer(1280)])
+        );
+    }
+
+    #[test]
+    fn names_the_line_of_each_mistake() {
+        let error = |text: &str| parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("[audio]\ndevice = default"),
+            "line 2: default is not a value, strings need quotes"
+        );
+        assert_eq!(
+            error("[log]\n[log]"),
+            "line 2: [log] was already given on line 1"
+        );
+        assert_eq!(
+            error("a = 1\na = 2"),
+            "line 2: a was already set on line 1"
+        );
+        assert_eq!(
+            error("audio.device = \"x\""),
+            "line 1: dotted keys are not supported, use a [table]"
+        );
+        assert_eq!(error("a = \"open"), "line 1: unterminated string");
+        assert_eq!(error("a = 1 2"), "line 1: unexpected '2' after the value");
+    }
+
+    #[test]
+    fn written_values_read_back() {
+        let values = std::vec![
+            Value::String("a \"quoted\"\\ path".into()),
+            Value::Integer(-7),
+            Value::Float(0.25),
+            Value::Boolean(false),
+            Value::Array(std::vec![Value::Integer(1), Value::Float(2.0)]),
+        ];
+        for value in values {
+            let line = format!("key = {}", written(&value));
+            let tables = parse(&line).unwrap();
+            assert_eq!(tables[0].entries[0].value, value);
+        }
+    }
+}
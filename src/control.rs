@@ -0,0 +1,488 @@
+/// This is where changes reach the running recorder. Each
+/// part of the recorder holds a Setting for its section of
+/// the configuration, and Settings holds one of each, so a
+/// configuration that is read again while recording can be
+/// compared section by section and each change handed only
+/// to the part it is for. A part picks up its change where
+/// it is safe to: motion detection and the UI on their next
+/// update, the log filter at once, storage at its next
+/// update, and capture, which has to close and open its
+/// device, at the next segment boundary so no file is cut
+/// short. Queue sizes cannot change while their queues are
+/// open, so those are reported and wait for a restart.
+/// Controls are changed the same way, by the control socket
+/// rather than the file, for what is asked of the recorder
+/// while it runs, and carry back what the socket reports
+pub mod socket;
+
+use crate::config::{
+    AudioConfig, CameraConfig, ComputeConfig, Config, ControlConfig,
+    HttpConfig, LogConfig, StorageConfig, UiConfig,
+};
+use crate::hardware::storage::failure::Failed;
+use crate::hardware::storage::{MainStorage, RemovableStorage};
+use crate::log::{Job, LogPipe};
+use crate::queue::telemetry::QueueSample;
+use crate::queue::VideoUpdate;
+use crate::recordings::catalog::{self, Bookmark};
+use crate::ui::Status;
+
+use anyhow::Result;
+
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use core::time::Duration;
+
+use std::string::String;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::vec::Vec;
+
+/// one section of the configuration, shared by whoever
+/// changes it and the task it is for, each clone notices
+/// each change once
+pub struct Setting<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    /// the version this clone last returned
+    seen: u64,
+}
+
+struct Shared<T> {
+    value: T,
+    /// counts the changes since the setting was made
+    version: u64,
+    /// the tasks waiting in changed
+    waiting: Vec<Waker>,
+}
+
+impl<T: Clone> Setting<T> {
+    pub fn new(value: T) -> Setting<T> {
+        Setting {
+            shared: Arc::new(Mutex::new(Shared {
+                value,
+                version: 0,
+                waiting: Vec::new(),
+            })),
+            seen: 0,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared<T>> {
+        self.shared.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// this function returns the value without marking it
+    /// as seen
+    pub fn get(&self) -> T {
+        self.lock().value.clone()
+    }
+
+    /// this function returns the value, after which only a
+    /// later change is returned by try_changed or changed,
+    /// a task calls this as it starts
+    pub fn current(&mut self) -> T {
+        let shared = self.lock();
+        let value = shared.value.clone();
+        let version = shared.version;
+        drop(shared);
+        self.seen = version;
+        value
+    }
+
+    /// this function replaces the value and wakes every
+    /// task waiting for it to change
+    pub fn set(&self, value: T) {
+        let mut shared = self.lock();
+        shared.value = value;
+        shared.version += 1;
+        for waker in shared.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// this function changes the value where it is, so
+    /// tasks changing it at once each keep their change
+    pub fn update(&self, change: impl FnOnce(&mut T)) {
+        let mut shared = self.lock();
+        change(&mut shared.value);
+        shared.version += 1;
+        for waker in shared.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// this function returns the value if it changed since
+    /// it was last returned, for tasks that look once an
+    /// update
+    pub fn try_changed(&mut self) -> Option<T> {
+        let shared = self.lock();
+        if shared.version == self.seen {
+            return None;
+        }
+        let value = shared.value.clone();
+        let version = shared.version;
+        drop(shared);
+        self.seen = version;
+        Some(value)
+    }
+
+    /// this function waits for the value to change
+    pub async fn changed(&mut self) -> T {
+        poll_fn(|cx| {
+            let mut shared = self.lock();
+            if shared.version == self.seen {
+                let waker = cx.waker();
+                if !shared.waiting.iter().any(|w| w.will_wake(waker)) {
+                    shared.waiting.push(waker.clone());
+                }
+                return Poll::Pending;
+            }
+            let value = shared.value.clone();
+            let version = shared.version;
+            drop(shared);
+            self.seen = version;
+            Poll::Ready(value)
+        })
+        .await
+    }
+}
+
+impl<T> Clone for Setting<T> {
+    fn clone(&self) -> Setting<T> {
+        Setting {
+            shared: self.shared.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+/// the control channel, one Setting for each section
+#[derive(Clone)]
+pub struct Settings {
+    pub audio: Setting<AudioConfig>,
+    pub cameras: Setting<Vec<CameraConfig>>,
+    pub compute: Setting<ComputeConfig>,
+    pub storage: Setting<StorageConfig>,
+    pub log: Setting<LogConfig>,
+    pub ui: Setting<UiConfig>,
+    pub control: Setting<ControlConfig>,
+    pub http: Setting<HttpConfig>,
+}
+
+impl Settings {
+    pub fn new(config: &Config) -> Settings {
+        Settings {
+            audio: Setting::new(config.audio.clone()),
+            cameras: Setting::new(config.cameras.clone()),
+            compute: Setting::new(config.compute.clone()),
+            storage: Setting::new(config.storage.clone()),
+            log: Setting::new(config.log.clone()),
+            ui: Setting::new(config.ui.clone()),
+            control: Setting::new(config.control.clone()),
+            http: Setting::new(config.http.clone()),
+        }
+    }
+
+    /// this function hands each section of `config` that
+    /// differs from the running one to the part it is for,
+    /// returning the sections that changed. A key only read
+    /// at startup keeps its running value, so the parts see
+    /// what is really in use
+    pub fn apply(
+        &self,
+        mut config: Config,
+        log: &LogPipe,
+    ) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        let audio = self.audio.get();
+        restart_needed(
+            log,
+            "[audio] queue_size",
+            audio.queue_size,
+            config.audio.queue_size,
+        );
+        config.audio.queue_size = audio.queue_size;
+        if config.audio != audio {
+            if config.audio.device != audio.device {
+                crate::info!(
+                    log,
+                    Job::Main,
+                    "the microphone is reopened as {} at the next segment \
+                     boundary",
+                    config.audio.device.as_deref().unwrap_or("the default")
+                );
+            }
+            self.audio.set(config.audio);
+            changed.push("audio");
+        }
+
+        if config.cameras != self.cameras.get() {
+            crate::info!(
+                log,
+                Job::Main,
+                "the cameras are reopened at the next segment boundary"
+            );
+            self.cameras.set(config.cameras);
+            changed.push("cameras");
+        }
+
+        let compute = self.compute.get();
+        restart_needed(
+            log,
+            "[compute] video_queue_size",
+            compute.video_queue_size,
+            config.compute.video_queue_size,
+        );
+        config.compute.video_queue_size = compute.video_queue_size;
+        if config.compute != compute {
+            self.compute.set(config.compute);
+            changed.push("compute");
+        }
+
+        if config.storage != self.storage.get() {
+            self.storage.set(config.storage);
+            changed.push("storage");
+        }
+
+        let log_config = self.log.get();
+        restart_needed(
+            log,
+            "[log] queue_size",
+            log_config.queue_size,
+            config.log.queue_size,
+        );
+        restart_needed(
+            log,
+            "[log] trace_setup",
+            log_config.trace_setup,
+            config.log.trace_setup,
+        );
+        config.log.queue_size = log_config.queue_size;
+        config.log.trace_setup = log_config.trace_setup;
+        if config.log != log_config {
+            if config.log.filter != log_config.filter {
+                log.set_filter(config.log.filter.clone());
+            }
+            self.log.set(config.log);
+            changed.push("log");
+        }
+
+        let ui = self.ui.get();
+        restart_needed(
+            log,
+            "[ui] queue_size",
+            ui.queue_size,
+            config.ui.queue_size,
+        );
+        config.ui.queue_size = ui.queue_size;
+        if config.ui != ui {
+            self.ui.set(config.ui);
+            changed.push("ui");
+        }
+
+        let control = self.control.get();
+        restart_needed(
+            log,
+            "[control] socket",
+            &control.socket,
+            &config.control.socket,
+        );
+        config.control.socket = control.socket.clone();
+        if config.control != control {
+            self.control.set(config.control);
+            changed.push("control");
+        }
+
+        let http = self.http.get();
+        restart_needed(
+            log,
+            "[http] address",
+            &http.address,
+            &config.http.address,
+        );
+        config.http.address = http.address.clone();
+        if config.http != http {
+            self.http.set(config.http);
+            changed.push("http");
+        }
+
+        for section in changed.iter() {
+            crate::info!(log, Job::Main, "reloaded [{}]", section);
+        }
+        changed
+    }
+}
+
+/// what the control socket asks of the running recorder,
+/// and what the recorder tells it back
+#[derive(Clone)]
+pub struct Controls {
+    /// whether storage writes what reaches it
+    pub recording: Setting<bool>,
+    /// changed to have storage start new files at once
+    pub new_segment: Setting<u64>,
+    /// the audio file storage is writing
+    pub audio_file: Setting<Option<String>>,
+    /// every queue as main last sampled it
+    pub queues: Setting<Vec<QueueSample>>,
+    /// what the UI last showed
+    pub status: Setting<Status>,
+    /// the newest frame the UI was sent, for the preview
+    pub preview: Setting<Option<Arc<VideoUpdate>>>,
+    /// the latest bookmark, storage records through its
+    /// post-roll even while recording is stopped
+    pub bookmark: Setting<Option<Bookmark>>,
+    /// each stream that could not write to a directory,
+    /// until it writes again
+    pub storage_failed: Setting<Vec<Failed>>,
+    /// how full the main disk is, as storage last found it
+    pub main_storage: Setting<MainStorage>,
+    /// how full removable storage is, as storage last
+    /// found it
+    pub removable_storage: Setting<RemovableStorage>,
+}
+
+impl Default for Controls {
+    /// recording, with nothing written or sampled yet
+    fn default() -> Controls {
+        Controls {
+            recording: Setting::new(true),
+            new_segment: Setting::new(0),
+            audio_file: Setting::new(None),
+            queues: Setting::new(Vec::new()),
+            status: Setting::new(Status::MainDiskAndMainCam),
+            preview: Setting::new(None),
+            bookmark: Setting::new(None),
+            storage_failed: Setting::new(Vec::new()),
+            main_storage: Setting::new(MainStorage::HasCapacity),
+            removable_storage: Setting::new(RemovableStorage::HasCapacity),
+        }
+    }
+}
+
+impl Controls {
+    /// this function asks storage to close its files and
+    /// start new ones with the next update
+    pub fn start_new_segment(&self) {
+        self.new_segment.set(self.new_segment.get() + 1);
+    }
+
+    /// this function marks now, with `note`, in the catalog
+    /// of the directory being recorded to, and has storage
+    /// keep the pre-roll and post-roll around it
+    pub fn mark(
+        &self,
+        note: &str,
+        storage: &StorageConfig,
+        log: &LogPipe,
+    ) -> Result<Bookmark> {
+        let bookmark = Bookmark::new(
+            crate::time::now().wall_nanos,
+            note,
+            Duration::from_secs(storage.pre_roll_seconds as u64),
+            Duration::from_secs(storage.post_roll_seconds as u64),
+        );
+        catalog::append(&storage.directory, &bookmark)?;
+        self.bookmark.set(Some(bookmark.clone()));
+        crate::info!(
+            log,
+            Job::Control,
+            "event marked, keeping {}s before and {}s after: {}",
+            storage.pre_roll_seconds,
+            storage.post_roll_seconds,
+            note
+        );
+        Ok(bookmark)
+    }
+}
+
+/// this function reports a key that only takes effect
+/// once the recorder is started again
+fn restart_needed<T>(log: &LogPipe, key: &str, running: T, read: T)
+where
+    T: PartialEq + core::fmt::Debug,
+{
+    if running != read {
+        crate::warn!(
+            log,
+            Job::Main,
+            "{} stays {:?} until the recorder is restarted, the file says {:?}",
+            key,
+            running,
+            read
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_clone_sees_each_change_once() {
+        let mut motion = Setting::new(12.0);
+        let mut ui = motion.clone();
+        assert_eq!(motion.current(), 12.0);
+        assert_eq!(motion.try_changed(), None);
+
+        motion.set(20.0);
+        motion.set(30.0);
+        assert_eq!(motion.try_changed(), Some(30.0));
+        assert_eq!(motion.try_changed(), None);
+        // a clone made before a change still sees it
+        assert_eq!(ui.try_changed(), Some(30.0));
+
+        let waiting = std::thread::spawn(move || {
+            pasts::Executor::default().block_on(async move {
+                assert_eq!(ui.changed().await, 40.0);
+            })
+        });
+        std::thread::sleep(core::time::Duration::from_millis(20));
+        motion.set(40.0);
+        waiting.join().unwrap();
+    }
+
+    #[test]
+    fn routes_only_the_sections_that_changed() {
+        let _pipe = crate::log::TEST_PIPE
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let (_out, out_sender) = crate::queue::LOG_OUT.open();
+        let (mut stored, storage_sender) = crate::queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+
+        let config = Config::default();
+        let settings = Settings::new(&config);
+        let mut motion = settings.compute.clone();
+        let mut storage = settings.storage.clone();
+        motion.current();
+        storage.current();
+
+        let mut reloaded = config.clone();
+        reloaded.compute.motion_threshold = 30.0;
+        reloaded.ui.queue_size = 10;
+        assert_eq!(settings.apply(reloaded.clone(), &log), ["compute"]);
+        assert_eq!(motion.try_changed().unwrap().motion_threshold, 30.0);
+        assert_eq!(storage.try_changed(), None);
+        // the queue was opened at startup and keeps its size
+        assert_eq!(settings.ui.get().queue_size, 4);
+        // the same file again changes nothing
+        assert!(settings.apply(reloaded, &log).is_empty());
+        LogPipe::shutdown();
+
+        let mut lines = Vec::new();
+        while let Ok(update) = stored.try_dequeue() {
+            lines.push(update.user_string);
+        }
+        assert_eq!(
+            lines,
+            [
+                "[ui] queue_size stays 4 until the recorder is restarted, \
+                 the file says 10",
+                "reloaded [compute]",
+                "[ui] queue_size stays 4 until the recorder is restarted, \
+                 the file says 10",
+            ]
+        );
+    }
+}
@@ -0,0 +1,541 @@
+/// This is where the control socket is served, and where
+/// `camera control` talks to it. A request is one line of
+/// JSON naming a command, {"command":"status"}, and each is
+/// answered with one line of JSON, {"ok":true,...} or
+/// {"ok":false,"error":"..."}, so one connection can carry
+/// as many requests as the client likes, and nc or socat
+/// are enough to talk to the recorder by hand. Commands
+/// change the running recorder through Settings and
+/// Controls, the way a reloaded file does, so nothing here
+/// touches a device or a recording itself. Each client is
+/// answered on a thread of its own, so one left open does
+/// not hold up the next, and at most MAX_CLIENTS at once
+use super::{Controls, Settings};
+use crate::hardware;
+use crate::log::filter::LogFilter;
+use crate::log::json::{escape, Parser};
+use crate::log::{Job, LogPipe};
+use crate::shutdown;
+
+use anyhow::{anyhow, bail, Result};
+
+use core::time::Duration;
+
+use std::format;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::string::{String, ToString};
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+/// how often the listener looks for a stop request
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// how long a client may leave a request half written,
+/// or the recorder leave a client without an answer
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the most clients answered at once, each has a thread
+const MAX_CLIENTS: usize = 4;
+
+/// the longest request line read, a client that sends
+/// more without a newline is answered with an error and
+/// hung up on
+const MAX_REQUEST_BYTES: usize = 4096;
+
+/// one line sent to the control socket
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    /// what is being recorded, where, and how full
+    /// each queue is
+    Status,
+    /// write what is captured again after a stop
+    Start,
+    /// keep capturing but write nothing, closing the
+    /// files being written
+    Stop,
+    /// close the files being written and start new ones
+    NewSegment,
+    /// record to `directory` from the next update
+    SwitchStorage { directory: String },
+    /// bookmark now in the catalog, keeping the recording
+    /// around it
+    Mark { note: String },
+    /// replace the log filter, see LogFilter::parse
+    LogLevel { filter: String },
+}
+
+impl Request {
+    /// this function reads a request written by to_line
+    pub fn parse(line: &str) -> Result<Request> {
+        let mut parser = Parser::new(line);
+        let mut command = None;
+        let mut directory = None;
+        let mut note = None;
+        let mut filter = None;
+        parser.expect('{')?;
+        if parser.peek()? == '}' {
+            bail!("the request names no command");
+        }
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            let value = parser.string()?;
+            match key.as_str() {
+                "command" => command = Some(value),
+                "directory" => directory = Some(value),
+                "note" => note = Some(value),
+                "filter" => filter = Some(value),
+                other => bail!("unknown request field: {}", other),
+            }
+            match parser.next()? {
+                ',' => continue,
+                '}' => break,
+                other => bail!("expected ',' or '}}' but found '{}'", other),
+            }
+        }
+        let command = command.ok_or(anyhow!("the request names no command"))?;
+        let missing = |field: &str| anyhow!("{} needs a {}", command, field);
+        Ok(match command.as_str() {
+            "status" => Request::Status,
+            "start" => Request::Start,
+            "stop" => Request::Stop,
+            "new_segment" => Request::NewSegment,
+            "switch_storage" => Request::SwitchStorage {
+                directory: directory.ok_or(missing("directory"))?,
+            },
+            "mark" => Request::Mark {
+                note: note.unwrap_or_default(),
+            },
+            "log_level" => Request::LogLevel {
+                filter: filter.ok_or(missing("filter"))?,
+            },
+            other => bail!("unknown command: {}", other),
+        })
+    }
+
+    /// this function writes the request as one line of
+    /// JSON, terminated by a newline
+    pub fn to_line(&self) -> String {
+        let (command, field) = match self {
+            Request::Status => ("status", None),
+            Request::Start => ("start", None),
+            Request::Stop => ("stop", None),
+            Request::NewSegment => ("new_segment", None),
+            Request::SwitchStorage { directory } => {
+                ("switch_storage", Some(("directory", directory)))
+            }
+            Request::Mark { note } => ("mark", Some(("note", note))),
+            Request::LogLevel { filter } => {
+                ("log_level", Some(("filter", filter)))
+            }
+        };
+        match field {
+            Some((key, value)) => format!(
+                "{{\"command\":\"{}\",\"{}\":{}}}\n",
+                command,
+                key,
+                escape(value)
+            ),
+            None => format!("{{\"command\":\"{}\"}}\n", command),
+        }
+    }
+}
+
+/// this function starts a thread answering on `path`
+/// until the recorder is asked to stop, a socket left
+/// behind by a recorder that did not stop cleanly is
+/// replaced, one that is still answering is not
+pub fn serve(
+    path: &str,
+    settings: Settings,
+    controls: Controls,
+    log: LogPipe,
+) -> Result<JoinHandle<()>> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} is already there, and is not a socket", path);
+        }
+        if UnixStream::connect(path).is_ok() {
+            bail!("another recorder is answering on {}", path);
+        }
+        std::fs::remove_file(path)
+            .map_err(|err| anyhow!("{}: {}", path, err))?;
+    }
+    let listener =
+        UnixListener::bind(path).map_err(|err| anyhow!("{}: {}", path, err))?;
+    // so the loop can look for a stop request between clients
+    listener.set_nonblocking(true)?;
+    let path = path.to_string();
+    let thread = std::thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            crate::info!(log, Job::Control, "answering on {}", path);
+            let mut clients: Vec<JoinHandle<()>> = Vec::new();
+            while !shutdown::requested() {
+                clients.retain(|client| !client.is_finished());
+                match listener.accept() {
+                    Ok((mut stream, _)) if clients.len() >= MAX_CLIENTS => {
+                        // a client that goes away is not a fault
+                        let _ = stream.write_all(
+                            b"{\"ok\":false,\"error\":\"too many clients\"}\n",
+                        );
+                    }
+                    Ok((stream, _)) => {
+                        let settings = settings.clone();
+                        let controls = controls.clone();
+                        let client_log = log.clone();
+                        let spawned = std::thread::Builder::new()
+                            .name("control client".to_string())
+                            .spawn(move || {
+                                let answered = answer(
+                                    stream,
+                                    &settings,
+                                    &controls,
+                                    &client_log,
+                                );
+                                if let Err(err) = answered {
+                                    crate::warn!(
+                                        client_log,
+                                        Job::Control,
+                                        "lost a control client: {}",
+                                        err
+                                    );
+                                }
+                            });
+                        match spawned {
+                            Ok(client) => clients.push(client),
+                            Err(err) => crate::warn!(
+                                log,
+                                Job::Control,
+                                "could not start a control client: {}",
+                                err
+                            ),
+                        }
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        std::thread::sleep(POLL_INTERVAL)
+                    }
+                    Err(err) => {
+                        crate::warn!(
+                            log,
+                            Job::Control,
+                            "could not accept a control client: {}",
+                            err
+                        );
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                }
+            }
+            for client in clients {
+                let _ = client.join();
+            }
+            let _ = std::fs::remove_file(&path);
+        })?;
+    Ok(thread)
+}
+
+/// this function answers each request a client sends,
+/// until it hangs up, goes quiet for READ_TIMEOUT, sends
+/// a line longer than MAX_REQUEST_BYTES or the recorder is
+/// asked to stop
+fn answer(
+    stream: UnixStream,
+    settings: &Settings,
+    controls: &Controls,
+    log: &LogPipe,
+) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    // woken this often to look for a stop request
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    let mut quiet = Duration::ZERO;
+    while !shutdown::requested() {
+        // a read that times out keeps what it got of the line
+        let room = (MAX_REQUEST_BYTES - line.len()) as u64;
+        match reader.by_ref().take(room).read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => quiet = Duration::ZERO,
+            Err(err)
+                if err.kind() == ErrorKind::WouldBlock
+                    || err.kind() == ErrorKind::TimedOut =>
+            {
+                quiet += POLL_INTERVAL;
+                if quiet >= READ_TIMEOUT {
+                    break;
+                }
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+        if line.len() >= MAX_REQUEST_BYTES && !line.ends_with(b"\n") {
+            writer.write_all(
+                b"{\"ok\":false,\"error\":\"the request is too long\"}\n",
+            )?;
+            break;
+        }
+        let request = String::from_utf8(core::mem::take(&mut line))
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+        if request.trim().is_empty() {
+            continue;
+        }
+        let mut answer = respond(request.trim(), settings, controls, log);
+        answer.push('\n');
+        writer.write_all(answer.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// this function carries out one request, returning the
+/// line to answer it with, without its newline
+pub fn respond(
+    line: &str,
+    settings: &Settings,
+    controls: &Controls,
+    log: &LogPipe,
+) -> String {
+    let done = Request::parse(line)
+        .and_then(|request| carry_out(request, settings, controls, log));
+    match done {
+        Ok(fields) => format!("{{\"ok\":true{}}}", fields),
+        Err(err) => {
+            format!("{{\"ok\":false,\"error\":{}}}", escape(&err.to_string()))
+        }
+    }
+}
+
+/// this function returns the fields to answer with after
+/// "ok", each starting with a comma
+fn carry_out(
+    request: Request,
+    settings: &Settings,
+    controls: &Controls,
+    log: &LogPipe,
+) -> Result<String> {
+    match request {
+        Request::Status => return Ok(status(settings, controls)),
+        Request::Start => {
+            controls.recording.set(true);
+            crate::info!(log, Job::Control, "recording started");
+        }
+        Request::Stop => {
+            controls.recording.set(false);
+            crate::info!(
+                log,
+                Job::Control,
+                "recording stopped, capture continues"
+            );
+        }
+        Request::NewSegment => {
+            controls.start_new_segment();
+            crate::info!(log, Job::Control, "starting a new segment");
+        }
+        Request::SwitchStorage { directory } => {
+            if !std::path::Path::new(&directory).is_dir() {
+                bail!("{} is not a directory", directory);
+            }
+            let mut storage = settings.storage.get();
+            storage.directory = directory;
+            crate::info!(
+                log,
+                Job::Control,
+                "switching storage to {}, until the file next changes",
+                storage.directory
+            );
+            settings.storage.set(storage);
+        }
+        Request::Mark { note } => {
+            let bookmark = controls.mark(&note, &settings.storage.get(), log)?;
+            let wall = |wall_nanos| {
+                crate::time::Timestamp {
+                    wall_nanos,
+                    monotonic_nanos: 0,
+                }
+                .rfc3339()
+            };
+            return Ok(format!(
+                ",\"at\":\"{}\",\"protected_from\":\"{}\",\
+                 \"protected_to\":\"{}\"",
+                wall(bookmark.at),
+                wall(bookmark.from),
+                wall(bookmark.to)
+            ));
+        }
+        Request::LogLevel { filter } => {
+            log.set_filter(LogFilter::parse(&filter)?);
+            crate::info!(log, Job::Control, "log filter is now {}", filter);
+        }
+    }
+    Ok(String::new())
+}
+
+/// this function describes the running recorder, as
+/// fields each starting with a comma
+pub(crate) fn status(settings: &Settings, controls: &Controls) -> String {
+    let present = hardware::camera::devices();
+    let cameras: Vec<String> = settings
+        .cameras
+        .get()
+        .iter()
+        .map(|camera| {
+            format!(
+                "{{\"name\":{},\"device\":{},\"present\":{}}}",
+                escape(&camera.name),
+                escape(&camera.device),
+                present.contains(&camera.device)
+            )
+        })
+        .collect();
+    let queues: Vec<String> = controls
+        .queues
+        .get()
+        .iter()
+        .map(|queue| {
+            format!(
+                "{{\"name\":{},\"depth\":{},\"capacity\":{},\
+                 \"dropped\":{},\"in_deficit\":{}}}",
+                escape(queue.name),
+                queue.depth,
+                queue.capacity,
+                queue.total_dropped,
+                queue.in_deficit()
+            )
+        })
+        .collect();
+    let storage = settings.storage.get();
+    format!(
+        ",\"recording\":{},\"cameras\":[{}],\"storage\":{{\"directory\":{},\
+         \"segment_minutes\":{},\"retention_hours\":{},\"audio_file\":{},\
+         \"main\":\"{:?}\",\"removable\":\"{:?}\"}},\
+         \"battery_percent\":{},\"clock_jumps\":{},\"queues\":[{}]",
+        controls.recording.get(),
+        cameras.join(","),
+        escape(&storage.directory),
+        storage.segment_minutes,
+        storage.retention_hours,
+        controls
+            .audio_file
+            .get()
+            .map(|file| escape(&file))
+            .unwrap_or("null".to_string()),
+        controls.main_storage.get(),
+        controls.removable_storage.get(),
+        hardware::battery::percent()
+            .map(|percent| percent.to_string())
+            .unwrap_or("null".to_string()),
+        crate::time::clock_jumps(),
+        queues.join(",")
+    )
+}
+
+/// this function sends one request to the recorder
+/// answering on `path`, and returns its answer
+pub fn send(path: &str, request: &Request) -> Result<String> {
+    let mut stream = UnixStream::connect(path).map_err(|err| {
+        anyhow!("could not reach a recorder on {}: {}", path, err)
+    })?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.write_all(request.to_line().as_bytes())?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    if answer.is_empty() {
+        bail!("the recorder on {} hung up without answering", path);
+    }
+    Ok(answer.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn requests_round_trip() {
+        for request in [
+            Request::Status,
+            Request::Stop,
+            Request::NewSegment,
+            Request::SwitchStorage {
+                directory: "/media/usb \"1\"".into(),
+            },
+            Request::Mark { note: "".into() },
+            Request::LogLevel {
+                filter: "warn,AudioInput=debug".into(),
+            },
+        ] {
+            assert_eq!(Request::parse(&request.to_line()).unwrap(), request);
+        }
+        let error = |line: &str| Request::parse(line).unwrap_err().to_string();
+        assert_eq!(error("{}"), "the request names no command");
+        assert_eq!(
+            error("{\"command\":\"reboot\"}"),
+            "unknown command: reboot"
+        );
+        assert_eq!(
+            error("{\"command\":\"log_level\"}"),
+            "log_level needs a filter"
+        );
+    }
+
+    #[test]
+    fn answers_over_the_socket() {
+        let _pipe = crate::log::TEST_PIPE
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        crate::shutdown::reset();
+        let (_out, out_sender) = crate::queue::LOG_OUT.open();
+        let (_stored, storage_sender) = crate::queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+
+        let path = std::env::temp_dir()
+            .join(format!("control_{}.sock", std::process::id()))
+            .display()
+            .to_string();
+        let settings = Settings::new(&Config::default());
+        let controls = Controls::default();
+        let mut recording = controls.recording.clone();
+        recording.current();
+        let thread =
+            serve(&path, settings.clone(), controls.clone(), log.clone())
+                .unwrap();
+        assert!(serve(&path, settings.clone(), controls, log.clone()).is_err());
+
+        // a client that never says anything holds up no one
+        let _quiet = UnixStream::connect(&path).unwrap();
+        let asked = std::time::Instant::now();
+        let status = send(&path, &Request::Status).unwrap();
+        assert!(status.starts_with("{\"ok\":true,\"recording\":true,"));
+        assert!(status.contains("\"directory\":\".\""));
+        assert!(status.contains("\"clock_jumps\":"));
+        assert!(asked.elapsed() < READ_TIMEOUT);
+        assert_eq!(send(&path, &Request::Stop).unwrap(), "{\"ok\":true}");
+        assert_eq!(recording.try_changed(), Some(false));
+        let missing = Request::SwitchStorage {
+            directory: "/no/such/place".into(),
+        };
+        assert_eq!(
+            send(&path, &missing).unwrap(),
+            "{\"ok\":false,\"error\":\"/no/such/place is not a directory\"}"
+        );
+        assert_eq!(settings.storage.get().directory, ".");
+
+        // a line with no end is cut off at MAX_REQUEST_BYTES
+        let mut endless = UnixStream::connect(&path).unwrap();
+        endless.write_all(&std::vec![b'a'; MAX_REQUEST_BYTES]).unwrap();
+        let mut answer = String::new();
+        endless.read_to_string(&mut answer).unwrap();
+        assert_eq!(
+            answer,
+            "{\"ok\":false,\"error\":\"the request is too long\"}\n"
+        );
+
+        crate::shutdown::request();
+        thread.join().unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+        LogPipe::shutdown();
+        crate::shutdown::reset();
+    }
+}
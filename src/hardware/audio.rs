@@ -1,4 +1,6 @@
 //#![no_std]
+use crate::config::{AudioConfig, StorageConfig};
+use crate::control::Setting;
 use crate::log::LogPipe;
 // WE SHOULD DEFINITELY USE MPSC FOR THE ENDS HERE,
 // SO THAT WE CAN THREAD A BUNCH OF HANDLES TO CALLBACKS
@@ -11,181 +13,469 @@ extern crate std;
 use core::sync::atomic::AtomicBool;
 static mut INITIALIZED: AtomicBool = AtomicBool::new(false);
 
-static mut MICROPHONE_LOG: MaybeUninit<LogPipe> = MaybeUninit::<LogPipe>::uninit();
+/// the audio input's log, replaced each time the task is
+/// started, for the functions that open the stream
+static MICROPHONE_LOG: Mutex<Option<LogPipe>> = Mutex::new(None);
 
-use core::mem::MaybeUninit;
-static mut TO_AUDIO_COMPUTE: MaybeUninit<Sender<AudioUpdate>> =
-    MaybeUninit::<Sender<AudioUpdate>>::uninit();
+/// the queue each stream's callback gets its own sender to,
+/// taken when the stream stops, so the task can be started
+/// again after a panic
+static TO_AUDIO_COMPUTE: Mutex<Option<Sender<AudioUpdate>>> = Mutex::new(None);
 
+use std::sync::{Arc, Mutex};
+/// the name of the device last opened, replaced at each
+/// reopen and shared by every update from its stream
+static DEVICE_NAME: Mutex<Option<Arc<str>>> = Mutex::new(None);
+
+use core::sync::atomic::{AtomicU16, AtomicU32};
+/// the sample rate and channel count of the open stream
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(0);
+static CHANNELS: AtomicU16 = AtomicU16::new(0);
+
+use crate::queue::BufferPool;
+use std::sync::OnceLock;
+/// the buffers the callback copies samples into, they come
+/// back once audio storage has written them
+static AUDIO_POOL: OnceLock<BufferPool<f32>> = OnceLock::new();
+/// how many buffers are made before the stream starts
+const PREALLOCATED_BUFFERS: usize = 64;
+
+use core::task::Waker;
 use std::string::String;
-static mut DEVICE_NAME: MaybeUninit<String> = MaybeUninit::<String>::uninit();
+/// the error the stream reported from its own thread, and
+/// the audio task waiting to hear of it, a panic over there
+/// would take down the sound server thread rather than ours
+static STREAM_ERROR: Mutex<(Option<String>, Option<Waker>)> =
+    Mutex::new((None, None));
 
 
 /// this function sets up and begins streaming data from
 /// a USB or analogue microphone, currently making use
 /// of the linux audio server layer, it then sends each
 /// audio frame down the queue to the audio processing
-/// functions
+/// functions, a change of device closes the stream and
+/// opens the new device at the next segment boundary
 pub async fn start(
     to_audio_compute: Sender<AudioUpdate>,
     microphone_log: LogPipe,
+    mut audio: Setting<AudioConfig>,
+    storage: Setting<StorageConfig>,
 ) {
     use crate::log::Job;
-    unsafe {
-        MICROPHONE_LOG.write(microphone_log);
+    match MICROPHONE_LOG.lock() {
+        Ok(mut log) => *log = Some(microphone_log.clone()),
+        Err(poisoned) => *poisoned.into_inner() = Some(microphone_log.clone()),
     }
-    unsafe {
-        TO_AUDIO_COMPUTE.write(to_audio_compute);
+    match TO_AUDIO_COMPUTE.lock() {
+        Ok(mut sender) => *sender = Some(to_audio_compute),
+        Err(poisoned) => *poisoned.into_inner() = Some(to_audio_compute),
     }
-    let this_microphone_log;
-    unsafe {
-        
-        this_microphone_log = MICROPHONE_LOG
-            .assume_init_read()
-            .new_task_log();
+    match STREAM_ERROR.lock() {
+        Ok(mut error) => *error = (None, None),
+        Err(poisoned) => *poisoned.into_inner() = (None, None),
     }
-    this_microphone_log.info("started audio input", Job::AudioInput);
+    let this_microphone_log = microphone_log;
+    crate::info!(this_microphone_log, Job::AudioInput, "started audio input");
 
-    match has_side_effects::get_device() {
-        Some(device) => {
-            has_side_effects::use_stream(device);
-            this_microphone_log.info("fell through use_stream to busy loop", Job::AudioInput);
-            loop{}
-            // this should not terminate (might if the device is unplugged)
+    let mut config = audio.current();
+    loop {
+        let device = match has_side_effects::get_device(config.device.as_deref())
+        {
+            Some(device) => device,
+            None => panic!("failed to get an input device from cpal"),
+        };
+        // the stream stops when it is dropped, so we hold it
+        // here and wait, which lets the other tasks on this
+        // thread run while the callbacks push frames
+        // both audio queues may be full of pooled buffers
+        let keep = config.queue_size * 2;
+        let sender = match TO_AUDIO_COMPUTE.lock() {
+            Ok(sender) => sender.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        let input_stream = match sender {
+            Some(sender) => has_side_effects::use_stream(device, keep, sender),
+            None => panic!("the audio input has no queue to compute"),
+        };
+        crate::info!(
+            this_microphone_log,
+            Job::AudioInput,
+            "holding the audio input stream open"
+        );
+        let wake =
+            hold_stream(&mut audio, &storage, &mut config, &this_microphone_log)
+                .await;
+        // no callback runs once the stream is dropped, which
+        // drops its sender too, so once ours goes the queue to
+        // compute closes after the audio already in it
+        drop(input_stream);
+        if let Wake::Reopen = wake {
+            crate::info!(
+                this_microphone_log,
+                Job::AudioInput,
+                "reopening the audio input"
+            );
+            continue;
+        }
+        let sender = match TO_AUDIO_COMPUTE.lock() {
+            Ok(mut sender) => sender.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Wake::Failed(err) = wake {
+            // the supervisor starts us again, with the
+            // sender the pipeline kept
+            panic!("the audio input stream had an error: {err}");
         }
-        None => panic!("failed to get an input device from cpal"),
+        drop(sender);
+        crate::info!(
+            this_microphone_log,
+            Job::AudioInput,
+            "stopped audio input"
+        );
+        return;
+    }
+}
+
+/// this function returns a copy of the audio input's log
+fn microphone_log() -> LogPipe {
+    let log = match MICROPHONE_LOG.lock() {
+        Ok(log) => log.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
     };
+    log.expect("the audio input sets its log before opening a stream")
 }
 
-mod has_side_effects {
-    use std::format;
+/// why the audio input stream is no longer held open
+enum Wake {
+    Stopped,
+    Failed(String),
+    /// the device changed and the segment it changed in
+    /// has ended
+    Reopen,
+}
+
+/// this function holds the stream open until a shutdown,
+/// a stream error, or a change of device, taking any other
+/// change to the [audio] section as it comes
+async fn hold_stream(
+    audio: &mut Setting<AudioConfig>,
+    storage: &Setting<StorageConfig>,
+    config: &mut AudioConfig,
+    microphone_log: &LogPipe,
+) -> Wake {
+    use crate::log::Job;
+
+    loop {
+        let changed = match until_stopped_failed_or_changed(audio).await {
+            Ok(changed) => changed,
+            Err(None) => return Wake::Stopped,
+            Err(Some(err)) => return Wake::Failed(err),
+        };
+        if changed.device == config.device {
+            *config = changed;
+            continue;
+        }
+        // the current segment is finished with the device it
+        // started on, so storage cuts the file where we switch
+        let now = crate::time::now().wall_nanos;
+        let boundary = crate::recordings::segment_end(
+            now,
+            storage.get().segment_minutes,
+        );
+        crate::info!(
+            microphone_log,
+            Job::AudioInput,
+            "switching the audio input to {} at {}",
+            changed.device.as_deref().unwrap_or("the default"),
+            crate::time::Timestamp {
+                wall_nanos: boundary,
+                monotonic_nanos: 0,
+            }
+            .rfc3339()
+        );
+        crate::supervisor::delay(core::time::Duration::from_nanos(
+            boundary.saturating_sub(now),
+        ))
+        .await;
+        *config = changed;
+        if crate::shutdown::requested() {
+            return Wake::Stopped;
+        }
+        // an error the old stream had goes with it
+        match STREAM_ERROR.lock() {
+            Ok(mut error) => error.0 = None,
+            Err(poisoned) => poisoned.into_inner().0 = None,
+        }
+        return Wake::Reopen;
+    }
+}
+
+/// this function waits for a shutdown, returning None,
+/// for the stream to report an error, returning it, or for
+/// the [audio] section to change, returning the change
+async fn until_stopped_failed_or_changed(
+    audio: &mut Setting<AudioConfig>,
+) -> Result<AudioConfig, Option<String>> {
+    use core::future::Future;
+    use core::task::Poll;
+
+    let mut stopped = core::pin::pin!(crate::shutdown::stopped());
+    let mut changed = core::pin::pin!(audio.changed());
+    core::future::poll_fn(|cx| {
+        if stopped.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(None));
+        }
+        if let Poll::Ready(changed) = changed.as_mut().poll(cx) {
+            return Poll::Ready(Ok(changed));
+        }
+        let mut error = match STREAM_ERROR.lock() {
+            Ok(error) => error,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match error.0.take() {
+            Some(err) => Poll::Ready(Err(Some(err))),
+            None => {
+                error.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
 
+/// this function returns the name of every audio input,
+/// and the name of the default input, for the devices
+/// command, without opening any of them
+pub fn devices() -> (std::vec::Vec<String>, Option<String>) {
+    has_side_effects::device_names()
+}
+
+mod has_side_effects {
     use cpal::traits::{DeviceTrait, HostTrait};
     use cpal::Device;
+    use std::string::String;
+    use std::vec::Vec;
 
-    use super::{DEVICE_NAME, INITIALIZED};
+    pub fn device_names() -> (Vec<String>, Option<String>) {
+        let host = cpal::default_host();
+        let names = match host.input_devices() {
+            Ok(devices) => {
+                devices.filter_map(|device| device.name().ok()).collect()
+            }
+            Err(_) => Vec::new(),
+        };
+        let default = host
+            .default_input_device()
+            .and_then(|device| device.name().ok());
+        (names, default)
+    }
 
-    pub fn get_device() -> Option<Device> {
+    use super::{
+        AUDIO_POOL, CHANNELS, DEVICE_NAME, INITIALIZED, PREALLOCATED_BUFFERS,
+        SAMPLE_RATE,
+    };
+
+    /// this function returns the first input device with
+    /// `wanted` in its name, or the default input device
+    pub fn get_device(wanted: Option<&str>) -> Option<Device> {
         use core::sync::atomic::Ordering;
         use crate::log::Job;
 
-        let this_microphone_log;
-        unsafe {
-            this_microphone_log = super::MICROPHONE_LOG
-                .assume_init_read()
-                .new_task_log();
-        }
+        let this_microphone_log = super::microphone_log();
 
         let host = cpal::default_host();
 
-        match host.default_input_device() {
+        let chosen = match wanted {
+            Some(wanted) => host.input_devices().ok().and_then(|mut devices| {
+                devices.find(|device| {
+                    device.name().is_ok_and(|name| name.contains(wanted))
+                })
+            }),
+            None => host.default_input_device(),
+        };
+        match chosen {
             Some(device) => {
-                this_microphone_log.info(
-                    &format!(
-                        "got device: {}",
-                        &device.name().expect("failed to retrieve device name")
-                    ),
+                crate::info!(
+                    this_microphone_log,
                     Job::AudioInput,
+                    "got device: {}",
+                    &device.name().expect("failed to retrieve device name")
                 );
-                unsafe {
-                    super::DEVICE_NAME.write(device.name().unwrap());
+                let name: Arc<str> = device.name().unwrap().into();
+                match DEVICE_NAME.lock() {
+                    Ok(mut named) => *named = Some(name),
+                    Err(poisoned) => *poisoned.into_inner() = Some(name),
                 }
                 unsafe{INITIALIZED.store(true, Ordering::SeqCst);}
                 return Some(device);
             }
             None => {
-                this_microphone_log
-                    .debug(&format!("no audio input device found",));
+                crate::warn!(
+                    this_microphone_log,
+                    Job::AudioInput,
+                    "no audio input device found named {}",
+                    wanted.unwrap_or("default")
+                );
                 return None;
             }
         }
     }
 
-    pub fn use_stream(device: Device) {
+    use crate::log::LogPipe;
+    use crate::queue::{AudioUpdate, Sender};
+    use std::sync::Arc;
+    use crate::time::presentation::PresentationClock;
+
+    pub fn use_stream(
+        device: Device,
+        keep: usize,
+        sender: Sender<AudioUpdate>,
+    ) -> cpal::Stream {
         // CPAL/examples/feedback.rs works
         // so it might be a good place to figure out
         // how to get this mic pushing frames
         use crate::log::Job;
         use cpal::traits::StreamTrait;
-        use std::time::Duration;
 
-        let mut this_microphone_log;
-        unsafe {
-            this_microphone_log = super::MICROPHONE_LOG
-                .assume_init_read()
-                .new_task_log();
-        }
+        let this_microphone_log = super::microphone_log();
 
-        let microphone_stream_log = this_microphone_log.new_task_log();
-        let microphone_error_log = this_microphone_log.new_task_log();
         let config: cpal::StreamConfig = match device.default_input_config() {
             Ok(config) => config,
             Err(err) => panic!("could not configure input stream"),
         }
         .into();
-        this_microphone_log
-            .info("got default input stream config", Job::AudioInput);
+        crate::info!(
+            this_microphone_log,
+            Job::AudioInput,
+            "got default input stream config"
+        );
 
+        {
+            use core::sync::atomic::Ordering;
+            SAMPLE_RATE.store(config.sample_rate.0, Ordering::SeqCst);
+            CHANNELS.store(config.channels, Ordering::SeqCst);
+            // room for a tenth of a second, most callbacks deliver less
+            let samples = config.sample_rate.0 as usize / 10
+                * config.channels as usize;
+            AUDIO_POOL.get_or_init(|| {
+                crate::queue::BufferPool::new(
+                    "audio",
+                    PREALLOCATED_BUFFERS,
+                    samples,
+                    keep,
+                )
+            });
+        }
+
+        // the callback owns the clock and its sender, it runs on
+        // the realtime thread and must never wait on a lock to
+        // stamp samples or send them on
+        let mut clock = PresentationClock::audio(config.sample_rate.0);
+        let callback_log = this_microphone_log.clone();
+        let name = match DEVICE_NAME.lock() {
+            Ok(named) => named.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+        .expect("get_device names the device before the stream opens");
+        let stamped = move |data: &[f32], info: &cpal::InputCallbackInfo| {
+            got_data(data, info, &mut clock, &sender, &callback_log, &name)
+        };
         let input_stream =
-            match device.build_input_stream(&config, got_data, got_err, None) {
+            match device.build_input_stream(&config, stamped, got_err, None) {
                 Ok(stream) => stream, //block on call backs
                 Err(err) => panic!("building the input stream returned {err}"),
             };
 
-        this_microphone_log.info("built audio input stream", Job::AudioInput);
+        crate::info!(
+            this_microphone_log,
+            Job::AudioInput,
+            "built audio input stream"
+        );
 
         match input_stream.play() {
-            //must busy loop to keep from dropping stream
-            //should probably use a shutdown atomic
+            //the caller must hold on to the stream
+            //to keep it from being dropped
             Ok(_) => {
-                this_microphone_log
-                    .info("started audio input stream", Job::AudioInput);
-                loop {
-                    std::thread::sleep(Duration::new(0, 1000));
-                }
+                crate::info!(
+                    this_microphone_log,
+                    Job::AudioInput,
+                    "started audio input stream"
+                );
+                input_stream
             }
             Err(err) => panic!("there was an error starting the stream: {err}"),
         }
     }
 
-    pub fn got_data(data: &[f32], _: &cpal::InputCallbackInfo) {
+    /// this function sends what the stream delivered on to
+    /// `sender`, with its pts from `clock`, which counts
+    /// every sample, naming `name` as the device, and
+    /// logging to the log the stream was opened with
+    pub fn got_data(
+        data: &[f32],
+        _: &cpal::InputCallbackInfo,
+        clock: &mut PresentationClock,
+        sender: &Sender<AudioUpdate>,
+        this_microphone_log: &LogPipe,
+        name: &Arc<str>,
+    ) {
         use crate::log::Job;
-        use crate::queue::AudioUpdate;
         use core::sync::atomic::Ordering;
 
-        let this_microphone_log;
-        unsafe {
-            this_microphone_log = super::MICROPHONE_LOG
-                .assume_init_read()
-                .new_task_log();
-        }
-        this_microphone_log.info("got audio input frame", Job::AudioInput);
+        crate::trace!(this_microphone_log, Job::AudioInput, "got audio input frame");
 
         //now we need to pass the input frame to audio compute, using an AudioUpdate
         if unsafe{INITIALIZED.load(Ordering::SeqCst)} {
+            let timestamp = crate::time::now();
+            let sample_rate = SAMPLE_RATE.load(Ordering::SeqCst);
+            let channels = CHANNELS.load(Ordering::SeqCst).max(1);
+            let samples_per_channel = (data.len() / channels as usize) as u64;
+            let pts = clock.stamp(samples_per_channel, timestamp);
             let update = AudioUpdate {
-                timestamp: crate::get_timestamp(),
-                data: data.to_vec(),
-                name: unsafe { DEVICE_NAME.assume_init_read() },
+                timestamp,
+                pts,
+                sample_rate,
+                channels,
+                data: match AUDIO_POOL.get() {
+                    Some(pool) => pool.copy_of(data),
+                    None => crate::queue::Buffer::unpooled(data.to_vec()),
+                },
+                name: name.clone(),
             };
-            this_microphone_log
-                .info("packed AudioUpdate with new frame", Job::AudioInput);
-    
-            unsafe {
-                match super::TO_AUDIO_COMPUTE.assume_init_read().enqueue(update) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        panic!("failed to enqueue the new update: {:?}", err)
-                    }
-                };
+            crate::trace!(
+                this_microphone_log,
+                Job::AudioInput,
+                "packed AudioUpdate with new frame"
+            );
+
+            if let Err((_, err)) = sender.enqueue(update) {
+                crate::error!(
+                    this_microphone_log,
+                    Job::AudioInput,
+                    "failed to enqueue the new update: {:?}",
+                    err
+                );
             }
-            this_microphone_log
-                .info("pushed new audio update to audio compute", Job::AudioInput);    
+            crate::trace!(
+                this_microphone_log,
+                Job::AudioInput,
+                "pushed new audio update to audio compute"
+            );
         }
     }
 
     pub fn got_err(err: cpal::StreamError) {
-        panic!("the audio input stream had an error: {err}")
+        let waker = {
+            let mut error = match super::STREAM_ERROR.lock() {
+                Ok(error) => error,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            error.0 = Some(std::format!("{err}"));
+            error.1.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
     }
 }
 
@@ -203,9 +493,11 @@ mod test {
                     move |data: &[$sample], _: &InputCallbackInfo| {
                         for &$x in data {
                             let s = $convert;
-                            microphone_stream_log.info(
-                                &format!("got an audio frame!: {:?}", s),
+                            crate::info!(
+                                microphone_stream_log,
                                 Job::AudioInput,
+                                "got an audio frame!: {:?}",
+                                s
                             );
 
                         }
@@ -39,3 +39,23 @@ pub enum SecondaryBattery {
     /// this indicates the system will power down within five minutes
     Empty,
 }
+
+/// this function reads how full the first battery the
+/// kernel knows of is, in percent, None without one
+pub fn percent() -> Option<u8> {
+    let supplies = std::fs::read_dir("/sys/class/power_supply").ok()?;
+    for supply in supplies.filter_map(|supply| supply.ok()) {
+        let path = supply.path();
+        match std::fs::read_to_string(path.join("type")) {
+            Ok(kind) if kind.trim() == "Battery" => {}
+            _ => continue,
+        }
+        if let Some(capacity) = std::fs::read_to_string(path.join("capacity"))
+            .ok()
+            .and_then(|capacity| capacity.trim().parse().ok())
+        {
+            return Some(capacity);
+        }
+    }
+    None
+}
@@ -18,7 +18,7 @@ static mut SHUTDOWN: AtomicBool = AtomicBool::new(false);
 /// correct bluetooth device is near by, sends Warn
 /// and Error variants as a message to the user's device
 pub async fn start(queue: Receiver<LogUpdate>, log_out_log: LogPipe) {
-    log_out_log.info("started bluetooth logger", Job::LogOut);
+    crate::info!(log_out_log, Job::LogOut, "started bluetooth logger");
     'send_log_update: loop {
         unimplemented!()
     }
@@ -120,11 +120,104 @@ impl IRSensitiveCamera {
         }
     }
 }
+use crate::config::{CameraConfig, StorageConfig};
+use crate::control::Setting;
 use crate::log::{Job, LogPipe};
+use std::string::ToString;
+use std::vec::Vec;
+
+/// this function returns the video device nodes, e.g.
+/// /dev/video0, in order, for the devices command
+pub fn devices() -> Vec<std::string::String> {
+    let mut devices: Vec<std::string::String> =
+        match std::fs::read_dir("/dev") {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    entry.file_name().to_string_lossy().starts_with("video")
+                })
+                .map(|entry| entry.path().display().to_string())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+    devices.sort();
+    devices
+}
 
 /// this function sets up and begins streaming frames from
 /// either USB or CSI cameras, and sends them through the
-/// queue to the video post processing functions.
-pub async fn start(mut queue: Sender<VideoUpdate>, camera_log: LogPipe) {
-    camera_log.info("started camera task", Job::VideoInput);
+/// queue to the video post processing functions, changed
+/// cameras are reopened at the next segment boundary
+pub async fn start(
+    mut queue: Sender<VideoUpdate>,
+    camera_log: LogPipe,
+    mut cameras: Setting<Vec<CameraConfig>>,
+    storage: Setting<StorageConfig>,
+) {
+    crate::info!(camera_log, Job::VideoInput, "started camera task");
+    let mut opened = cameras.current();
+    loop {
+        for camera in opened.iter() {
+            crate::info!(
+                camera_log,
+                Job::VideoInput,
+                "camera {} on {} at {}x{} {}fps",
+                camera.name,
+                camera.device,
+                camera.width,
+                camera.height,
+                camera.fps
+            );
+        }
+        // the queue to compute closes when it is dropped, so it
+        // is held until the recorder stops
+        let changed = match until_stopped_or_changed(&mut cameras).await {
+            Some(changed) => changed,
+            None => break,
+        };
+        let now = crate::time::now().wall_nanos;
+        let boundary = crate::recordings::segment_end(
+            now,
+            storage.get().segment_minutes,
+        );
+        crate::info!(
+            camera_log,
+            Job::VideoInput,
+            "reopening the cameras at {}",
+            crate::time::Timestamp {
+                wall_nanos: boundary,
+                monotonic_nanos: 0,
+            }
+            .rfc3339()
+        );
+        crate::supervisor::delay(core::time::Duration::from_nanos(
+            boundary.saturating_sub(now),
+        ))
+        .await;
+        if crate::shutdown::requested() {
+            break;
+        }
+        // a change made while we waited is opened now too
+        opened = cameras.try_changed().unwrap_or(changed);
+    }
+    crate::info!(camera_log, Job::VideoInput, "stopped camera task");
+}
+
+/// this function waits for a shutdown, returning None, or
+/// for the cameras to change, returning the change
+async fn until_stopped_or_changed(
+    cameras: &mut Setting<Vec<CameraConfig>>,
+) -> Option<Vec<CameraConfig>> {
+    use core::future::Future;
+    use core::task::Poll;
+
+    let mut stopped = core::pin::pin!(crate::shutdown::stopped());
+    let mut changed = core::pin::pin!(cameras.changed());
+    core::future::poll_fn(|cx| {
+        if stopped.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        changed.as_mut().poll(cx).map(Some)
+    })
+    .await
 }
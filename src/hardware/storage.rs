@@ -1,8 +1,34 @@
-use crate::log::{Job, LogPipe};
+use crate::config::{LogConfig, StorageConfig};
+use crate::control::{Controls, Setting};
+use crate::log::{json, Job, LogFormat, LogPipe};
 use crate::queue::{AudioUpdate, LogUpdate, Receiver, VideoUpdate};
 
+use crate::recordings::catalog;
+
+use std::collections::VecDeque;
 use std::io::Write;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub mod failure;
+pub mod frames;
+pub mod mounts;
+#[cfg(test)]
+pub mod simulated;
+pub mod wav;
+use failure::Stream;
+#[cfg(test)]
+pub use simulated::check_write;
+use frames::FramesWriter;
+use wav::WavWriter;
+
+/// this function is where tests fail a write of `len`
+/// bytes to `path` as a simulated device would, a real
+/// device fails the write itself
+#[cfg(not(test))]
+#[inline]
+pub fn check_write(_path: &str, _len: usize) -> std::io::Result<()> {
+    Ok(())
+}
 /// This is where we will retrieve frames in order
 /// from the video and audio queues, and begin a file
 /// or continue a file for each type. The events which will
@@ -24,6 +50,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 /// This enum describes which state that your
 /// main storage is in, this storage is
 /// attached to the laptop internally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MainStorage {
     /// indicates the device has sufficient capacity
     HasCapacity,
@@ -39,6 +66,7 @@ pub enum MainStorage {
 /// This enum describes which state your
 /// removable storage is in, this storage
 /// is attached to the laptop's USB port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RemovableStorage {
     /// indicates the device has sufficient capacity
     HasCapacity,
@@ -51,82 +79,178 @@ pub enum RemovableStorage {
     Full,
 }
 
-/// this is used to communicate to the started storage loop
-/// that is on a seperate thread
-static mut SHUTDOWN: AtomicBool = AtomicBool::new(false);
-
 /// this function opens a file on either the main storage
 /// usb storage or both, and appends each LogUpdate that
-/// comes down the pipe, to the file that was created
+/// comes down the pipe, to the file that was created,
+/// when the format asks for JSON lines, each update is
+/// also appended as one JSON object to the json file,
+/// when the files or format change the new files are
+/// opened before the next update is written. A line that
+/// cannot be written is held, with those after it, and
+/// written once the file can be written again, the disk
+/// it failed on is reported as the other storage does,
+/// but only to the terminal and log out. It stops once
+/// LogPipe::shutdown has passed on every update
 /// TODO: EXTRACT SIDE EFFECTS
 pub async fn log_start(
     mut queue: Receiver<LogUpdate>,
     log_storage_log: LogPipe,
+    mut settings: Setting<LogConfig>,
+    mut storage_settings: Setting<StorageConfig>,
+    controls: Controls,
 ) {
-    log_storage_log.info("started log storage", Job::LogStorage);
+    crate::info!(log_storage_log, Job::LogStorage, "started log storage");
+    let failure_log = log_storage_log.new_unstored_log();
 
-    use std::fs::OpenOptions;
-    let mut file;
-    file = match OpenOptions::new()
-        .read(false)
-        .append(true)
-        .create(true)
-        .open("main_log.txt")
-    {
-        Ok(file) => file,
-        Err(err) => panic!("unable to open or create file because: {:?}", err),
-    };
-    log_storage_log.info("created or opened file", Job::LogStorage);
-
-    use std::thread::sleep;
-    use std::time::{Duration, SystemTime};
-    'store_log_update: loop {
-        match queue.try_dequeue() {
-            Ok(update) => {
-                //we will start with newline delimited text
-                //noria is also a good option, and has webui
-                let line_buffer = format!(
-                    "{} :: {} :: {} :: from {{thread: {}, task: {}}}\n",
-                    update.timestamp,
-                    pad_job_string(&update.job),
-                    pad_user_string(&update.user_string),
-                    update.thread_name,
-                    update.from_task
-                );
+    let mut config = settings.current();
+    let mut storage = storage_settings.current();
+    let mut file = LogFile::new(&config.file, "file");
+    let mut json_file = LogFile::new(&config.json_file, "json lines file");
+    let mut lines = Stream::new("the log", Job::LogStorage);
+    let mut json_lines = Stream::new("the json log", Job::LogStorage);
 
-                match file.write_all(line_buffer.as_bytes()) {
-                    Ok(_) => {} //fall through to ,
-                    Err(err) => panic!(
-                        "could not appened new log update with open file, {:?}",
-                        err
-                    ),
-                };
-                match file.flush() {
-                    Ok(_) => {}, //fall through to sync_all
-                    Err(err) => panic!("there was an error flushing the file buffers to disk: {:?}", err)
-                };
-                match file.sync_all() {
-                    Ok(_) => {}, //fall through to shutdown check
-                    Err(err) => panic!("there was a error syncing all os metadata to disk: {:?}", err)
-                };
-            }
-            Err(_) => {} //fall through to shutdown check
-        }
-        sleep(Duration::new(0, 1000));
-        if unsafe { SHUTDOWN.load(Ordering::SeqCst) } {
-            break 'store_log_update;
-        } else {
-            continue 'store_log_update;
+    // the queue closes once the log listener has passed on
+    // every update and gone, so everything sent is written
+    while let Ok(update) = queue.dequeue().await {
+        if let Some(changed) = settings.try_changed() {
+            if changed.file != config.file {
+                file = LogFile::new(&changed.file, "file");
+            }
+            if changed.json_file != config.json_file {
+                json_file =
+                    LogFile::new(&changed.json_file, "json lines file");
+            }
+            config = changed;
+        }
+        if let Some(changed) = storage_settings.try_changed() {
+            storage = changed;
+        }
+
+        //we will start with newline delimited text
+        //noria is also a good option, and has webui
+        let line_buffer = format!(
+            "{} :: {} :: {} :: from {{thread: {}, task: {}}}\n",
+            update.timestamp.rfc3339(),
+            pad_job_string(&update.job),
+            pad_user_string(&update.user_string),
+            update.thread_name,
+            update.from_task
+        );
+        let wall_nanos = update.timestamp.wall_nanos;
+        let bytes = line_buffer.len();
+        let change = lines.write(
+            line_buffer,
+            bytes,
+            &directory_of(&config.file),
+            wall_nanos,
+            &failure_log,
+            |line| file.append(line, &log_storage_log),
+        );
+        if let Some(change) = change {
+            change.report(&storage, &controls);
+        }
+
+        if config.format == LogFormat::TextAndJsonLines {
+            let json_line = json::to_line(&update);
+            let bytes = json_line.len();
+            let change = json_lines.write(
+                json_line,
+                bytes,
+                &directory_of(&config.json_file),
+                wall_nanos,
+                &failure_log,
+                |line| json_file.append(line, &log_storage_log),
+            );
+            if let Some(change) = change {
+                change.report(&storage, &controls);
+            }
         }
     }
+    let wall_nanos = crate::time::now().wall_nanos;
+    let directory = directory_of(&config.file);
+    lines.finish(&directory, wall_nanos, &failure_log, |line| {
+        file.append(line, &log_storage_log)
+    });
+    let directory = directory_of(&config.json_file);
+    json_lines.finish(&directory, wall_nanos, &failure_log, |line| {
+        json_file.append(line, &log_storage_log)
+    });
+}
+
+/// the directory a log file is written to, a full or
+/// missing disk fails every file in it alike
+fn directory_of(file: &str) -> String {
+    match std::path::Path::new(file).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            parent.display().to_string()
+        }
+        _ => ".".to_string(),
+    }
+}
+
+/// a log file, opened as it is first written to, and
+/// opened again after a write to it fails
+struct LogFile {
+    path: String,
+    /// what it is, for the log
+    name: &'static str,
+    file: Option<std::fs::File>,
 }
 
-pub fn log_stop() {
-    unsafe { SHUTDOWN.store(true, Ordering::SeqCst) }
+impl LogFile {
+    fn new(path: &str, name: &'static str) -> LogFile {
+        LogFile {
+            path: path.to_string(),
+            name,
+            file: None,
+        }
+    }
+
+    /// this function appends `line`, and syncs it to the
+    /// disk, creating the file if it is not there
+    fn append(
+        &mut self,
+        line: &str,
+        log_storage_log: &LogPipe,
+    ) -> std::io::Result<()> {
+        let appended = check_write(&self.path, line.len())
+            .and_then(|()| self.open(log_storage_log))
+            .and_then(|file| {
+                file.write_all(line.as_bytes())?;
+                file.flush()?;
+                file.sync_all()
+            });
+        if appended.is_err() {
+            self.file = None;
+        }
+        appended
+    }
+
+    fn open(
+        &mut self,
+        log_storage_log: &LogPipe,
+    ) -> std::io::Result<&mut std::fs::File> {
+        if self.file.is_none() {
+            let opened = std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&self.path)?;
+            crate::info!(
+                log_storage_log,
+                Job::LogStorage,
+                "created or opened {} {}",
+                self.name,
+                self.path
+            );
+            self.file = Some(opened);
+        }
+        Ok(self.file.as_mut().expect("the log file was just opened"))
+    }
 }
 
 use std::format;
-use std::string::String;
+use std::string::{String, ToString};
+use std::vec::Vec;
 fn pad_user_string(user_string: &str) -> String {
     let mut padded_string = format!("{}", user_string);
     let desired_length = 45;
@@ -156,12 +280,219 @@ fn pad_job_string(job: &Job) -> String {
 /// this function opens a file on either the main storage
 /// (if no removable exists), or by default to the usb
 /// storage, allowing the user to view the videos on
-/// a PlayStation 3
+/// a PlayStation 3. Frames go to a frame file of their
+/// own for each segment, started at the first keyframe
+/// after the boundary, when the directory changes, or
+/// when the control socket asks for one. While recording
+/// is stopped the last pre-roll of frames is held, from the
+/// keyframe before it, and the rest dropped, so a bookmark
+/// made then is recorded from its pre-roll to the end of
+/// its post-roll, as audio is. Frames that cannot be
+/// written are held until they can
 pub async fn video_start(
-    queue: Receiver<VideoUpdate>,
+    mut queue: Receiver<Arc<VideoUpdate>>,
     video_storage_log: LogPipe,
+    mut settings: Setting<StorageConfig>,
+    mut controls: Controls,
 ) {
-    video_storage_log.info("started video storage", Job::VideoStorage);
+    crate::info!(video_storage_log, Job::VideoStorage, "started video storage");
+    let mut config = settings.current();
+    note_storage_policy(&config, &video_storage_log);
+    let mut recording = controls.recording.current();
+    controls.new_segment.current();
+    controls.bookmark.current();
+    let mut file = VideoFile {
+        frames: None,
+        segment_end: 0,
+        new_segment: false,
+    };
+    let mut pre_roll: VecDeque<Arc<VideoUpdate>> = VecDeque::new();
+    let mut stream = Stream::new("video", Job::VideoStorage);
+    // the wall clock a bookmark has us record until
+    let mut post_roll_end = 0;
+    while let Ok(update) = queue.dequeue().await {
+        file.new_segment |= controls.new_segment.try_changed().is_some();
+        if let Some(changed) = settings.try_changed() {
+            note_storage_policy(&changed, &video_storage_log);
+            file.new_segment |= changed.directory != config.directory;
+            config = changed;
+        }
+        if let Some(changed) = controls.recording.try_changed() {
+            recording = changed;
+            pre_roll.clear();
+        }
+        if let Some(Some(bookmark)) = controls.bookmark.try_changed() {
+            post_roll_end = post_roll_end.max(bookmark.to);
+            if !recording {
+                crate::info!(
+                    video_storage_log,
+                    Job::VideoStorage,
+                    "recording {:.1}s of video held from before the \
+                     bookmark, and what follows until its post-roll ends",
+                    held_seconds(&pre_roll, |held| held.timestamp)
+                );
+            }
+        }
+        if !recording && update.timestamp.wall_nanos >= post_roll_end {
+            file.finish(&video_storage_log);
+            let kept_from = update.timestamp.wall_nanos.saturating_sub(
+                config.pre_roll_seconds as u64 * 1_000_000_000,
+            );
+            pre_roll.push_back(update);
+            // the oldest frame kept has to be a keyframe, the
+            // last one before the pre-roll, or failing that
+            // the first one in it
+            let oldest = pre_roll
+                .iter()
+                .rposition(|held| {
+                    held.keyframe && held.timestamp.wall_nanos <= kept_from
+                })
+                .or_else(|| pre_roll.iter().position(|held| held.keyframe))
+                .unwrap_or(pre_roll.len());
+            pre_roll.drain(..oldest);
+            continue;
+        }
+
+        for held in pre_roll.drain(..).chain(core::iter::once(update)) {
+            let bytes = held.data.len();
+            let wall_nanos = held.timestamp.wall_nanos;
+            let change = stream.write(
+                held,
+                bytes,
+                &config.directory,
+                wall_nanos,
+                &video_storage_log,
+                |held| file.write(held, &config, &video_storage_log),
+            );
+            if let Some(change) = change {
+                change.report(&config, &controls);
+            }
+        }
+    }
+    let change = stream.finish(
+        &config.directory,
+        crate::time::now().wall_nanos,
+        &video_storage_log,
+        |update| file.write(update, &config, &video_storage_log),
+    );
+    if let Some(change) = change {
+        change.report(&config, &controls);
+    }
+    file.finish(&video_storage_log);
+}
+
+/// the video file being written, if there is one
+struct VideoFile {
+    frames: Option<FramesWriter>,
+    /// the wall clock the open file's segment ends at
+    segment_end: u64,
+    /// whether a new file was asked for, it is started at
+    /// the next keyframe
+    new_segment: bool,
+}
+
+impl VideoFile {
+    /// this function writes a frame, to a new file when one
+    /// is due and the frame is a keyframe, frames before the
+    /// first keyframe have nothing to be decoded from and
+    /// are dropped. A file that fails is given up
+    fn write(
+        &mut self,
+        update: &VideoUpdate,
+        config: &StorageConfig,
+        video_storage_log: &LogPipe,
+    ) -> std::io::Result<()> {
+        let due = self.frames.is_none()
+            || self.new_segment
+            || update.timestamp.wall_nanos >= self.segment_end;
+        if due && update.keyframe {
+            self.new_segment = false;
+            if self.frames.is_some() {
+                self.finish(video_storage_log);
+                remove_expired(config, Job::VideoStorage, video_storage_log);
+            }
+            self.segment_end = crate::recordings::segment_end(
+                update.timestamp.wall_nanos,
+                config.segment_minutes,
+            );
+            let path = std::path::Path::new(&config.directory)
+                .join(crate::recordings::video_file_name(&update.timestamp))
+                .display()
+                .to_string();
+            self.frames = Some(FramesWriter::create(&path)?);
+            crate::info!(
+                video_storage_log,
+                Job::VideoStorage,
+                "recording {}",
+                path
+            );
+            note_stream_start(
+                StreamStart {
+                    pts: update.pts,
+                    timestamp: update.timestamp,
+                    sample_rate: 0,
+                },
+                false,
+                &config.directory,
+                self.segment_end,
+                video_storage_log,
+            );
+        }
+
+        if let Some(open) = self.frames.as_mut() {
+            if let Err(err) = open.write_frame(
+                update.timestamp.wall_nanos,
+                update.keyframe,
+                &update.data,
+            ) {
+                self.give_up(video_storage_log);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// this function gives up the open file after a write
+    /// to it failed, what was written before is kept
+    fn give_up(&mut self, video_storage_log: &LogPipe) {
+        if let Some(failed) = self.frames.take() {
+            if let Err(err) = failed.finalize() {
+                crate::debug!(
+                    video_storage_log,
+                    Job::VideoStorage,
+                    "could not finish the failed video file: {}",
+                    err
+                );
+            }
+        }
+    }
+
+    /// this function finishes the open file, if there is one
+    fn finish(&mut self, video_storage_log: &LogPipe) {
+        if let Some(finished) = self.frames.take() {
+            if let Err(err) = finished.finalize() {
+                crate::error!(
+                    video_storage_log,
+                    Job::VideoStorage,
+                    "could not finish video file: {:?}",
+                    err
+                );
+            }
+        }
+    }
+}
+
+fn note_storage_policy(config: &StorageConfig, video_storage_log: &LogPipe) {
+    crate::info!(
+        video_storage_log,
+        Job::VideoStorage,
+        "recording to {} in {} minute segments, reserving {} hours on \
+         the main disk and {} on removable storage",
+        config.directory,
+        config.segment_minutes,
+        config.main_reserve_hours,
+        config.removable_reserve_hours
+    );
 }
 
 /// this function opens a file on the main storage
@@ -169,10 +500,890 @@ pub async fn video_start(
 /// seperate from the video files to be used in legal
 /// proceedings, audio files should only be used for
 /// intelligence gathering rather than for capturing
-/// disruptive activity directly
+/// disruptive activity directly. A new file is started at
+/// each segment boundary, when the directory changes, and
+/// when the control socket asks for one, and recordings
+/// older than the retention are deleted as it is. While
+/// the control socket has stopped recording, the last
+/// pre-roll of updates is held and the rest dropped, so a
+/// bookmark made then is recorded from its pre-roll to
+/// the end of its post-roll. Updates that cannot be
+/// written are held until they can
 pub async fn audio_start(
-    queue: Receiver<AudioUpdate>,
+    mut queue: Receiver<AudioUpdate>,
     audio_storage_log: LogPipe,
+    mut settings: Setting<StorageConfig>,
+    mut controls: Controls,
 ) {
-    audio_storage_log.info("started audio storage", Job::AudioStorage);
+    crate::info!(audio_storage_log, Job::AudioStorage, "started audio storage");
+    let mut config = settings.current();
+    let mut recording = controls.recording.current();
+    controls.new_segment.current();
+    controls.bookmark.current();
+    let mut file = AudioFile {
+        wav: None,
+        segment_end: 0,
+        alignment: Alignment::Aligned,
+    };
+    let mut pre_roll: VecDeque<AudioUpdate> = VecDeque::new();
+    let mut stream = Stream::new("audio", Job::AudioStorage);
+    // the wall clock a bookmark has us record until
+    let mut post_roll_end = 0;
+    while let Ok(update) = queue.dequeue().await {
+        let mut new_segment = controls.new_segment.try_changed().is_some();
+        if let Some(changed) = settings.try_changed() {
+            new_segment |= changed.directory != config.directory;
+            config = changed;
+        }
+        if let Some(changed) = controls.recording.try_changed() {
+            recording = changed;
+            pre_roll.clear();
+            crate::info!(
+                audio_storage_log,
+                Job::AudioStorage,
+                "audio storage {}",
+                if recording { "resumed" } else { "paused" }
+            );
+        }
+        if let Some(Some(bookmark)) = controls.bookmark.try_changed() {
+            post_roll_end = post_roll_end.max(bookmark.to);
+            if !recording {
+                crate::info!(
+                    audio_storage_log,
+                    Job::AudioStorage,
+                    "recording {:.1}s held from before the bookmark, and \
+                     what follows until its post-roll ends",
+                    held_seconds(&pre_roll, |held| held.timestamp)
+                );
+            }
+        }
+        if !recording && update.timestamp.wall_nanos >= post_roll_end {
+            file.finish(&controls, &audio_storage_log);
+            let kept_from = update.timestamp.wall_nanos.saturating_sub(
+                config.pre_roll_seconds as u64 * 1_000_000_000,
+            );
+            pre_roll.push_back(update);
+            while pre_roll
+                .front()
+                .is_some_and(|held| held.timestamp.wall_nanos < kept_from)
+            {
+                pre_roll.pop_front();
+            }
+            continue;
+        }
+
+        for held in pre_roll.drain(..).chain(core::iter::once(update)) {
+            let bytes = held.data.len() * 4;
+            let wall_nanos = held.timestamp.wall_nanos;
+            let change = stream.write(
+                held,
+                bytes,
+                &config.directory,
+                wall_nanos,
+                &audio_storage_log,
+                |held| {
+                    file.write(
+                        held,
+                        core::mem::take(&mut new_segment),
+                        &config,
+                        &controls,
+                        &audio_storage_log,
+                    )
+                },
+            );
+            if let Some(change) = change {
+                change.report(&config, &controls);
+            }
+        }
+    }
+    let change = stream.finish(
+        &config.directory,
+        crate::time::now().wall_nanos,
+        &audio_storage_log,
+        |held| file.write(held, false, &config, &controls, &audio_storage_log),
+    );
+    if let Some(change) = change {
+        change.report(&config, &controls);
+    }
+    file.finish(&controls, &audio_storage_log);
+}
+
+/// the seconds of updates held for a pre-roll, each
+/// captured at the time `timestamp` gives
+fn held_seconds<T>(
+    pre_roll: &VecDeque<T>,
+    timestamp: impl Fn(&T) -> Timestamp,
+) -> f64 {
+    match (pre_roll.front(), pre_roll.back()) {
+        (Some(first), Some(last)) => {
+            timestamp(last).since(&timestamp(first)).as_secs_f64()
+        }
+        _ => 0.0,
+    }
+}
+
+/// the audio file being written, if there is one
+struct AudioFile {
+    wav: Option<WavWriter>,
+    /// the wall clock the open file's segment ends at
+    segment_end: u64,
+    /// how the open file is still to be lined up with the
+    /// video of its segment
+    alignment: Alignment,
+}
+
+impl AudioFile {
+    /// this function writes an update, to a new file when
+    /// the segment ends, the format changes, or `new_segment`
+    /// asks for one. A file that fails is given up
+    fn write(
+        &mut self,
+        update: &AudioUpdate,
+        new_segment: bool,
+        config: &StorageConfig,
+        controls: &Controls,
+        audio_storage_log: &LogPipe,
+    ) -> std::io::Result<()> {
+        // a device with a different format needs a file of its own
+        let reopen = match self.wav.as_ref() {
+            Some(open) => {
+                open.sample_rate != update.sample_rate
+                    || open.channels != update.channels
+                    || update.timestamp.wall_nanos >= self.segment_end
+                    || new_segment
+            }
+            None => true,
+        };
+        if reopen {
+            if let Some(finished) = self.wav.take() {
+                finish_wav(finished, audio_storage_log);
+                remove_expired(config, Job::AudioStorage, audio_storage_log);
+            }
+            self.segment_end = crate::recordings::segment_end(
+                update.timestamp.wall_nanos,
+                config.segment_minutes,
+            );
+            let path = std::path::Path::new(&config.directory)
+                .join(crate::recordings::audio_file_name(&update.timestamp))
+                .display()
+                .to_string();
+            let created =
+                WavWriter::create(&path, update.sample_rate, update.channels);
+            match created {
+                Ok(created) => {
+                    crate::info!(
+                        audio_storage_log,
+                        Job::AudioStorage,
+                        "recording {} from {}",
+                        path,
+                        update.name
+                    );
+                    controls.audio_file.set(Some(path.clone()));
+                    self.wav = Some(created);
+                    self.alignment = note_stream_start(
+                        StreamStart {
+                            pts: update.pts,
+                            timestamp: update.timestamp,
+                            sample_rate: update.sample_rate,
+                        },
+                        true,
+                        &config.directory,
+                        self.segment_end,
+                        audio_storage_log,
+                    );
+                }
+                Err(err) => {
+                    controls.audio_file.set(None);
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some(open) = self.wav.as_mut() {
+            let written = match self.alignment {
+                Alignment::Aligned => open.write_samples(&update.data),
+                alignment => {
+                    let mut data = update.data.to_vec();
+                    align_audio(&mut data, update.channels, alignment);
+                    // a skip longer than one update goes on
+                    // into the next
+                    let channels = update.channels.max(1) as usize;
+                    self.alignment = match alignment {
+                        Alignment::SkipSamples(frames) => {
+                            let skipped = update.data.len() / channels;
+                            match frames.saturating_sub(skipped as u64) {
+                                0 => Alignment::Aligned,
+                                left => Alignment::SkipSamples(left),
+                            }
+                        }
+                        _ => Alignment::Aligned,
+                    };
+                    open.write_samples(&data)
+                }
+            };
+            if let Err(err) = written {
+                // what was written before stays playable as
+                // far as the header was last patched
+                if let Some(failed) = self.wav.take() {
+                    if let Err(err) = failed.finalize() {
+                        crate::debug!(
+                            audio_storage_log,
+                            Job::AudioStorage,
+                            "could not finish the failed audio file: {}",
+                            err
+                        );
+                    }
+                }
+                controls.audio_file.set(None);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// this function finishes the open file, if there is one
+    fn finish(&mut self, controls: &Controls, audio_storage_log: &LogPipe) {
+        if let Some(finished) = self.wav.take() {
+            finish_wav(finished, audio_storage_log);
+            controls.audio_file.set(None);
+        }
+    }
+}
+
+/// this function deletes the recordings in the directory
+/// that are older than the retention allows, but not those
+/// a bookmark in the catalog protects. Audio and video
+/// storage both call this as they finish a file, so a file
+/// the other has just deleted is not a problem
+fn remove_expired(config: &StorageConfig, job: Job, storage_log: &LogPipe) {
+    if config.retention_hours == 0 {
+        return;
+    }
+    let recordings = crate::recordings::scan(&config.directory).and_then(
+        |mut audio| {
+            audio.extend(crate::recordings::scan_video(&config.directory)?);
+            Ok(audio)
+        },
+    );
+    let recordings = match recordings {
+        Ok(recordings) => recordings,
+        Err(err) => {
+            crate::warn!(
+                storage_log,
+                job,
+                "could not look for expired recordings: {}",
+                err
+            );
+            return;
+        }
+    };
+    let bookmarks = match catalog::read(&config.directory) {
+        Ok(bookmarks) => bookmarks,
+        Err(err) => {
+            // without the catalog nothing can be known to be
+            // safe to delete
+            crate::warn!(
+                storage_log,
+                job,
+                "keeping every recording, the catalog is unreadable: {}",
+                err
+            );
+            return;
+        }
+    };
+    let expired = crate::recordings::expired(
+        recordings,
+        crate::time::now().wall_nanos,
+        config.retention_hours,
+    );
+    for recording in expired {
+        if catalog::protects(&bookmarks, &recording) {
+            crate::debug!(
+                storage_log,
+                job.clone(),
+                "keeping {}, a bookmark protects it",
+                recording.path
+            );
+            continue;
+        }
+        match std::fs::remove_file(&recording.path) {
+            Ok(()) => crate::info!(
+                storage_log,
+                job.clone(),
+                "deleted {}, it is older than {} hours",
+                recording.path,
+                config.retention_hours
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => crate::warn!(
+                storage_log,
+                job.clone(),
+                "could not delete expired {}: {}",
+                recording.path,
+                err
+            ),
+        }
+    }
+}
+
+fn finish_wav(wav: WavWriter, audio_storage_log: &LogPipe) {
+    if let Err(err) = wav.finalize() {
+        crate::error!(
+            audio_storage_log,
+            Job::AudioStorage,
+            "could not finish audio file: {:?}",
+            err
+        );
+    }
+}
+
+use crate::time::presentation::{offset_nanos, Alignment, Pts};
+use crate::time::Timestamp;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// the first update in a file storage writes, which is
+/// where the file starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamStart {
+    /// the pts of the first sample or frame in the file
+    pub pts: Pts,
+    /// the capture time of the first sample or frame
+    pub timestamp: Timestamp,
+    /// samples per second for audio, zero for video
+    pub sample_rate: u32,
+}
+
+/// the furthest storage moves the start of an audio file
+/// to line it up with the video, a file started further
+/// from the video than this was not started with it
+pub const MAX_ALIGNMENT: core::time::Duration =
+    core::time::Duration::from_secs(2);
+
+/// where an audio or a video file started, and the end of
+/// the segment it holds
+#[derive(Debug, Clone, Copy)]
+struct Started {
+    start: StreamStart,
+    segment_end: u64,
+}
+
+/// this holds the start of the audio and the video files
+/// that are being written together to one directory, so
+/// that the exact offset between them can be recorded
+/// once both exist
+#[derive(Default)]
+struct AvLink {
+    audio: Option<Started>,
+    video: Option<Started>,
+}
+
+/// the files being written to each directory, which is
+/// only ever the main directory and the removable ones
+static AV_LINKS: Mutex<BTreeMap<String, AvLink>> = Mutex::new(BTreeMap::new());
+
+/// this function remembers where the audio or video file
+/// started, and once both files of a segment in
+/// `directory` have, appends a line to av_links.txt there
+/// recording the offset between them. An audio file
+/// started just after the video of its segment is lined
+/// up with it, as the returned Alignment says, and the
+/// line records the offset once it is, an audio file
+/// started first is recorded as it is
+fn note_stream_start(
+    start: StreamStart,
+    is_audio: bool,
+    directory: &str,
+    segment_end: u64,
+    storage_log: &LogPipe,
+) -> Alignment {
+    let job = if is_audio {
+        Job::AudioStorage
+    } else {
+        Job::VideoStorage
+    };
+    let (line, alignment) = {
+        let mut links = match AV_LINKS.lock() {
+            Ok(links) => links,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let link = links.entry(directory.to_string()).or_default();
+        let started = Some(Started { start, segment_end });
+        if is_audio {
+            link.audio = started;
+        } else {
+            link.video = started;
+        }
+        let (audio, video) = match (link.audio, link.video) {
+            (Some(audio), Some(video))
+                if audio.segment_end == video.segment_end =>
+            {
+                (audio.start, video.start)
+            }
+            _ => return Alignment::Aligned,
+        };
+        let offset = offset_nanos(audio.pts, video.pts).unsigned_abs();
+        if !is_audio || offset > MAX_ALIGNMENT.as_nanos() as u64 {
+            (av_link_line(&audio, &video), Alignment::Aligned)
+        } else {
+            let alignment =
+                Alignment::between(audio.pts, video.pts, audio.sample_rate);
+            let aligned = StreamStart {
+                pts: video.pts,
+                ..audio
+            };
+            (av_link_line(&aligned, &video), alignment)
+        }
+    };
+
+    use std::fs::OpenOptions;
+    let path = std::path::Path::new(directory).join("av_links.txt");
+    match OpenOptions::new().append(true).create(true).open(&path)
+    {
+        Ok(mut file) => match file.write_all(line.as_bytes()) {
+            Ok(_) => crate::info!(
+                storage_log,
+                job,
+                "linked audio and video: {}",
+                line.trim_end()
+            ),
+            Err(err) => crate::error!(
+                storage_log,
+                job,
+                "could not record audio and video link: {:?}",
+                err
+            ),
+        },
+        Err(err) => crate::error!(
+            storage_log,
+            job,
+            "could not open {}: {:?}",
+            path.display(),
+            err
+        ),
+    }
+    alignment
+}
+
+/// this function describes the link between an audio
+/// file and a video file as one line, with the exact
+/// offset from the first frame to the first sample and
+/// how to trim or pad the audio to line them up
+pub fn av_link_line(audio: &StreamStart, video: &StreamStart) -> String {
+    format!(
+        "video {} audio {} offset_nanos {} alignment {:?}\n",
+        video.timestamp.rfc3339(),
+        audio.timestamp.rfc3339(),
+        offset_nanos(audio.pts, video.pts),
+        Alignment::between(audio.pts, video.pts, audio.sample_rate),
+    )
+}
+
+/// a line of av_links.txt read back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AvLinkLine {
+    /// the wall clock of the first frame and the first sample
+    pub video: u64,
+    pub audio: u64,
+    /// from the first frame to the first sample, on the
+    /// presentation clock
+    pub offset_nanos: i64,
+}
+
+/// this function reads a line written by av_link_line,
+/// returning None for one that is not
+pub fn parse_av_link_line(line: &str) -> Option<AvLinkLine> {
+    let mut words = line.split_whitespace();
+    let mut value = |name: &str| match (words.next(), words.next()) {
+        (Some(word), Some(value)) if word == name => Some(value),
+        _ => None,
+    };
+    let video = crate::time::parse_wall(value("video")?).ok()?;
+    let audio = crate::time::parse_wall(value("audio")?).ok()?;
+    let offset_nanos = value("offset_nanos")?.parse().ok()?;
+    Some(AvLinkLine {
+        video,
+        audio,
+        offset_nanos,
+    })
+}
+
+/// this function lines interleaved audio up with the start
+/// of the video it will be stored with in an AV container,
+/// by putting silence in front of it or trimming its start
+pub fn align_audio(data: &mut Vec<f32>, channels: u16, alignment: Alignment) {
+    let channels = channels.max(1) as usize;
+    match alignment {
+        Alignment::Aligned => {}
+        Alignment::PadSamples(frames) => {
+            let silence = frames as usize * channels;
+            data.splice(0..0, core::iter::repeat(0.0).take(silence));
+        }
+        Alignment::SkipSamples(frames) => {
+            let skipped = (frames as usize * channels).min(data.len());
+            data.drain(0..skipped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::simulated::EIO;
+    use super::*;
+    use crate::config::Config;
+    use crate::simulation::{
+        after, recorded, recorded_at, Simulation, MAIN_DEVICE, PERIOD,
+        SAMPLE_RATE,
+    };
+    use crate::ui::Status;
+
+    fn start(pts: Pts, sample_rate: u32) -> StreamStart {
+        StreamStart {
+            pts,
+            timestamp: Timestamp {
+                wall_nanos: 1_690_500_706_000_000_000 + pts,
+                monotonic_nanos: pts,
+            },
+            sample_rate,
+        }
+    }
+
+    #[test]
+    fn records_the_offset_between_linked_files() {
+        let line = av_link_line(
+            &start(1_010_000_000, 48_000),
+            &start(1_000_000_000, 0),
+        );
+        assert_eq!(
+            line,
+            "video 2023-07-27T23:31:47.000000000Z audio 2023-07-27T23:31:47.010000000Z offset_nanos 10000000 alignment PadSamples(480)\n"
+        );
+        assert_eq!(
+            parse_av_link_line(&line),
+            Some(AvLinkLine {
+                video: 1_690_500_707_000_000_000,
+                audio: 1_690_500_707_010_000_000,
+                offset_nanos: 10_000_000,
+            })
+        );
+        assert_eq!(parse_av_link_line("video 2023-07-27 audio"), None);
+    }
+
+    #[test]
+    fn lines_up_audio_started_just_after_its_video() {
+        let _pipe = crate::log::TEST_PIPE
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let (_out, out_sender) = crate::queue::LOG_OUT.open();
+        let (_storage, storage_sender) = crate::queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+        let directory = std::env::temp_dir()
+            .join(format!("av_links_{}", std::process::id()))
+            .display()
+            .to_string();
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let video = start(1_000_000_000, 0);
+        let audio = start(1_010_000_000, 48_000);
+        let late = start(600_000_000_000, 48_000);
+        assert_eq!(
+            note_stream_start(video, false, &directory, 1, &log),
+            Alignment::Aligned
+        );
+        assert_eq!(
+            note_stream_start(audio, true, &directory, 1, &log),
+            Alignment::PadSamples(480)
+        );
+        // a file started far from the video is only recorded
+        assert_eq!(
+            note_stream_start(late, true, &directory, 1, &log),
+            Alignment::Aligned
+        );
+        // and the next segment waits for its own video
+        assert_eq!(
+            note_stream_start(audio, true, &directory, 2, &log),
+            Alignment::Aligned
+        );
+        let links =
+            std::fs::read_to_string(format!("{}/av_links.txt", directory))
+                .unwrap();
+        let lines: Vec<&str> = links.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("offset_nanos 0 alignment Aligned"));
+        assert!(lines[1].ends_with("offset_nanos 599000000000 alignment \
+             PadSamples(28752000)"));
+        std::fs::remove_dir_all(&directory).unwrap();
+        crate::log::LogPipe::shutdown();
+    }
+
+    #[test]
+    fn pads_and_trims_interleaved_audio() {
+        let mut data = std::vec![1.0, 2.0, 3.0, 4.0];
+        align_audio(&mut data, 2, Alignment::PadSamples(1));
+        assert_eq!(data, std::vec![0.0, 0.0, 1.0, 2.0, 3.0, 4.0]);
+        align_audio(&mut data, 2, Alignment::SkipSamples(2));
+        assert_eq!(data, std::vec![3.0, 4.0]);
+        align_audio(&mut data, 2, Alignment::SkipSamples(5));
+        assert!(data.is_empty());
+    }
+
+    /// this function starts recording in 10 minute segments,
+    /// to removable storage from 5 minutes in
+    fn recording_to_stick(
+        name: &str,
+        configure: impl FnOnce(&mut Config),
+    ) -> (Simulation, String) {
+        let mut simulation = Simulation::start(name, |config| {
+            config.storage.segment_minutes = 10;
+            configure(config);
+        });
+        // the desktop takes a while to mount it
+        simulation.devices.insert("STICK", None);
+        simulation.run_for(after(4, 0));
+        simulation.devices.mount("STICK");
+        simulation.run_for(after(1, 0));
+        let stick = simulation.recording_directory("STICK");
+        (simulation, stick)
+    }
+
+    /// how many times a stream was found failing in a new way
+    fn failures(simulation: &Simulation) -> usize {
+        simulation
+            .lines
+            .iter()
+            .filter(|line| line.contains("could not write"))
+            .filter(|line| line.contains("holding"))
+            .count()
+    }
+
+    #[test]
+    fn links_audio_and_video_in_every_segment_of_each_directory() {
+        let (mut simulation, stick) = recording_to_stick("linked", |_| {});
+        simulation.run_for(after(14, 0));
+        simulation.stop();
+
+        let links = |directory: &str, starts: &[core::time::Duration]| {
+            let written =
+                std::fs::read_to_string(format!("{}/av_links.txt", directory))
+                    .unwrap();
+            let expected: Vec<String> = starts
+                .iter()
+                .map(|start| {
+                    let at = Timestamp {
+                        wall_nanos: Simulation::at(*start),
+                        monotonic_nanos: 0,
+                    }
+                    .rfc3339();
+                    format!(
+                        "video {} audio {} offset_nanos 0 alignment Aligned",
+                        at, at
+                    )
+                })
+                .collect();
+            assert_eq!(written.lines().collect::<Vec<&str>>(), expected);
+        };
+        links(&simulation.main, &[after(0, 0)]);
+        links(&stick, &[after(5, 5), after(10, 0)]);
+    }
+
+    #[test]
+    fn records_on_after_a_stick_is_pulled_mid_segment() {
+        let (mut simulation, stick) = recording_to_stick("pulled", |_| {});
+        simulation.run_for(after(10, 0));
+        // pulled out before the desktop notices, what could
+        // not be written goes to the main directory
+        simulation.devices.remove("STICK");
+        simulation.run_for(after(15, 0));
+        simulation.stop();
+
+        assert_eq!(
+            recorded(&stick),
+            recorded_at(&[after(5, 5), after(10, 0)])
+        );
+        assert_eq!(
+            recorded(&simulation.main),
+            recorded_at(&[
+                after(0, 0),
+                after(15, 5),
+                after(20, 0),
+                after(30, 0)
+            ])
+        );
+        assert!(simulation.logged("it is gone"));
+        assert!(simulation.logged(&format!(
+            "writing audio to {} again, 0 held updates were dropped",
+            simulation.main
+        )));
+    }
+
+    #[test]
+    fn records_on_after_a_stick_is_remounted_read_only() {
+        let (mut simulation, stick) = recording_to_stick("read_only", |_| {});
+        simulation.run_for(after(10, 0));
+        simulation.devices.remount_read_only("STICK");
+        simulation.run_for(after(15, 0));
+        simulation.stop();
+
+        assert_eq!(
+            recorded(&stick),
+            recorded_at(&[after(5, 5), after(10, 0)])
+        );
+        assert_eq!(
+            recorded(&simulation.main),
+            recorded_at(&[
+                after(0, 0),
+                after(15, 5),
+                after(20, 0),
+                after(30, 0)
+            ])
+        );
+        assert!(simulation.logged(&format!(
+            "removable storage {} failed, it is read only",
+            stick
+        )));
+    }
+
+    #[test]
+    fn retries_a_failed_write_at_the_next_update() {
+        let (mut simulation, stick) =
+            recording_to_stick("failed_write", |_| {});
+        simulation.run_for(after(10, 0));
+        simulation.devices.fail_writes("STICK", EIO, 1);
+        simulation.run_for(after(15, 0));
+        simulation.stop();
+
+        // the stream that failed started a new file with the
+        // update it failed to write
+        let (audio, video) = recorded(&stick);
+        let (expected_audio, expected_video) = recorded_at(&[
+            after(5, 5),
+            after(10, 0),
+            after(15, 5),
+            after(20, 0),
+            after(30, 0),
+        ]);
+        assert_eq!(audio.len() + video.len(), 9);
+        assert!(audio.iter().all(|name| expected_audio.contains(name)));
+        assert!(video.iter().all(|name| expected_video.contains(name)));
+        assert_eq!(recorded(&simulation.main), recorded_at(&[after(0, 0)]));
+        assert_eq!(failures(&simulation), 1);
+        assert!(simulation.logged("it failed, holding 1 updates"));
+    }
+
+    #[test]
+    fn moves_off_a_full_stick_and_back_once_it_has_space() {
+        let (mut simulation, stick) = recording_to_stick("full", |_| {});
+        simulation.run_for(after(10, 0));
+        simulation.devices.fill("STICK");
+        simulation.run_for(after(1, 0));
+        assert_eq!(
+            simulation.controls.removable_storage.get(),
+            RemovableStorage::Full
+        );
+        simulation.run_for(after(4, 0));
+        // while recording goes on to the main directory
+        assert_eq!(simulation.controls.status.get(), Status::StorageFull);
+        simulation.devices.free("STICK", 1 << 30);
+        // it is tried again RETRY after it failed
+        simulation.run_for(after(10, 0));
+        simulation.stop();
+
+        assert_eq!(
+            recorded(&stick),
+            recorded_at(&[
+                after(5, 5),
+                after(10, 0),
+                after(21, 5),
+                after(30, 0)
+            ])
+        );
+        assert_eq!(
+            recorded(&simulation.main),
+            recorded_at(&[after(0, 0), after(15, 5), after(20, 0)])
+        );
+        assert!(simulation.logged("No space left on device"));
+        assert!(simulation.logged(&format!(
+            "trying removable storage {} again",
+            stick
+        )));
+        assert_eq!(
+            simulation.controls.removable_storage.get(),
+            RemovableStorage::HasCapacity
+        );
+    }
+
+    #[test]
+    fn pauses_on_a_full_main_disk_without_losing_what_it_holds() {
+        let mut simulation = Simulation::start("main_full", |config| {
+            config.storage.segment_minutes = 10;
+            config.log.file = format!("{}/log.txt", config.storage.directory);
+        });
+        simulation.run_for(after(10, 0));
+        simulation.devices.fill(MAIN_DEVICE);
+        simulation.run_for(after(3, 0));
+        assert_eq!(simulation.controls.main_storage.get(), MainStorage::Full);
+        assert_eq!(simulation.controls.status.get(), Status::StorageFull);
+        simulation.devices.free(MAIN_DEVICE, 1 << 30);
+        simulation.run_for(after(7, 0));
+        simulation.stop();
+
+        let main = simulation.main.clone();
+        assert_eq!(
+            recorded(&main),
+            recorded_at(&[
+                after(0, 0),
+                after(10, 0),
+                after(10, 5),
+                after(20, 0)
+            ])
+        );
+        // every chunk from 10:05 to 19:55 was held and then
+        // written once there was space
+        let (audio, _) = recorded_at(&[after(10, 5)]);
+        let held = wav::WavReader::open(&format!("{}/{}", main, audio[0]))
+            .unwrap();
+        assert_eq!(
+            held.format.frames(),
+            119 * PERIOD.as_secs() * SAMPLE_RATE as u64
+        );
+        assert_eq!(
+            simulation.controls.main_storage.get(),
+            MainStorage::HasCapacity
+        );
+        let stored =
+            std::fs::read_to_string(format!("{}/log.txt", main)).unwrap();
+        assert!(stored.contains("could not write audio"));
+        // what log storage says of itself is not stored
+        assert!(simulation.logged(&format!("writing the log to {}", main)));
+        assert!(!stored.contains("writing the log to"));
+    }
+
+    #[test]
+    fn records_the_pre_roll_of_a_bookmark_made_while_stopped() {
+        let mut simulation = Simulation::start("paused_mark", |config| {
+            config.storage.segment_minutes = 60;
+        });
+        simulation.run_for(after(10, 0));
+        simulation.controls.recording.set(false);
+        simulation.run_for(after(10, 0));
+        // 30s of pre-roll and 60s of post-roll
+        simulation.mark("someone at the door");
+        simulation.run_for(after(5, 0));
+        simulation.stop();
+
+        let main = simulation.main.clone();
+        assert_eq!(
+            recorded(&main),
+            recorded_at(&[after(0, 0), after(19, 30)])
+        );
+        let (_, video) = recorded_at(&[after(19, 30)]);
+        let path = format!("{}/{}", main, video[0]);
+        let frames = frames::FramesReader::open(&path)
+            .unwrap()
+            .last_frame()
+            .unwrap();
+        // from 19:30 to 20:55, a frame each PERIOD
+        assert_eq!(frames, (18, Some(Simulation::at(after(20, 55)))));
+        assert!(simulation.logged("recording 30.0s of video held"));
+    }
 }
@@ -0,0 +1,466 @@
+/// This is where storage survives the disk it writes to.
+/// A write that fails is sorted by why: the disk is full,
+/// the device has gone, it was remounted read only, or the
+/// error may pass. The update is held in memory, with each
+/// one after it, until the stream can write again: at the
+/// next update after a transient error, as soon as storage
+/// moves to another directory, or once RETRY has passed on
+/// the updates' wall clock. The file a write failed in is
+/// given up, a new one is started by the retry, so no
+/// record is left half written in the middle of a file. A
+/// removable directory that fails is reported through
+/// Controls, and Removable moves recording back to the
+/// main directory, while a main directory that fails
+/// leaves the stream paused, holding what comes, until it
+/// can be written again. Past MAX_HELD_BYTES the oldest
+/// held updates are dropped, the bounded queues before
+/// storage keep what is still to come
+use super::mounts;
+use crate::config::StorageConfig;
+use crate::control::Controls;
+use crate::hardware::storage::{MainStorage, RemovableStorage};
+use crate::log::{Job, LogPipe};
+
+use core::time::Duration;
+
+use std::collections::VecDeque;
+use std::io;
+use std::string::{String, ToString};
+
+/// the errors a write fails with that are told apart, as
+/// linux numbers them
+pub const ENOENT: i32 = 2;
+pub const ENXIO: i32 = 6;
+pub const ENODEV: i32 = 19;
+pub const ENOSPC: i32 = 28;
+pub const EROFS: i32 = 30;
+pub const ENOTCONN: i32 = 107;
+pub const ESTALE: i32 = 116;
+pub const EDQUOT: i32 = 122;
+
+/// how long a directory that failed is left before it is
+/// written to again
+pub const RETRY: Duration = Duration::from_secs(5 * 60);
+
+/// transient errors in a row before a directory is taken
+/// to be gone
+pub const TRANSIENT_LIMIT: u32 = 3;
+
+/// the bytes of updates a stream holds while it cannot write
+pub const MAX_HELD_BYTES: usize = 64 * 1024 * 1024;
+
+/// why a write failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// the disk, or our quota on it, is full
+    NoSpace,
+    /// the device was pulled out or unmounted
+    Gone,
+    /// the filesystem was remounted read only, as the
+    /// kernel does when it finds it damaged
+    ReadOnly,
+    /// anything else, such as an EIO that may pass
+    Transient,
+}
+
+impl Failure {
+    /// this function sorts an error by the errno it carries
+    pub fn of(err: &io::Error) -> Failure {
+        match err.raw_os_error() {
+            Some(ENOSPC) | Some(EDQUOT) => Failure::NoSpace,
+            Some(EROFS) => Failure::ReadOnly,
+            Some(ENOENT) | Some(ENXIO) | Some(ENODEV) | Some(ENOTCONN)
+            | Some(ESTALE) => Failure::Gone,
+            // such as EIO, from a bad block or a device on its
+            // way out, which TRANSIENT_LIMIT errors in a row
+            // tell apart
+            _ => Failure::Transient,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Failure::NoSpace => "it is full",
+            Failure::Gone => "it is gone",
+            Failure::ReadOnly => "it is read only",
+            Failure::Transient => "it failed",
+        }
+    }
+}
+
+/// a directory a stream could not write to, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failed {
+    /// the name of the stream
+    pub stream: &'static str,
+    pub directory: String,
+    pub failure: Failure,
+}
+
+/// what a stream found out as it wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// a directory failed in a way that will not pass at
+    /// the next write
+    Failed(Failed),
+    /// the stream is writing again, to the directory
+    Recovered {
+        stream: &'static str,
+        directory: String,
+    },
+}
+
+impl Change {
+    /// this function hands the change to the UI, and to
+    /// Removable, which find every stream still failing in
+    /// Controls::storage_failed. A full disk is shown as
+    /// full until no stream finds it full, once one has
+    /// written to it again
+    pub fn report(self, config: &StorageConfig, controls: &Controls) {
+        let root = &config.removable_root;
+        match self {
+            Change::Failed(failed) => {
+                if failed.failure == Failure::NoSpace {
+                    if mounts::under(root, &failed.directory) {
+                        controls.removable_storage.set(RemovableStorage::Full);
+                    } else {
+                        controls.main_storage.set(MainStorage::Full);
+                    }
+                }
+                controls.storage_failed.update(|failing| {
+                    failing.retain(|known| known.stream != failed.stream);
+                    failing.push(failed);
+                });
+            }
+            Change::Recovered { stream, directory } => {
+                let removable = mounts::under(root, &directory);
+                let mut full = false;
+                controls.storage_failed.update(|failing| {
+                    failing.retain(|known| known.stream != stream);
+                    full = failing.iter().any(|known| {
+                        known.failure == Failure::NoSpace
+                            && mounts::under(root, &known.directory)
+                                == removable
+                    });
+                });
+                if full {
+                    return;
+                }
+                if removable {
+                    if controls.removable_storage.get()
+                        != RemovableStorage::HasCapacity
+                    {
+                        controls
+                            .removable_storage
+                            .set(RemovableStorage::HasCapacity);
+                    }
+                } else if controls.main_storage.get()
+                    != MainStorage::HasCapacity
+                {
+                    controls.main_storage.set(MainStorage::HasCapacity);
+                }
+            }
+        }
+    }
+}
+
+/// the failure a stream is holding its updates through
+struct Failing {
+    failed: Failed,
+    /// transient errors in a row
+    transient: u32,
+    /// the wall clock of the update it is tried again at
+    retry_at: u64,
+}
+
+/// one stream storage writes, with what it holds while it
+/// cannot write
+pub struct Stream<T> {
+    /// what is written, for the log
+    name: &'static str,
+    job: Job,
+    /// each update not yet written, with its size
+    held: VecDeque<(T, usize)>,
+    held_bytes: usize,
+    /// the held updates dropped since the stream failed
+    dropped: u64,
+    failing: Option<Failing>,
+}
+
+impl<T> Stream<T> {
+    pub fn new(name: &'static str, job: Job) -> Stream<T> {
+        Stream {
+            name,
+            job,
+            held: VecDeque::new(),
+            held_bytes: 0,
+            dropped: 0,
+            failing: None,
+        }
+    }
+
+    /// this function writes `update`, of `bytes`, to
+    /// `directory` with `write`, after every update held
+    /// before it. While the stream is failing the update is
+    /// only held, until RETRY has passed by `wall_nanos` or
+    /// storage has moved on to another directory. `write`
+    /// gives up the file it fails to write to
+    pub fn write(
+        &mut self,
+        update: T,
+        bytes: usize,
+        directory: &str,
+        wall_nanos: u64,
+        log: &LogPipe,
+        write: impl FnMut(&T) -> io::Result<()>,
+    ) -> Option<Change> {
+        self.hold(update, bytes);
+        if let Some(failing) = self.failing.as_ref() {
+            if failing.failed.directory == directory
+                && wall_nanos < failing.retry_at
+            {
+                return None;
+            }
+        }
+        self.write_held(directory, wall_nanos, log, write)
+    }
+
+    /// this function tries a last time to write what is
+    /// held, as the stream ends
+    pub fn finish(
+        &mut self,
+        directory: &str,
+        wall_nanos: u64,
+        log: &LogPipe,
+        write: impl FnMut(&T) -> io::Result<()>,
+    ) -> Option<Change> {
+        if self.held.is_empty() {
+            return None;
+        }
+        let change = self.write_held(directory, wall_nanos, log, write);
+        if !self.held.is_empty() {
+            crate::error!(
+                log,
+                self.job.clone(),
+                "{} held updates of {} were never written",
+                self.held.len(),
+                self.name
+            );
+        }
+        change
+    }
+
+    fn hold(&mut self, update: T, bytes: usize) {
+        self.held.push_back((update, bytes));
+        self.held_bytes += bytes;
+        while self.held_bytes > MAX_HELD_BYTES && self.held.len() > 1 {
+            if let Some((_, dropped)) = self.held.pop_front() {
+                self.held_bytes -= dropped;
+                self.dropped += 1;
+            }
+        }
+    }
+
+    fn write_held(
+        &mut self,
+        directory: &str,
+        wall_nanos: u64,
+        log: &LogPipe,
+        mut write: impl FnMut(&T) -> io::Result<()>,
+    ) -> Option<Change> {
+        while let Some((next, bytes)) = self.held.pop_front() {
+            match write(&next) {
+                Ok(()) => self.held_bytes -= bytes,
+                Err(err) => {
+                    self.held.push_front((next, bytes));
+                    return self.failed(err, directory, wall_nanos, log);
+                }
+            }
+        }
+        self.failing.take()?;
+        crate::info!(
+            log,
+            self.job.clone(),
+            "writing {} to {} again, {} held updates were dropped",
+            self.name,
+            directory,
+            self.dropped
+        );
+        self.dropped = 0;
+        Some(Change::Recovered {
+            stream: self.name,
+            directory: directory.to_string(),
+        })
+    }
+
+    /// this function notes a failed write, a change of
+    /// failure is logged and reported, the same failure
+    /// again is only logged at debug
+    fn failed(
+        &mut self,
+        err: io::Error,
+        directory: &str,
+        wall_nanos: u64,
+        log: &LogPipe,
+    ) -> Option<Change> {
+        let mut failure = Failure::of(&err);
+        let transient = match self.failing.as_ref() {
+            _ if failure != Failure::Transient => 0,
+            Some(failing) if failing.failed.directory == directory => {
+                failing.transient + 1
+            }
+            _ => 1,
+        };
+        if transient >= TRANSIENT_LIMIT {
+            failure = Failure::Gone;
+        }
+        let retry_at = match failure {
+            Failure::Transient => wall_nanos,
+            _ => wall_nanos + RETRY.as_nanos() as u64,
+        };
+        let failed = Failed {
+            stream: self.name,
+            directory: directory.to_string(),
+            failure,
+        };
+        let changed = !self
+            .failing
+            .as_ref()
+            .is_some_and(|failing| failing.failed == failed);
+        self.failing = Some(Failing {
+            failed: failed.clone(),
+            transient,
+            retry_at,
+        });
+        if !changed {
+            crate::debug!(
+                log,
+                self.job.clone(),
+                "still could not write {} to {}: {}",
+                self.name,
+                directory,
+                err
+            );
+            return None;
+        }
+        crate::warn!(
+            log,
+            self.job.clone(),
+            "could not write {} to {}, {}, holding {} updates: {}",
+            self.name,
+            directory,
+            failure.describe(),
+            self.held.len(),
+            err
+        );
+        match failure {
+            Failure::Transient => None,
+            _ => Some(Change::Failed(failed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::storage::simulated::EIO;
+
+    #[test]
+    fn holds_what_it_cannot_write_until_it_can() {
+        let _pipe = crate::log::TEST_PIPE
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let (_out, out_sender) = crate::queue::LOG_OUT.open();
+        let (_storage, storage_sender) = crate::queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+        let retry = RETRY.as_nanos() as u64;
+        let mut stream = Stream::new("numbers", Job::Main);
+        let mut written = std::vec::Vec::new();
+        let mut errors = std::vec![EIO, EIO, ENOSPC];
+        let mut write = |number: &u64| match errors.pop() {
+            Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+            None => {
+                written.push(*number);
+                Ok(())
+            }
+        };
+
+        let full = stream.write(1, 8, "/main", 0, &log, &mut write);
+        assert_eq!(
+            full,
+            Some(Change::Failed(Failed {
+                stream: "numbers",
+                directory: "/main".to_string(),
+                failure: Failure::NoSpace,
+            }))
+        );
+        // held until the retry is due, or the directory moves
+        assert_eq!(
+            stream.write(2, 8, "/main", retry - 1, &log, &mut write),
+            None
+        );
+        assert_eq!(
+            stream.write(3, 8, "/stick", retry - 1, &log, &mut write),
+            None
+        );
+        assert_eq!(
+            stream.write(4, 8, "/stick", retry - 1, &log, &mut write),
+            None
+        );
+        assert_eq!(
+            stream.write(5, 8, "/stick", retry - 1, &log, &mut write),
+            Some(Change::Recovered {
+                stream: "numbers",
+                directory: "/stick".to_string(),
+            })
+        );
+        assert_eq!(written, [1, 2, 3, 4, 5]);
+        assert_eq!(
+            Failure::of(&io::Error::from_raw_os_error(EROFS)),
+            Failure::ReadOnly
+        );
+        assert_eq!(
+            Failure::of(&io::Error::from_raw_os_error(ENODEV)),
+            Failure::Gone
+        );
+        crate::log::LogPipe::shutdown();
+    }
+
+    #[test]
+    fn lists_each_failing_stream_until_it_writes_again() {
+        let mut config = crate::config::Config::default().storage;
+        config.directory = "/main".to_string();
+        config.removable_root = "/media".to_string();
+        let controls = Controls::default();
+        let failed = |stream, directory: &str| {
+            Change::Failed(Failed {
+                stream,
+                directory: directory.to_string(),
+                failure: Failure::NoSpace,
+            })
+        };
+        failed("audio", "/media/STICK/camera").report(&config, &controls);
+        failed("video", "/media/STICK/camera").report(&config, &controls);
+        failed("the log", "/main").report(&config, &controls);
+        assert_eq!(controls.storage_failed.get().len(), 3);
+        // audio writing to the main directory again leaves
+        // the others failing, and the stick full
+        Change::Recovered {
+            stream: "audio",
+            directory: "/main".to_string(),
+        }
+        .report(&config, &controls);
+        let failing = controls.storage_failed.get();
+        assert_eq!(failing.len(), 2);
+        assert_eq!(failing[0].stream, "video");
+        assert_eq!(controls.main_storage.get(), MainStorage::Full);
+        assert_eq!(controls.removable_storage.get(), RemovableStorage::Full);
+        Change::Recovered {
+            stream: "the log",
+            directory: "/main".to_string(),
+        }
+        .report(&config, &controls);
+        assert_eq!(controls.main_storage.get(), MainStorage::HasCapacity);
+        assert_eq!(controls.storage_failed.get().len(), 1);
+    }
+}
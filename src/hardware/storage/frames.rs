@@ -0,0 +1,218 @@
+/// This is where video is written to, and read back from,
+/// the frame files video storage makes. The camera hands
+/// us frames already encoded as JPEG, at whatever pace it
+/// manages, so rather than a container that wants a fixed
+/// frame rate each frame is kept with the wall clock it
+/// was captured at. A file is the magic below, then for
+/// each frame its wall clock in nanoseconds as 8 bytes, 1
+/// byte that is 1 for a keyframe, the length of the frame
+/// as 4 bytes, all little endian, and the frame itself.
+/// Nothing is patched after it is written, so a file cut
+/// short by a crash or a pulled cable only loses the frame
+/// that was being written
+use anyhow::{anyhow, bail, Result};
+
+use super::check_write;
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// the first bytes of every frame file
+pub const FRAMES_MAGIC: &[u8; 8] = b"CAMFRAME";
+
+/// the bytes written before each frame
+const FRAME_HEADER_LEN: usize = 13;
+
+/// how often frames are flushed to the disk, in
+/// nanoseconds of wall clock
+const SYNC_EVERY: u64 = 1_000_000_000;
+
+/// this writes frames, with when they were captured, to a
+/// frame file
+pub struct FramesWriter {
+    file: std::fs::File,
+    /// where it is, for simulated devices to fail
+    path: String,
+    /// the wall clock of the last frame flushed to the disk
+    synced: u64,
+}
+
+impl FramesWriter {
+    pub fn create(path: &str) -> std::io::Result<FramesWriter> {
+        check_write(path, FRAMES_MAGIC.len())?;
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(FRAMES_MAGIC)?;
+        Ok(FramesWriter {
+            file,
+            path: path.to_string(),
+            synced: 0,
+        })
+    }
+
+    pub fn write_frame(
+        &mut self,
+        wall_nanos: u64,
+        keyframe: bool,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let mut bytes = Vec::with_capacity(FRAME_HEADER_LEN + data.len());
+        bytes.extend_from_slice(&wall_nanos.to_le_bytes());
+        bytes.push(keyframe as u8);
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        check_write(&self.path, bytes.len())?;
+        self.file.write_all(&bytes)?;
+        if wall_nanos.saturating_sub(self.synced) >= SYNC_EVERY {
+            self.file.sync_data()?;
+            self.synced = wall_nanos;
+        }
+        Ok(())
+    }
+
+    pub fn finalize(self) -> std::io::Result<()> {
+        check_write(&self.path, 0)?;
+        self.file.sync_all()
+    }
+}
+
+/// one frame read back from a frame file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFrame {
+    /// the wall clock it was captured at, in nanoseconds
+    /// since the UNIX_EPOCH
+    pub wall_nanos: u64,
+    pub keyframe: bool,
+    /// the encoded frame
+    pub data: Vec<u8>,
+}
+
+/// this reads the frames of a frame file we wrote, in the
+/// order they were written
+pub struct FramesReader {
+    file: std::io::BufReader<std::fs::File>,
+}
+
+impl FramesReader {
+    pub fn open(path: &str) -> Result<FramesReader> {
+        let mut file = std::fs::File::open(path)
+            .map_err(|err| anyhow!("{}: {}", path, err))?;
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)
+            .map_err(|err| anyhow!("{}: reading the header: {}", path, err))?;
+        if &magic != FRAMES_MAGIC {
+            bail!("{}: not a frame file", path);
+        }
+        Ok(FramesReader {
+            file: std::io::BufReader::new(file),
+        })
+    }
+
+    /// this function reads the header of the next frame,
+    /// returning its wall clock, whether it is a keyframe
+    /// and its length, none at the end of the file or where
+    /// it was cut short
+    fn next_header(&mut self) -> Result<Option<(u64, bool, u32)>> {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        if !read_all(&mut self.file, &mut header)? {
+            return Ok(None);
+        }
+        let mut wall = [0u8; 8];
+        wall.copy_from_slice(&header[0..8]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[9..13]);
+        Ok(Some((
+            u64::from_le_bytes(wall),
+            header[8] == 1,
+            u32::from_le_bytes(len),
+        )))
+    }
+
+    /// this function returns the next frame, none at the
+    /// end of the file or where it was cut short
+    pub fn next_frame(&mut self) -> Result<Option<StoredFrame>> {
+        let (wall_nanos, keyframe, len) = match self.next_header()? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let mut data = std::vec![0u8; len as usize];
+        if !read_all(&mut self.file, &mut data)? {
+            return Ok(None);
+        }
+        Ok(Some(StoredFrame {
+            wall_nanos,
+            keyframe,
+            data,
+        }))
+    }
+
+    /// this function skips to the end of the file, returning
+    /// how many whole frames it holds and the wall clock of
+    /// the last of them
+    pub fn last_frame(mut self) -> Result<(u64, Option<u64>)> {
+        let mut count = 0;
+        let mut last = None;
+        while let Some((wall_nanos, _, len)) = self.next_header()? {
+            let at = self.file.stream_position()?;
+            let end = self.file.get_ref().metadata()?.len();
+            if at + len as u64 > end {
+                break;
+            }
+            self.file.seek(SeekFrom::Current(len as i64))?;
+            count += 1;
+            last = Some(wall_nanos);
+        }
+        Ok((count, last))
+    }
+}
+
+/// this function fills `buffer`, returning false if the
+/// file ends first
+fn read_all(file: &mut impl Read, buffer: &mut [u8]) -> Result<bool> {
+    match file.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_was_written_and_cut_short() {
+        let path = std::env::temp_dir()
+            .join(std::format!("frames_round_trip_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut writer = FramesWriter::create(path).unwrap();
+        writer.write_frame(10, true, b"first").unwrap();
+        writer.write_frame(20, false, b"second").unwrap();
+        writer.finalize().unwrap();
+
+        let mut reader = FramesReader::open(path).unwrap();
+        let first = reader.next_frame().unwrap().unwrap();
+        assert_eq!(first.wall_nanos, 10);
+        assert!(first.keyframe);
+        assert_eq!(first.data, b"first");
+        assert_eq!(reader.next_frame().unwrap().unwrap().data, b"second");
+        assert_eq!(reader.next_frame().unwrap(), None);
+        assert_eq!(
+            FramesReader::open(path).unwrap().last_frame().unwrap(),
+            (2, Some(20))
+        );
+
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::write(path, &bytes[..bytes.len() - 2]).unwrap();
+        let mut reader = FramesReader::open(path).unwrap();
+        assert!(reader.next_frame().unwrap().is_some());
+        assert_eq!(reader.next_frame().unwrap(), None);
+        assert_eq!(
+            FramesReader::open(path).unwrap().last_frame().unwrap(),
+            (1, Some(10))
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+/// This is where removable storage is found. The kernel
+/// lists each mounted filesystem in /proc/self/mounts, one
+/// a line of device, directory, filesystem type, options
+/// and two numbers, with a space or any other awkward byte
+/// in a name written as an octal escape like \040. A USB
+/// stick is mounted by the desktop under /media, so while a
+/// writable filesystem is mounted under the removable_root
+/// recordings go to a camera directory on it, and back to
+/// the main directory once it is gone, or once storage
+/// reports it failed, when it is tried again after RETRY.
+/// The table is read again on each of main's ticks, and
+/// tests point mount_table at a file of their own
+use super::failure::{Failed, RETRY};
+use super::RemovableStorage;
+use crate::config::StorageConfig;
+use crate::control::{Controls, Setting};
+use crate::log::{Job, LogPipe};
+
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// the directory made on removable storage for recordings
+pub const REMOVABLE_DIRECTORY: &str = "camera";
+
+/// one line of the mount table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub device: String,
+    /// where it is mounted
+    pub directory: String,
+    pub filesystem: String,
+    /// whether it is mounted ro, as the kernel remounts a
+    /// filesystem that has errors
+    pub read_only: bool,
+}
+
+/// this function reads each line of a mount table that has
+/// the fields we need, skipping any that do not
+pub fn parse(table: &str) -> Vec<Mount> {
+    table
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = unescape(fields.next()?);
+            let directory = unescape(fields.next()?);
+            let filesystem = unescape(fields.next()?);
+            let read_only =
+                fields.next()?.split(',').any(|option| option == "ro");
+            Some(Mount {
+                device,
+                directory,
+                filesystem,
+                read_only,
+            })
+        })
+        .collect()
+}
+
+/// this function turns the octal escapes the kernel writes
+/// back into the bytes they stand for
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut at = 0;
+    while at < bytes.len() {
+        let escape = bytes.get(at + 1..at + 4).filter(|digits| {
+            bytes[at] == b'\\'
+                && digits.iter().all(|digit| (b'0'..=b'7').contains(digit))
+        });
+        match escape {
+            Some(digits) => {
+                let value = digits.iter().fold(0u32, |value, digit| {
+                    value * 8 + (digit - b'0') as u32
+                });
+                unescaped.push(value as u8);
+                at += 4;
+            }
+            None => {
+                unescaped.push(bytes[at]);
+                at += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// this function reads the mount table at `path`
+pub fn read(path: &str) -> std::io::Result<Vec<Mount>> {
+    std::fs::read_to_string(path).map(|table| parse(&table))
+}
+
+/// whether `directory` is below `root`, nothing is below
+/// an empty root
+pub fn under(root: &str, directory: &str) -> bool {
+    let root = root.trim_end_matches('/');
+    !root.is_empty()
+        && directory
+            .strip_prefix(root)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// this function returns the writable filesystem mounted
+/// under `root` most recently, there is none when `root`
+/// is empty
+pub fn removable<'a>(mounts: &'a [Mount], root: &str) -> Option<&'a Mount> {
+    mounts
+        .iter()
+        .rev()
+        .find(|mount| !mount.read_only && under(root, &mount.directory))
+}
+
+/// this follows removable storage, moving the storage
+/// directory onto it as it is mounted and back again as
+/// it is removed
+pub struct Removable {
+    /// the directory recorded to without removable storage
+    main: String,
+    /// the directory on removable storage recorded to
+    using: Option<String>,
+    /// a directory that could not be made, or that failed,
+    /// so it is only reported once
+    refused: Option<String>,
+    /// the wall clock a directory that failed is tried
+    /// again at
+    retry_at: Option<u64>,
+    /// where storage reports failures, and how full
+    /// removable storage is shown
+    controls: Controls,
+}
+
+impl Removable {
+    pub fn new(storage: &StorageConfig, controls: &Controls) -> Removable {
+        Removable {
+            main: storage.directory.clone(),
+            using: None,
+            refused: None,
+            retry_at: None,
+            controls: controls.clone(),
+        }
+    }
+
+    /// this function reads the mount table, and changes the
+    /// storage directory when removable storage has come or
+    /// gone since it was last called
+    pub fn poll(&mut self, settings: &Setting<StorageConfig>, log: &LogPipe) {
+        let mut config = settings.get();
+        if self.using.as_deref() != Some(config.directory.as_str()) {
+            // a reload or the control socket changed the
+            // directory, which is the main directory from now
+            self.main = config.directory.clone();
+            self.using = None;
+        }
+        // a stream failing on the directory in use stays
+        // listed until it writes again, so none is missed
+        let failed = self.using.as_ref().and_then(|using| {
+            self.controls
+                .storage_failed
+                .get()
+                .into_iter()
+                .find(|failed| &failed.directory == using)
+        });
+        if let Some(failed) = failed {
+            self.give_up(failed, settings, log);
+            return;
+        }
+        let wanted = if config.removable_root.is_empty() {
+            None
+        } else {
+            match read(&config.mount_table) {
+                Ok(mounts) => {
+                    removable(&mounts, &config.removable_root).map(|mount| {
+                        std::path::Path::new(&mount.directory)
+                            .join(REMOVABLE_DIRECTORY)
+                            .display()
+                            .to_string()
+                    })
+                }
+                Err(err) => {
+                    crate::debug!(
+                        log,
+                        Job::Main,
+                        "could not read {}: {}",
+                        config.mount_table,
+                        err
+                    );
+                    return;
+                }
+            }
+        };
+        if self.refused.is_some() && wanted != self.refused {
+            // what was refused has gone, whatever replaces it
+            // is tried at once
+            self.refused = None;
+            self.retry_at = None;
+            self.has_capacity();
+        }
+        let now = crate::time::now().wall_nanos;
+        if self.retry_at.is_some_and(|retry_at| now >= retry_at) {
+            crate::info!(
+                log,
+                Job::Main,
+                "trying removable storage {} again",
+                self.refused.as_deref().unwrap_or_default()
+            );
+            if let Some(refused) = self.refused.take() {
+                // a stream that has not written since is
+                // found failing again if it still fails
+                self.controls.storage_failed.update(|failing| {
+                    failing.retain(|failed| failed.directory != refused)
+                });
+            }
+            self.retry_at = None;
+            // storage finds it full again if it still is
+            self.has_capacity();
+        }
+        if wanted == self.using || (wanted.is_some() && wanted == self.refused)
+        {
+            return;
+        }
+        match wanted {
+            Some(directory) => {
+                if let Err(err) = std::fs::create_dir_all(&directory) {
+                    crate::warn!(
+                        log,
+                        Job::Main,
+                        "could not record to removable storage {}: {}",
+                        directory,
+                        err
+                    );
+                    self.refused = Some(directory);
+                    return;
+                }
+                crate::info!(
+                    log,
+                    Job::Main,
+                    "removable storage mounted, recording to {}",
+                    directory
+                );
+                config.directory = directory.clone();
+                self.using = Some(directory);
+            }
+            None => {
+                crate::info!(
+                    log,
+                    Job::Main,
+                    "removable storage removed, recording to {}",
+                    self.main
+                );
+                config.directory = self.main.clone();
+                self.using = None;
+            }
+        }
+        self.refused = None;
+        settings.set(config);
+    }
+
+    /// this function shows removable storage as having
+    /// space again
+    fn has_capacity(&self) {
+        let removable_storage = &self.controls.removable_storage;
+        if removable_storage.get() != RemovableStorage::HasCapacity {
+            removable_storage.set(RemovableStorage::HasCapacity);
+        }
+    }
+
+    /// this function moves recording back to the main
+    /// directory from removable storage that failed, which
+    /// is tried again after RETRY
+    fn give_up(
+        &mut self,
+        failed: Failed,
+        settings: &Setting<StorageConfig>,
+        log: &LogPipe,
+    ) {
+        crate::warn!(
+            log,
+            Job::Main,
+            "removable storage {} failed, {}, recording to {}",
+            failed.directory,
+            failed.failure.describe(),
+            self.main
+        );
+        let mut config = settings.get();
+        config.directory = self.main.clone();
+        self.using = None;
+        self.refused = Some(failed.directory);
+        self.retry_at =
+            Some(crate::time::now().wall_nanos + RETRY.as_nanos() as u64);
+        settings.set(config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_writable_storage_under_the_root() {
+        let mounts = parse(
+            "/dev/nvme0n1p2 / ext4 rw,relatime 0 0\n\
+             proc /proc proc rw,nosuid 0 0\n\
+             /dev/sdb1 /media/me/OLD\\040STICK vfat ro,nosuid 0 0\n\
+             /dev/sdc1 /media/me/NEW\\040STICK vfat rw,nosuid 0 0\n\
+             short line\n",
+        );
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(mounts[2].directory, "/media/me/OLD STICK");
+        assert!(mounts[2].read_only);
+        assert_eq!(
+            removable(&mounts, "/media/").map(|mount| mount.device.as_str()),
+            Some("/dev/sdc1")
+        );
+        // a filesystem remounted read only is not recorded to
+        assert_eq!(removable(&mounts[..3], "/media"), None);
+        assert_eq!(removable(&mounts, "/med"), None);
+        assert_eq!(removable(&mounts, ""), None);
+    }
+}
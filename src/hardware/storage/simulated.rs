@@ -0,0 +1,322 @@
+/// This is where storage devices are faked for tests. Each
+/// device is a directory, under the removable root unless
+/// it stands in for a fixed disk, which is listed in a
+/// mount table of our own while it is mounted, so
+/// mounts::Removable finds it as it would a USB stick.
+/// The WAV and frame writers ask check_write before each
+/// write, and a write to a device fails as the kernel would
+/// fail it: with EIO once the device is pulled out from
+/// under an open file, EROFS once it is remounted read only,
+/// ENOSPC once it is full, and with whatever error a test
+/// asks for on the writes it picks. A device can also be
+/// inserted without being mounted, as the desktop does when
+/// it is slow to mount, and mounted later
+use super::failure::{ENOSPC, EROFS};
+
+use std::collections::VecDeque;
+use std::format;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::string::{String, ToString};
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+/// the error a device fails a write with once it is
+/// unplugged, which real disks also fail with for a bad
+/// block, as linux numbers it
+pub const EIO: i32 = 5;
+
+/// one fake device
+struct Device {
+    name: String,
+    /// where it is mounted
+    directory: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    mounted: bool,
+    read_only: bool,
+    /// the bytes that fit on it, unlimited when none
+    capacity: Option<u64>,
+    /// the bytes written to it so far
+    written: u64,
+    /// the errors the next writes fail with
+    failing: VecDeque<i32>,
+}
+
+/// every fake device, so check_write can find them from
+/// any thread
+static DEVICES: Mutex<Vec<Arc<Device>>> = Mutex::new(Vec::new());
+
+/// this function fails a write of `len` bytes to `path` the
+/// way the fake device it is on would, a path on no fake
+/// device is always written
+pub fn check_write(path: &str, len: usize) -> io::Result<()> {
+    let device = DEVICES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .find(|device| Path::new(path).starts_with(&device.directory))
+        .cloned();
+    let device = match device {
+        Some(device) => device,
+        None => return Ok(()),
+    };
+    let mut state = device.state.lock().unwrap_or_else(|err| err.into_inner());
+    if !state.mounted {
+        return Err(io::Error::from_raw_os_error(EIO));
+    }
+    if state.read_only {
+        return Err(io::Error::from_raw_os_error(EROFS));
+    }
+    if let Some(errno) = state.failing.pop_front() {
+        return Err(io::Error::from_raw_os_error(errno));
+    }
+    let written = state.written + len as u64;
+    if state.capacity.is_some_and(|capacity| written > capacity) {
+        return Err(io::Error::from_raw_os_error(ENOSPC));
+    }
+    state.written = written;
+    Ok(())
+}
+
+/// the fake devices of one test, with the mount table that
+/// lists them, they are all removed when this is dropped
+pub struct Devices {
+    /// the directory the devices and table are made in
+    directory: PathBuf,
+    /// where removable devices are mounted
+    root: PathBuf,
+    table: PathBuf,
+    devices: Vec<Arc<Device>>,
+}
+
+impl Devices {
+    /// this function makes an empty removable root and a
+    /// mount table with only the root filesystem in it,
+    /// under `directory`
+    pub fn new(directory: &Path) -> Devices {
+        let root = directory.join("media");
+        std::fs::create_dir_all(&root).unwrap();
+        let devices = Devices {
+            directory: directory.to_path_buf(),
+            root,
+            table: directory.join("mounts"),
+            devices: Vec::new(),
+        };
+        devices.write_table();
+        devices
+    }
+
+    /// the path to point [storage] mount_table at
+    pub fn mount_table(&self) -> String {
+        self.table.display().to_string()
+    }
+
+    /// the path to point [storage] removable_root at
+    pub fn removable_root(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    /// the directory the device called `name` is mounted at
+    pub fn directory(&self, name: &str) -> String {
+        self.device(name).directory.display().to_string()
+    }
+
+    /// this function plugs in a removable device that holds
+    /// `capacity` bytes, or any amount, without mounting it
+    pub fn insert(&mut self, name: &str, capacity: Option<u64>) {
+        let directory = self.root.join(name);
+        self.insert_at(name, &directory, capacity);
+    }
+
+    /// this function plugs in a device mounted at
+    /// `directory` when it is mounted, outside the
+    /// removable root it stands in for a fixed disk
+    pub fn insert_at(
+        &mut self,
+        name: &str,
+        directory: &Path,
+        capacity: Option<u64>,
+    ) {
+        let directory = directory.to_path_buf();
+        std::fs::create_dir_all(&directory).unwrap();
+        let device = Arc::new(Device {
+            name: name.to_string(),
+            directory,
+            state: Mutex::new(State {
+                capacity,
+                ..State::default()
+            }),
+        });
+        let mut devices = DEVICES.lock().unwrap_or_else(|err| err.into_inner());
+        // one pulled out earlier from the same directory is
+        // gone for good
+        devices.retain(|known| known.directory != device.directory);
+        devices.push(device.clone());
+        self.devices.push(device);
+    }
+
+    /// this function mounts the device read and write
+    pub fn mount(&mut self, name: &str) {
+        self.change(name, |state| {
+            state.mounted = true;
+            state.read_only = false;
+        });
+    }
+
+    /// this function unmounts the device, a file still open
+    /// on it fails its next write
+    pub fn unmount(&mut self, name: &str) {
+        self.change(name, |state| state.mounted = false);
+    }
+
+    /// this function pulls the device out, mounted or not,
+    /// a file left open on it fails its writes until the
+    /// test is over
+    pub fn remove(&mut self, name: &str) {
+        self.unmount(name);
+        self.devices.retain(|device| device.name != name);
+        self.write_table();
+    }
+
+    /// this function remounts the device read only, as the
+    /// kernel does when it finds the filesystem damaged
+    pub fn remount_read_only(&mut self, name: &str) {
+        self.change(name, |state| state.read_only = true);
+    }
+
+    /// this function fills the device, so the next write
+    /// to it fails with ENOSPC
+    pub fn fill(&mut self, name: &str) {
+        self.change(name, |state| state.capacity = Some(state.written));
+    }
+
+    /// this function frees `bytes` on the device
+    pub fn free(&mut self, name: &str, bytes: u64) {
+        self.change(name, |state| {
+            state.capacity = Some(state.written + bytes);
+        });
+    }
+
+    /// this function fails the next `count` writes to the
+    /// device with `errno`
+    pub fn fail_writes(&mut self, name: &str, errno: i32, count: usize) {
+        self.change(name, |state| {
+            state.failing.extend(core::iter::repeat(errno).take(count));
+        });
+    }
+
+    /// the bytes written to the device so far
+    pub fn written(&self, name: &str) -> u64 {
+        self.device(name)
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .written
+    }
+
+    fn device(&self, name: &str) -> Arc<Device> {
+        match self.devices.iter().find(|device| device.name == name) {
+            Some(device) => device.clone(),
+            None => panic!("no device called {} is plugged in", name),
+        }
+    }
+
+    fn change(&mut self, name: &str, change: impl FnOnce(&mut State)) {
+        let device = self.device(name);
+        change(&mut device.state.lock().unwrap_or_else(|err| err.into_inner()));
+        self.write_table();
+    }
+
+    /// this function writes the mount table, the devices
+    /// as sdb, sdc and so on in the order they went in
+    fn write_table(&self) {
+        let mut table = "/dev/vda1 / ext4 rw,relatime 0 0\n".to_string();
+        for (index, device) in self.devices.iter().enumerate() {
+            let state =
+                device.state.lock().unwrap_or_else(|err| err.into_inner());
+            if !state.mounted {
+                continue;
+            }
+            table.push_str(&format!(
+                "/dev/sd{}1 {} vfat {},nosuid,nodev 0 0\n",
+                (b'b' + index as u8) as char,
+                device.directory.display().to_string().replace(' ', "\\040"),
+                if state.read_only { "ro" } else { "rw" }
+            ));
+        }
+        std::fs::write(&self.table, table).unwrap();
+    }
+}
+
+impl Drop for Devices {
+    fn drop(&mut self) {
+        let directory = self.directory.clone();
+        DEVICES
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .retain(|device| !device.directory.starts_with(&directory));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::storage::mounts;
+
+    #[test]
+    fn fails_writes_as_the_device_would() {
+        let directory = std::env::temp_dir()
+            .join(format!("simulated_devices_{}", std::process::id()));
+        let mut devices = Devices::new(&directory);
+        devices.insert("STICK", Some(100));
+        let file = format!("{}/camera/audio.wav", devices.directory("STICK"));
+        let removable = |devices: &Devices| {
+            let table = mounts::read(&devices.mount_table()).unwrap();
+            mounts::removable(&table, &devices.removable_root()).cloned()
+        };
+
+        // inserted but not mounted is not yet recorded to
+        assert!(removable(&devices).is_none());
+        assert_eq!(
+            check_write(&file, 10).unwrap_err().raw_os_error(),
+            Some(EIO)
+        );
+        devices.mount("STICK");
+        assert_eq!(removable(&devices).unwrap().device, "/dev/sdb1");
+        check_write(&file, 60).unwrap();
+        devices.fail_writes("STICK", EIO, 1);
+        assert_eq!(
+            check_write(&file, 10).unwrap_err().raw_os_error(),
+            Some(EIO)
+        );
+        check_write(&file, 40).unwrap();
+        assert_eq!(
+            check_write(&file, 1).unwrap_err().raw_os_error(),
+            Some(ENOSPC)
+        );
+        devices.free("STICK", 10);
+        check_write(&file, 10).unwrap();
+        assert_eq!(devices.written("STICK"), 110);
+        devices.remount_read_only("STICK");
+        assert!(removable(&devices).is_none());
+        assert_eq!(
+            check_write(&file, 1).unwrap_err().raw_os_error(),
+            Some(EROFS)
+        );
+        devices.remove("STICK");
+        assert_eq!(
+            check_write(&file, 1).unwrap_err().raw_os_error(),
+            Some(EIO)
+        );
+        // the main disk is not faked
+        check_write(&format!("{}/main/audio.wav", directory.display()), 1)
+            .unwrap();
+        drop(devices);
+        check_write(&file, 1).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+/// This is where audio is written to, and read back from,
+/// the WAV files audio storage makes. Only the one layout
+/// we write is understood: a 44 byte header followed by
+/// interleaved 32 bit IEEE float samples. The recorder,
+/// and the list, export and verify commands, all go
+/// through here, so what one writes the others can read
+use anyhow::{anyhow, bail, Result};
+
+use core::time::Duration;
+
+use super::check_write;
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// the size of the RIFF, fmt and data headers
+/// at the start of every WAV file we write
+pub const WAV_HEADER_LEN: u32 = 44;
+
+/// this writes interleaved f32 samples to a WAV file
+/// as 32 bit IEEE float, the sizes in the header are
+/// patched about once a second of audio, so a file cut
+/// short by a crash or a pulled cable still plays up to
+/// the last second
+pub struct WavWriter {
+    file: std::fs::File,
+    /// where it is, for simulated devices to fail
+    path: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// bytes of samples written after the header
+    data_len: u32,
+    /// bytes written since the header was last patched
+    unsynced: u32,
+}
+
+impl WavWriter {
+    pub fn create(
+        path: &str,
+        sample_rate: u32,
+        channels: u16,
+    ) -> std::io::Result<WavWriter> {
+        check_write(path, WAV_HEADER_LEN as usize)?;
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(&wav_header(sample_rate, channels, 0))?;
+        Ok(WavWriter {
+            file,
+            path: path.to_string(),
+            sample_rate,
+            channels,
+            data_len: 0,
+            unsynced: 0,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 4);
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        check_write(&self.path, bytes.len())?;
+        self.file.write_all(&bytes)?;
+        self.data_len = self.data_len.saturating_add(bytes.len() as u32);
+        self.unsynced = self.unsynced.saturating_add(bytes.len() as u32);
+        let one_second = self.sample_rate * self.channels as u32 * 4;
+        if self.unsynced >= one_second {
+            self.patch_header()?;
+        }
+        Ok(())
+    }
+
+    fn patch_header(&mut self) -> std::io::Result<()> {
+        // the header is written over, so it takes no space
+        check_write(&self.path, 0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&wav_header(
+            self.sample_rate,
+            self.channels,
+            self.data_len,
+        ))?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> std::io::Result<()> {
+        self.patch_header()?;
+        self.file.sync_all()
+    }
+}
+
+/// this function lays out the header of a 32 bit IEEE float
+/// WAV file holding `data_len` bytes of samples
+pub fn wav_header(sample_rate: u32, channels: u16, data_len: u32) -> Vec<u8> {
+    let block_align = channels * 4;
+    let byte_rate = sample_rate * block_align as u32;
+    let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_LEN - 8 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // format 3 is IEEE float
+    header.extend_from_slice(&3u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&32u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+/// what the header of a WAV file says about its samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// bytes of samples after the header
+    pub data_len: u32,
+    /// what the RIFF chunk says the file holds after
+    /// its first eight bytes
+    pub riff_len: u32,
+}
+
+impl WavFormat {
+    /// bytes in one sample of every channel
+    pub fn block_align(&self) -> u32 {
+        self.channels as u32 * 4
+    }
+
+    /// samples of every channel in the file
+    pub fn frames(&self) -> u64 {
+        (self.data_len / self.block_align()) as u64
+    }
+
+    /// how long the file plays for
+    pub fn duration(&self) -> Duration {
+        self.frames_duration(self.frames())
+    }
+
+    /// how long `frames` samples of every channel play for
+    pub fn frames_duration(&self, frames: u64) -> Duration {
+        Duration::from_nanos(
+            (frames as u128 * 1_000_000_000 / self.sample_rate as u128) as u64,
+        )
+    }
+
+    /// the number of whole frames that play in `duration`
+    pub fn frames_in(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * self.sample_rate as u128 / 1_000_000_000) as u64
+    }
+}
+
+/// this function reads a header laid out by wav_header,
+/// any other kind of WAV file is an error
+pub fn read_header(header: &[u8]) -> Result<WavFormat> {
+    if header.len() < WAV_HEADER_LEN as usize {
+        bail!("only {} bytes, too short for a header", header.len());
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([header[at], header[at + 1]]);
+    let u32_at = |at: usize| {
+        u32::from_le_bytes([
+            header[at],
+            header[at + 1],
+            header[at + 2],
+            header[at + 3],
+        ])
+    };
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        bail!("not a RIFF WAVE file");
+    }
+    if &header[12..16] != b"fmt " || u32_at(16) != 16 {
+        bail!("the fmt chunk is not where we write it");
+    }
+    if u16_at(20) != 3 || u16_at(34) != 32 {
+        bail!("the samples are not 32 bit float");
+    }
+    if &header[36..40] != b"data" {
+        bail!("the data chunk is not where we write it");
+    }
+    let format = WavFormat {
+        sample_rate: u32_at(24),
+        channels: u16_at(22),
+        data_len: u32_at(40),
+        riff_len: u32_at(4),
+    };
+    if format.sample_rate == 0 || format.channels == 0 {
+        bail!(
+            "{} channels at {}Hz is not audio",
+            format.channels,
+            format.sample_rate
+        );
+    }
+    if u32_at(28) != format.sample_rate * format.block_align()
+        || u16_at(32) as u32 != format.block_align()
+    {
+        bail!("the byte rate does not match the sample rate");
+    }
+    Ok(format)
+}
+
+/// this reads the samples of a WAV file we wrote
+pub struct WavReader {
+    file: std::fs::File,
+    pub format: WavFormat,
+}
+
+impl WavReader {
+    pub fn open(path: &str) -> Result<WavReader> {
+        let mut file = std::fs::File::open(path)
+            .map_err(|err| anyhow!("{}: {}", path, err))?;
+        let mut header = [0u8; WAV_HEADER_LEN as usize];
+        file.read_exact(&mut header)
+            .map_err(|err| anyhow!("{}: reading the header: {}", path, err))?;
+        let format =
+            read_header(&header).map_err(|err| anyhow!("{}: {}", path, err))?;
+        Ok(WavReader { file, format })
+    }
+
+    /// this function returns `count` frames of interleaved
+    /// samples starting at frame `first`, fewer if the
+    /// file ends before them
+    pub fn read_frames(&mut self, first: u64, count: u64) -> Result<Vec<f32>> {
+        let frames = self.format.frames();
+        let first = first.min(frames);
+        let count = count.min(frames - first);
+        let block_align = self.format.block_align() as u64;
+        self.file.seek(SeekFrom::Start(
+            WAV_HEADER_LEN as u64 + first * block_align,
+        ))?;
+        let mut bytes = std::vec![0u8; (count * block_align) as usize];
+        self.file.read_exact(&mut bytes)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|sample| {
+                f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]])
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_header_describes_float_samples() {
+        let header = wav_header(48_000, 2, 800);
+        assert_eq!(header.len(), WAV_HEADER_LEN as usize);
+        assert_eq!(&header[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(header[4..8].try_into().unwrap()), 836);
+        assert_eq!(u16::from_le_bytes([header[20], header[21]]), 3);
+        assert_eq!(
+            u32::from_le_bytes(header[28..32].try_into().unwrap()),
+            384_000
+        );
+        assert_eq!(u32::from_le_bytes(header[40..44].try_into().unwrap()), 800);
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let path = std::env::temp_dir()
+            .join(std::format!("wav_round_trip_{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut writer = WavWriter::create(path, 8_000, 2).unwrap();
+        let samples: Vec<f32> = (0..16).map(|n| n as f32).collect();
+        writer.write_samples(&samples).unwrap();
+        writer.finalize().unwrap();
+
+        let mut reader = WavReader::open(path).unwrap();
+        assert_eq!(reader.format.frames(), 8);
+        assert_eq!(reader.format.duration(), Duration::from_millis(1));
+        assert_eq!(reader.read_frames(6, 5).unwrap(), &samples[12..]);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(read_header(&wav_header(0, 2, 0)).is_err());
+        assert!(read_header(b"RIFF").is_err());
+    }
+}
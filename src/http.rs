@@ -0,0 +1,476 @@
+/// This is where the recorder can be checked from a phone
+/// on the local network. When [http] address is set, a
+/// small HTTP/1.1 server answers GET requests for three
+/// things: a status page at / filled with the color the
+/// screen shows, with the storage, battery and queue
+/// details under it, the same details as JSON at
+/// /status.json, and a live preview at /stream.mjpeg,
+/// a multipart/x-mixed-replace stream of the newest frame
+/// the UI was sent from the view queue. There is no TLS
+/// and no login, so the address should only be one the
+/// local network can reach. Each client gets a thread of
+/// its own, so a preview left open does not hold up the
+/// status page, and every thread stops with the recorder.
+/// At most MAX_CLIENTS are answered at once, any more are
+/// told to come back later and closed
+use crate::control::{socket, Controls, Settings};
+use crate::log::{Job, LogPipe};
+use crate::shutdown;
+
+use anyhow::{anyhow, Result};
+
+use core::time::Duration;
+
+use std::format;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::string::{String, ToString};
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+/// how often the server looks for a stop request, and
+/// for a new frame to send to a preview
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// how long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the longest request line and headers read, together
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// how long a preview client may leave a frame unread
+/// before it is given up on
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the most clients answered at once, each has a thread
+const MAX_CLIENTS: usize = 8;
+
+/// how long a client turned away may take to read why
+const REFUSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// how often the status page reloads itself, in seconds
+const REFRESH_SECONDS: u32 = 2;
+
+/// this function binds `address` and starts serving it
+pub fn serve(
+    address: &str,
+    settings: Settings,
+    controls: Controls,
+    log: LogPipe,
+) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(address)
+        .map_err(|err| anyhow!("{}: {}", address, err))?;
+    start(listener, settings, controls, log)
+}
+
+/// this function starts a thread answering on `listener`
+/// until the recorder is asked to stop, it waits for the
+/// thread of every client before it returns
+pub fn start(
+    listener: TcpListener,
+    settings: Settings,
+    controls: Controls,
+    log: LogPipe,
+) -> Result<JoinHandle<()>> {
+    // so the loop can look for a stop request between clients
+    listener.set_nonblocking(true)?;
+    let address = listener.local_addr()?;
+    let thread = std::thread::Builder::new().name("http".to_string()).spawn(
+        move || {
+            crate::info!(log, Job::Control, "serving http on {}", address);
+            let mut clients: Vec<JoinHandle<()>> = Vec::new();
+            while !shutdown::requested() {
+                clients.retain(|client| !client.is_finished());
+                match listener.accept() {
+                    Ok((stream, _)) if clients.len() >= MAX_CLIENTS => {
+                        crate::debug!(
+                            log,
+                            Job::Control,
+                            "turned away an http client, {} are being \
+                             answered",
+                            clients.len()
+                        );
+                        // a client that goes away is not a fault
+                        let _ = refuse(stream);
+                    }
+                    Ok((stream, _)) => {
+                        let settings = settings.clone();
+                        let controls = controls.clone();
+                        let spawned = std::thread::Builder::new()
+                            .name("http client".to_string())
+                            .spawn(move || {
+                                // a client that goes away is not a fault
+                                let _ = answer(stream, &settings, &controls);
+                            });
+                        match spawned {
+                            Ok(client) => clients.push(client),
+                            Err(err) => crate::warn!(
+                                log,
+                                Job::Control,
+                                "could not start an http client: {}",
+                                err
+                            ),
+                        }
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        std::thread::sleep(POLL_INTERVAL)
+                    }
+                    Err(err) => {
+                        crate::warn!(
+                            log,
+                            Job::Control,
+                            "could not accept an http client: {}",
+                            err
+                        );
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                }
+            }
+            for client in clients {
+                let _ = client.join();
+            }
+        },
+    )?;
+    Ok(thread)
+}
+
+/// this function reads one request and answers it, HTTP
+/// keep alive is not offered, so the connection closes.
+/// A request longer than MAX_REQUEST_BYTES is refused
+fn answer(
+    stream: TcpStream,
+    settings: &Settings,
+    controls: &Controls,
+) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_BYTES);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers say nothing we act on
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    if reader.limit() == 0 {
+        return respond(
+            &mut writer,
+            "431 Request Header Fields Too Large",
+            "text/plain",
+            b"the request is too long\n",
+        );
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (method, path) = (words.next(), words.next());
+    // the query string changes nothing
+    let path = path.map(|path| path.split('?').next().unwrap_or(path));
+    match (method, path) {
+        (Some("GET"), Some("/")) => respond(
+            &mut writer,
+            "200 OK",
+            "text/html; charset=utf-8",
+            page(settings, controls).as_bytes(),
+        ),
+        (Some("GET"), Some("/status.json")) => respond(
+            &mut writer,
+            "200 OK",
+            "application/json",
+            status_json(settings, controls).as_bytes(),
+        ),
+        (Some("GET"), Some("/stream.mjpeg")) => {
+            stream_preview(writer, controls)
+        }
+        (Some("GET"), _) => {
+            respond(&mut writer, "404 Not Found", "text/plain", b"not found\n")
+        }
+        _ => respond(
+            &mut writer,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"only GET is served\n",
+        ),
+    }
+}
+
+/// this function tells a client past MAX_CLIENTS to come
+/// back later, without waiting long on it
+fn refuse(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(REFUSE_TIMEOUT))?;
+    respond(
+        &mut stream,
+        "503 Service Unavailable",
+        "text/plain",
+        b"too many clients, try again later\n",
+    )
+}
+
+fn respond(
+    writer: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    writer.write_all(
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+            status,
+            content_type,
+            body.len()
+        )
+        .as_bytes(),
+    )?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+/// this function sends each new preview frame as a part of
+/// its own, until the client goes away or we stop
+fn stream_preview(
+    mut writer: TcpStream,
+    controls: &Controls,
+) -> std::io::Result<()> {
+    // a client that stops reading is dropped, rather than
+    // holding its thread past a stop request
+    writer.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\
+          Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+    )?;
+    let mut preview = controls.preview.clone();
+    // the frame already there is sent first, so the
+    // preview is not blank until the next one arrives
+    let mut frame = preview.current();
+    while !shutdown::requested() {
+        if let Some(video) = frame.take() {
+            writer.write_all(
+                format!(
+                    "--frame\r\nContent-Type: image/jpeg\r\n\
+                     Content-Length: {}\r\n\r\n",
+                    video.data.len()
+                )
+                .as_bytes(),
+            )?;
+            writer.write_all(&video.data)?;
+            writer.write_all(b"\r\n")?;
+            writer.flush()?;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+        frame = preview.try_changed().flatten();
+    }
+    Ok(())
+}
+
+/// this function describes the recorder as one JSON object,
+/// the control socket's status with what the UI shows
+fn status_json(settings: &Settings, controls: &Controls) -> String {
+    let status = controls.status.get();
+    format!(
+        "{{\"ui_status\":\"{:?}\",\"color\":\"{}\"{}}}",
+        status,
+        hex(status.color(&settings.ui.get().colors)),
+        socket::status(settings, controls)
+    )
+}
+
+/// this function lays out the status page
+fn page(settings: &Settings, controls: &Controls) -> String {
+    let status = controls.status.get();
+    let storage = settings.storage.get();
+    let present = crate::hardware::camera::devices();
+    let mut details = Vec::new();
+    details.push(format!(
+        "recording: {}",
+        if controls.recording.get() {
+            "yes"
+        } else {
+            "stopped"
+        }
+    ));
+    details.push(format!("storage: {}", html(&storage.directory)));
+    details.push(format!(
+        "audio file: {}",
+        controls
+            .audio_file
+            .get()
+            .map(|file| html(&file))
+            .unwrap_or("none".to_string())
+    ));
+    details.push(format!(
+        "battery: {}",
+        crate::hardware::battery::percent()
+            .map(|percent| format!("{}%", percent))
+            .unwrap_or("no battery found".to_string())
+    ));
+    for camera in settings.cameras.get().iter() {
+        details.push(format!(
+            "camera {}: {} ({})",
+            html(&camera.name),
+            html(&camera.device),
+            if present.contains(&camera.device) {
+                "found"
+            } else {
+                "missing"
+            }
+        ));
+    }
+    for queue in controls.queues.get().iter() {
+        details.push(format!(
+            "{} queue: {}/{}{}",
+            html(queue.name),
+            queue.depth,
+            queue.capacity,
+            if queue.in_deficit() {
+                ", in deficit"
+            } else {
+                ""
+            }
+        ));
+    }
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\">\
+         <meta http-equiv=\"refresh\" content=\"{}\">\
+         <title>camera</title></head>\n\
+         <body style=\"background:{};font-family:sans-serif\">\n\
+         <h1>{}</h1>\n<ul>\n<li>{}</li>\n</ul>\n\
+         <p><a href=\"/stream.mjpeg\">live preview</a> \
+         <a href=\"/status.json\">status.json</a></p>\n\
+         </body></html>\n",
+        REFRESH_SECONDS,
+        hex(status.color(&settings.ui.get().colors)),
+        status.describe(),
+        details.join("</li>\n<li>")
+    )
+}
+
+/// this function writes a 0xRRGGBB color as "#rrggbb"
+fn hex(color: u32) -> String {
+    format!("#{:06x}", color)
+}
+
+/// this function escapes the characters HTML gives a
+/// meaning to, for names that come from the file
+fn html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            other => escaped.push(other),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::queue::pool::Buffer;
+    use crate::queue::VideoUpdate;
+    use crate::time::Timestamp;
+    use std::sync::Arc;
+
+    fn get(address: std::net::SocketAddr, path: &str, bytes: usize) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        stream
+            .write_all(
+                format!("GET {} HTTP/1.1\r\nHost: camera\r\n\r\n", path)
+                    .as_bytes(),
+            )
+            .unwrap();
+        let mut answer = std::vec![0u8; bytes];
+        let mut read = 0;
+        while read < bytes {
+            match stream.read(&mut answer[read..]) {
+                Ok(0) | Err(_) => break,
+                Ok(more) => read += more,
+            }
+        }
+        String::from_utf8_lossy(&answer[..read]).to_string()
+    }
+
+    #[test]
+    fn serves_the_status_and_the_preview() {
+        let _pipe = crate::log::TEST_PIPE
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        shutdown::reset();
+        let (_out, out_sender) = crate::queue::LOG_OUT.open();
+        let (_stored, storage_sender) = crate::queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+
+        let settings = Settings::new(&Config::default());
+        let controls = Controls::default();
+        controls.preview.set(Some(Arc::new(VideoUpdate {
+            timestamp: Timestamp {
+                wall_nanos: 0,
+                monotonic_nanos: 0,
+            },
+            pts: 0,
+            sequence: 0,
+            data: Buffer::unpooled(std::vec![0xff, 0xd8, 0xff, 0xd9]),
+            keyframe: true,
+        })));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let thread = start(listener, settings, controls, log.clone()).unwrap();
+
+        let page = get(address, "/", 4096);
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(page.contains("background:#808080"));
+        let status = get(address, "/status.json?now", 4096);
+        assert!(status.contains(
+            "{\"ui_status\":\"MainDiskAndMainCam\",\"color\":\"#808080\",\
+             \"recording\":true,"
+        ));
+        assert!(get(address, "/nope", 4096).starts_with("HTTP/1.1 404"));
+
+        // a request with no end is cut off at MAX_REQUEST_BYTES
+        let mut endless = TcpStream::connect(address).unwrap();
+        endless.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        endless
+            .write_all(&std::vec![b'a'; MAX_REQUEST_BYTES as usize])
+            .unwrap();
+        let mut answer = String::new();
+        let _ = endless.read_to_string(&mut answer);
+        assert!(answer.starts_with("HTTP/1.1 431"));
+
+        // the headers, one part's headers and its four bytes
+        let preview = get(address, "/stream.mjpeg", 180);
+        assert!(preview.contains("boundary=frame\r\n"));
+        assert!(preview.contains(
+            "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\n"
+        ));
+
+        // previews left open take every client thread
+        let previews: Vec<TcpStream> = (0..MAX_CLIENTS)
+            .map(|_| {
+                let mut stream = TcpStream::connect(address).unwrap();
+                stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+                stream
+                    .write_all(b"GET /stream.mjpeg HTTP/1.1\r\n\r\n")
+                    .unwrap();
+                stream.read_exact(&mut [0; 16]).unwrap();
+                stream
+            })
+            .collect();
+        assert!(get(address, "/", 4096).starts_with("HTTP/1.1 503"));
+        drop(previews);
+
+        shutdown::request();
+        thread.join().unwrap();
+        LogPipe::shutdown();
+        shutdown::reset();
+    }
+}
@@ -1,22 +1,111 @@
+use core::fmt;
 use core::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, Thread};
 
 use nolock::queues::mpsc::jiffy::{queue, Sender};
 use nolock::queues::spsc::unbounded::UnboundedSender;
 
 use cfg_if::cfg_if;
 
+use crate::time::Timestamp;
+
+#[cfg(feature = "std")]
+mod console;
+pub mod filter;
+pub mod json;
+
 #[cfg(feature = "std")]
 use std::format;
 #[cfg(feature = "std")]
 use std::string::String;
 
+/// these macros take a LogPipe, a Job, and format arguments,
+/// and record the module path and line they were called from,
+/// e.g. info!(log, Job::AudioInput, "got device: {}", name)
+#[macro_export]
+macro_rules! info {
+    ($log:expr, $job:expr, $($arg:tt)+) => {
+        $log.log_at(
+            $crate::log::Level::Info,
+            $job,
+            module_path!(),
+            line!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($log:expr, $job:expr, $($arg:tt)+) => {
+        $log.log_at(
+            $crate::log::Level::Warn,
+            $job,
+            module_path!(),
+            line!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($log:expr, $job:expr, $($arg:tt)+) => {
+        $log.log_at(
+            $crate::log::Level::Error,
+            $job,
+            module_path!(),
+            line!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($log:expr, $job:expr, $($arg:tt)+) => {
+        $log.log_at(
+            $crate::log::Level::Debug,
+            $job,
+            module_path!(),
+            line!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($log:expr, $job:expr, $($arg:tt)+) => {
+        $log.log_at(
+            $crate::log::Level::Trace,
+            $job,
+            module_path!(),
+            line!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
 /// a global that marks whether set_pipe() has been called
 static mut INITIALIZED: AtomicBool = AtomicBool::new(false);
 
-/// a global that marks whether the system is headed for a shutdown
+/// a global that marks whether the system is headed for a
+/// shutdown, updates made after it are dropped, since the
+/// listener may already have passed on its last one
 static mut SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
+/// the join handle of the log listener thread,
+/// taken by shutdown() so it can wait for the
+/// last updates to be passed on
+static LISTENER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// there is only one log pipe at a time, so tests that
+/// set one up hold this until they have shut it down
+#[cfg(test)]
+pub(crate) static TEST_PIPE: Mutex<()> = Mutex::new(());
+
 #[derive(Clone)]
 /// this structure is used to hold important
 /// logging state, so that it may be used
@@ -27,6 +116,9 @@ pub struct LogPipe {
     /// of the pipe for use with
     /// multiple senders
     sender: Arc<Sender<LogUpdate>>,
+    /// the log listener thread, which is
+    /// unparked after every update we send
+    listener: Thread,
     /// this indicates the log message
     /// came from inside an async task
     from_task: bool,
@@ -34,6 +126,10 @@ pub struct LogPipe {
     /// came from inside another thread
     /// besides main
     from_thread: bool,
+    /// this indicates the log message
+    /// goes to log storage as well as
+    /// the terminal and log out
+    stored: bool,
     // refrain from holding job state in
     // here, it is global to the entire
     // program
@@ -58,323 +154,125 @@ impl LogPipe {
             */
 
             let (mut receiver, sender) = queue::<LogUpdate>();
+            unsafe {
+                SHUTDOWN.store(false, Ordering::SeqCst);
+            }
 
-            let _ = std::thread::Builder::new().name("log listener".into()).spawn(move || {
-                'read_update: loop {
-                    let update = match receiver.try_dequeue() {
-                        Ok(update) => update,
-                        Err(err) => match err {
-                            nolock::queues::DequeueError::Closed => {
-                                panic!("mpsc log queue was closed by sender?")
-                            }
-                            nolock::queues::DequeueError::Empty => {
-                                continue 'read_update;
-                            }
-                        },
-                    };
-
-                    #[allow(unused_variables)]
-                    let padded_string = LogPipe::pad_user_string(&update.user_string);
-                    //println!("{} job: {:?}, thread: {}, task: {}", user_string, update.job, update.from_thread, update.from_task);
-
-                    match update.job {
-                        Job::LogOut => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "logout", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "logout")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::LogStorage => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "logstorage", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "logstorage")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::LogSetup => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "logsetup", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "logsetup")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::AudioCompute => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "audiocompute", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "audiocompute")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::AudioInput => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "audioinput", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "audioinput")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::AudioStorage => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "audiostorage", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "audiostorage")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::AudioSetup => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "audiosetup", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "audiosetup")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::VideoCompute => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "videocompute", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "videocompute")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::VideoInput => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "videoinput", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "videoinput")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::VideoStorage => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "videostorage", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "videostorage")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::VideoSetup => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "videosetup", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "videosetup")] {
-                                    LogPipe::embedded_write(&update);
+            let listener = std::thread::Builder::new().name("log listener".into()).spawn(move || {
+                'read_batch: loop {
+                    // drain everything that has arrived since we
+                    // last woke up, before going back to sleep
+                    'read_update: loop {
+                        let update = match receiver.try_dequeue() {
+                            Ok(update) => update,
+                            Err(err) => match err {
+                                nolock::queues::DequeueError::Closed => {
+                                    panic!("mpsc log queue was closed by sender?")
                                 }
-
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::UI => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "ui", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "ui")] {
-                                    LogPipe::embedded_write(&update);
+                                nolock::queues::DequeueError::Empty => {
+                                    break 'read_update;
                                 }
+                            },
+                        };
 
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
+                        // updates the filter does not allow are dropped
+                        // here, before console output and before storage
+                        if !filter::allows(&update) {
+                            continue 'read_update;
                         }
-                        Job::UISetup => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "uisetup", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "uisetup")] {
-                                    LogPipe::embedded_write(&update);
-                                }
 
-                            }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
-                        }
-                        Job::Main => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "main", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "main")] {
-                                    LogPipe::embedded_write(&update);
-                                }
+                        #[allow(unused_variables)]
+                        let padded_string = LogPipe::pad_user_string(&update.user_string);
 
+                        cfg_if! {
+                            if #[cfg(feature = "std")] {
+                                LogPipe::use_simple_logger(&update, &padded_string);
+                            } else {
+                                LogPipe::embedded_write(&update);
                             }
-                            log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
                         }
-                        Job::Debug => {
-                            cfg_if! {
-                                if #[cfg(all(feature = "debug", feature = "std"))] {
-                                    LogPipe::use_simple_logger(&update, &padded_string);
-                                } else if #[cfg(feature = "debug")] {
-                                    LogPipe::embedded_write(&update);
-                                }
-
-                            }
+                        if update.stored {
                             log_storage_sender.enqueue(update.clone()).expect("failed to send LogUpdate to log_storage");
-                            log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
                         }
-                    }
-                    
-                    if unsafe{SHUTDOWN.load(Ordering::SeqCst)} {break 'read_update;} else {continue 'read_update;}
-                } // 'read_update
+                        log_out_sender.enqueue(update).expect("failed to send LogUpdate to log_out");
+                    } // 'read_update
+
+                    // the queue is empty, so the shutdown flag
+                    // is only honoured once every update is out
+                    if unsafe{SHUTDOWN.load(Ordering::SeqCst)} {break 'read_batch;}
+
+                    // sleep until a producer or shutdown() unparks
+                    // us, an unpark that lands between the drain
+                    // and here makes park() return straight away
+                    std::thread::park();
+                } // 'read_batch
                 // drop the receiver, so it cleans up
                 // and marks itself as closed so further
                 // use returns a handleable error
                 unsafe{INITIALIZED.store(false, Ordering::SeqCst);}
                 drop(receiver);
-            }); // log listener thread;
+            }) // log listener thread;
+            .expect("failed to spawn the log listener thread");
+            let listener_thread = listener.thread().clone();
+            match LISTENER.lock() {
+                Ok(mut handle) => *handle = Some(listener),
+                Err(poisoned) => *poisoned.into_inner() = Some(listener),
+            }
             unsafe {
                 INITIALIZED.store(true, Ordering::SeqCst);
             }
             LogPipe {
                 sender: Arc::new(sender),
+                listener: listener_thread,
                 from_task: false,
                 from_thread: false,
+                stored: true,
             }
         } else {
             panic!("you cannot call log::set_pipe() twice");
         }
     }
 
-    /// call this function to log to stdout
-    /// when in debug mode, and to disk and
-    /// through broadcast when in production
-    pub fn info(&self, explanation: &str, job: Job) {
-        if unsafe { INITIALIZED.load(Ordering::SeqCst) } {
-            self.sender
-                .clone()
-                .enqueue(LogUpdate {
-                    timestamp: crate::get_timestamp(),
-                    user_string: explanation.into(),
-                    level: Level::Info,
-                    job: job,
-                    from_task: self.from_task,
-                    thread_name: std::thread::current()
-                        .name()
-                        .unwrap_or("no name returned")
-                        .into(),
-                })
-                .expect("failed to send info log update to the receiver");
-        } else {
-            panic!("must call log::set_pipe() before using log::info()");
+    /// this function is what the info!, warn!, error!,
+    /// debug! and trace! macros expand to, the message
+    /// is only formatted when the filter allows the
+    /// level and job, so filtered updates cost nothing
+    /// but the check. Threads still running once the log
+    /// has been shut down have their updates dropped
+    pub fn log_at(
+        &self,
+        level: Level,
+        job: Job,
+        module_path: &str,
+        line: u32,
+        message: fmt::Arguments,
+    ) {
+        if unsafe { SHUTDOWN.load(Ordering::SeqCst) } {
+            return;
         }
-    }
-
-    /// call this function to log to stdout
-    /// when in debug mode, and to disk and
-    /// through broadcast when in production
-    pub fn debug(&self, explanation: &str) {
-        if unsafe { INITIALIZED.load(Ordering::SeqCst) } {
-            self.sender
-                .clone()
-                .enqueue(LogUpdate {
-                    timestamp: crate::get_timestamp(),
-                    user_string: explanation.into(),
-                    level: Level::Info,
-                    job: Job::Debug,
-                    from_task: self.from_task,
-                    thread_name: std::thread::current()
-                        .name()
-                        .unwrap_or("no name returned")
-                        .into(),
-                })
-                .expect("failed to send debug log update to the receiver");
-        } else {
-            panic!("must call log::set_pipe() before using log::debug()");
-        }
-    }
-
-    /// call this function to log to stdout
-    /// when in debug mode, and to disk and
-    /// through broadcast when in production
-    pub fn warn(&self, condition: &str, job: Job) {
-        if unsafe { INITIALIZED.load(Ordering::SeqCst) } {
-            self.sender
-                .clone()
-                .enqueue(LogUpdate {
-                    timestamp: crate::get_timestamp(),
-                    user_string: condition.into(),
-                    level: Level::Warn,
-                    job,
-                    from_task: self.from_task,
-                    thread_name: std::thread::current()
-                        .name()
-                        .unwrap_or("no name returned")
-                        .into(),
-                })
-                .expect("failed to send warn log update to the receiver");
-        } else {
-            panic!("must call log::set_pipe() before using log::warn()");
+        if unsafe { !INITIALIZED.load(Ordering::SeqCst) } {
+            panic!("must call log::set_pipe() before logging a {:?}", level);
         }
-    }
-
-    /// call this function to log to stdout
-    /// when in debug mode, and to disk and
-    /// through broadcast when in production
-    pub fn error(&self, shouldnt_happen: &str, job: Job) {
-        if unsafe { INITIALIZED.load(Ordering::SeqCst) } {
-            self.sender
-                .clone()
-                .enqueue(LogUpdate {
-                    timestamp: crate::get_timestamp(),
-                    user_string: shouldnt_happen.into(),
-                    level: Level::Error,
-                    job,
-                    from_task: self.from_task,
-                    thread_name: std::thread::current()
-                        .name()
-                        .unwrap_or("no name returned")
-                        .into(),
-                })
-                .expect("failed to send warn log update to the receiver");
-        } else {
-            panic!("must call log::set_pipe() before using log::error()");
+        if !filter::enabled(level, &job) {
+            return;
         }
+        // the queue is closed once the listener has stopped,
+        // which can happen after the shutdown check above
+        let _ = self.sender.clone().enqueue(LogUpdate {
+            timestamp: crate::time::now(),
+            user_string: std::fmt::format(message),
+            level,
+            job,
+            from_task: self.from_task,
+            thread_name: std::thread::current()
+                .name()
+                .unwrap_or("no name returned")
+                .into(),
+            module_path: module_path.into(),
+            line,
+            stored: self.stored,
+        });
+        self.listener.unpark();
     }
 
     /// this function creates a new log sender
@@ -392,6 +290,17 @@ impl LogPipe {
         self.clone()
     }
 
+    /// this function creates a new log sender whose
+    /// updates go to the terminal and log out, but not to
+    /// log storage, so log storage can report that it
+    /// cannot write without queueing more for itself
+    pub fn new_unstored_log(&self) -> LogPipe {
+        LogPipe {
+            stored: false,
+            ..self.clone()
+        }
+    }
+
     /// this function pads user log strings so that
     /// they display well as print statements
     fn pad_user_string(user_string: &str) -> String {
@@ -407,33 +316,11 @@ impl LogPipe {
         padded_string
     }
 
-    /// THIS IS CURRENTLY A NO OP
+    /// this function prints the update to the terminal,
+    /// it is only called from the log listener thread so
+    /// the threads producing updates never wait on stdout
     fn use_simple_logger(update: &LogUpdate, padded_string: &str) {
-        /*
-        match update.level {
-            Level::Error => log::error!(
-                "{} job: {:?}, thread: {}, task: {}",
-                padded_string,
-                update.job,
-                update.from_thread,
-                update.from_task
-            ),
-            Level::Warn => log::warn!(
-                "{} job: {:?}, thread: {}, task: {}",
-                padded_string,
-                update.job,
-                update.from_thread,
-                update.from_task
-            ),
-            Level::Info => log::info!(
-                "{} job: {:?}, thread: {}, task: {}",
-                padded_string,
-                update.job,
-                update.from_thread,
-                update.from_task
-            ),
-        }
-        */
+        console::write(update, padded_string);
     }
 
     /// this function will be used to print
@@ -442,22 +329,41 @@ impl LogPipe {
         unimplemented!()
     }
 
-    /// this function shuts down the log receivers correctly
+    /// this function replaces the runtime log filter,
+    /// updates already in the queue are checked
+    /// against the new filter as they are read
+    pub fn set_filter(&self, new_filter: filter::LogFilter) {
+        filter::set(new_filter);
+    }
+
+    /// this function shuts down the log receivers correctly,
+    /// the listener passes on every update already in the
+    /// queue before it exits, and we wait for it to do so
     pub fn shutdown() {
         unsafe {
             SHUTDOWN.store(true, Ordering::SeqCst);
         }
+        let listener = match LISTENER.lock() {
+            Ok(mut handle) => handle.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(listener) = listener {
+            listener.thread().unpark();
+            if listener.thread().id() != std::thread::current().id() {
+                let _ = listener.join();
+            }
+        }
     }
 }
 
 /// this is a single frame
 /// of the logger
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LogUpdate {
     /// this is where we store the string given to us
     pub user_string: String,
     /// this is where we store an enum of the level
-    level: Level,
+    pub level: Level,
     /// this is where we store an enum of where the
     /// message comes from within the program
     pub job: Job,
@@ -469,15 +375,30 @@ pub struct LogUpdate {
     pub thread_name: String,
     /// this is where we store the log's
     /// timestamp for use when storing the file
-    pub timestamp: u64,
+    pub timestamp: Timestamp,
+    /// this is where we store the module path
+    /// of the macro that made the update
+    pub module_path: String,
+    /// this is where we store the line number
+    /// the update was made on
+    pub line: u32,
+    /// this is where we make note of whether
+    /// the update goes to log storage
+    pub stored: bool,
 }
 
 /// the level describes the severity
 /// of a log update, meaning with some
 /// work the user can hide irrelevant
 /// messages like Info
-#[derive(Debug, Clone)]
-enum Level {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// designed to follow values through
+    /// a single function while working on it
+    Trace,
+    /// designed to help figure out a bug,
+    /// and hidden unless asked for
+    Debug,
     /// designed to walk new users through
     /// this program's execution
     Info,
@@ -489,7 +410,7 @@ enum Level {
     Error,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 /// Distinct Jobs allow us to sort
 /// our logs based on the area we are
 /// working in, besides just the typical
@@ -541,6 +462,10 @@ pub enum Job {
     /// in the main function
     UISetup,
     /// this indicates the message came from
+    /// the control socket, or a command sent
+    /// over it
+    Control,
+    /// this indicates the message came from
     /// the main function where all of the
     /// functions are tied together as
     /// a program
@@ -549,3 +474,158 @@ pub enum Job {
     /// used to help figure out a bug
     Debug,
 }
+
+impl Level {
+    /// every Level from least to most severe
+    pub const ALL: [Level; 5] = [
+        Level::Trace,
+        Level::Debug,
+        Level::Info,
+        Level::Warn,
+        Level::Error,
+    ];
+
+    /// this function finds a Level by name,
+    /// ignoring case, e.g. "warn" or "Warn"
+    pub fn named(name: &str) -> Option<Level> {
+        Level::ALL
+            .into_iter()
+            .find(|level| format!("{:?}", level).eq_ignore_ascii_case(name))
+    }
+}
+
+impl Job {
+    /// this function finds a Job by its name,
+    /// ignoring case, e.g. "AudioInput"
+    pub fn named(name: &str) -> Option<Job> {
+        Job::ALL
+            .iter()
+            .find(|job| format!("{:?}", job).eq_ignore_ascii_case(name))
+            .cloned()
+    }
+
+    /// every Job in the order they are declared,
+    /// used when reading a Job back from its name
+    pub const ALL: [Job; 16] = [
+        Job::LogOut,
+        Job::LogStorage,
+        Job::LogSetup,
+        Job::AudioCompute,
+        Job::AudioInput,
+        Job::AudioStorage,
+        Job::AudioSetup,
+        Job::VideoCompute,
+        Job::VideoInput,
+        Job::VideoStorage,
+        Job::VideoSetup,
+        Job::UI,
+        Job::UISetup,
+        Job::Control,
+        Job::Main,
+        Job::Debug,
+    ];
+}
+
+/// this describes which formats the log storage
+/// task writes each LogUpdate in, it is chosen
+/// once at startup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// only the padded text lines in main_log.txt
+    Text,
+    /// the padded text lines in main_log.txt, and
+    /// one JSON object per line in main_log.jsonl
+    TextAndJsonLines,
+}
+
+impl LogFormat {
+    /// this reads CAMERA_LOG_FORMAT from the environment,
+    /// "jsonl" adds the JSON lines sink, anything else
+    /// (or nothing) keeps the text format alone
+    pub fn from_env() -> LogFormat {
+        match std::env::var("CAMERA_LOG_FORMAT") {
+            Ok(format) if format.eq_ignore_ascii_case("jsonl") => {
+                LogFormat::TextAndJsonLines
+            }
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// this reads the user and system time the named thread
+    /// has spent on the CPU, in clock ticks, from /proc
+    fn cpu_ticks_of_thread(name: &str) -> Option<u64> {
+        for task in std::fs::read_dir("/proc/self/task").ok()? {
+            let task = task.ok()?.path();
+            let comm = std::fs::read_to_string(task.join("comm")).ok()?;
+            if comm.trim() != name {
+                continue;
+            }
+            let stat = std::fs::read_to_string(task.join("stat")).ok()?;
+            // the fields after the command name, which is
+            // in brackets, start with the thread state
+            let fields: std::vec::Vec<&str> =
+                stat.rsplit_once(')')?.1.split_whitespace().collect();
+            let user: u64 = fields.get(11)?.parse().ok()?;
+            let system: u64 = fields.get(12)?.parse().ok()?;
+            return Some(user + system);
+        }
+        None
+    }
+
+    #[test]
+    fn listener_sleeps_when_idle_and_exits_on_shutdown() {
+        let _pipe = TEST_PIPE.lock().unwrap_or_else(|err| err.into_inner());
+        let (mut out_receiver, out_sender) = crate::queue::LOG_OUT.open();
+        let (mut storage_receiver, storage_sender) =
+            crate::queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+
+        for number in 0..100 {
+            crate::info!(log, Job::Debug, "update number {}", number);
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut received = 0;
+        while received < 100 && Instant::now() < deadline {
+            match storage_receiver.try_dequeue() {
+                Ok(update) => {
+                    assert_eq!(
+                        update.user_string,
+                        format!("update number {}", received)
+                    );
+                    received += 1;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        assert_eq!(received, 100);
+
+        // a spinning listener burns the whole second,
+        // which is 100 ticks at the usual clock rate
+        let before = cpu_ticks_of_thread("log listener")
+            .expect("the log listener thread should be running");
+        std::thread::sleep(Duration::from_secs(1));
+        let after = cpu_ticks_of_thread("log listener")
+            .expect("the log listener thread should still be running");
+        assert!(after - before <= 2, "idle listener used {} ticks", after - before);
+
+        crate::warn!(log, Job::Debug, "last update before shutdown");
+        LogPipe::shutdown();
+        assert!(cpu_ticks_of_thread("log listener").is_none());
+        assert!(unsafe { !INITIALIZED.load(Ordering::SeqCst) });
+        let mut last = None;
+        while let Ok(update) = out_receiver.try_dequeue() {
+            last = Some(update.user_string);
+        }
+        assert_eq!(last.as_deref(), Some("last update before shutdown"));
+
+        // a thread that outlived the log has its updates dropped
+        crate::warn!(log, Job::Debug, "update after shutdown");
+        assert!(out_receiver.try_dequeue().is_err());
+    }
+}
@@ -0,0 +1,114 @@
+/// This is where the log listener thread prints each
+/// LogUpdate to the terminal. Printing only ever happens
+/// on the listener thread, so a slow terminal holds up
+/// the listener rather than the thread that logged.
+/// Colors are used when stdout is a terminal, and left
+/// out when it is redirected to a file or a pipe, or
+/// when NO_COLOR is set in the environment
+use super::{Level, LogUpdate};
+
+use std::format;
+use std::io::{IsTerminal, Write};
+use std::string::String;
+use std::sync::OnceLock;
+
+/// whether stdout is a terminal that should get colors,
+/// this is decided once, the first time we print
+static COLORED: OnceLock<bool> = OnceLock::new();
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const BLUE: &str = "\x1b[34m";
+const CYAN: &str = "\x1b[36m";
+
+/// this function prints a single update to stdout,
+/// a closed or broken stdout is ignored so that
+/// losing the terminal never stops the logger
+pub fn write(update: &LogUpdate, padded_string: &str) {
+    let colored = *COLORED.get_or_init(|| {
+        std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+    });
+    let line = format_line(update, padded_string, colored);
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(line.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// this function lays out the timestamp, level, job,
+/// thread name and message of an update as one line
+fn format_line(update: &LogUpdate, padded_string: &str, colored: bool) -> String {
+    let level = format!("{:<5}", format!("{:?}", update.level));
+    let job = format!("{:<15}", format!("{:?}", update.job));
+    if colored {
+        let level_color = match update.level {
+            Level::Trace => DIM,
+            Level::Debug => BLUE,
+            Level::Info => GREEN,
+            Level::Warn => YELLOW,
+            Level::Error => RED,
+        };
+        format!(
+            "{DIM}{}{RESET} {level_color}{}{RESET} {CYAN}{}{RESET} {} {DIM}from {{thread: {}, task: {}}}{RESET}\n",
+            update.timestamp.rfc3339(),
+            level,
+            job,
+            padded_string,
+            update.thread_name,
+            update.from_task,
+        )
+    } else {
+        format!(
+            "{} {} {} {} from {{thread: {}, task: {}}}\n",
+            update.timestamp.rfc3339(),
+            level,
+            job,
+            padded_string,
+            update.thread_name,
+            update.from_task,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::Job;
+    use crate::time::Timestamp;
+
+    fn update() -> LogUpdate {
+        LogUpdate {
+            user_string: "got device: default".into(),
+            level: Level::Warn,
+            job: Job::AudioInput,
+            from_task: true,
+            thread_name: "audio".into(),
+            timestamp: Timestamp {
+                wall_nanos: 1_690_500_724_000_000_000,
+                monotonic_nanos: 18_000_000_000,
+            },
+            module_path: "camera::hardware::audio::has_side_effects".into(),
+            line: 83,
+            stored: true,
+        }
+    }
+
+    #[test]
+    fn plain_lines_have_no_escape_codes() {
+        let line = format_line(&update(), "got device: default !>", false);
+        assert_eq!(
+            line,
+            "2023-07-27T23:32:04.000000000Z Warn  AudioInput      got device: default !> from {thread: audio, task: true}\n"
+        );
+    }
+
+    #[test]
+    fn colored_lines_reset_every_color() {
+        let line = format_line(&update(), "got device: default !>", true);
+        assert!(line.contains(YELLOW));
+        assert_eq!(line.matches(RESET).count(), 4);
+        assert!(line.ends_with(&format!("{RESET}\n")));
+    }
+}
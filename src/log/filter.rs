@@ -0,0 +1,180 @@
+/// This is where we decide at runtime which log updates
+/// are kept, rather than choosing at compile time with
+/// cargo features. A filter holds a default level and
+/// an optional override for each Job, an update is kept
+/// when its level is at least as severe as the level
+/// chosen for its Job. The filter is read from the
+/// CAMERA_LOG environment variable at startup, and can
+/// be swapped while running with LogPipe::set_filter()
+use super::{Job, Level, LogUpdate};
+
+use anyhow::{anyhow, Result};
+
+use std::sync::RwLock;
+use std::vec::Vec;
+
+/// the filter in use by the log listener, None until
+/// someone sets one, which keeps the default
+static FILTER: RwLock<Option<LogFilter>> = RwLock::new(None);
+
+/// this describes the least severe level that
+/// will be kept, either globally or for one Job,
+/// a threshold of None turns the logs off
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    /// the threshold used for any Job
+    /// without an override
+    default: Option<Level>,
+    /// the threshold for each Job that
+    /// was named explicitly
+    overrides: Vec<(Job, Option<Level>)>,
+}
+
+impl Default for LogFilter {
+    /// keeps every update from Info up, Debug and
+    /// Trace have to be asked for by name
+    fn default() -> LogFilter {
+        LogFilter {
+            default: Some(Level::Info),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    /// this function reads a filter written as a comma
+    /// separated list, where a bare level sets the default
+    /// and Job=level sets an override, e.g.
+    /// "warn,AudioInput=info,Debug=off"
+    pub fn parse(description: &str) -> Result<LogFilter> {
+        let mut filter = LogFilter::default();
+        for directive in description.split(',') {
+            let directive = directive.trim();
+            if directive.is_empty() {
+                continue;
+            }
+            match directive.split_once('=') {
+                Some((job, threshold)) => {
+                    let job = match Job::named(job.trim()) {
+                        Some(job) => job,
+                        None => {
+                            return Err(anyhow!("unknown log job: {}", job))
+                        }
+                    };
+                    let threshold = threshold_named(threshold.trim())?;
+                    filter.set_override(job, threshold);
+                }
+                None => filter.default = threshold_named(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    /// this function reads the CAMERA_LOG environment
+    /// variable, using the default when it is not set
+    pub fn from_env() -> Result<LogFilter> {
+        match std::env::var("CAMERA_LOG") {
+            Ok(description) => LogFilter::parse(&description),
+            Err(_) => Ok(LogFilter::default()),
+        }
+    }
+
+    /// this function sets the threshold for a single Job,
+    /// replacing any override it already had
+    pub fn set_override(&mut self, job: Job, threshold: Option<Level>) {
+        self.overrides.retain(|(existing, _)| *existing != job);
+        self.overrides.push((job, threshold));
+    }
+
+    /// this function reports whether an update with
+    /// this level and Job passes the filter
+    pub fn allows(&self, level: Level, job: &Job) -> bool {
+        let threshold = match self
+            .overrides
+            .iter()
+            .find(|(overridden, _)| overridden == job)
+        {
+            Some((_, threshold)) => threshold,
+            None => &self.default,
+        };
+        match threshold {
+            Some(threshold) => level >= *threshold,
+            None => false,
+        }
+    }
+}
+
+/// this function replaces the filter used by the log
+/// listener, taking effect from the next update
+pub fn set(filter: LogFilter) {
+    match FILTER.write() {
+        Ok(mut current) => *current = Some(filter),
+        Err(poisoned) => *poisoned.into_inner() = Some(filter),
+    }
+}
+
+/// this function checks an update against the filter in use
+pub fn allows(update: &LogUpdate) -> bool {
+    enabled(update.level, &update.job)
+}
+
+/// this function checks a level and Job against the
+/// filter in use, before any update has been built
+pub fn enabled(level: Level, job: &Job) -> bool {
+    match FILTER.read() {
+        Ok(current) => match current.as_ref() {
+            Some(filter) => filter.allows(level, job),
+            None => LogFilter::default().allows(level, job),
+        },
+        Err(poisoned) => match poisoned.into_inner().as_ref() {
+            Some(filter) => filter.allows(level, job),
+            None => LogFilter::default().allows(level, job),
+        },
+    }
+}
+
+/// this function reads a threshold by name, where
+/// "off" means nothing is kept
+fn threshold_named(name: &str) -> Result<Option<Level>> {
+    if name.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    match Level::named(name) {
+        Some(level) => Ok(Some(level)),
+        None => Err(anyhow!("unknown log level: {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_keeps_info_and_up() {
+        let filter = LogFilter::default();
+        for job in Job::ALL.iter() {
+            assert!(filter.allows(Level::Info, job));
+            assert!(!filter.allows(Level::Debug, job));
+        }
+    }
+
+    #[test]
+    fn overrides_beat_the_default() {
+        let filter =
+            LogFilter::parse("warn, AudioInput=info ,Debug=off").unwrap();
+        assert!(!filter.allows(Level::Info, &Job::Main));
+        assert!(filter.allows(Level::Warn, &Job::Main));
+        assert!(filter.allows(Level::Info, &Job::AudioInput));
+        assert!(!filter.allows(Level::Error, &Job::Debug));
+
+        let filter = LogFilter::parse("AudioCompute=trace").unwrap();
+        assert!(filter.allows(Level::Trace, &Job::AudioCompute));
+        assert!(!filter.allows(Level::Debug, &Job::Main));
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        assert!(LogFilter::parse("loud").is_err());
+        assert!(LogFilter::parse("Microwave=info").is_err());
+    }
+}
@@ -0,0 +1,313 @@
+/// This is where we turn each LogUpdate into a single
+/// line of JSON, so that the log can be read back by
+/// scripts doing automated analysis, rather than by
+/// a person looking at the padded text format.
+/// Every field of the LogUpdate is written, one object
+/// per line, and the parser below reads the same lines
+/// back into a LogUpdate so that we can prove nothing
+/// was lost on the way to disk
+use super::{Job, Level, LogUpdate};
+use crate::time::Timestamp;
+
+use anyhow::{anyhow, bail, Result};
+
+use std::format;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// this function writes every field of the update
+/// as one JSON object, terminated by a newline
+pub fn to_line(update: &LogUpdate) -> String {
+    format!(
+        "{{\"timestamp\":\"{}\",\"wall_nanos\":{},\"monotonic_nanos\":{},\"level\":\"{:?}\",\"job\":\"{:?}\",\"thread_name\":{},\"from_task\":{},\"module_path\":{},\"line\":{},\"stored\":{},\"user_string\":{}}}\n",
+        update.timestamp.rfc3339(),
+        update.timestamp.wall_nanos,
+        update.timestamp.monotonic_nanos,
+        update.level,
+        update.job,
+        escape(&update.thread_name),
+        update.from_task,
+        escape(&update.module_path),
+        update.line,
+        update.stored,
+        escape(&update.user_string),
+    )
+}
+
+/// this function reads a single line written by to_line()
+/// back into a LogUpdate, returning an error describing
+/// the first thing that was not understood
+pub fn from_line(line: &str) -> Result<LogUpdate> {
+    let mut parser = Parser::new(line);
+
+    let mut wall_nanos = None;
+    let mut monotonic_nanos = None;
+    let mut level = None;
+    let mut job = None;
+    let mut thread_name = None;
+    let mut from_task = None;
+    let mut module_path = None;
+    let mut line = None;
+    let mut stored = None;
+    let mut user_string = None;
+
+    parser.expect('{')?;
+    loop {
+        let key = parser.string()?;
+        parser.expect(':')?;
+        match key.as_str() {
+            // the RFC 3339 time is only there for people
+            // reading the file, the nanoseconds are exact
+            "timestamp" => {
+                parser.string()?;
+            }
+            "wall_nanos" => wall_nanos = Some(parser.number()?),
+            "monotonic_nanos" => monotonic_nanos = Some(parser.number()?),
+            "level" => level = Some(level_named(&parser.string()?)?),
+            "job" => job = Some(job_named(&parser.string()?)?),
+            "thread_name" => thread_name = Some(parser.string()?),
+            "from_task" => from_task = Some(parser.boolean()?),
+            "module_path" => module_path = Some(parser.string()?),
+            "line" => line = Some(u32::try_from(parser.number()?)?),
+            "stored" => stored = Some(parser.boolean()?),
+            "user_string" => user_string = Some(parser.string()?),
+            other => bail!("unknown log field: {}", other),
+        }
+        match parser.next()? {
+            ',' => continue,
+            '}' => break,
+            other => bail!("expected ',' or '}}' but found '{}'", other),
+        }
+    }
+
+    Ok(LogUpdate {
+        timestamp: Timestamp {
+            wall_nanos: wall_nanos.ok_or(anyhow!("missing field: wall_nanos"))?,
+            monotonic_nanos: monotonic_nanos
+                .ok_or(anyhow!("missing field: monotonic_nanos"))?,
+        },
+        level: level.ok_or(anyhow!("missing field: level"))?,
+        job: job.ok_or(anyhow!("missing field: job"))?,
+        thread_name: thread_name.ok_or(anyhow!("missing field: thread_name"))?,
+        from_task: from_task.ok_or(anyhow!("missing field: from_task"))?,
+        module_path: module_path.ok_or(anyhow!("missing field: module_path"))?,
+        line: line.ok_or(anyhow!("missing field: line"))?,
+        user_string: user_string.ok_or(anyhow!("missing field: user_string"))?,
+        stored: stored.ok_or(anyhow!("missing field: stored"))?,
+    })
+}
+
+/// this function quotes a string and escapes the
+/// characters that JSON does not allow inside one
+pub(crate) fn escape(unescaped: &str) -> String {
+    let mut escaped = String::with_capacity(unescaped.len() + 2);
+    escaped.push('"');
+    for character in unescaped.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            control if (control as u32) < 0x20 => {
+                escaped.push_str(&format!("\\u{:04x}", control as u32))
+            }
+            other => escaped.push(other),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// this function finds the Level written by its Debug name
+fn level_named(name: &str) -> Result<Level> {
+    match Level::named(name) {
+        Some(level) => Ok(level),
+        None => Err(anyhow!("unknown log level: {}", name)),
+    }
+}
+
+/// this function finds the Job written by its Debug name
+fn job_named(name: &str) -> Result<Job> {
+    match Job::named(name) {
+        Some(job) => Ok(job),
+        None => Err(anyhow!("unknown log job: {}", name)),
+    }
+}
+
+/// this holds our place in the line
+/// while we read it one character at a time,
+/// the control socket reads its requests with it too
+pub(crate) struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    pub(crate) fn new(line: &str) -> Parser {
+        Parser {
+            chars: line.trim().chars().collect(),
+            position: 0,
+        }
+    }
+
+    /// returns the next character that is not whitespace
+    pub(crate) fn next(&mut self) -> Result<char> {
+        while let Some(character) = self.chars.get(self.position) {
+            self.position += 1;
+            if !character.is_whitespace() {
+                return Ok(*character);
+            }
+        }
+        bail!("unexpected end of line")
+    }
+
+    /// returns the next character without consuming it
+    pub(crate) fn peek(&mut self) -> Result<char> {
+        let character = self.next()?;
+        self.position -= 1;
+        Ok(character)
+    }
+
+    pub(crate) fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next()? {
+            found if found == expected => Ok(()),
+            found => bail!("expected '{}' but found '{}'", expected, found),
+        }
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut unescaped = String::new();
+        loop {
+            let character = match self.chars.get(self.position) {
+                Some(character) => *character,
+                None => bail!("unterminated string"),
+            };
+            self.position += 1;
+            match character {
+                '"' => return Ok(unescaped),
+                '\\' => {
+                    let escaped = match self.chars.get(self.position) {
+                        Some(escaped) => *escaped,
+                        None => bail!("unterminated escape"),
+                    };
+                    self.position += 1;
+                    match escaped {
+                        '"' => unescaped.push('"'),
+                        '\\' => unescaped.push('\\'),
+                        '/' => unescaped.push('/'),
+                        'n' => unescaped.push('\n'),
+                        'r' => unescaped.push('\r'),
+                        't' => unescaped.push('\t'),
+                        'u' => {
+                            let end = self.position + 4;
+                            let hex: String = match self
+                                .chars
+                                .get(self.position..end)
+                            {
+                                Some(hex) => hex.iter().collect(),
+                                None => bail!("short unicode escape"),
+                            };
+                            self.position = end;
+                            let code = u32::from_str_radix(&hex, 16)?;
+                            match char::from_u32(code) {
+                                Some(decoded) => unescaped.push(decoded),
+                                None => bail!("invalid unicode escape"),
+                            }
+                        }
+                        other => bail!("unknown escape '\\{}'", other),
+                    }
+                }
+                other => unescaped.push(other),
+            }
+        }
+    }
+
+    pub(crate) fn number(&mut self) -> Result<u64> {
+        self.peek()?;
+        let start = self.position;
+        while let Some(digit) = self.chars.get(self.position) {
+            if !digit.is_ascii_digit() {
+                break;
+            }
+            self.position += 1;
+        }
+        let digits: String = self.chars[start..self.position].iter().collect();
+        Ok(digits.parse::<u64>()?)
+    }
+
+    fn boolean(&mut self) -> Result<bool> {
+        self.peek()?;
+        for (word, value) in [("true", true), ("false", false)] {
+            let end = self.position + word.len();
+            if let Some(found) = self.chars.get(self.position..end) {
+                if found.iter().copied().eq(word.chars()) {
+                    self.position = end;
+                    return Ok(value);
+                }
+            }
+        }
+        bail!("expected true or false in log line")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(user_string: &str, job: Job, level: Level) -> LogUpdate {
+        LogUpdate {
+            user_string: user_string.into(),
+            level,
+            job,
+            from_task: true,
+            thread_name: "log effects".into(),
+            timestamp: Timestamp {
+                wall_nanos: 1_690_500_708_123_456_789,
+                monotonic_nanos: 2_000_000_017,
+            },
+            module_path: "camera::hardware::storage".into(),
+            line: 68,
+            stored: true,
+        }
+    }
+
+    #[test]
+    fn round_trips_every_job_and_level() {
+        for job in Job::ALL.iter() {
+            for level in Level::ALL {
+                let written = update("started log storage", job.clone(), level);
+                let line = to_line(&written);
+                assert!(line.ends_with('\n'));
+                assert_eq!(from_line(&line).unwrap(), written);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_escaped_strings() {
+        let written = update(
+            "got device: \"default\"\n\tpath C:\\mic \u{1}",
+            Job::AudioInput,
+            Level::Warn,
+        );
+        assert_eq!(from_line(&to_line(&written)).unwrap(), written);
+    }
+
+    #[test]
+    fn round_trips_unstored_updates() {
+        let written = LogUpdate {
+            stored: false,
+            ..update("could not write the log", Job::LogStorage, Level::Error)
+        };
+        assert_eq!(from_line(&to_line(&written)).unwrap(), written);
+    }
+
+    #[test]
+    fn rejects_truncated_lines() {
+        let line = to_line(&update("cut short", Job::Main, Level::Info));
+        assert!(from_line(&line[..line.len() / 2]).is_err());
+        assert!(from_line("{\"level\":\"Info\"}").is_err());
+    }
+}
@@ -1,10 +1,29 @@
 #![no_std]
+mod cli;
 mod compute;
+mod config;
+mod control;
 mod hardware;
+mod http;
 mod log;
+mod pipeline;
 mod queue;
+mod recorder;
+mod recordings;
+mod shutdown;
+#[cfg(test)]
+mod simulation;
+mod supervisor;
+mod time;
 mod ui;
-use crate::log::{Job, LogPipe};
+use crate::cli::{Command, RecordOptions};
+use crate::config::{Config, Watcher};
+use crate::control::{Controls, Settings};
+use crate::hardware::storage::mounts::Removable;
+use crate::log::filter::LogFilter;
+use crate::log::{Job, LogFormat, LogPipe};
+use crate::pipeline::Link;
+use crate::recorder::{Recorder, Sources};
 
 use creusot_contracts::*;
 
@@ -13,15 +32,13 @@ use core::time::Duration;
 #[cfg(feature = "std")]
 extern crate std;
 #[cfg(feature = "std")]
-use std::println;
-#[cfg(feature = "std")]
-use std::thread;
+use std::{eprintln, println};
 #[cfg(feature = "std")]
-use std::thread::sleep;
+use std::vec::Vec;
 
 /*
        YAY THREADFUL LOGGING
-       NO LONGER WORKS OVER STDOUT?
+       WORKS OVER STDOUT AGAIN!
 
        YAY DEFAULT INPUT AUDIO
        MAYBE BETTER TO SELECT KNOWN DEVICES?
@@ -29,211 +46,237 @@ use std::thread::sleep;
        YAY THREAD NAMES
        EVEN BETTER WITH SUGAR!
 */
-static DEBUG_LOGGING: bool = false;
+
+/// how long the threads get to drain their queues and
+/// finalize their files once we are asked to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() {
-    if DEBUG_LOGGING {
+    let cli = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    // watched from before it is read, so no change is missed
+    let watcher = Watcher::new(Config::path(cli.config.as_deref()));
+    // there is no log yet to report a bad file to
+    let loaded = match cli.config.as_deref() {
+        Some(path) => Config::load(path),
+        None => Config::from_env(),
+    };
+    let mut config = match loaded {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("could not read the configuration, {}", err);
+            std::process::exit(2);
+        }
+    };
+    match cli.command {
+        Command::Record(options) => {
+            options.apply(&mut config);
+            record(config, options, watcher);
+        }
+        command => match cli::run(command, &config) {
+            Ok(status) => std::process::exit(status),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+/// this function runs the pipeline, capturing from the
+/// sources `options` leaves on, until we are asked to stop,
+/// reading the configuration again whenever it changes
+fn record(mut config: Config, options: RecordOptions, mut watcher: Watcher) {
+    let trace_setup = config.log.trace_setup;
+    let log_queue_size = config.log.queue_size;
+
+    if trace_setup {
         println!("creating new log out queue !>");
     }
-    let (_log_out_queue_receiver, log_out_queue) = queue::LogOut::new();
+    let (_log_out_queue_receiver, log_out_queue) =
+        queue::LOG_OUT.with_size(log_queue_size).open();
 
-    if DEBUG_LOGGING {
+    if trace_setup {
         println!("creating new log storage queue !>");
     }
     let (log_storage_queue_receiver, log_storage_queue) =
-        queue::LogStorage::new();
+        queue::LOG_STORAGE.with_size(log_queue_size).open();
+    let log_storage_link = Link::new(log_storage_queue_receiver);
 
-    if DEBUG_LOGGING {
+    if trace_setup {
         println!("setting up proper logging facilities !>");
     }
-    let mut log = LogPipe::set_pipe(log_out_queue, log_storage_queue);
-
-    log.info("creating logging thread", Job::Main);
-    let mut logging_thread_log = log.new_thread_log();
-    let logging_thread = thread_named("log effects").spawn(move || {
-        logging_thread_log.info("logging thread started", Job::LogSetup);
-
-        let tasks = pasts::Executor::default();
-        let log_out_log = logging_thread_log.new_task_log();
-        let log_storage_log = logging_thread_log.new_task_log();
-
-        tasks.clone().block_on(async move {
-            /* WE ARE NOT HANDLING BLUETOOTH AT THIS TIME
-            tasks.spawn_boxed(async {
-                log_out_log.info("log out task started", Job::LogOut);
-                hardware::bluetooth::start(log_out_queue_receiver, log_out_log)
-                    .await;
-            });
-            */
-            tasks.spawn_boxed(async {
-                log_storage_log
-                    .info("log storage task started", Job::LogStorage);
-                hardware::storage::log_start(
-                    log_storage_queue_receiver,
-                    log_storage_log,
-                )
-                .await;
-            });
-        });
-    });
-
-    log.info("creating new audio in queue", Job::Main);
-    let (audio_in_queue, audio_in_queue_sender) = queue::AudioIn::new();
-
-    log.info("creating new input queue for audio compute", Job::Main);
-    let (audio_compute_out_receiver, audio_compute_out) =
-        queue::ToAudioCompute::new();
-
-    log.info("creating new output queue for audio compute", Job::Main);
-    let (audio_compute_in, audio_compute_in_sender) =
-        queue::FromAudioCompute::new();
-
-    log.info("creating new audio storage queue", Job::Main);
-    let (audio_storage_queue_receiver, audio_storage_queue) =
-        queue::AudioStorage::new();
-
-    log.info("creating audio thread", Job::Main);
-    let mut audio_thread_log = log.new_thread_log();
-    let audio_thread = thread_named("audio").spawn(move || {
-        audio_thread_log.info("audio thread started", Job::AudioSetup);
-
-        let tasks = pasts::Executor::default();
-        let microphone_log = audio_thread_log.new_task_log();
-        let audio_compute_log = audio_thread_log.new_task_log();
-        let audio_storage_log = audio_thread_log.new_task_log();
-
-        tasks.clone().block_on(async move {
-            tasks.spawn_boxed(async {
-                microphone_log.info("microphone task started", Job::AudioInput);
-                hardware::audio::start(audio_in_queue_sender, microphone_log)
-                    .await;
-            });
-            tasks.spawn_boxed(async {
-                audio_compute_log
-                    .info("audio compute task started", Job::AudioCompute);
-                compute::audio::start(
-                    audio_compute_out_receiver,
-                    audio_compute_in_sender,
-                    audio_compute_log,
-                )
-                .await;
-            });
-            tasks.spawn_boxed(async {
-                audio_storage_log
-                    .info("audio storage task started", Job::AudioStorage);
-                hardware::storage::audio_start(
-                    audio_storage_queue_receiver,
-                    audio_storage_log,
-                )
-                .await;
-            });
-        });
-    });
-
-    log.info("creating new video in queue", Job::Main);
-    let (mut video_in_queue, video_in_queue_sender) = queue::VideoIn::new();
-
-    log.info("creating new input queue for video compute", Job::Main);
-    let (video_compute_out_receiver, video_compute_out) =
-        queue::ToVideoCompute::new();
-
-    log.info("creating new output queue for video compute", Job::Main);
-    let (video_compute_in, video_compute_in_sender) =
-        queue::FromVideoCompute::new();
-
-    log.info("creating new video storage queue", Job::Main);
-    let (video_storage_queue_receiver, video_storage_queue) =
-        queue::VideoStorage::new();
-
-    log.info("creating video thread", Job::Main);
-    let mut video_thread_log = log.new_thread_log();
-    let video_thread = thread_named("video").spawn(move || {
-        video_thread_log.info("video thread started", Job::VideoSetup);
-
-        let tasks = pasts::Executor::default();
-        let camera_log = video_thread_log.new_task_log();
-        let video_compute_log = video_thread_log.new_task_log();
-        let video_storage_log = video_thread_log.new_task_log();
-
-        tasks.clone().block_on(async move {
-            tasks.spawn_boxed(async {
-                camera_log.info("camera task started", Job::VideoInput);
-                hardware::camera::start(video_in_queue_sender, camera_log)
-                    .await;
-            });
-
-            tasks.spawn_boxed(async {
-                video_compute_log
-                    .info("video compute task started", Job::VideoCompute);
-                compute::video::start(
-                    video_compute_out_receiver,
-                    video_compute_in_sender,
-                    video_compute_log,
-                )
-                .await;
-            });
-
-            tasks.spawn_boxed(async {
-                video_storage_log
-                    .info("video storage task started", Job::VideoStorage);
-                hardware::storage::video_start(
-                    video_storage_queue_receiver,
-                    video_storage_log,
-                )
-                .await;
-            });
-        });
-    });
-
-    // Last set up the ui queue for the user
-    log.info("creating new input queue for the UI", Job::Main);
-    let (view_out_queue_receiver, view_out_queue) = queue::ViewOut::new();
-
-    log.info("creating the UI thread", Job::Main);
-    let mut ui_thread_log = log.new_thread_log();
-    let ui_thread = thread_named("ui").spawn(move || {
-        ui_thread_log.info("UI thread started", Job::UISetup);
-
-        let tasks = pasts::Executor::default();
-        let ui_log = ui_thread_log.new_task_log();
-
-        tasks.clone().block_on(async move {
-            tasks.spawn_boxed(async {
-                ui_log.info("UI task started", Job::UI);
-                ui::start(view_out_queue_receiver, ui_log).await;
-            });
-        });
-    });
-    // Begin popping new frames from the in pipelines
-    // processing them
-    // then pushing them onto the storage pipelines
+    let log = LogPipe::set_pipe(log_out_queue, log_storage_queue);
+    with_environment(&mut config, &log);
+    log.set_filter(config.log.filter.clone());
+    let settings = Settings::new(&config);
+    let controls = Controls::default();
+
+    shutdown::install(&log);
+
+    let mut removable = Removable::new(&config.storage, &controls);
+    let sources = Sources::devices(&options, &settings);
+    let Recorder {
+        mut threads,
+        view_queue: view_telemetry_queue,
+    } = recorder::start(
+        &config,
+        &settings,
+        &controls,
+        sources,
+        &log,
+        log_storage_link,
+    );
+    crate::info!(log, Job::Main, "watching {} for changes", watcher.path());
+    if !config.control.socket.is_empty() {
+        match control::socket::serve(
+            &config.control.socket,
+            settings.clone(),
+            controls.clone(),
+            log.clone().new_thread_log(),
+        ) {
+            Ok(thread) => threads.push(thread),
+            Err(err) => crate::error!(
+                log,
+                Job::Main,
+                "could not open the control socket, {}",
+                err
+            ),
+        }
+    }
+    match ui::read_keys(
+        settings.storage.clone(),
+        controls.clone(),
+        log.clone().new_thread_log(),
+    ) {
+        Ok(thread) => threads.push(thread),
+        Err(err) => crate::error!(
+            log,
+            Job::Main,
+            "could not read the keyboard, {}",
+            err
+        ),
+    }
+    if !config.http.address.is_empty() {
+        match http::serve(
+            &config.http.address,
+            settings.clone(),
+            controls.clone(),
+            log.clone().new_thread_log(),
+        ) {
+            Ok(thread) => threads.push(thread),
+            Err(err) => crate::error!(
+                log,
+                Job::Main,
+                "could not start the status page, {}",
+                err
+            ),
+        }
+    }
 
 //valgrind --fair-sched=yes --trace-children=yes --leak-check=full --time-stamp=yes --show-leak-kinds=all --log-file=valgrind.txt target/debug/camera
 
     /*
         SOMEHOW THIS ALL !WORKS!
     */
-    loop {
-        sleep(Duration::from_millis(1000));
-        log.debug("woke up from 1 second sleep");
-    }
-}
+    shutdown::wait(Duration::from_millis(1000), || {
+        if let Some(reloaded) = watcher.changed() {
+            match reloaded {
+                Ok(mut reloaded) => {
+                    options.apply(&mut reloaded);
+                    with_environment(&mut reloaded, &log);
+                    settings.apply(reloaded, &log);
+                }
+                Err(err) => crate::warn!(
+                    log,
+                    Job::Main,
+                    "keeping the running configuration, {}",
+                    err
+                ),
+            }
+        }
+        removable.poll(&settings.storage, &log);
+        let samples = queue::telemetry::sample();
+        queue::telemetry::report(&log, &samples);
+        queue::pool::report(&log);
+        pipeline::report(&log);
+        time::report(&log);
+        controls.queues.set(samples.clone());
+        if let Err((_, err)) =
+            view_telemetry_queue.enqueue(ui::ViewUpdate::from_queues(samples))
+        {
+            crate::warn!(
+                log,
+                Job::Main,
+                "could not send telemetry to the UI: {:?}",
+                err
+            );
+        }
+    });
 
-fn thread_named(name: &str) -> std::thread::Builder {
-    thread::Builder::new().name(name.into())
+    // capture has been told to stop, and each queue closes
+    // behind it, so every thread but the log's finishes once
+    // storage has written what was queued and closed its files
+    crate::info!(log, Job::Main, "stopping capture and draining the queues");
+    drop(view_telemetry_queue);
+    let (log_threads, threads): (Vec<_>, Vec<_>) = threads
+        .into_iter()
+        .partition(|thread| thread.thread().name() == Some("log effects"));
+    // the keys thread only sees the shutdown after the next
+    // line is typed, so it is not waited for
+    let (keys, threads): (Vec<_>, Vec<_>) = threads
+        .into_iter()
+        .partition(|thread| thread.thread().name() == Some("keys"));
+    for thread in shutdown::join_within(threads, SHUTDOWN_TIMEOUT) {
+        crate::warn!(
+            log,
+            Job::Main,
+            "{} thread did not stop in time",
+            thread.thread().name().unwrap_or("unnamed")
+        );
+    }
+    if !shutdown::join_within(keys, Duration::ZERO).is_empty() {
+        crate::debug!(
+            log,
+            Job::Main,
+            "the keys thread is still waiting for a line, it stops \
+             with the recorder"
+        );
+    }
+    crate::info!(log, Job::Main, "flushing the log");
+
+    // nothing may log past this point, the log storage
+    // queue closes behind the last update, so log storage
+    // stops once it is written
+    LogPipe::shutdown();
+    hardware::bluetooth::stop();
+    if !shutdown::join_within(log_threads, SHUTDOWN_TIMEOUT).is_empty() {
+        std::eprintln!("log storage did not stop in time");
+    }
 }
 
-/// this function provides a count of seconds
-/// since the UNIX_EPOCH (1 Jan 1970)
-fn get_timestamp() -> u64 {
-    use std::time::SystemTime;
-    let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(seconds_since_1970) => seconds_since_1970,
-        Err(err) => {
-            panic!("there was a problem getting the system time: {:?}", err)
+/// this function lets the environment beat the file for
+/// the log, for one-off runs, at start and on each reload
+fn with_environment(config: &mut Config, log: &LogPipe) {
+    if std::env::var_os("CAMERA_LOG_FORMAT").is_some() {
+        config.log.format = LogFormat::from_env();
+    }
+    if std::env::var_os("CAMERA_LOG").is_some() {
+        match LogFilter::from_env() {
+            Ok(filter) => config.log.filter = filter,
+            Err(err) => crate::warn!(
+                log,
+                Job::LogSetup,
+                "ignoring CAMERA_LOG, keeping the configured filter: {}",
+                err
+            ),
         }
-    };
-    now.as_secs()
+    }
 }
 
 #[cfg(test)]
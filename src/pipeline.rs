@@ -0,0 +1,584 @@
+/// This is where the threads, tasks and queues of the
+/// recorder are put together. A Pipeline is built from
+/// sources, which only give updates (the microphone, the
+/// camera), stages, which take one type of update and give
+/// another (compute), fan outs, which share each update
+/// with every subscriber of a Broadcast (storage, motion
+/// detection, the viewfinder), and sinks, which only take
+/// updates (storage, the UI). Each is given the name of the
+/// thread it runs on, and the queue between two of them is
+/// opened from a QueueSpec. Nothing runs until spawn, which
+/// starts one thread per name, each with its own pasts
+/// executor running every task given to that thread.
+/// Every source, stage and sink runs under a Restart
+/// policy, so each is made from a function that can be
+/// called again, with the same queues, after a panic.
+/// Adding a processing step, e.g. a denoiser, is one Stage
+/// impl and one more call to stage() in recorder.
+/// Each task counts what it received, what it passed on,
+/// and what was dropped on the way, read from the queues
+/// either side of it, so we can see from the log where
+/// updates stop flowing
+use crate::log::{Job, LogPipe};
+use crate::queue::{
+    Broadcast, Keyframe, QueueCounter, QueueSpec, Receiver, Sender,
+};
+use crate::supervisor::{supervise, Restart};
+
+use core::future::Future;
+use core::pin::Pin;
+
+use std::boxed::Box;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+/// the future a task runs as, boxed so that tasks of
+/// every type can share one executor
+pub type StageFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+/// a task waiting for its thread to start, it is
+/// given the task's log once it is on its thread
+type Task = Box<dyn FnOnce(LogPipe) -> StageFuture + Send>;
+
+/// this is a processing step, which takes updates of one
+/// type from the queue before it, and puts updates of
+/// another type on the queue after it, it is cloned each
+/// time the stage is restarted
+pub trait Stage: Clone + Send + 'static {
+    type Input: Send + 'static;
+    type Output: Keyframe + Send + 'static;
+
+    /// the name the stage's task is logged under
+    const NAME: &'static str;
+
+    /// the job the stage's log messages are filed under
+    fn job(&self) -> Job;
+
+    /// this function runs the stage until its input closes
+    fn run(
+        self,
+        input: Receiver<Self::Input>,
+        output: Sender<Self::Output>,
+        log: LogPipe,
+    ) -> StageFuture;
+}
+
+/// this is the receiving end of a queue that no stage has
+/// taken yet, every Link must be given to a stage or a sink
+#[must_use = "updates on a link nothing takes are never received"]
+pub struct Link<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Link<T> {
+    /// this function wraps a queue opened outside the
+    /// pipeline, like the log queues made before logging
+    pub fn new(receiver: Receiver<T>) -> Link<T> {
+        Link { receiver }
+    }
+}
+
+/// the queues either side of one task
+struct StageCounters {
+    /// the name and thread used when the counts are logged
+    name: &'static str,
+    thread: &'static str,
+    input: Option<QueueCounter>,
+    outputs: Vec<QueueCounter>,
+}
+
+impl StageCounters {
+    /// this function returns the counts, or None once
+    /// every queue of the task is gone
+    fn counts(&self) -> Option<StageCounts> {
+        let input = self.input.as_ref().and_then(QueueCounter::counts);
+        let outputs: Vec<_> =
+            self.outputs.iter().filter_map(QueueCounter::counts).collect();
+        if input.is_none() && outputs.is_empty() {
+            return None;
+        }
+        Some(StageCounts {
+            name: self.name,
+            thread: self.thread,
+            received: input.map_or(0, |(_, dequeued, _)| dequeued),
+            forwarded: outputs.iter().map(|(enqueued, _, _)| enqueued).sum(),
+            dropped: outputs.iter().map(|(_, _, dropped)| dropped).sum(),
+        })
+    }
+}
+
+/// every task given a queue, so they can be reported
+static STAGES: Mutex<Vec<StageCounters>> = Mutex::new(Vec::new());
+
+/// this is what one task has done so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageCounts {
+    pub name: &'static str,
+    pub thread: &'static str,
+    /// updates taken from the queue before it
+    pub received: u64,
+    /// updates put on the queues after it
+    pub forwarded: u64,
+    /// updates the queues after it dropped
+    pub dropped: u64,
+}
+
+/// this function returns the counts of every task whose
+/// queues are still open, forgetting the rest
+pub fn counts() -> Vec<StageCounts> {
+    let mut stages = match STAGES.lock() {
+        Ok(stages) => stages,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut counts = Vec::with_capacity(stages.len());
+    stages.retain(|stage| match stage.counts() {
+        Some(stage_counts) => {
+            counts.push(stage_counts);
+            true
+        }
+        None => false,
+    });
+    counts
+}
+
+/// this function logs the counts of every task
+pub fn report(log: &LogPipe) {
+    for stage in counts() {
+        crate::debug!(
+            log,
+            Job::Main,
+            "{} on {}: received {}, forwarded {}, dropped {}",
+            stage.name,
+            stage.thread,
+            stage.received,
+            stage.forwarded,
+            stage.dropped
+        );
+    }
+}
+
+/// this function starts counting a task from its queues
+fn count(
+    name: &'static str,
+    thread: &'static str,
+    input: Option<QueueCounter>,
+    outputs: Vec<QueueCounter>,
+) {
+    let stage = StageCounters {
+        name,
+        thread,
+        input,
+        outputs,
+    };
+    match STAGES.lock() {
+        Ok(mut stages) => stages.push(stage),
+        Err(poisoned) => poisoned.into_inner().push(stage),
+    }
+}
+
+/// the tasks that will run on one thread
+struct Thread {
+    name: &'static str,
+    tasks: Vec<(&'static str, Job, Task)>,
+}
+
+/// this collects the tasks of every thread, until spawn
+pub struct Pipeline {
+    log: LogPipe,
+    threads: Vec<Thread>,
+}
+
+impl Pipeline {
+    pub fn new(log: &LogPipe) -> Pipeline {
+        Pipeline {
+            log: log.clone(),
+            threads: Vec::new(),
+        }
+    }
+
+    /// this function adds a task that only gives updates,
+    /// returning the link to the queue it fills
+    pub fn source<T, F, Fut>(
+        &mut self,
+        thread: &'static str,
+        name: &'static str,
+        job: Job,
+        queue: QueueSpec,
+        restart: Restart,
+        mut task: F,
+    ) -> Link<T>
+    where
+        T: Keyframe + Send + 'static,
+        F: FnMut(Sender<T>, LogPipe) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let (receiver, sender) = queue.open();
+        count(name, thread, None, std::vec![sender.counter()]);
+        let link = Link::new(receiver);
+        self.supervised(thread, name, job, restart, move |log| {
+            Some(Box::pin(task(sender.clone(), log)))
+        });
+        link
+    }
+
+    /// this function adds a stage taking updates from `input`,
+    /// returning the link to the queue it fills
+    pub fn stage<S: Stage>(
+        &mut self,
+        thread: &'static str,
+        input: Link<S::Input>,
+        queue: QueueSpec,
+        restart: Restart,
+        stage: S,
+    ) -> Link<S::Output> {
+        let (receiver, sender) = queue.open();
+        count(
+            S::NAME,
+            thread,
+            Some(input.receiver.counter()),
+            std::vec![sender.counter()],
+        );
+        let link = Link::new(receiver);
+        let input = input.receiver.lend();
+        let job = stage.job();
+        self.supervised(thread, S::NAME, job, restart, move |log| {
+            let input = input.take()?;
+            Some(stage.clone().run(input, sender.clone(), log))
+        });
+        link
+    }
+
+    /// this function adds a task sharing each update from
+    /// `input` with every subscriber of `broadcast`
+    pub fn fan_out<T>(
+        &mut self,
+        thread: &'static str,
+        input: Link<T>,
+        job: Job,
+        broadcast: Broadcast<T>,
+    ) where
+        T: Keyframe + Send + Sync + 'static,
+    {
+        let mut input = input.receiver;
+        count(
+            "fan out",
+            thread,
+            Some(input.counter()),
+            broadcast.counters(),
+        );
+        let task_job = job.clone();
+        self.add(
+            thread,
+            "fan out",
+            job,
+            Box::new(move |log| {
+                Box::pin(async move {
+                    while let Ok(update) = input.dequeue().await {
+                        if broadcast.send(update).await == 0 {
+                            crate::warn!(
+                                log,
+                                task_job.clone(),
+                                "every subscriber has gone"
+                            );
+                        }
+                    }
+                    crate::warn!(log, task_job, "fan out input queue closed");
+                })
+            }),
+        );
+    }
+
+    /// this function adds a task that only takes updates
+    pub fn sink<T, F, Fut>(
+        &mut self,
+        thread: &'static str,
+        name: &'static str,
+        job: Job,
+        input: Link<T>,
+        restart: Restart,
+        mut task: F,
+    ) where
+        T: Send + 'static,
+        F: FnMut(Receiver<T>, LogPipe) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        count(name, thread, Some(input.receiver.counter()), Vec::new());
+        let input = input.receiver.lend();
+        self.supervised(thread, name, job, restart, move |log| {
+            let input = input.take()?;
+            Some(Box::pin(task(input, log)))
+        });
+    }
+
+    /// this function adds a task that is not joined to the
+    /// rest by a Link, like one taking from a Broadcast
+    pub fn task<F, Fut>(
+        &mut self,
+        thread: &'static str,
+        name: &'static str,
+        job: Job,
+        restart: Restart,
+        mut task: F,
+    ) where
+        F: FnMut(LogPipe) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.supervised(thread, name, job, restart, move |log| {
+            Some(Box::pin(task(log)))
+        });
+    }
+
+    /// this function adds a task made by `make`, which is
+    /// called again whenever the task panics, until `restart`
+    /// gives up on it or `make` has nothing to make it from
+    fn supervised<F>(
+        &mut self,
+        thread: &'static str,
+        name: &'static str,
+        job: Job,
+        restart: Restart,
+        make: F,
+    ) where
+        F: FnMut(LogPipe) -> Option<StageFuture> + Send + 'static,
+    {
+        let task_job = job.clone();
+        self.add(
+            thread,
+            name,
+            job,
+            Box::new(move |log| {
+                Box::pin(supervise(name, task_job, restart, log, make))
+            }),
+        );
+    }
+
+    fn add(
+        &mut self,
+        thread: &'static str,
+        name: &'static str,
+        job: Job,
+        task: Task,
+    ) {
+        match self.threads.iter_mut().find(|known| known.name == thread) {
+            Some(known) => known.tasks.push((name, job, task)),
+            None => self.threads.push(Thread {
+                name: thread,
+                tasks: std::vec![(name, job, task)],
+            }),
+        }
+    }
+
+    /// this function starts a thread for each thread name,
+    /// running every task that was given to it
+    pub fn spawn(mut self) -> Vec<JoinHandle<()>> {
+        let mut handles = Vec::new();
+        for thread in self.threads.drain(..) {
+            crate::info!(
+                self.log,
+                Job::Main,
+                "creating {} thread",
+                thread.name
+            );
+            let mut thread_log = self.log.new_thread_log();
+            // a simulation's threads all read its clock
+            #[cfg(test)]
+            let clock = crate::time::simulated::installed();
+            let spawned = std::thread::Builder::new()
+                .name(thread.name.into())
+                .spawn(move || {
+                    #[cfg(test)]
+                    crate::time::simulated::install(clock);
+                    crate::info!(
+                        thread_log,
+                        Job::Main,
+                        "{} thread started",
+                        thread.name
+                    );
+                    let tasks = pasts::Executor::default();
+                    let mut started = Vec::new();
+                    for (name, job, task) in thread.tasks {
+                        let task_log = thread_log.new_task_log();
+                        crate::info!(task_log, job, "{} task started", name);
+                        started.push(task(task_log));
+                    }
+                    tasks.clone().block_on(async move {
+                        for task in started {
+                            tasks.spawn_boxed(task);
+                        }
+                    });
+                });
+            match spawned {
+                Ok(handle) => handles.push(handle),
+                Err(err) => crate::error!(
+                    self.log,
+                    Job::Main,
+                    "could not create {} thread: {:?}",
+                    thread.name,
+                    err
+                ),
+            }
+        }
+        handles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Policy;
+    use std::sync::Arc;
+
+    const NUMBERS: QueueSpec = QueueSpec {
+        name: "test numbers",
+        size: 16,
+        policy: Policy::Block,
+    };
+
+    #[derive(Debug)]
+    struct Number(u32);
+
+    impl Keyframe for Number {
+        fn is_keyframe(&self) -> bool {
+            true
+        }
+    }
+
+    #[derive(Clone)]
+    struct Double;
+
+    impl Stage for Double {
+        type Input = Number;
+        type Output = Number;
+        const NAME: &'static str = "double";
+
+        fn job(&self) -> Job {
+            Job::Debug
+        }
+
+        fn run(
+            self,
+            mut input: Receiver<Number>,
+            output: Sender<Number>,
+            _log: LogPipe,
+        ) -> StageFuture {
+            Box::pin(async move {
+                while let Ok(Number(number)) = input.dequeue().await {
+                    let _ = output.enqueue_waiting(Number(number * 2)).await;
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn counts_what_each_task_passes_on() {
+        let (mut input, sender) = NUMBERS.open();
+        let (receiver, output) = QueueSpec {
+            name: "test numbers",
+            size: 2,
+            policy: Policy::DropNewest,
+        }
+        .open();
+        count(
+            "test counted",
+            "test thread",
+            Some(input.counter()),
+            std::vec![output.counter()],
+        );
+        let counted = || {
+            counts()
+                .into_iter()
+                .find(|stage| stage.name == "test counted")
+        };
+        for number in 0..3 {
+            sender.enqueue(Number(number)).unwrap();
+            input.try_dequeue().unwrap();
+        }
+        for number in 0..4 {
+            output.enqueue(Number(number)).unwrap();
+        }
+        let stage = counted().unwrap();
+        assert_eq!(stage.received, 3);
+        assert_eq!(stage.forwarded, 2);
+        assert_eq!(stage.dropped, 2);
+        drop((input, sender, receiver, output));
+        assert_eq!(counted(), None);
+    }
+
+    #[test]
+    fn stages_run_on_their_threads_in_order() {
+        let _pipe = crate::log::TEST_PIPE
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let (_out, out_sender) = crate::queue::LOG_OUT.open();
+        let (_storage, storage_sender) = crate::queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+
+        let (results, collected) = std::sync::mpsc::channel();
+        let mut pipeline = Pipeline::new(&log);
+        let numbers = pipeline.source(
+            "test source",
+            "count",
+            Job::Debug,
+            NUMBERS,
+            Restart::NEVER,
+            |sender: Sender<Number>, _log| async move {
+                for number in 0..5 {
+                    let _ = sender.enqueue_waiting(Number(number)).await;
+                }
+            },
+        );
+        let doubled = pipeline.stage(
+            "test stage",
+            numbers,
+            NUMBERS,
+            Restart::NEVER,
+            Double,
+        );
+        let mut broadcast = Broadcast::new();
+        let first = Link::new(broadcast.subscribe(NUMBERS));
+        let second = Link::new(broadcast.subscribe(NUMBERS));
+        pipeline.fan_out("test stage", doubled, Job::Debug, broadcast);
+        let copy_results = results.clone();
+        pipeline.sink(
+            "test sink",
+            "collect",
+            Job::Debug,
+            first,
+            Restart::NEVER,
+            move |mut receiver: Receiver<Arc<Number>>, _log| {
+                let results = results.clone();
+                async move {
+                    while let Ok(number) = receiver.dequeue().await {
+                        let _ = results.send(number.0);
+                    }
+                }
+            },
+        );
+        pipeline.sink(
+            "test sink",
+            "collect copies",
+            Job::Debug,
+            second,
+            Restart::NEVER,
+            move |mut receiver: Receiver<Arc<Number>>, _log| {
+                let copy_results = copy_results.clone();
+                async move {
+                    while let Ok(number) = receiver.dequeue().await {
+                        let _ = copy_results.send(100 + number.0);
+                    }
+                }
+            },
+        );
+
+        let threads = pipeline.spawn();
+        assert_eq!(threads.len(), 3);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let mut received: Vec<u32> = collected.try_iter().collect();
+        received.sort();
+        assert_eq!(received, std::vec![0, 2, 4, 6, 8, 100, 102, 104, 106, 108]);
+        LogPipe::shutdown();
+    }
+}
@@ -10,12 +10,51 @@
 /// keep away from race conditions, without locking the
 /// queue when its in use. This can be thought of as
 /// a pipeline with a sender and a receiver.
-pub use nolock::queues::mpsc::jiffy::async_queue as new_unbounded_queue;
-pub use nolock::queues::mpsc::jiffy::AsyncReceiver as Receiver;
-pub use nolock::queues::mpsc::jiffy::AsyncSender as Sender;
+/// Each queue holds at most its _QUEUE_SIZE updates, and
+/// has a Policy for what to do when it is full, see bounded,
+/// and is sampled for depth, rates and drops by telemetry.
+/// Audio and video payloads are Buffers from a pool, which
+/// go back to the pool once storage drops the update, and a
+/// video frame is shared with each consumer by broadcast
+mod bounded;
+pub mod broadcast;
+pub mod pool;
+pub mod telemetry;
+pub use broadcast::Broadcast;
+pub use pool::{Buffer, BufferPool};
+pub use bounded::{bounded, Keyframe, Policy, QueueCounter, Receiver, Sender};
+
+use crate::time::presentation::Pts;
+use crate::time::Timestamp;
+use std::sync::Arc;
+
+/// this describes one queue between two stages of the
+/// pipeline, the stages themselves only say what type
+/// of update they take and give, and the pipeline opens
+/// the queue between them from one of these
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueSpec {
+    /// the name the queue is reported under
+    pub name: &'static str,
+    /// how many updates the queue may hold
+    pub size: usize,
+    /// what the queue does once it is full
+    pub policy: Policy,
+}
+
+impl QueueSpec {
+    /// this function opens a new queue to this spec
+    pub fn open<T: Keyframe>(&self) -> (Receiver<T>, Sender<T>) {
+        bounded(self.name, self.size, self.policy)
+    }
+
+    /// this function returns the same spec holding `size`
+    /// updates, for queues sized by the configuration
+    pub const fn with_size(self, size: usize) -> QueueSpec {
+        QueueSpec { size, ..self }
+    }
+}
 
-use std::string::String;
-use std::vec::Vec;
 /// this is where we store our audio
 /// frames and information like timestamp
 /// in order to pass the audio through
@@ -23,152 +62,153 @@ use std::vec::Vec;
 #[derive(Debug)]
 
 pub struct AudioUpdate {
-    pub data: Vec<f32>,
-    pub timestamp: u64,
-    pub name: String,
-}
-/// one minute worth of frames
-const AUDIO_QUEUE_SIZE: usize = 60 * 60;
-
-/// this is how we lock a pipeline (queue)
-/// to accept only the AudioUpdate type
-/// we use this one for Audio Input
-pub struct AudioIn {}
-impl AudioIn {
-    pub fn new() -> (Receiver<AudioUpdate>, Sender<AudioUpdate>) {
-        new_unbounded_queue()
-    }
-}
-/// this is how we lock a pipeline(channel)
-/// to accept only the AudioUpdate type
-/// we use this one for sending frames
-/// to the audio processing thread
-pub struct ToAudioCompute {}
-impl ToAudioCompute {
-    pub fn new() -> (Receiver<AudioUpdate>, Sender<AudioUpdate>) {
-        new_unbounded_queue()
-    }
+    /// interleaved samples, channels at a time
+    pub data: Buffer<f32>,
+    /// when the callback delivered these samples
+    pub timestamp: Timestamp,
+    /// when the first sample should be played,
+    /// counted from the samples before it
+    pub pts: Pts,
+    /// samples per second, per channel
+    pub sample_rate: u32,
+    /// how many channels are interleaved in data
+    pub channels: u16,
+    /// the name of the device, shared by every update
+    pub name: Arc<str>,
 }
 
-/// this is how we lock a pipeline (channel)
-/// to accept only the AudioUpdate type
-/// we use this one for receiving frames
-/// from the audio processing thread
-pub struct FromAudioCompute {}
-impl FromAudioCompute {
-    pub fn new() -> (Receiver<AudioUpdate>, Sender<AudioUpdate>) {
-        new_unbounded_queue()
+/// every chunk of audio plays on its own
+impl Keyframe for AudioUpdate {
+    fn is_keyframe(&self) -> bool {
+        true
     }
 }
+/// one minute worth of frames
+const AUDIO_QUEUE_SIZE: usize = 60 * 60;
 
-/// this is how we lock a pipeline(queue)
-/// to accept only the AudioUpdate type
-/// we use this one for Audio Storage
-pub struct AudioStorage {}
-impl AudioStorage {
-    pub fn new() -> (Receiver<AudioUpdate>, Sender<AudioUpdate>) {
-        new_unbounded_queue()
-    }
-}
+/// from the microphone to audio compute, the callback
+/// can never wait, so the oldest audio gives way
+pub const AUDIO_IN: QueueSpec = QueueSpec {
+    name: "audio in",
+    size: AUDIO_QUEUE_SIZE,
+    policy: Policy::DropOldest,
+};
+
+/// from audio compute to audio storage
+pub const AUDIO_STORAGE: QueueSpec = QueueSpec {
+    name: "audio storage",
+    size: AUDIO_QUEUE_SIZE,
+    policy: Policy::Block,
+};
 
 /// this is where we store our log
 /// entries and information like timestamp
 /// in order to pass the log entry though
 /// the pipeline (queue)
 pub use crate::log::LogUpdate;
-///one minute worth of frames
-const LOG_QUEUE_SIZE: usize = 60 * 60;
-/// this is how we lock a pipeline (queue)
-/// to accept only the LogUpdate type
-/// we use this one to move log entries
-/// to storage
-pub struct LogStorage {}
-impl LogStorage {
-    pub fn new() -> (Receiver<LogUpdate>, Sender<LogUpdate>) {
-        new_unbounded_queue()
+impl Keyframe for LogUpdate {
+    fn is_keyframe(&self) -> bool {
+        true
     }
 }
+///one minute worth of frames
+const LOG_QUEUE_SIZE: usize = 60 * 60;
 
-/// this is how we lock a pipeline (queue)
-/// to accept only the LogUpdate type
-/// we use this one to move log entries
-/// to the bluetooth radio for sending
-pub struct LogOut {}
-impl LogOut {
-    pub fn new() -> (Receiver<LogUpdate>, Sender<LogUpdate>) {
-        new_unbounded_queue()
-    }
-}
+/// from the log listener to log storage, the listener
+/// never waits on the disk, so new entries give way
+pub const LOG_STORAGE: QueueSpec = QueueSpec {
+    name: "log storage",
+    size: LOG_QUEUE_SIZE,
+    policy: Policy::DropNewest,
+};
+
+/// from the log listener to the bluetooth radio,
+/// which only ever wants the latest entries
+pub const LOG_OUT: QueueSpec = QueueSpec {
+    name: "log out",
+    size: LOG_QUEUE_SIZE,
+    policy: Policy::DropOldest,
+};
 
 /// this is where we store our statuses
 /// for the UI to display to the user
 use crate::ui::ViewUpdate;
-/// one minute worth of frames
-const VIEW_QUEUE_SIZE: usize = 60 * 60;
-
-/// this is how we lock a pipeline (queue)
-/// to accept only the View Update type
-/// we use this one to send information
-/// to the UI to show the user on screen
-pub struct ViewOut {}
-impl ViewOut {
-    pub fn new() -> (Receiver<ViewUpdate>, Sender<ViewUpdate>) {
-        new_unbounded_queue()
+impl Keyframe for ViewUpdate {
+    fn is_keyframe(&self) -> bool {
+        true
     }
 }
+/// a few updates, enough to ride out a slow reader
+/// without holding on to frames nobody will look at
+const LATEST_QUEUE_SIZE: usize = 4;
+
+/// from video compute and telemetry to the UI,
+/// which only ever shows the latest
+pub const VIEW_OUT: QueueSpec = QueueSpec {
+    name: "view out",
+    size: LATEST_QUEUE_SIZE,
+    policy: Policy::DropOldest,
+};
 
 /// this is where we store our video frames
 /// and information like timestamp
 /// through the pipeline (queue)
 #[derive(Debug)]
-pub struct VideoUpdate {}
-/// one minute worth of frames
-const VIDEO_QUEUE_SIZE: usize = 3600;
-
-/// this is how we lock a pipeline (queue)
-/// to accept only the Video Update type
-/// this one moves video frames from the
-/// camera to the main function
-
-pub struct VideoIn {}
-impl VideoIn {
-    pub fn new() -> (Receiver<VideoUpdate>, Sender<VideoUpdate>) {
-        new_unbounded_queue()
-    }
-}
-
-/// this is how we lock a pipeline(channel)
-/// to accept only the VideoUpdate type
-/// we use this one for sending frames
-/// to the video processing thread
-pub struct ToVideoCompute {}
-impl ToVideoCompute {
-    pub fn new() -> (Receiver<VideoUpdate>, Sender<VideoUpdate>) {
-        new_unbounded_queue()
-    }
+pub struct VideoUpdate {
+    /// when the camera delivered this frame
+    pub timestamp: Timestamp,
+    /// when this frame should be shown,
+    /// on the monotonic clock
+    pub pts: Pts,
+    /// the number of this frame since the camera started
+    pub sequence: u64,
+    /// the encoded frame
+    pub data: Buffer<u8>,
+    /// whether this frame can be decoded without
+    /// the frames before it
+    pub keyframe: bool,
 }
 
-/// this is how we lock a pipeline (channel)
-/// to accept only the VideoUpdate type
-/// we use this one for receiving frames
-/// from the video processing thread
-pub struct FromVideoCompute {}
-impl FromVideoCompute {
-    pub fn new() -> (Receiver<VideoUpdate>, Sender<VideoUpdate>) {
-        new_unbounded_queue()
+impl Keyframe for VideoUpdate {
+    fn is_keyframe(&self) -> bool {
+        self.keyframe
     }
 }
+/// one minute worth of frames
+const VIDEO_QUEUE_SIZE: usize = 3600;
 
-/// this is how we lock a pipeline (queue)
-/// to accept only the Video Update type
-/// this one moves video frames from the
-/// main function to storage
-pub struct VideoStorage {}
-impl VideoStorage {
-    pub fn new() -> (Receiver<VideoUpdate>, Sender<VideoUpdate>) {
-        new_unbounded_queue()
-    }
-}
+/// from the camera to video compute, when compute falls
+/// behind only the keyframes are kept
+pub const VIDEO_IN: QueueSpec = QueueSpec {
+    name: "video in",
+    size: VIDEO_QUEUE_SIZE,
+    policy: Policy::KeyframesOnly,
+};
+
+/// from video compute to be shared with storage,
+/// motion detection and the viewfinder
+pub const VIDEO_COMPUTED: QueueSpec = QueueSpec {
+    name: "video computed",
+    size: VIDEO_QUEUE_SIZE,
+    policy: Policy::Block,
+};
+
+/// the frames shared with video storage, which never
+/// loses one, so a full queue holds up the broadcast
+pub const VIDEO_STORAGE: QueueSpec = QueueSpec {
+    name: "video storage",
+    size: VIDEO_QUEUE_SIZE,
+    policy: Policy::Block,
+};
+
+/// the frames shared with motion detection, which
+/// only cares about the most recent ones, so it is
+/// not sized from [compute] video_queue_size
+pub const MOTION: QueueSpec = QueueSpec {
+    name: "motion",
+    size: LATEST_QUEUE_SIZE,
+    policy: Policy::DropOldest,
+};
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,782 @@
+/// This is where the queues get their limits. Each queue
+/// is still a lockfree jiffy queue underneath, with a
+/// count of the updates in it kept beside it, so that a
+/// slow disk can no longer grow memory without end.
+/// What happens when a queue is full is decided by its
+/// Policy: the sender can wait for room, the newest
+/// update can be dropped, the oldest update can be
+/// dropped, or for video, only frames that are not
+/// keyframes are dropped. Only the receiver can take
+/// updates off a jiffy queue, so dropping the oldest
+/// marks it to be skipped, and the receiver throws it
+/// away when it reaches it. A video queue keeps a note of
+/// each frame it holds, so it can mark the oldest frame
+/// that is not a keyframe wherever it is in the queue.
+/// A receiver that has stopped could leave the marked
+/// updates in memory forever, so once a queue holds twice
+/// its capacity we drop the newest instead. Every drop is
+/// counted, along with what goes in, what comes out, and
+/// how long updates waited, so that telemetry can sample
+/// every queue.
+/// A jiffy sender does not wake the receiver when it is
+/// dropped, so the last sender to go wakes it here,
+/// which lets a stage finish once its input has drained,
+/// and a jiffy receiver looks for an update before it
+/// stores its waker, so dequeue looks once more after.
+/// A receiver can be lent to a task that may panic, and
+/// goes back to its Lender when the task drops it, so the
+/// supervisor can hand the same queue to the restarted task
+use nolock::queues::mpsc::jiffy::{async_queue, AsyncReceiver, AsyncSender};
+pub use nolock::queues::{DequeueError, EnqueueError};
+
+use core::future::{poll_fn, Future};
+use core::mem::ManuallyDrop;
+use core::ops::Deref;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::vec::Vec;
+
+/// this decides what a full queue does with a new update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// the sender waits until the receiver makes room,
+    /// nothing is ever dropped
+    Block,
+    /// the oldest update is dropped to make room,
+    /// so the receiver always gets the latest
+    DropOldest,
+    /// the new update is dropped, so the receiver
+    /// gets an unbroken run of the earliest
+    DropNewest,
+    /// keyframes make room by dropping the oldest frame
+    /// that is not a keyframe, or the oldest keyframe when
+    /// nothing else is queued, every other frame is
+    /// dropped when full
+    KeyframesOnly,
+}
+
+/// this tells a KeyframesOnly queue which updates
+/// it must keep, anything that can be decoded on
+/// its own counts as a keyframe
+pub trait Keyframe {
+    fn is_keyframe(&self) -> bool;
+}
+
+/// this is what the sender and receiver of one queue share
+pub(super) struct Shared {
+    /// the name used when the queue is reported
+    pub(super) name: &'static str,
+    /// how many queues were made before this one
+    pub(super) number: usize,
+    /// how many updates the queue may hold
+    pub(super) capacity: usize,
+    pub(super) policy: Policy,
+    /// updates in the queue that will be received
+    pub(super) len: AtomicUsize,
+    /// updates at the front of the queue that were
+    /// dropped and will be skipped by the receiver
+    skip: AtomicUsize,
+    /// the frames in a KeyframesOnly queue
+    frames: Mutex<Frames>,
+    /// the number given to the next update
+    sequence: AtomicU64,
+    /// updates dropped since the queue was made
+    pub(super) dropped: AtomicU64,
+    /// updates put on the queue since it was made
+    pub(super) enqueued: AtomicU64,
+    /// updates taken off the queue since it was made
+    pub(super) dequeued: AtomicU64,
+    /// the total time the dequeued updates spent
+    /// in the queue, in nanoseconds
+    pub(super) waited_nanos: AtomicU64,
+    /// the longest any update spent in the queue since
+    /// telemetry last sampled it, in nanoseconds
+    pub(super) max_wait_nanos: AtomicU64,
+    /// senders waiting for room in a Block queue
+    waiting: Mutex<Vec<Waker>>,
+    /// the receiver waiting for an update, or the close
+    receiving: Mutex<Option<Waker>>,
+}
+
+impl Shared {
+    /// this function takes a slot for a new update,
+    /// returning false when the queue is full
+    fn reserve(&self) -> bool {
+        self.len
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                if len < self.capacity {
+                    Some(len + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// this function marks the oldest update to be skipped,
+    /// returning false once too many are already marked
+    fn skip_oldest(&self) -> bool {
+        self.skip
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |skip| {
+                if skip < self.capacity {
+                    Some(skip + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// this function takes one skipped update off the count,
+    /// returning true when the update received should be dropped
+    fn take_skipped(&self) -> bool {
+        self.skip
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |skip| {
+                skip.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// this function marks the oldest frame that is not a
+    /// keyframe to be skipped, or the oldest keyframe when
+    /// there is none, returning what it marked, or None
+    /// once too many are already marked
+    fn skip_oldest_frame(&self) -> Option<(u64, bool)> {
+        let mut frames = lock(&self.frames);
+        if frames.skipped.len() >= self.capacity || frames.queued.is_empty() {
+            return None;
+        }
+        let index = frames
+            .queued
+            .iter()
+            .position(|(_, keyframe)| !keyframe)
+            .unwrap_or(0);
+        let frame = frames.queued.remove(index)?;
+        frames.skipped.insert(frame.0);
+        Some(frame)
+    }
+
+    /// this function puts a frame marked by
+    /// skip_oldest_frame back, as the frame that was to
+    /// take its place could not be sent
+    fn unskip_frame(&self, frame: (u64, bool)) {
+        let mut frames = lock(&self.frames);
+        frames.skipped.remove(&frame.0);
+        let index = frames
+            .queued
+            .iter()
+            .position(|(sequence, _)| *sequence > frame.0)
+            .unwrap_or(frames.queued.len());
+        frames.queued.insert(index, frame);
+    }
+
+    /// this function takes a received frame off the notes,
+    /// returning true when it was skipped and is dropped
+    fn take_frame(&self, sequence: u64) -> bool {
+        let mut frames = lock(&self.frames);
+        if frames.skipped.remove(&sequence) {
+            return true;
+        }
+        if let Some(index) = frames
+            .queued
+            .iter()
+            .position(|(queued, _)| *queued == sequence)
+        {
+            frames.queued.remove(index);
+        }
+        false
+    }
+
+    fn wake_waiting(&self) {
+        let waiting = match self.waiting.lock() {
+            Ok(mut waiting) => core::mem::take(&mut *waiting),
+            Err(poisoned) => core::mem::take(&mut *poisoned.into_inner()),
+        };
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+
+    fn wake_receiving(&self) {
+        let receiving = match self.receiving.lock() {
+            Ok(mut receiving) => receiving.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(waker) = receiving {
+            waker.wake();
+        }
+    }
+}
+
+/// what a KeyframesOnly queue knows of the frames in it
+#[derive(Default)]
+struct Frames {
+    /// the number of each frame to be received, oldest
+    /// first, and whether it is a keyframe
+    queued: VecDeque<(u64, bool)>,
+    /// the numbers of frames the receiver will drop
+    skipped: BTreeSet<u64>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// every queue still open, so they can be sampled, a
+/// queue is forgotten once its last sender and receiver
+/// are dropped
+static QUEUES: Mutex<Vec<Weak<Shared>>> = Mutex::new(Vec::new());
+
+/// the number of queues made so far
+static MADE: AtomicUsize = AtomicUsize::new(0);
+
+/// this function returns every queue still open, in the
+/// order they were made
+pub(super) fn registered() -> Vec<Arc<Shared>> {
+    let mut queues = lock(&QUEUES);
+    queues.retain(|queue| queue.strong_count() > 0);
+    queues.iter().filter_map(Weak::upgrade).collect()
+}
+
+/// this function returns how many queues have been made
+#[cfg(test)]
+pub(super) fn made() -> usize {
+    MADE.load(Ordering::SeqCst)
+}
+
+/// each update travels with the monotonic time
+/// it was put on the queue at, and its number
+type Stamped<T> = (T, u64, u64);
+
+/// the jiffy sender every clone of a Sender shares, which
+/// closes the queue and wakes the receiver when the last
+/// clone is dropped
+struct Closing<T> {
+    queue: ManuallyDrop<AsyncSender<Stamped<T>>>,
+    shared: Arc<Shared>,
+}
+
+impl<T> Deref for Closing<T> {
+    type Target = AsyncSender<Stamped<T>>;
+
+    fn deref(&self) -> &AsyncSender<Stamped<T>> {
+        &self.queue
+    }
+}
+
+impl<T> Drop for Closing<T> {
+    fn drop(&mut self) {
+        // the queue must be closed before the receiver
+        // wakes, or it would find it empty and wait again
+        unsafe { ManuallyDrop::drop(&mut self.queue) };
+        self.shared.wake_receiving();
+    }
+}
+
+/// this is the sending end of a bounded queue, it can be
+/// cloned to give more than one producer the same queue,
+/// which closes once every clone is gone
+pub struct Sender<T> {
+    inner: Arc<Closing<T>>,
+    shared: Arc<Shared>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        Sender {
+            inner: self.inner.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// this reads the counters of a queue without keeping it
+/// open, so the stages on either side can be reported
+#[derive(Clone)]
+pub struct QueueCounter {
+    shared: Weak<Shared>,
+}
+
+impl QueueCounter {
+    /// this function returns how many updates were put on
+    /// the queue, taken off it and dropped from it, or None
+    /// once the queue is gone
+    pub fn counts(&self) -> Option<(u64, u64, u64)> {
+        let shared = self.shared.upgrade()?;
+        Some((
+            shared.enqueued.load(Ordering::Relaxed),
+            shared.dequeued.load(Ordering::Relaxed),
+            shared.dropped.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+/// where a lent receiver goes back to when it is dropped
+type Home<T> = Arc<Mutex<Option<AsyncReceiver<Stamped<T>>>>>;
+
+/// this is the receiving end of a bounded queue
+pub struct Receiver<T> {
+    inner: ManuallyDrop<AsyncReceiver<Stamped<T>>>,
+    shared: Arc<Shared>,
+    /// set when the receiver was lent, and goes back
+    home: Option<Home<T>>,
+}
+
+/// this holds a lent receiver between the tasks it is
+/// lent to, the queue only closes once this is dropped
+pub struct Lender<T> {
+    home: Home<T>,
+    shared: Arc<Shared>,
+}
+
+impl<T> Lender<T> {
+    /// this function takes the receiver back out, it
+    /// returns None while a task still holds it
+    pub fn take(&self) -> Option<Receiver<T>> {
+        let inner = match self.home.lock() {
+            Ok(mut home) => home.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }?;
+        Some(Receiver {
+            inner: ManuallyDrop::new(inner),
+            shared: self.shared.clone(),
+            home: Some(self.home.clone()),
+        })
+    }
+}
+
+/// this function makes a queue holding at most `capacity`
+/// updates, that follows `policy` once it is full
+pub fn bounded<T: Keyframe>(
+    name: &'static str,
+    capacity: usize,
+    policy: Policy,
+) -> (Receiver<T>, Sender<T>) {
+    let (inner_receiver, inner_sender) = async_queue();
+    let shared = Arc::new(Shared {
+        name,
+        number: MADE.fetch_add(1, Ordering::SeqCst),
+        capacity: capacity.max(1),
+        policy,
+        len: AtomicUsize::new(0),
+        skip: AtomicUsize::new(0),
+        frames: Mutex::new(Frames::default()),
+        sequence: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+        enqueued: AtomicU64::new(0),
+        dequeued: AtomicU64::new(0),
+        waited_nanos: AtomicU64::new(0),
+        max_wait_nanos: AtomicU64::new(0),
+        waiting: Mutex::new(Vec::new()),
+        receiving: Mutex::new(None),
+    });
+    lock(&QUEUES).push(Arc::downgrade(&shared));
+    (
+        Receiver {
+            inner: ManuallyDrop::new(inner_receiver),
+            shared: shared.clone(),
+            home: None,
+        },
+        Sender {
+            inner: Arc::new(Closing {
+                queue: ManuallyDrop::new(inner_sender),
+                shared: shared.clone(),
+            }),
+            shared,
+        },
+    )
+}
+
+impl<T: Keyframe> Sender<T> {
+    /// this function puts an update on the queue without
+    /// waiting, a full Block queue hands the update back
+    /// with EnqueueError::Full, the other policies drop an
+    /// update and return Ok
+    pub fn enqueue(&self, data: T) -> Result<(), (T, EnqueueError)> {
+        if self.inner.is_closed() {
+            return Err((data, EnqueueError::Closed));
+        }
+        if self.shared.reserve() {
+            return match self.stamp_and_enqueue(data) {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    self.shared.len.fetch_sub(1, Ordering::SeqCst);
+                    Err(err)
+                }
+            };
+        }
+        let make_room = match self.shared.policy {
+            Policy::Block => return Err((data, EnqueueError::Full)),
+            Policy::DropNewest => false,
+            Policy::DropOldest => true,
+            Policy::KeyframesOnly => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                if !data.is_keyframe() {
+                    return Ok(());
+                }
+                return match self.shared.skip_oldest_frame() {
+                    Some(frame) => {
+                        self.stamp_and_enqueue(data).map_err(|err| {
+                            self.shared.unskip_frame(frame);
+                            err
+                        })
+                    }
+                    None => Ok(()),
+                };
+            }
+        };
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        if make_room && self.shared.skip_oldest() {
+            // the new update takes the place of the skipped
+            // one, so the count of updates to receive holds
+            if let Err(err) = self.stamp_and_enqueue(data) {
+                self.shared.take_skipped();
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn stamp_and_enqueue(&self, data: T) -> Result<(), (T, EnqueueError)> {
+        let sequence = self.shared.sequence.fetch_add(1, Ordering::SeqCst);
+        let keyframes = self.shared.policy == Policy::KeyframesOnly;
+        if keyframes {
+            // noted before it is sent, so the receiver
+            // always finds the note
+            let frame = (sequence, data.is_keyframe());
+            lock(&self.shared.frames).queued.push_back(frame);
+        }
+        let stamped = (data, crate::time::now().monotonic_nanos, sequence);
+        match self.inner.enqueue(stamped) {
+            Ok(()) => {
+                self.shared.enqueued.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(((data, _, _), err)) => {
+                if keyframes {
+                    self.shared.take_frame(sequence);
+                }
+                Err((data, err))
+            }
+        }
+    }
+
+    /// this function puts an update on the queue, waiting
+    /// for the receiver to make room when a Block queue is
+    /// full, for every other policy it is the same as enqueue
+    pub async fn enqueue_waiting(
+        &self,
+        mut data: T,
+    ) -> Result<(), (T, EnqueueError)> {
+        loop {
+            match self.enqueue(data) {
+                Err((returned, EnqueueError::Full)) => {
+                    data = returned;
+                    Room { sender: self }.await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    pub fn counter(&self) -> QueueCounter {
+        QueueCounter {
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+}
+
+/// this resolves once a full queue has room, or is closed
+struct Room<'queue, T> {
+    sender: &'queue Sender<T>,
+}
+
+impl<'queue, T> Future for Room<'queue, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let shared = &self.sender.shared;
+        let has_room = |sender: &Sender<T>| {
+            sender.inner.is_closed()
+                || shared.len.load(Ordering::SeqCst) < shared.capacity
+        };
+        if has_room(self.sender) {
+            return Poll::Ready(());
+        }
+        match shared.waiting.lock() {
+            Ok(mut waiting) => waiting.push(cx.waker().clone()),
+            Err(poisoned) => poisoned.into_inner().push(cx.waker().clone()),
+        }
+        // the receiver may have made room before we were
+        // waiting, in which case nobody would wake us
+        if has_room(self.sender) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// this function waits for the next update, skipping any
+    /// that were dropped, until every sender has gone
+    pub async fn dequeue(&mut self) -> Result<T, DequeueError> {
+        loop {
+            let data = poll_fn(|cx| {
+                // stored before looking, so a close that lands
+                // after the look still wakes us
+                match self.shared.receiving.lock() {
+                    Ok(mut receiving) => *receiving = Some(cx.waker().clone()),
+                    Err(poisoned) => {
+                        *poisoned.into_inner() = Some(cx.waker().clone())
+                    }
+                }
+                match Pin::new(&mut self.inner.dequeue()).poll(cx) {
+                    // jiffy looks before it stores the waker, so an
+                    // update sent in between wakes nobody, looking
+                    // again now that it is stored catches it
+                    Poll::Pending => match self.inner.try_dequeue() {
+                        Err(DequeueError::Empty) => Poll::Pending,
+                        result => Poll::Ready(result),
+                    },
+                    ready => ready,
+                }
+            })
+            .await?;
+            if let Some(data) = self.received(data) {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// this function takes the next update if there is one
+    #[cfg(test)]
+    pub fn try_dequeue(&mut self) -> Result<T, DequeueError> {
+        loop {
+            let data = self.inner.try_dequeue()?;
+            if let Some(data) = self.received(data) {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// the number of updates waiting to be received
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.shared.len.load(Ordering::SeqCst)
+    }
+
+    pub fn counter(&self) -> QueueCounter {
+        QueueCounter {
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+
+    /// this function makes the receiver go back to the
+    /// returned Lender whenever it is dropped, even by
+    /// a task that panicked while holding it
+    pub fn lend(mut self) -> Lender<T> {
+        let home: Home<T> = Arc::new(Mutex::new(None));
+        self.home = Some(home.clone());
+        let shared = self.shared.clone();
+        drop(self);
+        Lender { home, shared }
+    }
+
+    fn received(&self, (data, enqueued_at, sequence): Stamped<T>) -> Option<T> {
+        let skipped = match self.shared.policy {
+            Policy::KeyframesOnly => self.shared.take_frame(sequence),
+            _ => self.shared.take_skipped(),
+        };
+        if skipped {
+            return None;
+        }
+        self.shared.len.fetch_sub(1, Ordering::SeqCst);
+        let waited = crate::time::now()
+            .monotonic_nanos
+            .saturating_sub(enqueued_at);
+        self.shared.dequeued.fetch_add(1, Ordering::Relaxed);
+        self.shared
+            .waited_nanos
+            .fetch_add(waited, Ordering::Relaxed);
+        self.shared
+            .max_wait_nanos
+            .fetch_max(waited, Ordering::Relaxed);
+        self.shared.wake_waiting();
+        Some(data)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // a lent receiver goes home, and the queue stays open
+        // for the next task it is lent to
+        let inner = unsafe { ManuallyDrop::take(&mut self.inner) };
+        if let Some(home) = self.home.take() {
+            match home.lock() {
+                Ok(mut home) => *home = Some(inner),
+                Err(poisoned) => *poisoned.into_inner() = Some(inner),
+            }
+            return;
+        }
+        drop(inner);
+        // waiting senders will now find the queue closed
+        self.shared.wake_waiting();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Update(u32, bool);
+
+    impl Keyframe for Update {
+        fn is_keyframe(&self) -> bool {
+            self.1
+        }
+    }
+
+    fn drain(receiver: &mut Receiver<Update>) -> Vec<u32> {
+        let mut received = Vec::new();
+        while let Ok(update) = receiver.try_dequeue() {
+            received.push(update.0);
+        }
+        received
+    }
+
+    #[test]
+    fn forgets_a_queue_once_both_ends_are_dropped() {
+        let (receiver, sender) =
+            bounded::<Update>("test", 2, Policy::DropOldest);
+        let number = sender.shared.number;
+        let open = || registered().iter().any(|queue| queue.number == number);
+        assert!(open());
+        drop(sender);
+        assert!(open());
+        drop(receiver);
+        assert!(!open());
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_earliest() {
+        let (mut receiver, sender) = bounded("test", 2, Policy::DropNewest);
+        for number in 0..5 {
+            sender.enqueue(Update(number, true)).unwrap();
+        }
+        assert_eq!(drain(&mut receiver), std::vec![0, 1]);
+        assert_eq!(sender.shared.dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest() {
+        let (mut receiver, sender) = bounded("test", 3, Policy::DropOldest);
+        for number in 0..5 {
+            sender.enqueue(Update(number, true)).unwrap();
+        }
+        assert_eq!(receiver.len(), 3);
+        assert_eq!(drain(&mut receiver), std::vec![2, 3, 4]);
+        assert_eq!(receiver.len(), 0);
+    }
+
+    #[test]
+    fn drop_oldest_stays_bounded_without_a_receiver() {
+        let (receiver, sender) = bounded("test", 2, Policy::DropOldest);
+        for number in 0..100 {
+            sender.enqueue(Update(number, true)).unwrap();
+        }
+        assert_eq!(receiver.shared.skip.load(Ordering::SeqCst), 2);
+        assert_eq!(receiver.len(), 2);
+    }
+
+    #[test]
+    fn keyframes_only_drops_the_frames_between() {
+        let (mut receiver, sender) = bounded("test", 2, Policy::KeyframesOnly);
+        sender.enqueue(Update(0, true)).unwrap();
+        sender.enqueue(Update(1, false)).unwrap();
+        sender.enqueue(Update(2, false)).unwrap();
+        sender.enqueue(Update(3, true)).unwrap();
+        assert_eq!(drain(&mut receiver), std::vec![0, 3]);
+        // with only keyframes queued the oldest one goes
+        sender.enqueue(Update(4, true)).unwrap();
+        sender.enqueue(Update(5, false)).unwrap();
+        sender.enqueue(Update(6, true)).unwrap();
+        sender.enqueue(Update(7, true)).unwrap();
+        assert_eq!(drain(&mut receiver), std::vec![6, 7]);
+        assert_eq!(sender.shared.dropped.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn block_hands_the_update_back_then_waits_for_room() {
+        let (mut receiver, sender) = bounded("test", 1, Policy::Block);
+        sender.enqueue(Update(0, true)).unwrap();
+        match sender.enqueue(Update(1, true)) {
+            Err((Update(1, _), EnqueueError::Full)) => {}
+            other => panic!("expected the update back, got {:?}", other),
+        }
+        let tasks = pasts::Executor::default();
+        tasks.clone().block_on(async move {
+            tasks.spawn_boxed(async move {
+                sender.enqueue_waiting(Update(1, true)).await.unwrap();
+            });
+            tasks.spawn_boxed(async move {
+                assert_eq!(receiver.dequeue().await, Ok(Update(0, true)));
+                assert_eq!(receiver.dequeue().await, Ok(Update(1, true)));
+                assert_eq!(receiver.shared.dropped.load(Ordering::Relaxed), 0);
+            });
+        });
+    }
+
+    #[test]
+    fn dropping_the_last_sender_wakes_the_receiver() {
+        let (mut receiver, sender) = bounded("test", 4, Policy::Block);
+        let copy = sender.clone();
+        let waiting = std::thread::spawn(move || {
+            pasts::Executor::default().block_on(async move {
+                assert_eq!(receiver.dequeue().await, Ok(Update(0, true)));
+                assert_eq!(receiver.dequeue().await, Err(DequeueError::Closed));
+            });
+        });
+        sender.enqueue(Update(0, true)).unwrap();
+        drop(sender);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(copy);
+        waiting.join().unwrap();
+    }
+
+    #[test]
+    fn a_lent_receiver_comes_back_when_dropped() {
+        let (receiver, sender) = bounded("test", 4, Policy::Block);
+        let lender = receiver.lend();
+        let mut first = lender.take().unwrap();
+        assert!(lender.take().is_none());
+        sender.enqueue(Update(0, true)).unwrap();
+        sender.enqueue(Update(1, true)).unwrap();
+        assert_eq!(first.try_dequeue(), Ok(Update(0, true)));
+        let panicked = std::panic::catch_unwind(
+            std::panic::AssertUnwindSafe(move || {
+                let _held = first;
+                panic!("the task holding the receiver panicked");
+            }),
+        );
+        assert!(panicked.is_err());
+        assert!(!sender.is_closed());
+        let mut second = lender.take().unwrap();
+        assert_eq!(second.try_dequeue(), Ok(Update(1, true)));
+        drop(second);
+        drop(lender);
+        assert!(sender.is_closed());
+    }
+}
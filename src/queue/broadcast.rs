@@ -0,0 +1,259 @@
+/// This is where one update is handed to more than one
+/// consumer. The queues are single consumer, so a Broadcast
+/// keeps a queue per subscriber, and puts the same Arc on
+/// each of them, so every subscriber reads the one frame
+/// buffer and nothing is copied. A subscriber that must see
+/// every update, like storage, gets a queue, and is waited
+/// for when its queue is Block and full, so it never loses
+/// a frame. A subscriber that only ever wants the newest
+/// update, like the viewfinder, gets a single slot instead,
+/// which each update replaces, so it can fall behind
+/// without holding anything up or piling anything up
+use super::bounded::{
+    DequeueError, Keyframe, QueueCounter, Receiver, Sender,
+};
+use super::QueueSpec;
+
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Poll, Waker};
+
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+/// a shared update is a keyframe when the update is
+impl<T: Keyframe> Keyframe for Arc<T> {
+    fn is_keyframe(&self) -> bool {
+        (**self).is_keyframe()
+    }
+}
+
+/// the single update held for a latest only subscriber
+struct Slot<T> {
+    latest: Mutex<Option<Arc<T>>>,
+    /// the subscriber waiting for the next update
+    waker: Mutex<Option<Waker>>,
+    /// set once the broadcast is gone
+    closed: AtomicBool,
+    /// updates replaced before the subscriber took them
+    skipped: AtomicU64,
+}
+
+impl<T> Slot<T> {
+    fn wake(&self) {
+        let waker = match self.waker.lock() {
+            Ok(mut waker) => waker.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// this is the receiving end of a latest only subscription,
+/// a clone takes from the same slot, so a restarted task
+/// picks up where the last one left off
+pub struct LatestReceiver<T> {
+    slot: Arc<Slot<T>>,
+}
+
+impl<T> Clone for LatestReceiver<T> {
+    fn clone(&self) -> LatestReceiver<T> {
+        LatestReceiver {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<T> LatestReceiver<T> {
+    /// this function waits for an update newer than the last
+    /// one taken, until the broadcast is gone
+    pub async fn dequeue(&mut self) -> Result<Arc<T>, DequeueError> {
+        poll_fn(|cx| {
+            match self.slot.waker.lock() {
+                Ok(mut waker) => *waker = Some(cx.waker().clone()),
+                Err(poisoned) => {
+                    *poisoned.into_inner() = Some(cx.waker().clone())
+                }
+            }
+            // the update or the close may have landed before
+            // the waker was stored, so look after storing it
+            match self.try_dequeue() {
+                Err(DequeueError::Empty) => Poll::Pending,
+                result => Poll::Ready(result),
+            }
+        })
+        .await
+    }
+
+    /// this function takes the newest update if there is one
+    pub fn try_dequeue(&mut self) -> Result<Arc<T>, DequeueError> {
+        let latest = match self.slot.latest.lock() {
+            Ok(mut latest) => latest.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        match latest {
+            Some(update) => Ok(update),
+            None if self.slot.closed.load(Ordering::SeqCst) => {
+                Err(DequeueError::Closed)
+            }
+            None => Err(DequeueError::Empty),
+        }
+    }
+
+    /// how many updates were replaced before they were taken
+    pub fn skipped(&self) -> u64 {
+        self.slot.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// this hands every update to each of its subscribers
+pub struct Broadcast<T> {
+    every: Vec<Sender<Arc<T>>>,
+    latest: Vec<Arc<Slot<T>>>,
+}
+
+impl<T: Keyframe> Broadcast<T> {
+    pub fn new() -> Broadcast<T> {
+        Broadcast {
+            every: Vec::new(),
+            latest: Vec::new(),
+        }
+    }
+
+    /// this function adds a subscriber that gets every
+    /// update, through a queue opened from `spec`, a Block
+    /// spec makes the broadcast wait rather than drop
+    pub fn subscribe(&mut self, spec: QueueSpec) -> Receiver<Arc<T>> {
+        let (receiver, sender) = spec.open();
+        self.every.push(sender);
+        receiver
+    }
+
+    /// the counters of every subscriber's queue
+    pub fn counters(&self) -> Vec<QueueCounter> {
+        self.every.iter().map(Sender::counter).collect()
+    }
+
+    /// this function adds a subscriber that only ever
+    /// gets the newest update
+    pub fn subscribe_latest(&mut self) -> LatestReceiver<T> {
+        let slot = Arc::new(Slot {
+            latest: Mutex::new(None),
+            waker: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            skipped: AtomicU64::new(0),
+        });
+        self.latest.push(slot.clone());
+        LatestReceiver { slot }
+    }
+
+    /// this function shares an update with every subscriber,
+    /// returning how many of them are still there to take it
+    pub async fn send(&self, update: T) -> usize {
+        let shared = Arc::new(update);
+        let mut delivered = 0;
+        for sender in self.every.iter() {
+            if sender.enqueue_waiting(shared.clone()).await.is_ok() {
+                delivered += 1;
+            }
+        }
+        for slot in self.latest.iter() {
+            let replaced = match slot.latest.lock() {
+                Ok(mut latest) => latest.replace(shared.clone()),
+                Err(poisoned) => poisoned.into_inner().replace(shared.clone()),
+            };
+            if replaced.is_some() {
+                slot.skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            slot.wake();
+            delivered += 1;
+        }
+        delivered
+    }
+}
+
+impl<T: Keyframe> Default for Broadcast<T> {
+    fn default() -> Broadcast<T> {
+        Broadcast::new()
+    }
+}
+
+impl<T> Drop for Broadcast<T> {
+    fn drop(&mut self) {
+        for slot in self.latest.iter() {
+            slot.closed.store(true, Ordering::SeqCst);
+            slot.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Policy;
+
+    #[derive(Debug, PartialEq)]
+    struct Frame(u32);
+
+    impl Keyframe for Frame {
+        fn is_keyframe(&self) -> bool {
+            true
+        }
+    }
+
+    const STORAGE: QueueSpec = QueueSpec {
+        name: "test storage",
+        size: 2,
+        policy: Policy::Block,
+    };
+
+    #[test]
+    fn subscribers_share_one_frame_and_storage_loses_none() {
+        let mut frames = Broadcast::new();
+        let mut storage = frames.subscribe(STORAGE);
+        let mut view = frames.subscribe_latest();
+
+        let tasks = pasts::Executor::default();
+        tasks.clone().block_on(async move {
+            tasks.spawn_boxed(async move {
+                for number in 0..10 {
+                    assert_eq!(frames.send(Frame(number)).await, 2);
+                }
+            });
+            tasks.spawn_boxed(async move {
+                let mut stored = Vec::new();
+                while let Ok(frame) = storage.dequeue().await {
+                    stored.push(frame.0);
+                    if let Ok(latest) = view.try_dequeue() {
+                        // the viewfinder reads the same buffer
+                        assert!(latest.0 >= frame.0);
+                    }
+                }
+                assert_eq!(stored, (0..10).collect::<Vec<u32>>());
+                assert!(view.skipped() > 0);
+                while view.try_dequeue().is_ok() {}
+                assert_eq!(view.try_dequeue(), Err(DequeueError::Closed));
+            });
+        });
+    }
+
+    #[test]
+    fn latest_only_keeps_the_newest() {
+        let mut frames = Broadcast::new();
+        let mut view = frames.subscribe_latest();
+        let tasks = pasts::Executor::default();
+        tasks.clone().block_on(async move {
+            for number in 1..5 {
+                frames.send(Frame(number)).await;
+            }
+            let latest = view.dequeue().await.unwrap();
+            assert_eq!(*latest, Frame(4));
+            assert_eq!(view.skipped(), 3);
+            assert_eq!(view.try_dequeue(), Err(DequeueError::Empty));
+            drop(frames);
+            assert_eq!(view.dequeue().await, Err(DequeueError::Closed));
+        });
+    }
+}
@@ -0,0 +1,289 @@
+/// This is where the audio and video payloads come from.
+/// Allocating a fresh Vec for every chunk of audio and
+/// every frame of video churns the heap and the caches,
+/// which on the AMD A4 and the Raspberry Pi costs more
+/// than the copy itself. A BufferPool hands out Buffers
+/// that go back to the pool when they are dropped, which
+/// happens once storage has written them, so after the
+/// first few chunks capture only ever reuses buffers.
+/// The pool counts every time it had to allocate or
+/// grow a buffer, so report can show that steady state
+/// capture does no heap allocation
+use crate::log::{Job, LogPipe};
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use std::sync::{Arc, Mutex, Weak};
+use std::vec::Vec;
+
+/// the counts of one pool, shared with its buffers
+struct Shared<T> {
+    /// the name used when the pool is reported
+    name: &'static str,
+    /// buffers that have come back, ready to reuse
+    free: Mutex<Vec<Vec<T>>>,
+    /// the most free buffers kept, any more are dropped
+    keep: usize,
+    /// new buffers, and buffers that had to grow
+    allocations: AtomicU64,
+    /// buffers handed out again after coming back
+    reuses: AtomicU64,
+}
+
+/// the counts of every pool, as the same type for
+/// pools of any payload, so they can be reported together
+trait Counted: Send + Sync {
+    fn counts(&self) -> PoolCounts;
+}
+
+impl<T: Send> Counted for Shared<T> {
+    fn counts(&self) -> PoolCounts {
+        PoolCounts {
+            name: self.name,
+            allocations: self.allocations.load(Ordering::Relaxed),
+            reuses: self.reuses.load(Ordering::Relaxed),
+            free: match self.free.lock() {
+                Ok(free) => free.len(),
+                Err(poisoned) => poisoned.into_inner().len(),
+            },
+        }
+    }
+}
+
+/// every pool still in use, so they can be reported, a
+/// pool is forgotten once it and all its buffers are dropped
+static POOLS: Mutex<Vec<Weak<dyn Counted>>> = Mutex::new(Vec::new());
+
+/// this is how one pool has been used so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolCounts {
+    pub name: &'static str,
+    /// new buffers, and buffers that had to grow
+    pub allocations: u64,
+    /// buffers handed out again after coming back
+    pub reuses: u64,
+    /// buffers waiting in the pool to be handed out
+    pub free: usize,
+}
+
+/// this hands out buffers, and takes them back when dropped
+pub struct BufferPool<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for BufferPool<T> {
+    fn clone(&self) -> BufferPool<T> {
+        BufferPool {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Copy + Send + 'static> BufferPool<T> {
+    /// this function makes a pool holding `count` buffers
+    /// of room for `len` items each, up front, and keeping
+    /// up to `keep` buffers that come back
+    pub fn new(
+        name: &'static str,
+        count: usize,
+        len: usize,
+        keep: usize,
+    ) -> BufferPool<T> {
+        let mut free = Vec::with_capacity(keep.max(count));
+        for _ in 0..count {
+            free.push(Vec::with_capacity(len));
+        }
+        let shared = Arc::new(Shared {
+            name,
+            free: Mutex::new(free),
+            keep: keep.max(count),
+            allocations: AtomicU64::new(count as u64),
+            reuses: AtomicU64::new(0),
+        });
+        let counted: Weak<dyn Counted> = Arc::downgrade(&shared) as _;
+        match POOLS.lock() {
+            Ok(mut pools) => pools.push(counted),
+            Err(poisoned) => poisoned.into_inner().push(counted),
+        }
+        BufferPool { shared }
+    }
+
+    /// this function returns an empty buffer, reusing one
+    /// that has come back when there is one
+    pub fn take(&self) -> Buffer<T> {
+        let reused = match self.shared.free.lock() {
+            Ok(mut free) => free.pop(),
+            Err(poisoned) => poisoned.into_inner().pop(),
+        };
+        let data = match reused {
+            Some(data) => {
+                self.shared.reuses.fetch_add(1, Ordering::Relaxed);
+                data
+            }
+            None => {
+                self.shared.allocations.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        };
+        Buffer {
+            data,
+            pool: Some(self.shared.clone()),
+        }
+    }
+
+    /// this function returns a buffer holding a copy of `items`
+    pub fn copy_of(&self, items: &[T]) -> Buffer<T> {
+        let mut buffer = self.take();
+        buffer.copy_from(items);
+        buffer
+    }
+
+    pub fn counts(&self) -> PoolCounts {
+        self.shared.counts()
+    }
+}
+
+/// this is a payload from a pool, it can be used as a Vec,
+/// and goes back to its pool when it is dropped
+pub struct Buffer<T: Send + 'static> {
+    data: Vec<T>,
+    pool: Option<Arc<Shared<T>>>,
+}
+
+impl<T: Copy + Send + 'static> Buffer<T> {
+    /// this function makes a buffer that belongs to no pool,
+    /// for payloads that are made once, like in tests
+    pub fn unpooled(data: Vec<T>) -> Buffer<T> {
+        Buffer { data, pool: None }
+    }
+
+    /// this function replaces the contents with a copy of
+    /// `items`, counting an allocation when it has to grow
+    pub fn copy_from(&mut self, items: &[T]) {
+        self.data.clear();
+        if items.len() > self.data.capacity() {
+            if let Some(pool) = self.pool.as_ref() {
+                pool.allocations.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.data.extend_from_slice(items);
+    }
+}
+
+impl<T: Send + 'static> Deref for Buffer<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.data
+    }
+}
+
+impl<T: Send + 'static> DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.data
+    }
+}
+
+impl<T: Send + core::fmt::Debug + 'static> core::fmt::Debug for Buffer<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Buffer")
+            .field("len", &self.data.len())
+            .field("pooled", &self.pool.is_some())
+            .finish()
+    }
+}
+
+impl<T: Send + 'static> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            let mut data = core::mem::take(&mut self.data);
+            data.clear();
+            let mut free = match pool.free.lock() {
+                Ok(free) => free,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if free.len() < pool.keep {
+                free.push(data);
+            }
+        }
+    }
+}
+
+/// this function returns the counts of every pool
+pub fn counts() -> Vec<PoolCounts> {
+    let mut pools = match POOLS.lock() {
+        Ok(pools) => pools,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    pools.retain(|pool| pool.strong_count() > 0);
+    pools
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|pool| pool.counts())
+        .collect()
+}
+
+/// this function logs the counts of every pool
+pub fn report(log: &LogPipe) {
+    for pool in counts() {
+        crate::debug!(
+            log,
+            Job::Main,
+            "{} pool: {} allocations, {} reuses, {} free",
+            pool.name,
+            pool.allocations,
+            pool.reuses,
+            pool.free
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steady_state_reuses_every_buffer() {
+        let pool = BufferPool::<f32>::new("test audio", 4, 960, 8);
+        let chunk = [0.5f32; 960];
+        // the queue holds a few chunks before storage drops them
+        let mut in_flight = std::collections::VecDeque::new();
+        for _ in 0..1000 {
+            in_flight.push_back(pool.copy_of(&chunk));
+            if in_flight.len() > 3 {
+                in_flight.pop_front();
+            }
+        }
+        let counts = pool.counts();
+        assert_eq!(counts.allocations, 4);
+        assert_eq!(counts.reuses, 1000);
+    }
+
+    #[test]
+    fn forgets_a_pool_once_its_buffers_come_back() {
+        let reported =
+            || counts().iter().any(|pool| pool.name == "test forgotten");
+        let pool = BufferPool::<u8>::new("test forgotten", 1, 4, 1);
+        let buffer = pool.take();
+        drop(pool);
+        assert!(reported());
+        drop(buffer);
+        assert!(!reported());
+    }
+
+    #[test]
+    fn counts_buffers_that_grow_or_run_out() {
+        let pool = BufferPool::<u8>::new("test video", 1, 4, 1);
+        let first = pool.copy_of(&[1, 2, 3, 4]);
+        let second = pool.copy_of(&[1, 2]);
+        assert_eq!(pool.counts().allocations, 3);
+        drop(first);
+        drop(second);
+        // only one buffer is kept
+        assert_eq!(pool.counts().free, 1);
+        let mut third = pool.take();
+        third.copy_from(&[0; 64]);
+        assert_eq!(pool.counts().allocations, 4);
+    }
+}
@@ -0,0 +1,289 @@
+/// This is where we watch for a storage deficit, more
+/// frames being produced per second than are being stored
+/// per second. Every queue is sampled about once a second
+/// for how deep it is, how fast updates go in and come out,
+/// how long they waited and how many were dropped. The
+/// samples are logged, and sent to the UI, so a queue that
+/// keeps growing is seen well before memory runs out
+use super::bounded::{registered, Policy};
+use crate::log::{Job, LogPipe};
+use crate::time::Timestamp;
+
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+/// a queue more than this full, that is still taking
+/// updates faster than it gives them out, is in deficit
+pub const DEFICIT_FILL: f64 = 0.5;
+
+/// this is one queue as it was when it was sampled
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueSample {
+    pub name: &'static str,
+    pub policy: Policy,
+    /// updates waiting to be received
+    pub depth: usize,
+    /// how many updates the queue may hold
+    pub capacity: usize,
+    /// updates put on the queue per second
+    pub enqueue_rate: f64,
+    /// updates taken off the queue per second
+    pub dequeue_rate: f64,
+    /// the average time updates taken off the queue waited
+    pub mean_wait: Duration,
+    /// the longest time any update waited
+    pub max_wait: Duration,
+    /// updates dropped since the last sample
+    pub dropped: u64,
+    /// updates dropped since the queue was made
+    pub total_dropped: u64,
+}
+
+impl QueueSample {
+    /// updates per second the queue grows by,
+    /// negative when it is catching up
+    pub fn growth_rate(&self) -> f64 {
+        self.enqueue_rate - self.dequeue_rate
+    }
+
+    /// how full the queue is, from 0 to 1
+    pub fn fill(&self) -> f64 {
+        self.depth as f64 / self.capacity as f64
+    }
+
+    /// whether the queue is filling faster than it empties,
+    /// with less than half its room left
+    pub fn in_deficit(&self) -> bool {
+        self.growth_rate() > 0.0 && self.fill() > DEFICIT_FILL
+    }
+
+    /// how long until a growing queue is full, at its current rate
+    pub fn time_to_full(&self) -> Option<Duration> {
+        let growth = self.growth_rate();
+        if growth <= 0.0 {
+            return None;
+        }
+        let room = self.capacity.saturating_sub(self.depth) as f64;
+        Some(Duration::from_secs_f64(room / growth))
+    }
+}
+
+/// the counters of one queue when it was last sampled
+#[derive(Clone, Copy)]
+struct Counts {
+    enqueued: u64,
+    dequeued: u64,
+    waited_nanos: u64,
+    dropped: u64,
+}
+
+/// the time of the last sample, and the counters
+/// of each queue by its number
+static LAST: Mutex<Option<(Timestamp, BTreeMap<usize, Counts>)>> =
+    Mutex::new(None);
+
+/// this function samples every queue, measuring the
+/// rates since the last time it was called
+pub fn sample() -> Vec<QueueSample> {
+    let now = crate::time::now();
+    let queues = registered();
+    let mut last = match LAST.lock() {
+        Ok(last) => last,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let (since, before) = match last.take() {
+        Some((since, before)) => (Some(since), before),
+        None => (None, BTreeMap::new()),
+    };
+
+    let mut samples = Vec::with_capacity(queues.len());
+    let mut counts = BTreeMap::new();
+    for queue in queues.iter() {
+        let current = Counts {
+            enqueued: queue.enqueued.load(Ordering::Relaxed),
+            dequeued: queue.dequeued.load(Ordering::Relaxed),
+            waited_nanos: queue.waited_nanos.load(Ordering::Relaxed),
+            dropped: queue.dropped.load(Ordering::Relaxed),
+        };
+        // a queue made since the last sample starts from zero
+        let previous = before.get(&queue.number).copied().unwrap_or(Counts {
+            enqueued: 0,
+            dequeued: 0,
+            waited_nanos: 0,
+            dropped: 0,
+        });
+        let elapsed = match since {
+            Some(since) => now.since(&since),
+            None => Duration::from_nanos(now.monotonic_nanos),
+        };
+        samples.push(measure(
+            queue.name,
+            queue.policy,
+            queue.len.load(Ordering::SeqCst),
+            queue.capacity,
+            previous,
+            current,
+            queue.max_wait_nanos.swap(0, Ordering::Relaxed),
+            elapsed,
+        ));
+        counts.insert(queue.number, current);
+    }
+    *last = Some((now, counts));
+    samples
+}
+
+/// this function turns two readings of a queue's
+/// counters into the rates between them
+#[allow(clippy::too_many_arguments)]
+fn measure(
+    name: &'static str,
+    policy: Policy,
+    depth: usize,
+    capacity: usize,
+    previous: Counts,
+    current: Counts,
+    max_wait_nanos: u64,
+    elapsed: Duration,
+) -> QueueSample {
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    let dequeued = current.dequeued - previous.dequeued;
+    let waited = current.waited_nanos - previous.waited_nanos;
+    QueueSample {
+        name,
+        policy,
+        depth,
+        capacity,
+        enqueue_rate: (current.enqueued - previous.enqueued) as f64 / seconds,
+        dequeue_rate: dequeued as f64 / seconds,
+        mean_wait: Duration::from_nanos(
+            waited.checked_div(dequeued).unwrap_or(0),
+        ),
+        max_wait: Duration::from_nanos(max_wait_nanos),
+        dropped: current.dropped - previous.dropped,
+        total_dropped: current.dropped,
+    }
+}
+
+/// this function logs each sample, as a warning when the
+/// queue dropped updates or is running a deficit
+pub fn report(log: &LogPipe, samples: &[QueueSample]) {
+    for sample in samples {
+        crate::debug!(
+            log,
+            Job::Main,
+            "{} queue: depth {}/{}, in {:.1}/s, out {:.1}/s, waited {:?} (max {:?}), dropped {}",
+            sample.name,
+            sample.depth,
+            sample.capacity,
+            sample.enqueue_rate,
+            sample.dequeue_rate,
+            sample.mean_wait,
+            sample.max_wait,
+            sample.dropped
+        );
+        if sample.dropped > 0 {
+            crate::warn!(
+                log,
+                Job::Main,
+                "{} queue is full, dropped {} updates ({:?}), {} in total",
+                sample.name,
+                sample.dropped,
+                sample.policy,
+                sample.total_dropped
+            );
+        }
+        if sample.in_deficit() {
+            crate::warn!(
+                log,
+                Job::Main,
+                "{} queue is growing by {:.1} updates a second, full in {:?}",
+                sample.name,
+                sample.growth_rate(),
+                sample.time_to_full().unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// this function returns how many queues have been made,
+/// for a later call to pending to leave out
+#[cfg(test)]
+pub fn made() -> usize {
+    super::bounded::made()
+}
+
+/// this function returns how many updates are waiting on
+/// the queues named one of `names` that were made after
+/// the first `since`, once it is zero the stages between
+/// them have taken everything they were given
+#[cfg(test)]
+pub fn pending(since: usize, names: &[&str]) -> usize {
+    registered()
+        .iter()
+        .filter(|queue| queue.number >= since)
+        .filter(|queue| names.contains(&queue.name))
+        .map(|queue| queue.len.load(Ordering::SeqCst))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(
+        enqueued: u64,
+        dequeued: u64,
+        waited_nanos: u64,
+        dropped: u64,
+    ) -> Counts {
+        Counts {
+            enqueued,
+            dequeued,
+            waited_nanos,
+            dropped,
+        }
+    }
+
+    #[test]
+    fn measures_rates_between_samples() {
+        let sample = measure(
+            "audio storage",
+            Policy::Block,
+            900,
+            1000,
+            counts(100, 100, 0, 2),
+            counts(400, 200, 5_000_000_000, 7),
+            80_000_000,
+            Duration::from_secs(2),
+        );
+        assert_eq!(sample.enqueue_rate, 150.0);
+        assert_eq!(sample.dequeue_rate, 50.0);
+        assert_eq!(sample.mean_wait, Duration::from_millis(50));
+        assert_eq!(sample.max_wait, Duration::from_millis(80));
+        assert_eq!(sample.dropped, 5);
+        assert_eq!(sample.total_dropped, 7);
+        assert!(sample.in_deficit());
+        assert_eq!(sample.time_to_full(), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn a_draining_queue_is_not_in_deficit() {
+        let sample = measure(
+            "video storage",
+            Policy::Block,
+            900,
+            1000,
+            counts(0, 0, 0, 0),
+            counts(10, 20, 0, 0),
+            0,
+            Duration::from_secs(1),
+        );
+        assert!(!sample.in_deficit());
+        assert_eq!(sample.time_to_full(), None);
+        assert_eq!(sample.mean_wait, Duration::ZERO);
+    }
+}
@@ -0,0 +1,271 @@
+/// This is where the recorder's pipeline is put together,
+/// from the microphone and the camera through compute to
+/// storage, motion detection and the UI. The two sources
+/// are handed in, so main captures from the devices while
+/// the simulation feeds the same pipeline made up audio and
+/// video on a simulated clock. Everything else, the log
+/// pipe, the control socket, the status page and the ticks
+/// of main, stays with whoever starts the recorder
+use crate::cli::RecordOptions;
+use crate::config::Config;
+use crate::control::{Controls, Settings};
+use crate::log::{Job, LogPipe, LogUpdate};
+use crate::pipeline::{Link, Pipeline, StageFuture};
+use crate::queue::{AudioUpdate, Sender, VideoUpdate};
+use crate::supervisor::Restart;
+use crate::{compute, hardware, queue, ui};
+
+use std::boxed::Box;
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+/// what a source runs, made again each time it is restarted
+pub type Source<T> = Box<dyn FnMut(Sender<T>, LogPipe) -> StageFuture + Send>;
+
+/// where audio and video come from, a source left out is
+/// not recorded
+pub struct Sources {
+    pub audio: Option<Source<AudioUpdate>>,
+    pub video: Option<Source<VideoUpdate>>,
+}
+
+impl Sources {
+    /// this function captures from the microphone and the
+    /// cameras, those `options` leaves on
+    pub fn devices(options: &RecordOptions, settings: &Settings) -> Sources {
+        let audio: Option<Source<AudioUpdate>> = if options.audio {
+            let audio_settings = settings.audio.clone();
+            let storage_settings = settings.storage.clone();
+            Some(Box::new(move |sender, microphone_log| {
+                Box::pin(hardware::audio::start(
+                    sender,
+                    microphone_log,
+                    audio_settings.clone(),
+                    storage_settings.clone(),
+                ))
+            }))
+        } else {
+            None
+        };
+        let video: Option<Source<VideoUpdate>> = if options.video {
+            let camera_settings = settings.cameras.clone();
+            let storage_settings = settings.storage.clone();
+            Some(Box::new(move |sender, camera_log| {
+                Box::pin(hardware::camera::start(
+                    sender,
+                    camera_log,
+                    camera_settings.clone(),
+                    storage_settings.clone(),
+                ))
+            }))
+        } else {
+            None
+        };
+        Sources { audio, video }
+    }
+}
+
+/// the running pipeline
+pub struct Recorder {
+    pub threads: Vec<JoinHandle<()>>,
+    /// the UI's queue, main sends it telemetry
+    pub view_queue: Sender<ui::ViewUpdate>,
+}
+
+/// this function builds the pipeline around `sources` and
+/// starts its threads, log storage takes from `log_storage`
+pub fn start(
+    config: &Config,
+    settings: &Settings,
+    controls: &Controls,
+    sources: Sources,
+    log: &LogPipe,
+    log_storage: Link<LogUpdate>,
+) -> Recorder {
+    crate::info!(log, Job::Main, "building the pipeline");
+    let mut pipeline = Pipeline::new(log);
+
+    /* WE ARE NOT HANDLING BLUETOOTH AT THIS TIME
+    pipeline.sink(
+        "log effects",
+        "log out",
+        Job::LogOut,
+        log_out_link,
+        hardware::bluetooth::start,
+    );
+    */
+    pipeline.sink(
+        "log effects",
+        "log storage",
+        Job::LogStorage,
+        log_storage,
+        Restart::QUICKLY,
+        {
+            let log_settings = settings.log.clone();
+            let storage_settings = settings.storage.clone();
+            let controls = controls.clone();
+            move |queue, log_storage_log| {
+                hardware::storage::log_start(
+                    queue,
+                    log_storage_log,
+                    log_settings.clone(),
+                    storage_settings.clone(),
+                    controls.clone(),
+                )
+            }
+        },
+    );
+
+    if let Some(microphone) = sources.audio {
+        let audio = pipeline.source(
+            "audio",
+            "microphone",
+            Job::AudioInput,
+            config.audio_queue(queue::AUDIO_IN),
+            Restart::CAPTURE,
+            microphone,
+        );
+        let audio = pipeline.stage(
+            "audio",
+            audio,
+            config.audio_queue(queue::AUDIO_STORAGE),
+            Restart::QUICKLY,
+            compute::audio::AudioCompute,
+        );
+        pipeline.sink(
+            "audio",
+            "audio storage",
+            Job::AudioStorage,
+            audio,
+            Restart::QUICKLY,
+            {
+                let storage_settings = settings.storage.clone();
+                let controls = controls.clone();
+                move |queue, audio_storage_log| {
+                    hardware::storage::audio_start(
+                        queue,
+                        audio_storage_log,
+                        storage_settings.clone(),
+                        controls.clone(),
+                    )
+                }
+            },
+        );
+    }
+
+    let viewfinder = if let Some(camera) = sources.video {
+        let video = pipeline.source(
+            "video",
+            "camera",
+            Job::VideoInput,
+            config.video_queue(queue::VIDEO_IN),
+            Restart::CAPTURE,
+            camera,
+        );
+        let video = pipeline.stage(
+            "video",
+            video,
+            config.video_queue(queue::VIDEO_COMPUTED),
+            Restart::QUICKLY,
+            compute::video::VideoCompute,
+        );
+        // every computed frame is shared, not copied, with
+        // storage, which must get all of them, motion detection,
+        // and the viewfinder, which only wants the newest
+        let mut frames = queue::Broadcast::new();
+        let video_storage = Link::new(
+            frames.subscribe(config.video_queue(queue::VIDEO_STORAGE)),
+        );
+        let motion = Link::new(frames.subscribe(queue::MOTION));
+        let viewfinder = frames.subscribe_latest();
+        pipeline.fan_out("video", video, Job::VideoCompute, frames);
+        pipeline.sink(
+            "video",
+            "video storage",
+            Job::VideoStorage,
+            video_storage,
+            Restart::QUICKLY,
+            {
+                let storage_settings = settings.storage.clone();
+                let controls = controls.clone();
+                move |queue, video_storage_log| {
+                    hardware::storage::video_start(
+                        queue,
+                        video_storage_log,
+                        storage_settings.clone(),
+                        controls.clone(),
+                    )
+                }
+            },
+        );
+        pipeline.sink(
+            "motion",
+            "motion detection",
+            Job::VideoCompute,
+            motion,
+            Restart::QUICKLY,
+            {
+                let compute_settings = settings.compute.clone();
+                move |frames, motion_log| {
+                    compute::motion::start(
+                        frames,
+                        motion_log,
+                        compute_settings.clone(),
+                    )
+                }
+            },
+        );
+        Some(viewfinder)
+    } else {
+        None
+    };
+
+    // Last set up the ui queue for the user
+    let (view_receiver, view_queue) =
+        queue::VIEW_OUT.with_size(config.ui.queue_size).open();
+    let view_telemetry_queue = view_queue.clone();
+    if let Some(viewfinder) = viewfinder {
+        pipeline.task(
+            "ui",
+            "viewfinder",
+            Job::UI,
+            Restart::QUICKLY,
+            move |viewfinder_log| {
+                let mut viewfinder = viewfinder.clone();
+                let view_queue = view_queue.clone();
+                async move {
+                    while let Ok(frame) = viewfinder.dequeue().await {
+                        let update = ui::ViewUpdate::from_video(&frame);
+                        if let Err((_, err)) = view_queue.enqueue(update) {
+                            crate::warn!(
+                                viewfinder_log,
+                                Job::UI,
+                                "could not send a frame to the UI: {:?}",
+                                err
+                            );
+                        }
+                    }
+                }
+            },
+        );
+    }
+    pipeline.sink(
+        "ui",
+        "UI",
+        Job::UI,
+        Link::new(view_receiver),
+        Restart::QUICKLY,
+        {
+            let ui_settings = settings.ui.clone();
+            let controls = controls.clone();
+            move |queue, ui_log| {
+                ui::start(queue, ui_log, ui_settings.clone(), controls.clone())
+            }
+        },
+    );
+
+    Recorder {
+        threads: pipeline.spawn(),
+        view_queue: view_telemetry_queue,
+    }
+}
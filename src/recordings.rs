@@ -0,0 +1,563 @@
+/// This is where the recordings storage wrote are found
+/// again, for the list, export and verify commands. A
+/// recording is known by its filename, which starts with
+/// its kind and ends with the wall clock time of its first
+/// sample, so a directory can be listed by time without
+/// opening every file. The header says how long each one
+/// plays for, video has no such header and plays until its
+/// last frame. Nothing here needs the recorder running,
+/// so these work just as well on a USB stick pulled from
+/// another laptop. Bookmarks are kept with the recordings
+/// they protect, see catalog. A range of both can be cut
+/// into one clip, see clip
+pub mod avi;
+pub mod catalog;
+pub mod clip;
+pub mod sha256;
+
+use crate::hardware::storage::frames::FramesReader;
+use crate::hardware::storage::wav::{self, WavFormat, WavReader, WavWriter};
+use crate::time::{self, Timestamp};
+
+use anyhow::{anyhow, bail, Result};
+
+use core::time::Duration;
+
+use std::format;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// audio files are named audio_<for_filename>.wav
+const AUDIO_PREFIX: &str = "audio_";
+const AUDIO_SUFFIX: &str = ".wav";
+/// video files are named video_<for_filename>.frames
+const VIDEO_PREFIX: &str = "video_";
+const VIDEO_SUFFIX: &str = ".frames";
+
+/// how much audio export reads and writes at once
+const EXPORT_CHUNK: Duration = Duration::from_secs(1);
+
+/// this function returns the wall clock at which the
+/// segment holding `wall_nanos` ends, segments are counted
+/// from the UNIX_EPOCH, so ten minute segments start on
+/// the hour and capture and storage agree on where they are
+pub fn segment_end(wall_nanos: u64, segment_minutes: u32) -> u64 {
+    let length = segment_minutes.max(1) as u64 * 60 * 1_000_000_000;
+    (wall_nanos / length + 1) * length
+}
+
+/// this function names the audio file that starts at `start`
+pub fn audio_file_name(start: &Timestamp) -> String {
+    format!("{}{}{}", AUDIO_PREFIX, start.for_filename(), AUDIO_SUFFIX)
+}
+
+/// this function names the video file that starts at `start`
+pub fn video_file_name(start: &Timestamp) -> String {
+    format!("{}{}{}", VIDEO_PREFIX, start.for_filename(), VIDEO_SUFFIX)
+}
+
+/// one file written by audio or video storage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+    pub path: String,
+    /// the wall clock of its first sample, in
+    /// nanoseconds since the UNIX_EPOCH
+    pub start: u64,
+}
+
+impl Recording {
+    /// this function reads what the header says
+    pub fn format(&self) -> Result<WavFormat> {
+        Ok(WavReader::open(&self.path)?.format)
+    }
+
+    /// the wall clock just after its last sample
+    pub fn end(&self, format: &WavFormat) -> u64 {
+        self.start + format.duration().as_nanos() as u64
+    }
+
+    /// the wall clock just after the last sample or frame,
+    /// read from the header of audio and the frames of video
+    pub fn played_until(&self) -> Result<u64> {
+        if self.path.ends_with(VIDEO_SUFFIX) {
+            let (_, last) = FramesReader::open(&self.path)?.last_frame()?;
+            Ok(last.map_or(self.start, |last| last + 1))
+        } else {
+            Ok(self.end(&self.format()?))
+        }
+    }
+}
+
+/// this function returns every audio recording in
+/// `directory`, oldest first, other files are passed over
+pub fn scan(directory: &str) -> Result<Vec<Recording>> {
+    scan_named(directory, AUDIO_PREFIX, AUDIO_SUFFIX)
+}
+
+/// this function returns every video recording in
+/// `directory`, oldest first
+pub fn scan_video(directory: &str) -> Result<Vec<Recording>> {
+    scan_named(directory, VIDEO_PREFIX, VIDEO_SUFFIX)
+}
+
+fn scan_named(
+    directory: &str,
+    prefix: &str,
+    suffix: &str,
+) -> Result<Vec<Recording>> {
+    let entries = std::fs::read_dir(directory)
+        .map_err(|err| anyhow!("{}: {}", directory, err))?;
+    let mut recordings = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|err| anyhow!("{}: {}", directory, err))?;
+        let name = entry.file_name();
+        let start = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|name| name.strip_suffix(suffix))
+            .and_then(|time| time::parse_wall(time).ok());
+        if let Some(start) = start {
+            recordings.push(Recording {
+                path: entry.path().display().to_string(),
+                start,
+            });
+        }
+    }
+    recordings.sort_by(|a, b| a.start.cmp(&b.start).then(a.path.cmp(&b.path)));
+    Ok(recordings)
+}
+
+/// this function returns the recordings that started
+/// more than `retention_hours` before `now`, none when the
+/// retention is zero
+pub fn expired(
+    recordings: Vec<Recording>,
+    now: u64,
+    retention_hours: u32,
+) -> Vec<Recording> {
+    if retention_hours == 0 {
+        return Vec::new();
+    }
+    let kept_from =
+        now.saturating_sub(retention_hours as u64 * 3600 * 1_000_000_000);
+    recordings
+        .into_iter()
+        .filter(|recording| recording.start < kept_from)
+        .collect()
+}
+
+/// this function keeps the recordings that play at some
+/// time from `from` up to `to`, along with their headers,
+/// a recording whose header cannot be read is kept when
+/// it starts in the range
+pub fn between(
+    recordings: Vec<Recording>,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Vec<(Recording, Result<WavFormat>)> {
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(u64::MAX);
+    recordings
+        .into_iter()
+        .filter(|recording| recording.start < to)
+        .map(|recording| {
+            let format = recording.format();
+            (recording, format)
+        })
+        .filter(|(recording, format)| match format {
+            Ok(format) => recording.end(format) > from,
+            Err(_) => recording.start >= from,
+        })
+        .collect()
+}
+
+/// this function checks that a recording can be played
+/// to its end, returning every problem with it, the
+/// header of a file cut short by a crash can be up to a
+/// second behind its samples, which is not a problem
+pub fn verify(path: &str) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let name = std::path::Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    let named_time = name
+        .strip_prefix(AUDIO_PREFIX)
+        .and_then(|name| name.strip_suffix(AUDIO_SUFFIX))
+        .map(time::parse_wall);
+    if !matches!(named_time, Some(Ok(_))) {
+        problems.push(format!(
+            "{} is not named {}<time>{}, so it cannot be found by time",
+            name, AUDIO_PREFIX, AUDIO_SUFFIX
+        ));
+    }
+    let format = match WavReader::open(path) {
+        Ok(reader) => reader.format,
+        Err(err) => {
+            problems.push(err.to_string());
+            return Ok(problems);
+        }
+    };
+    let file_len = std::fs::metadata(path)
+        .map_err(|err| anyhow!("{}: {}", path, err))?
+        .len();
+    let samples_len = file_len - wav::WAV_HEADER_LEN as u64;
+    if format.riff_len as u64 != file_len - 8 {
+        problems.push(format!(
+            "the RIFF chunk says {} bytes but the file has {}",
+            format.riff_len,
+            file_len - 8
+        ));
+    }
+    if samples_len % format.block_align() as u64 != 0 {
+        problems.push(format!(
+            "{} bytes of samples is not a whole number of {} byte frames",
+            samples_len,
+            format.block_align()
+        ));
+    }
+    let one_second = format.sample_rate as u64 * format.block_align() as u64;
+    if format.data_len as u64 > samples_len {
+        problems.push(format!(
+            "the header says {} bytes of samples but only {} were written",
+            format.data_len, samples_len
+        ));
+    } else if samples_len - format.data_len as u64 > one_second {
+        problems.push(format!(
+            "{} bytes of samples are past the end the header gives",
+            samples_len - format.data_len as u64
+        ));
+    }
+    Ok(problems)
+}
+
+/// what export wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exported {
+    /// the wall clock of the first sample written
+    pub start: u64,
+    /// frames of samples written, silence included
+    pub frames: u64,
+    /// frames of silence written where nothing was recorded
+    pub silent_frames: u64,
+    /// how many recordings the samples came from
+    pub sources: usize,
+    pub sample_rate: u32,
+}
+
+impl Exported {
+    /// how long `frames` of the clip play for
+    pub fn seconds(&self, frames: u64) -> f64 {
+        frames as f64 / self.sample_rate as f64
+    }
+}
+
+/// this function writes the audio recorded from `from` up
+/// to `to` to one WAV file at `output`, gaps between the
+/// recordings are filled with silence so the clip keeps
+/// time with the wall clock
+pub fn export(
+    recordings: Vec<Recording>,
+    from: u64,
+    to: u64,
+    output: &str,
+) -> Result<Exported> {
+    if to <= from {
+        bail!("the range ends before it starts");
+    }
+    let chosen = choose(recordings, from, to)?;
+    let (first, format) = match chosen.first() {
+        Some((first, format)) => (first.clone(), *format),
+        None => bail!("nothing was recorded in that range"),
+    };
+    let mut writer =
+        WavWriter::create(output, format.sample_rate, format.channels)
+            .map_err(|err| anyhow!("{}: {}", output, err))?;
+    let start = from.max(first.start);
+    let sources = chosen.len();
+    let mut audio = AudioSource::new(chosen, start, to);
+    loop {
+        let until = audio.cursor + EXPORT_CHUNK.as_nanos() as u64;
+        let samples = audio.read(until)?;
+        if samples.is_empty() {
+            break;
+        }
+        writer.write_samples(&samples)?;
+    }
+    writer
+        .finalize()
+        .map_err(|err| anyhow!("{}: {}", output, err))?;
+    Ok(Exported {
+        start,
+        frames: audio.frames,
+        silent_frames: audio.silent_frames,
+        sources,
+        sample_rate: format.sample_rate,
+    })
+}
+
+/// this function picks the audio recordings that play from
+/// `from` up to `to`, they must all be readable and share a
+/// format to be put into one file
+pub(crate) fn choose(
+    recordings: Vec<Recording>,
+    from: u64,
+    to: u64,
+) -> Result<Vec<(Recording, WavFormat)>> {
+    let mut chosen: Vec<(Recording, WavFormat)> = Vec::new();
+    for (recording, format) in between(recordings, Some(from), Some(to)) {
+        let format = format.map_err(|err| {
+            anyhow!(
+                "{} is in the range but cannot be read, {}",
+                recording.path,
+                err
+            )
+        })?;
+        if let Some((first, first_format)) = chosen.first() {
+            if format.sample_rate != first_format.sample_rate
+                || format.channels != first_format.channels
+            {
+                bail!(
+                    "{} is {} channels at {}Hz but {} is {} channels at {}Hz, \
+                     export a range that does not cross them",
+                    recording.path,
+                    format.channels,
+                    format.sample_rate,
+                    first.path,
+                    first_format.channels,
+                    first_format.sample_rate
+                );
+            }
+        }
+        chosen.push((recording, format));
+    }
+    Ok(chosen)
+}
+
+/// this reads the samples of chosen recordings in the order
+/// they play, with silence where nothing was recorded
+pub(crate) struct AudioSource {
+    chosen: Vec<(Recording, WavFormat)>,
+    /// the recording being read
+    next: usize,
+    /// the open recording, and the next frame in it
+    reader: Option<(WavReader, u64)>,
+    /// the wall clock of the next frame to be read
+    pub cursor: u64,
+    to: u64,
+    /// frames of samples read, silence included
+    pub frames: u64,
+    /// frames of silence where nothing was recorded
+    pub silent_frames: u64,
+}
+
+impl AudioSource {
+    pub fn new(
+        chosen: Vec<(Recording, WavFormat)>,
+        start: u64,
+        to: u64,
+    ) -> AudioSource {
+        AudioSource {
+            chosen,
+            next: 0,
+            reader: None,
+            cursor: start,
+            to,
+            frames: 0,
+            silent_frames: 0,
+        }
+    }
+
+    /// this function returns the frames that play from the
+    /// cursor up to `until`, nothing once the recordings or
+    /// the range have ended
+    pub fn read(&mut self, until: u64) -> Result<Vec<f32>> {
+        let until = until.min(self.to);
+        let mut samples = Vec::new();
+        while self.cursor < until {
+            let (recording, format) = match self.chosen.get(self.next) {
+                Some(chosen) => chosen,
+                None => break,
+            };
+            if self.reader.is_none() && recording.start > self.cursor {
+                let end = recording.start.min(until);
+                let silence =
+                    format.frames_in(Duration::from_nanos(end - self.cursor));
+                samples.resize(
+                    samples.len() + (silence * format.channels as u64) as usize,
+                    0.0,
+                );
+                self.silent_frames += silence;
+                self.frames += silence;
+                if end == recording.start {
+                    self.cursor = end;
+                } else if silence == 0 {
+                    break;
+                } else {
+                    self.cursor += format.frames_duration(silence).as_nanos()
+                        as u64;
+                }
+                continue;
+            }
+            if self.reader.is_none() {
+                // recordings that overlap are only read once
+                let first = format.frames_in(Duration::from_nanos(
+                    self.cursor - recording.start,
+                ));
+                self.reader = Some((WavReader::open(&recording.path)?, first));
+            }
+            let (reader, next_frame) = self.reader.as_mut().unwrap();
+            let last_frame = format
+                .frames_in(Duration::from_nanos(until - recording.start))
+                .min(format.frames());
+            if *next_frame >= format.frames() {
+                self.next += 1;
+                self.reader = None;
+                continue;
+            }
+            if last_frame <= *next_frame {
+                break;
+            }
+            samples.extend(
+                reader.read_frames(*next_frame, last_frame - *next_frame)?,
+            );
+            self.frames += last_frame - *next_frame;
+            *next_frame = last_frame;
+            self.cursor = recording.start
+                + format.frames_duration(last_frame).as_nanos() as u64;
+        }
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+    /// 2023-07-27T23:31:46Z
+    const RECORDED: u64 = 1_690_500_706 * SECOND;
+
+    /// this function makes a directory of its own for a test
+    fn scratch(name: &str) -> String {
+        let directory = std::env::temp_dir().join(format!(
+            "recordings_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory.display().to_string()
+    }
+
+    /// this function records `seconds` of mono audio at
+    /// 10Hz, each sample set to `value`
+    fn record(directory: &str, start: u64, seconds: u64, value: f32) {
+        let name = audio_file_name(&Timestamp {
+            wall_nanos: start,
+            monotonic_nanos: 0,
+        });
+        let path = format!("{}/{}", directory, name);
+        let mut writer = WavWriter::create(&path, 10, 1).unwrap();
+        writer
+            .write_samples(&std::vec![value; (seconds * 10) as usize])
+            .unwrap();
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn lists_the_recordings_in_a_range() {
+        let directory = scratch("list");
+        record(&directory, RECORDED, 10, 1.0);
+        record(&directory, RECORDED + 20 * SECOND, 10, 2.0);
+        std::fs::write(format!("{}/notes.txt", directory), "not audio")
+            .unwrap();
+
+        let recordings = scan(&directory).unwrap();
+        assert_eq!(recordings.len(), 2);
+        assert_eq!(recordings[0].start, RECORDED);
+        let found = between(
+            recordings.clone(),
+            Some(RECORDED + 5 * SECOND),
+            Some(RECORDED + 15 * SECOND),
+        );
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].1.as_ref().unwrap().duration(),
+            Duration::from_secs(10)
+        );
+        assert_eq!(between(recordings, None, None).len(), 2);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn segments_end_on_the_boundary_after() {
+        // 23:31:46 is in the segment that ends at 23:40
+        assert_eq!(segment_end(RECORDED, 10), RECORDED + 494 * SECOND);
+        assert_eq!(
+            segment_end(RECORDED + 494 * SECOND, 10),
+            RECORDED + 1094 * SECOND
+        );
+        let hour = 3600 * SECOND;
+        let recordings = std::vec![
+            Recording {
+                path: "old".into(),
+                start: RECORDED - 3 * hour,
+            },
+            Recording {
+                path: "new".into(),
+                start: RECORDED - hour,
+            },
+        ];
+        let expired = expired(recordings.clone(), RECORDED, 2);
+        assert_eq!(expired, &recordings[..1]);
+        assert!(super::expired(recordings, RECORDED, 0).is_empty());
+    }
+
+    #[test]
+    fn exports_across_a_gap_with_silence() {
+        let directory = scratch("export");
+        record(&directory, RECORDED, 10, 1.0);
+        record(&directory, RECORDED + 20 * SECOND, 10, 2.0);
+        let output = format!("{}/clip.out", directory);
+
+        let exported = export(
+            scan(&directory).unwrap(),
+            RECORDED + 8 * SECOND,
+            RECORDED + 22 * SECOND,
+            &output,
+        )
+        .unwrap();
+        assert_eq!(exported.start, RECORDED + 8 * SECOND);
+        assert_eq!(exported.frames, 140);
+        assert_eq!(exported.silent_frames, 100);
+        assert_eq!(exported.sources, 2);
+        assert_eq!(exported.seconds(exported.frames), 14.0);
+        let samples = WavReader::open(&output)
+            .unwrap()
+            .read_frames(0, 140)
+            .unwrap();
+        assert_eq!(&samples[18..22], &[1.0, 1.0, 0.0, 0.0]);
+        assert_eq!(&samples[118..122], &[0.0, 0.0, 2.0, 2.0]);
+        assert!(export(scan(&directory).unwrap(), 0, SECOND, &output).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn verify_finds_a_file_that_was_cut_short() {
+        let directory = scratch("verify");
+        record(&directory, RECORDED, 2, 1.0);
+        let path = scan(&directory).unwrap().remove(0).path;
+        assert!(verify(&path).unwrap().is_empty());
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 6]).unwrap();
+        let problems = verify(&path).unwrap();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[2].contains("only 74 were written"));
+
+        std::fs::write(&path, b"RIFF").unwrap();
+        assert_eq!(verify(&path).unwrap().len(), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+/// This is where clips are written as AVI files, the one
+/// container that takes the camera's JPEG frames as they
+/// are and that anything handed a clip can play. A clip
+/// holds a motion JPEG stream, 32 bit float audio as audio
+/// storage records it, or both, with chunks of each
+/// written in the order they play and an index at the end.
+/// AVI gives every frame the same length, so where the
+/// camera had nothing new a frame is written empty, which
+/// players take to mean the last one is shown again. The
+/// sizes in the header are only known at the end, so it
+/// is written last, over the placeholder it starts with
+use anyhow::{bail, Result};
+
+use std::io::{Seek, SeekFrom, Write};
+use std::vec::Vec;
+
+/// RIFF sizes are 32 bits
+const MAX_FILE_LEN: u64 = u32::MAX as u64;
+
+/// the index flag for a chunk that decodes on its own
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// the motion JPEG stream of a clip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoStream {
+    pub frames_per_second: u32,
+}
+
+/// the audio stream of a clip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioStream {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl AudioStream {
+    fn block_align(&self) -> u32 {
+        self.channels as u32 * 4
+    }
+}
+
+/// this writes an AVI file, a chunk at a time
+pub struct AviWriter {
+    file: std::io::BufWriter<std::fs::File>,
+    video: Option<VideoStream>,
+    audio: Option<AudioStream>,
+    /// the size of the first frame that said what it was
+    width: u32,
+    height: u32,
+    /// where the movi list's fourcc is, index offsets are
+    /// counted from it
+    movi_at: u64,
+    /// bytes written so far
+    len: u64,
+    /// id, flags, offset and size of every chunk
+    index: Vec<u8>,
+    video_frames: u32,
+    audio_bytes: u64,
+    largest_chunk: u32,
+}
+
+impl AviWriter {
+    pub fn create(
+        path: &str,
+        video: Option<VideoStream>,
+        audio: Option<AudioStream>,
+    ) -> Result<AviWriter> {
+        if video.is_none() && audio.is_none() {
+            bail!("a clip needs audio or video");
+        }
+        let mut writer = AviWriter {
+            file: std::io::BufWriter::new(std::fs::File::create(path)?),
+            video,
+            audio,
+            width: 0,
+            height: 0,
+            movi_at: 0,
+            len: 0,
+            index: Vec::new(),
+            video_frames: 0,
+            audio_bytes: 0,
+            largest_chunk: 0,
+        };
+        let header = writer.header();
+        writer.file.write_all(&header)?;
+        writer.len = header.len() as u64;
+        writer.movi_at = writer.len - 4;
+        Ok(writer)
+    }
+
+    /// this function writes the next frame, an empty one
+    /// shows the last frame again
+    pub fn write_frame(&mut self, frame: Option<&[u8]>) -> Result<()> {
+        if self.video.is_none() {
+            bail!("this clip has no video");
+        }
+        if self.width == 0 {
+            if let Some((width, height)) = frame.and_then(jpeg_size) {
+                self.width = width;
+                self.height = height;
+            }
+        }
+        let id = self.chunk_id(0, b"dc");
+        let flags = if frame.is_some() { AVIIF_KEYFRAME } else { 0 };
+        self.chunk(id, flags, frame.unwrap_or(&[]))?;
+        self.video_frames += 1;
+        Ok(())
+    }
+
+    /// this function writes interleaved samples, they play
+    /// straight after those written before them
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        let stream = match self.video {
+            Some(_) => 1,
+            None => 0,
+        };
+        if self.audio.is_none() {
+            bail!("this clip has no audio");
+        }
+        let mut bytes = Vec::with_capacity(samples.len() * 4);
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        let id = self.chunk_id(stream, b"wb");
+        self.chunk(id, AVIIF_KEYFRAME, &bytes)?;
+        self.audio_bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn chunk_id(&self, stream: u8, kind: &[u8; 2]) -> [u8; 4] {
+        [b'0', b'0' + stream, kind[0], kind[1]]
+    }
+
+    fn chunk(&mut self, id: [u8; 4], flags: u32, data: &[u8]) -> Result<()> {
+        let padded = data.len() as u64 + data.len() as u64 % 2;
+        // the index still has to fit after this chunk
+        let after = self.len + 8 + padded + self.index.len() as u64 + 24;
+        if after > MAX_FILE_LEN {
+            bail!("the clip would pass 4GB, export a shorter range");
+        }
+        self.index.extend_from_slice(&id);
+        self.index.extend_from_slice(&flags.to_le_bytes());
+        self.index.extend_from_slice(
+            &((self.len - self.movi_at) as u32).to_le_bytes(),
+        );
+        self.index
+            .extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.file.write_all(&id)?;
+        self.file.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.file.write_all(data)?;
+        if data.len() % 2 == 1 {
+            self.file.write_all(&[0])?;
+        }
+        self.len += 8 + padded;
+        self.largest_chunk = self.largest_chunk.max(data.len() as u32);
+        Ok(())
+    }
+
+    /// this function writes the index and the header, and
+    /// waits for the clip to reach the disk
+    pub fn finalize(mut self) -> Result<()> {
+        self.file.write_all(b"idx1")?;
+        self.file
+            .write_all(&(self.index.len() as u32).to_le_bytes())?;
+        self.file.write_all(&self.index)?;
+        self.len += 8 + self.index.len() as u64;
+        let header = self.header();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        let file = self.file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// this function lays out everything before the first
+    /// chunk, with the sizes and counts written so far
+    fn header(&self) -> Vec<u8> {
+        let mut streams = Vec::new();
+        if let Some(video) = self.video {
+            let mut strh = Vec::new();
+            strh.extend_from_slice(b"vidsMJPG");
+            // flags, priority and language, then initial frames
+            push_u32s(&mut strh, &[0, 0, 0]);
+            // scale, rate, start and length
+            push_u32s(
+                &mut strh,
+                &[1, video.frames_per_second, 0, self.video_frames],
+            );
+            // suggested buffer, quality and sample size
+            push_u32s(&mut strh, &[self.largest_chunk, u32::MAX, 0]);
+            strh.extend_from_slice(&[0, 0, 0, 0]);
+            strh.extend_from_slice(&(self.width as u16).to_le_bytes());
+            strh.extend_from_slice(&(self.height as u16).to_le_bytes());
+            let mut strf = Vec::new();
+            push_u32s(&mut strf, &[40, self.width, self.height]);
+            // one plane of 24 bit color
+            strf.extend_from_slice(&1u16.to_le_bytes());
+            strf.extend_from_slice(&24u16.to_le_bytes());
+            strf.extend_from_slice(b"MJPG");
+            push_u32s(&mut strf, &[self.width * self.height * 3, 0, 0, 0, 0]);
+            streams.push(list(
+                b"strl",
+                &[chunk(b"strh", &strh), chunk(b"strf", &strf)],
+            ));
+        }
+        if let Some(audio) = self.audio {
+            let block_align = audio.block_align();
+            let byte_rate = audio.sample_rate * block_align;
+            let mut strh = Vec::new();
+            strh.extend_from_slice(b"auds");
+            push_u32s(&mut strh, &[0, 0, 0, 0]);
+            push_u32s(
+                &mut strh,
+                &[
+                    block_align,
+                    byte_rate,
+                    0,
+                    (self.audio_bytes / block_align as u64) as u32,
+                ],
+            );
+            push_u32s(&mut strh, &[self.largest_chunk, u32::MAX, block_align]);
+            strh.extend_from_slice(&[0; 8]);
+            let mut strf = Vec::new();
+            // format 3 is IEEE float
+            strf.extend_from_slice(&3u16.to_le_bytes());
+            strf.extend_from_slice(&audio.channels.to_le_bytes());
+            push_u32s(&mut strf, &[audio.sample_rate, byte_rate]);
+            strf.extend_from_slice(&(block_align as u16).to_le_bytes());
+            strf.extend_from_slice(&32u16.to_le_bytes());
+            strf.extend_from_slice(&0u16.to_le_bytes());
+            streams.push(list(
+                b"strl",
+                &[chunk(b"strh", &strh), chunk(b"strf", &strf)],
+            ));
+        }
+
+        let mut avih = Vec::new();
+        let frame_micros = self
+            .video
+            .map_or(0, |video| 1_000_000 / video.frames_per_second.max(1));
+        // has an index, and the streams are interleaved
+        push_u32s(&mut avih, &[frame_micros, 0, 0, 0x110, self.video_frames]);
+        push_u32s(&mut avih, &[0, streams.len() as u32, self.largest_chunk]);
+        push_u32s(&mut avih, &[self.width, self.height, 0, 0, 0, 0]);
+        let mut hdrl = std::vec![chunk(b"avih", &avih)];
+        hdrl.extend(streams);
+        let hdrl = list(b"hdrl", &hdrl);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF");
+        // everything after the RIFF size, the whole file once
+        // the index is written
+        let riff_len = self.len.max(hdrl.len() as u64 + 24) - 8;
+        header.extend_from_slice(&(riff_len as u32).to_le_bytes());
+        header.extend_from_slice(b"AVI ");
+        header.extend_from_slice(&hdrl);
+        header.extend_from_slice(b"LIST");
+        let movi_len = if self.movi_at == 0 {
+            4
+        } else {
+            self.len - self.movi_at - 8 - self.index.len() as u64
+        };
+        header.extend_from_slice(&(movi_len as u32).to_le_bytes());
+        header.extend_from_slice(b"movi");
+        header
+    }
+}
+
+fn push_u32s(bytes: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() + 8);
+    bytes.extend_from_slice(id);
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = kind.to_vec();
+    for chunk in chunks {
+        data.extend_from_slice(chunk);
+    }
+    chunk(b"LIST", &data)
+}
+
+/// this function finds the width and height of a JPEG in
+/// its start of frame, none if it has none
+pub fn jpeg_size(jpeg: &[u8]) -> Option<(u32, u32)> {
+    if !jpeg.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut at = 2;
+    while at + 9 <= jpeg.len() {
+        if jpeg[at] != 0xff {
+            return None;
+        }
+        let marker = jpeg[at + 1];
+        let len = u16::from_be_bytes([jpeg[at + 2], jpeg[at + 3]]) as usize;
+        // the baseline, extended and progressive starts of frame
+        if (0xc0..=0xc2).contains(&marker) {
+            let height = u16::from_be_bytes([jpeg[at + 5], jpeg[at + 6]]);
+            let width = u16::from_be_bytes([jpeg[at + 7], jpeg[at + 8]]);
+            return Some((width as u32, height as u32));
+        }
+        at += 2 + len;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn sizes_and_index_agree_with_what_was_written() {
+        let path = std::env::temp_dir()
+            .join(std::format!("avi_{}.avi", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut writer = AviWriter::create(
+            path,
+            Some(VideoStream {
+                frames_per_second: 2,
+            }),
+            Some(AudioStream {
+                sample_rate: 10,
+                channels: 1,
+            }),
+        )
+        .unwrap();
+        // a start of frame saying 640 by 480
+        let jpeg = [
+            0xff, 0xd8, 0xff, 0xc0, 0, 11, 8, 0x01, 0xe0, 0x02, 0x80, 3, 0,
+        ];
+        writer.write_frame(Some(&jpeg)).unwrap();
+        writer.write_samples(&[0.5; 5]).unwrap();
+        writer.write_frame(None).unwrap();
+        writer.write_samples(&[0.25; 5]).unwrap();
+        writer.finalize().unwrap();
+
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..12], b"AVI ");
+        // avih: 2 frames, 2 streams, 640 by 480
+        assert_eq!(&bytes[24..28], b"avih");
+        assert_eq!(u32_at(&bytes, 32), 500_000);
+        assert_eq!(u32_at(&bytes, 48), 2);
+        assert_eq!(u32_at(&bytes, 56), 2);
+        assert_eq!(u32_at(&bytes, 64), 640);
+        assert_eq!(u32_at(&bytes, 68), 480);
+
+        let movi = bytes.windows(4).position(|at| at == b"movi").unwrap();
+        let idx1 = bytes.windows(4).rposition(|at| at == b"idx1").unwrap();
+        assert_eq!(u32_at(&bytes, movi - 4) as usize, idx1 - movi);
+        assert_eq!(u32_at(&bytes, idx1 + 4), 4 * 16);
+        let entries: Vec<_> = bytes[idx1 + 8..].chunks(16).collect();
+        assert_eq!(&entries[0][0..4], b"00dc");
+        assert_eq!(&entries[1][0..4], b"01wb");
+        assert_eq!(u32_at(entries[2], 4), 0);
+        for entry in entries {
+            let at = movi + u32_at(entry, 8) as usize;
+            assert_eq!(&bytes[at..at + 4], &entry[0..4]);
+            assert_eq!(u32_at(&bytes, at + 4), u32_at(entry, 12));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
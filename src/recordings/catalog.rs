@@ -0,0 +1,216 @@
+/// This is where bookmarks are kept. A bookmark is a moment
+/// someone marked, from the UI or the control socket, with
+/// a note saying what they saw, and the stretch of
+/// recording around it that is kept for it, from the
+/// pre-roll before it to the post-roll after. Bookmarks
+/// are appended to catalog.jsonl in the storage directory,
+/// one JSON object a line, next to the recordings they
+/// protect, so a USB stick pulled from the laptop carries
+/// its bookmarks with it. Retention never deletes a
+/// recording that plays during a bookmark
+use super::Recording;
+use crate::log::json::{escape, Parser};
+use crate::time::Timestamp;
+
+use anyhow::{anyhow, bail, Result};
+
+use core::time::Duration;
+
+use std::format;
+use std::io::Write;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// the file bookmarks are appended to, in the directory
+/// of the recordings they protect
+pub const CATALOG_FILE: &str = "catalog.jsonl";
+
+/// one marked moment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bookmark {
+    /// the wall clock when it was marked, in nanoseconds
+    /// since the UNIX_EPOCH
+    pub at: u64,
+    pub note: String,
+    /// the wall clock the protected recording starts at
+    pub from: u64,
+    /// the wall clock the protected recording ends at
+    pub to: u64,
+}
+
+impl Bookmark {
+    pub fn new(
+        at: u64,
+        note: &str,
+        pre_roll: Duration,
+        post_roll: Duration,
+    ) -> Bookmark {
+        Bookmark {
+            at,
+            note: note.into(),
+            from: at.saturating_sub(pre_roll.as_nanos() as u64),
+            to: at.saturating_add(post_roll.as_nanos() as u64),
+        }
+    }
+
+    /// whether a recording playing from `start` up to
+    /// `end` plays during the bookmark
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+        start < self.to && end > self.from
+    }
+
+    /// this function writes the bookmark as one JSON
+    /// object, terminated by a newline
+    pub fn to_line(&self) -> String {
+        format!(
+            "{{\"time\":\"{}\",\"at\":{},\"from\":{},\"to\":{},\"note\":{}}}\n",
+            wall(self.at).rfc3339(),
+            self.at,
+            self.from,
+            self.to,
+            escape(&self.note)
+        )
+    }
+
+    /// this function reads a line written by to_line
+    pub fn from_line(line: &str) -> Result<Bookmark> {
+        let mut parser = Parser::new(line);
+        let mut at = None;
+        let mut from = None;
+        let mut to = None;
+        let mut note = None;
+        parser.expect('{')?;
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            match key.as_str() {
+                // the RFC 3339 time is only there for people
+                // reading the file, the nanoseconds are exact
+                "time" => {
+                    parser.string()?;
+                }
+                "at" => at = Some(parser.number()?),
+                "from" => from = Some(parser.number()?),
+                "to" => to = Some(parser.number()?),
+                "note" => note = Some(parser.string()?),
+                other => bail!("unknown bookmark field: {}", other),
+            }
+            match parser.next()? {
+                ',' => continue,
+                '}' => break,
+                other => bail!("expected ',' or '}}' but found '{}'", other),
+            }
+        }
+        Ok(Bookmark {
+            at: at.ok_or(anyhow!("missing field: at"))?,
+            note: note.ok_or(anyhow!("missing field: note"))?,
+            from: from.ok_or(anyhow!("missing field: from"))?,
+            to: to.ok_or(anyhow!("missing field: to"))?,
+        })
+    }
+}
+
+fn path(directory: &str) -> String {
+    std::path::Path::new(directory)
+        .join(CATALOG_FILE)
+        .display()
+        .to_string()
+}
+
+/// this function adds a bookmark to the catalog in
+/// `directory`, and waits for it to reach the disk
+pub fn append(directory: &str, bookmark: &Bookmark) -> Result<()> {
+    let path = path(directory);
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .map_err(|err| anyhow!("{}: {}", path, err))?;
+    file.write_all(bookmark.to_line().as_bytes())
+        .and_then(|_| file.sync_data())
+        .map_err(|err| anyhow!("{}: {}", path, err))
+}
+
+/// this function reads every bookmark in `directory`, in
+/// the order they were marked, there are none when there
+/// is no catalog
+pub fn read(directory: &str) -> Result<Vec<Bookmark>> {
+    let path = path(directory);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(err) => bail!("{}: {}", path, err),
+    };
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            Bookmark::from_line(line)
+                .map_err(|err| anyhow!("{} line {}: {}", path, index + 1, err))
+        })
+        .collect()
+}
+
+/// this function finds whether any bookmark protects a
+/// recording, one whose header cannot be read is taken
+/// to play until now, so it is kept rather than lost
+pub fn protects(bookmarks: &[Bookmark], recording: &Recording) -> bool {
+    let end = recording.played_until().unwrap_or(u64::MAX);
+    bookmarks
+        .iter()
+        .any(|bookmark| bookmark.covers(recording.start, end))
+}
+
+fn wall(wall_nanos: u64) -> Timestamp {
+    Timestamp {
+        wall_nanos,
+        monotonic_nanos: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+    /// 2023-07-27T23:31:46Z
+    const MARKED: u64 = 1_690_500_706 * SECOND;
+
+    #[test]
+    fn bookmarks_round_trip_and_protect_what_they_cover() {
+        let directory = std::env::temp_dir()
+            .join(format!("catalog_{}", std::process::id()))
+            .display()
+            .to_string();
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        assert!(read(&directory).unwrap().is_empty());
+
+        let bookmark = Bookmark::new(
+            MARKED,
+            "car \"stopped\" outside",
+            Duration::from_secs(30),
+            Duration::from_secs(60),
+        );
+        assert_eq!(bookmark.from, MARKED - 30 * SECOND);
+        append(&directory, &bookmark).unwrap();
+        append(&directory, &bookmark).unwrap();
+        assert_eq!(read(&directory).unwrap(), [bookmark.clone(), bookmark]);
+
+        let bookmarks = read(&directory).unwrap();
+        // unreadable, so taken to play until now
+        let before = Recording {
+            path: format!("{}/missing.wav", directory),
+            start: MARKED - 600 * SECOND,
+        };
+        assert!(protects(&bookmarks, &before));
+        let after = Recording {
+            path: before.path,
+            start: MARKED + 60 * SECOND,
+        };
+        assert!(!protects(&bookmarks, &after));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
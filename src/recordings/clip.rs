@@ -0,0 +1,499 @@
+/// This is where a range of time is cut out of storage as
+/// one clip someone can be handed. The audio and video
+/// recordings that play in the range are trimmed to it and
+/// written to one AVI file, both starting at the same wall
+/// clock, audio with silence where nothing was recorded
+/// and video with the last frame held, so the two stay in
+/// step however the segments fell. Next to the clip goes a
+/// manifest naming every recording it was cut from with
+/// its SHA-256, and the clip's own, so it can be shown to
+/// have come from them. A recording still being written
+/// when the clip is cut will not match its hash afterwards.
+/// An audio recording storage linked to a video one in
+/// av_links.txt is placed by the offset between their
+/// presentation clocks, rather than by its own wall clock
+use super::avi::{AudioStream, AviWriter, VideoStream};
+use super::{choose, scan, scan_video, sha256, AudioSource, Recording};
+use crate::hardware::storage::frames::{FramesReader, StoredFrame};
+use crate::hardware::storage::parse_av_link_line;
+use crate::log::json::escape;
+use crate::time::Timestamp;
+
+use anyhow::{anyhow, bail, Result};
+
+use std::format;
+use std::string::String;
+use std::vec::Vec;
+
+/// how much of the clip is written at once, audio and
+/// video are interleaved at this pace
+const STEP: u64 = 1_000_000_000;
+
+/// the manifest is written next to the clip, as
+/// <clip>.manifest.json
+pub const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// a recording a clip was cut from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    /// audio or video
+    pub kind: &'static str,
+    pub recording: Recording,
+    pub sha256: String,
+}
+
+/// what a clip holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clip {
+    /// the wall clock the clip starts and ends at
+    pub start: u64,
+    pub end: u64,
+    /// frames of video, zero without video
+    pub frames_per_second: u32,
+    pub video_frames: u64,
+    /// video frames that show the one before again, where
+    /// the camera had nothing new
+    pub held_frames: u64,
+    /// frames of samples, and those that are silence
+    pub audio_frames: u64,
+    pub silent_frames: u64,
+    pub sample_rate: u32,
+    pub sources: Vec<Source>,
+    pub sha256: String,
+    /// where the manifest was written
+    pub manifest: String,
+}
+
+impl Clip {
+    /// how long the clip plays for
+    pub fn seconds(&self) -> f64 {
+        (self.end - self.start) as f64 / 1_000_000_000.0
+    }
+}
+
+/// this function cuts what was recorded in `directory`
+/// from `from` up to `to` into one AVI file at `output`,
+/// and writes its manifest
+pub fn export(
+    directory: &str,
+    from: u64,
+    to: u64,
+    output: &str,
+) -> Result<Clip> {
+    if to <= from {
+        bail!("the range ends before it starts");
+    }
+    let mut recordings = scan(directory)?;
+    link(directory, &mut recordings)?;
+    let audio = choose(recordings, from, to)?;
+    let video = VideoSource::new(scan_video(directory)?, from, to)?;
+    if audio.is_empty() && video.files.is_empty() {
+        bail!("nothing was recorded in that range");
+    }
+
+    // the clip starts with whichever was recorded first
+    let mut start = to;
+    let mut end = from;
+    if let (Some((first, _)), Some((last, format))) =
+        (audio.first(), audio.last())
+    {
+        start = start.min(first.start);
+        end = end.max(last.end(format));
+    }
+    if let (Some(first), Some(last)) = (video.first, video.last) {
+        start = start.min(first);
+        end = end.max(last + STEP / video.frames_per_second as u64);
+    }
+    let start = start.max(from);
+    let end = end.min(to);
+
+    let audio_stream = audio.first().map(|(_, format)| AudioStream {
+        sample_rate: format.sample_rate,
+        channels: format.channels,
+    });
+    let video_stream = (!video.files.is_empty()).then_some(VideoStream {
+        frames_per_second: video.frames_per_second,
+    });
+    let mut writer = AviWriter::create(output, video_stream, audio_stream)
+        .map_err(|err| anyhow!("{}: {}", output, err))?;
+
+    let mut sources: Vec<Source> = audio
+        .iter()
+        .map(|(recording, _)| Source {
+            kind: "audio",
+            recording: recording.clone(),
+            sha256: String::new(),
+        })
+        .chain(video.files.iter().map(|recording| Source {
+            kind: "video",
+            recording: recording.clone(),
+            sha256: String::new(),
+        }))
+        .collect();
+    let sample_rate = audio_stream.map_or(0, |stream| stream.sample_rate);
+    let mut audio = AudioSource::new(audio, start, end);
+    let mut video = video;
+    let frame_nanos = STEP / video.frames_per_second as u64;
+    let mut clip = Clip {
+        start,
+        end,
+        frames_per_second: video_stream
+            .map_or(0, |stream| stream.frames_per_second),
+        video_frames: 0,
+        held_frames: 0,
+        audio_frames: 0,
+        silent_frames: 0,
+        sample_rate,
+        sources: Vec::new(),
+        sha256: String::new(),
+        manifest: format!("{}{}", output, MANIFEST_SUFFIX),
+    };
+    let mut step = start;
+    while step < end {
+        let until = (step + STEP).min(end);
+        if audio_stream.is_some() {
+            let samples = audio.read(until)?;
+            if !samples.is_empty() {
+                writer.write_samples(&samples)?;
+            }
+        }
+        if video_stream.is_some() {
+            loop {
+                let at = start + clip.video_frames * frame_nanos;
+                if at >= until {
+                    break;
+                }
+                let frame = video.frame_at(at)?;
+                if frame.is_none() {
+                    clip.held_frames += 1;
+                }
+                writer.write_frame(frame.as_ref().map(|f| &f.data[..]))?;
+                clip.video_frames += 1;
+            }
+        }
+        step = until;
+    }
+    writer
+        .finalize()
+        .map_err(|err| anyhow!("{}: {}", output, err))?;
+    clip.audio_frames = audio.frames;
+    clip.silent_frames = audio.silent_frames;
+
+    for source in sources.iter_mut() {
+        source.sha256 = sha256::file(&source.recording.path)?;
+    }
+    clip.sources = sources;
+    clip.sha256 = sha256::file(output)?;
+    std::fs::write(&clip.manifest, manifest(&clip, output, from, to))
+        .map_err(|err| anyhow!("{}: {}", clip.manifest, err))?;
+    Ok(clip)
+}
+
+/// this function moves the start of each audio recording
+/// av_links.txt in `directory` links to a video recording
+/// onto the video's wall clock, by the offset between their
+/// first frame and first sample, a directory without it
+/// keeps the starts its file names give
+fn link(directory: &str, audio: &mut [Recording]) -> Result<()> {
+    let path = format!("{}/av_links.txt", directory);
+    let links = match std::fs::read_to_string(&path) {
+        Ok(links) => links,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(());
+        }
+        Err(err) => bail!("{}: {}", path, err),
+    };
+    for link in links.lines().filter_map(parse_av_link_line) {
+        // file names only keep the milliseconds
+        let named = link.audio / 1_000_000;
+        if let Some(recording) = audio
+            .iter_mut()
+            .find(|recording| recording.start / 1_000_000 == named)
+        {
+            recording.start =
+                (link.video as i128 + link.offset_nanos as i128).max(0) as u64;
+        }
+    }
+    audio.sort_by_key(|recording| recording.start);
+    Ok(())
+}
+
+/// this function lays out the manifest of a clip as JSON,
+/// a source to a line so it can be read by people too
+fn manifest(clip: &Clip, output: &str, from: u64, to: u64) -> String {
+    let sources: Vec<String> = clip
+        .sources
+        .iter()
+        .map(|source| {
+            format!(
+                "    {{\"kind\":\"{}\",\"path\":{},\"start\":\"{}\",\
+                 \"sha256\":\"{}\"}}",
+                source.kind,
+                escape(&source.recording.path),
+                wall(source.recording.start).rfc3339(),
+                source.sha256
+            )
+        })
+        .collect();
+    format!(
+        "{{\n  \"clip\":{},\n  \"sha256\":\"{}\",\n  \"from\":\"{}\",\n  \
+         \"to\":\"{}\",\n  \"start\":\"{}\",\n  \"end\":\"{}\",\n  \
+         \"frames_per_second\":{},\n  \"sample_rate\":{},\n  \
+         \"sources\":[\n{}\n  ]\n}}\n",
+        escape(output),
+        clip.sha256,
+        wall(from).rfc3339(),
+        wall(to).rfc3339(),
+        wall(clip.start).rfc3339(),
+        wall(clip.end).rfc3339(),
+        clip.frames_per_second,
+        clip.sample_rate,
+        sources.join(",\n")
+    )
+}
+
+fn wall(wall_nanos: u64) -> Timestamp {
+    Timestamp {
+        wall_nanos,
+        monotonic_nanos: 0,
+    }
+}
+
+/// this reads the frames of the video recordings in a
+/// range in the order they were captured, and hands out
+/// the newest one at each frame of the clip
+struct VideoSource {
+    files: Vec<Recording>,
+    /// the file being read
+    next: usize,
+    reader: Option<FramesReader>,
+    /// the frame read but not yet due
+    ahead: Option<StoredFrame>,
+    /// whether a keyframe has been handed out, frames
+    /// before the first have nothing to be decoded from
+    started: bool,
+    /// the wall clock of the first and last frame in range
+    first: Option<u64>,
+    last: Option<u64>,
+    frames_per_second: u32,
+}
+
+impl VideoSource {
+    /// this function finds the video recordings playing
+    /// from `from` up to `to`, and how many frames a second
+    /// the camera delivered in them
+    fn new(
+        recordings: Vec<Recording>,
+        from: u64,
+        to: u64,
+    ) -> Result<VideoSource> {
+        let mut files = Vec::new();
+        let mut first = None;
+        let mut last = None;
+        let mut frames = 0;
+        let mut span = 0;
+        for recording in recordings.into_iter().filter(|r| r.start < to) {
+            let (count, last_frame) = FramesReader::open(&recording.path)?
+                .last_frame()
+                .map_err(|err| anyhow!("{}: {}", recording.path, err))?;
+            let last_frame = match last_frame {
+                Some(last_frame) if last_frame >= from => last_frame,
+                _ => continue,
+            };
+            first = first.or(Some(recording.start.max(from)));
+            last = Some(last_frame.min(to));
+            frames += count - 1;
+            span += last_frame.saturating_sub(recording.start);
+            files.push(recording);
+        }
+        // the camera's pace, to the nearest whole frame
+        let frames_per_second = if span == 0 {
+            1
+        } else {
+            ((frames as u128 * STEP as u128 + span as u128 / 2) / span as u128)
+                .clamp(1, 60) as u32
+        };
+        Ok(VideoSource {
+            files,
+            next: 0,
+            reader: None,
+            ahead: None,
+            started: false,
+            first,
+            last,
+            frames_per_second,
+        })
+    }
+
+    /// this function reads the next frame of any file
+    fn read(&mut self) -> Result<Option<StoredFrame>> {
+        loop {
+            if self.reader.is_none() {
+                match self.files.get(self.next) {
+                    Some(recording) => {
+                        self.reader = Some(FramesReader::open(&recording.path)?)
+                    }
+                    None => return Ok(None),
+                }
+            }
+            if let Some(frame) = self.reader.as_mut().unwrap().next_frame()? {
+                return Ok(Some(frame));
+            }
+            self.reader = None;
+            self.next += 1;
+        }
+    }
+
+    /// this function returns the newest frame captured by
+    /// `at` that has not been handed out, none if there is
+    /// no new one, frames captured in between are dropped
+    fn frame_at(&mut self, at: u64) -> Result<Option<StoredFrame>> {
+        let mut newest = None;
+        loop {
+            let frame = match self.ahead.take() {
+                Some(frame) => frame,
+                None => match self.read()? {
+                    Some(frame) => frame,
+                    None => break,
+                },
+            };
+            if frame.wall_nanos > at {
+                self.ahead = Some(frame);
+                break;
+            }
+            if frame.keyframe || self.started {
+                newest = Some(frame);
+            }
+        }
+        self.started |= newest.is_some();
+        Ok(newest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::storage::frames::FramesWriter;
+    use crate::hardware::storage::wav::WavWriter;
+    use std::string::ToString;
+
+    const SECOND: u64 = 1_000_000_000;
+    /// 2023-07-27T23:31:46Z
+    const RECORDED: u64 = 1_690_500_706 * SECOND;
+
+    fn at(wall_nanos: u64) -> Timestamp {
+        wall(wall_nanos)
+    }
+
+    #[test]
+    fn cuts_audio_and_video_into_one_clip_in_step() {
+        let directory = std::env::temp_dir()
+            .join(format!("clip_{}", std::process::id()))
+            .display()
+            .to_string();
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        // audio starts a second after the video
+        let audio = format!(
+            "{}/{}",
+            directory,
+            super::super::audio_file_name(&at(RECORDED + SECOND))
+        );
+        let mut writer = WavWriter::create(&audio, 10, 1).unwrap();
+        writer.write_samples(&[0.5; 50]).unwrap();
+        writer.finalize().unwrap();
+        // two frames a second, with one missing
+        let video = format!(
+            "{}/{}",
+            directory,
+            super::super::video_file_name(&at(RECORDED))
+        );
+        let mut writer = FramesWriter::create(&video).unwrap();
+        for frame in [0, 1, 2, 4, 5, 6, 7, 8, 9, 10, 11] {
+            let wall_nanos = RECORDED + frame * SECOND / 2;
+            writer.write_frame(wall_nanos, true, b"jpeg").unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let output = format!("{}/clip.avi", directory);
+        let clip = export(
+            &directory,
+            RECORDED + SECOND / 2,
+            RECORDED + 4 * SECOND,
+            &output,
+        )
+        .unwrap();
+        assert_eq!(clip.start, RECORDED + SECOND / 2);
+        assert_eq!(clip.end, RECORDED + 4 * SECOND);
+        assert_eq!(clip.frames_per_second, 2);
+        assert_eq!(clip.video_frames, 7);
+        assert_eq!(clip.held_frames, 1);
+        // half a second of silence, then three of audio
+        assert_eq!(clip.silent_frames, 5);
+        assert_eq!(clip.audio_frames, 35);
+        assert_eq!(clip.sources.len(), 2);
+        assert_eq!(clip.sources[0].sha256, sha256::file(&audio).unwrap());
+        assert_eq!(clip.sha256, sha256::file(&output).unwrap());
+
+        let manifest = std::fs::read_to_string(&clip.manifest).unwrap();
+        assert!(manifest.contains(&clip.sha256));
+        assert!(manifest.contains("\"start\":\"2023-07-27T23:31:46.500"));
+        assert!(manifest.contains(&clip.sources[1].sha256));
+        assert!(export(&directory, 0, SECOND, &output).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn places_linked_audio_by_its_offset_from_the_video() {
+        let directory = std::env::temp_dir()
+            .join(format!("clip_linked_{}", std::process::id()))
+            .display()
+            .to_string();
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        // the wall clock said the audio started a second
+        // after the video, the presentation clock says half
+        let audio = format!(
+            "{}/{}",
+            directory,
+            super::super::audio_file_name(&at(RECORDED + SECOND))
+        );
+        let mut writer = WavWriter::create(&audio, 10, 1).unwrap();
+        writer.write_samples(&[0.5; 30]).unwrap();
+        writer.finalize().unwrap();
+        let video = format!(
+            "{}/{}",
+            directory,
+            super::super::video_file_name(&at(RECORDED))
+        );
+        let mut writer = FramesWriter::create(&video).unwrap();
+        for frame in 0..8 {
+            let wall_nanos = RECORDED + frame * SECOND / 2;
+            writer.write_frame(wall_nanos, true, b"jpeg").unwrap();
+        }
+        writer.finalize().unwrap();
+        std::fs::write(
+            format!("{}/av_links.txt", directory),
+            format!(
+                "video {} audio {} offset_nanos {} alignment PadSamples(5)\n",
+                at(RECORDED).rfc3339(),
+                at(RECORDED + SECOND).rfc3339(),
+                SECOND / 2
+            ),
+        )
+        .unwrap();
+
+        let output = format!("{}/clip.avi", directory);
+        let clip =
+            export(&directory, RECORDED, RECORDED + 4 * SECOND, &output)
+                .unwrap();
+        assert_eq!(clip.start, RECORDED);
+        // half a second of silence, then the three of audio
+        assert_eq!(clip.silent_frames, 5);
+        assert_eq!(clip.audio_frames, 35);
+        assert_eq!(clip.sources[0].recording.start, RECORDED + SECOND / 2);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
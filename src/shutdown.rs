@@ -0,0 +1,175 @@
+/// This is where the recorder is told to stop. SIGINT and
+/// SIGTERM only set a flag, which is all a signal handler
+/// may safely do, and main notices the flag between its
+/// telemetry ticks and calls request, which wakes every
+/// capture task waiting on stopped. Capture then drops its
+/// stream and its end of the queue, so each queue closes
+/// once it is drained, compute passes on what it holds and
+/// closes the queue after it, and storage writes the last
+/// updates and finalizes its open segments before it
+/// returns. The stop is ordered by the queues themselves,
+/// main only has to join the threads, flush the log, and
+/// give up on any thread that takes longer than it should
+use crate::log::{Job, LogPipe};
+
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Poll, Waker};
+use core::time::Duration;
+
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Instant;
+use std::vec::Vec;
+
+/// the signal numbers on linux
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+/// what signal() returns when it could not install a handler
+const SIG_ERR: usize = usize::MAX;
+
+/// how often main looks at the flag, and how often
+/// join_within looks for finished threads
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+/// set by the signal handler, or by request
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// set once the tasks waiting on stopped have been woken
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// the tasks waiting on stopped
+static WAITING: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+/// this is the signal handler, it must not lock or
+/// allocate, so it only sets the flag
+extern "C" fn on_signal(_signum: i32) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// this function installs the handlers for SIGINT
+/// and SIGTERM, which ask the recorder to stop
+pub fn install(log: &LogPipe) {
+    for (signum, name) in [(SIGINT, "SIGINT"), (SIGTERM, "SIGTERM")] {
+        if unsafe { signal(signum, on_signal) } == SIG_ERR {
+            crate::error!(
+                log,
+                Job::Main,
+                "could not install the {} handler",
+                name
+            );
+        }
+    }
+}
+
+/// this function asks every task waiting on stopped to
+/// stop, it is what a signal leads to, once main sees it
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+    STOPPING.store(true, Ordering::SeqCst);
+    let waiting = match WAITING.lock() {
+        Ok(mut waiting) => core::mem::take(&mut *waiting),
+        Err(poisoned) => core::mem::take(&mut *poisoned.into_inner()),
+    };
+    for waker in waiting {
+        waker.wake();
+    }
+}
+
+/// whether a signal or request has asked us to stop
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// this function waits until request is called, capture
+/// tasks hold their streams open until it returns
+pub async fn stopped() {
+    poll_fn(|cx| {
+        if STOPPING.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        match WAITING.lock() {
+            Ok(mut waiting) => waiting.push(cx.waker().clone()),
+            Err(poisoned) => poisoned.into_inner().push(cx.waker().clone()),
+        }
+        // request may have run before the waker was stored
+        if STOPPING.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// this function calls `every` once a `period` until a
+/// signal asks us to stop, then wakes the capture tasks
+pub fn wait(period: Duration, mut every: impl FnMut()) {
+    let mut next = Instant::now() + period;
+    while !requested() {
+        std::thread::sleep(POLL_INTERVAL);
+        if Instant::now() >= next {
+            every();
+            next += period;
+        }
+    }
+    request();
+}
+
+/// this function joins each thread that finishes before
+/// `timeout`, returning the ones that are still running
+pub fn join_within(
+    threads: Vec<JoinHandle<()>>,
+    timeout: Duration,
+) -> Vec<JoinHandle<()>> {
+    let deadline = Instant::now() + timeout;
+    let mut running = threads;
+    loop {
+        let (finished, still_running): (Vec<_>, Vec<_>) =
+            running.into_iter().partition(|thread| thread.is_finished());
+        running = still_running;
+        for thread in finished {
+            // a panicked thread has already been logged
+            // by the panic hook, there is nothing to undo
+            let _ = thread.join();
+        }
+        if running.is_empty() || Instant::now() >= deadline {
+            return running;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// this function forgets any earlier request, so
+/// tests that stop a pipeline can run one after another
+#[cfg(test)]
+pub(crate) fn reset() {
+    REQUESTED.store(false, Ordering::SeqCst);
+    STOPPING.store(false, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn join_within_gives_up_on_a_stuck_thread() {
+        let (release, stuck) = mpsc::channel::<()>();
+        let threads = std::vec![
+            std::thread::spawn(|| {}),
+            std::thread::spawn(move || {
+                let _ = stuck.recv();
+            }),
+        ];
+        let running = join_within(threads, Duration::from_millis(100));
+        assert_eq!(running.len(), 1);
+        let _ = release.send(());
+        assert!(join_within(running, Duration::from_secs(5)).is_empty());
+    }
+}
@@ -0,0 +1,646 @@
+/// This is where the whole recorder is run on a simulated
+/// clock, for tests of what only shows over hours of
+/// recording, like segments, retention, bookmarks and
+/// removable storage coming and going. A made up camera and
+/// microphone feed the pipeline recorder::start builds for
+/// main, storage writes to a directory of its own under the
+/// temp directory, and it and removable storage are the
+/// simulated devices of hardware::storage, which a test can
+/// pull out, fill or break from under the recorder. The clock only
+/// moves when the test runs the simulation on, a STEP at a
+/// time, and each step waits for the sources to give
+/// everything up to the new time and for the queues to
+/// empty, so the files, catalog and log lines after a step
+/// are always the same
+use crate::config::Config;
+use crate::control::{Controls, Setting, Settings};
+use crate::hardware::storage::mounts::{Removable, REMOVABLE_DIRECTORY};
+use crate::hardware::storage::simulated::Devices;
+use crate::log::{Job, LogPipe, LogUpdate};
+use crate::pipeline::Link;
+use crate::queue::{AudioUpdate, Buffer, Receiver, VideoUpdate};
+use crate::recorder::{Source, Sources};
+use crate::recordings::catalog::Bookmark;
+use crate::time::{simulated, Timestamp};
+use crate::{queue, recorder, shutdown};
+
+use core::future::Future;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::format;
+use std::path::PathBuf;
+use std::string::{String, ToString};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use std::vec::Vec;
+
+const SECOND: u64 = 1_000_000_000;
+
+/// the wall clock every simulation starts at,
+/// 2023-07-27T22:00:00Z
+pub const START: u64 = 1_690_495_200 * SECOND;
+
+/// how far the clock moves between looks at the pipeline
+pub const STEP: Duration = Duration::from_secs(60);
+
+/// how often the made up camera gives a frame, and the
+/// made up microphone a chunk of audio
+pub const PERIOD: Duration = Duration::from_secs(5);
+
+/// samples per second from the made up microphone
+pub const SAMPLE_RATE: u32 = 100;
+
+/// the simulated device the main directory is on
+pub const MAIN_DEVICE: &str = "MAIN";
+
+/// the queues a frame goes through on its way to motion
+/// detection, which only keeps the latest few
+const FRAME_QUEUES: [&str; 3] = [
+    queue::VIDEO_IN.name,
+    queue::VIDEO_COMPUTED.name,
+    queue::MOTION.name,
+];
+
+/// how long a step may take to settle before the
+/// simulation gives up on the pipeline
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the queues between the sources and storage or the UI, a
+/// step is over once they are all empty
+const PIPELINE_QUEUES: [&str; 7] = [
+    queue::AUDIO_IN.name,
+    queue::AUDIO_STORAGE.name,
+    queue::VIDEO_IN.name,
+    queue::VIDEO_COMPUTED.name,
+    queue::VIDEO_STORAGE.name,
+    queue::MOTION.name,
+    queue::VIEW_OUT.name,
+];
+
+/// the recorder running on a simulated clock
+pub struct Simulation {
+    /// the temp directory everything is written under
+    pub root: PathBuf,
+    /// the main storage directory
+    pub main: String,
+    pub settings: Settings,
+    pub controls: Controls,
+    /// every log line so far, in order
+    pub lines: Vec<String>,
+    log: LogPipe,
+    log_out: Receiver<LogUpdate>,
+    /// set each time the clock moves, waking the sources
+    clock: Setting<Timestamp>,
+    /// the wall clock each source has given updates up to
+    produced: [Arc<AtomicU64>; 2],
+    /// the times the made up camera sees movement
+    movement: Arc<Mutex<Vec<Range<u64>>>>,
+    /// the removable storage that can be plugged in
+    pub devices: Devices,
+    removable: Removable,
+    /// the queues made before the simulation started
+    queues_before: usize,
+    threads: Vec<JoinHandle<()>>,
+    view_queue: Option<queue::Sender<crate::ui::ViewUpdate>>,
+    _pipe: MutexGuard<'static, ()>,
+}
+
+impl Simulation {
+    /// this function starts the recorder at START, with the
+    /// configuration `configure` makes of the defaults
+    pub fn start(
+        name: &str,
+        configure: impl FnOnce(&mut Config),
+    ) -> Simulation {
+        let pipe = crate::log::TEST_PIPE
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        shutdown::reset();
+
+        let root = std::env::temp_dir().join(format!(
+            "simulation_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        let main = root.join("main");
+        std::fs::create_dir_all(&main).unwrap();
+        std::fs::create_dir_all(root.join("media")).unwrap();
+        let mut devices = Devices::new(&root);
+        devices.insert_at(MAIN_DEVICE, &main, None);
+        devices.mount(MAIN_DEVICE);
+        let main = main.display().to_string();
+
+        let mut config = Config::default();
+        config.storage.directory = main.clone();
+        config.storage.mount_table = devices.mount_table();
+        config.storage.removable_root = devices.removable_root();
+        config.log.file = root.join("main_log.txt").display().to_string();
+        config.log.json_file =
+            root.join("main_log.jsonl").display().to_string();
+        config.control.socket = String::new();
+        config.http.address = String::new();
+        configure(&mut config);
+
+        let started = simulated::start(START);
+        let queues_before = queue::telemetry::made();
+        let (log_out, out_sender) = queue::LOG_OUT.open();
+        let (log_storage, storage_sender) = queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+        log.set_filter(config.log.filter.clone());
+        let settings = Settings::new(&config);
+        let controls = Controls::default();
+
+        let clock = Setting::new(started);
+        let produced =
+            [Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0))];
+        let movement = Arc::new(Mutex::new(Vec::new()));
+        let sources = Sources {
+            audio: Some(microphone(clock.clone(), produced[0].clone())),
+            video: Some(camera(
+                clock.clone(),
+                produced[1].clone(),
+                movement.clone(),
+                queues_before,
+            )),
+        };
+        let running = recorder::start(
+            &config,
+            &settings,
+            &controls,
+            sources,
+            &log,
+            Link::new(log_storage),
+        );
+        let mut simulation = Simulation {
+            root,
+            main,
+            devices,
+            removable: Removable::new(&config.storage, &controls),
+            settings,
+            controls,
+            lines: Vec::new(),
+            log,
+            log_out,
+            clock,
+            produced,
+            movement,
+            queues_before,
+            threads: running.threads,
+            view_queue: Some(running.view_queue),
+            _pipe: pipe,
+        };
+        simulation.settle(START);
+        simulation
+    }
+
+    /// the wall clock `since_start` after START
+    pub fn at(since_start: Duration) -> u64 {
+        START + since_start.as_nanos() as u64
+    }
+
+    /// this function has the camera see movement from
+    /// `from` to `to` after START
+    pub fn movement(&self, from: Duration, to: Duration) {
+        self.movement
+            .lock()
+            .unwrap()
+            .push(Simulation::at(from)..Simulation::at(to));
+    }
+
+    /// this function moves the clock on by `duration`, a STEP
+    /// at a time, letting the pipeline catch up at each
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = crate::time::now().wall_nanos + duration.as_nanos() as u64;
+        while crate::time::now().wall_nanos < until {
+            let now = simulated::advance(STEP);
+            self.clock.set(now);
+            self.settle(now.wall_nanos);
+            self.removable.poll(&self.settings.storage, &self.log);
+        }
+    }
+
+    /// this function waits for both sources to give their
+    /// updates up to `wall_nanos`, and for every queue
+    /// between them and storage to be emptied
+    fn settle(&mut self, wall_nanos: u64) {
+        let deadline = Instant::now() + SETTLE_TIMEOUT;
+        let mut quiet = 0;
+        while quiet < 2 {
+            self.drain_log();
+            let caught_up = self
+                .produced
+                .iter()
+                .all(|produced| produced.load(Ordering::SeqCst) >= wall_nanos);
+            let pending =
+                queue::telemetry::pending(self.queues_before, &PIPELINE_QUEUES);
+            if caught_up && pending == 0 {
+                quiet += 1;
+            } else {
+                quiet = 0;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "the pipeline did not catch up with {}, {} updates queued",
+                Timestamp {
+                    wall_nanos,
+                    monotonic_nanos: 0,
+                }
+                .rfc3339(),
+                pending
+            );
+            std::thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    fn drain_log(&mut self) {
+        while let Ok(update) = self.log_out.try_dequeue() {
+            self.lines.push(update.user_string);
+        }
+    }
+
+    /// this function plugs in and mounts removable storage
+    /// called `name`, returning the directory recordings
+    /// will go to on it
+    pub fn insert(&mut self, name: &str) -> String {
+        self.devices.insert(name, None);
+        self.devices.mount(name);
+        self.removable.poll(&self.settings.storage, &self.log);
+        self.recording_directory(name)
+    }
+
+    /// the directory recordings go to on the removable
+    /// storage called `name`
+    pub fn recording_directory(&self, name: &str) -> String {
+        std::path::Path::new(&self.devices.directory(name))
+            .join(REMOVABLE_DIRECTORY)
+            .display()
+            .to_string()
+    }
+
+    /// this function unmounts and pulls out the removable
+    /// storage called `name`, after it is noticed to be
+    /// going, tests that pull it out from under the
+    /// recorder use devices
+    pub fn remove(&mut self, name: &str) {
+        self.devices.remove(name);
+        self.removable.poll(&self.settings.storage, &self.log);
+    }
+
+    /// this function bookmarks now, as the M key does
+    pub fn mark(&self, note: &str) -> Bookmark {
+        self.controls
+            .mark(note, &self.settings.storage.get(), &self.log)
+            .unwrap()
+    }
+
+    /// whether a log line so far contains `text`
+    pub fn logged(&self, text: &str) -> bool {
+        self.lines.iter().any(|line| line.contains(text))
+    }
+
+    /// this function stops the recorder as main does, once
+    /// every thread has finished the log is shut down too
+    pub fn stop(&mut self) {
+        let stuck = self.shut_down();
+        assert_eq!(stuck, 0, "{} threads did not stop", stuck);
+    }
+
+    /// this function stops everything the simulation
+    /// started, returning how many threads did not stop
+    fn shut_down(&mut self) -> usize {
+        let view_queue = match self.view_queue.take() {
+            Some(view_queue) => view_queue,
+            None => return 0,
+        };
+        shutdown::request();
+        drop(view_queue);
+        let (log_threads, threads): (Vec<_>, Vec<_>) = self
+            .threads
+            .drain(..)
+            .partition(|thread| thread.thread().name() == Some("log effects"));
+        let stuck = shutdown::join_within(threads, SETTLE_TIMEOUT);
+        LogPipe::shutdown();
+        let log_stuck = shutdown::join_within(log_threads, SETTLE_TIMEOUT);
+        self.drain_log();
+        shutdown::reset();
+        simulated::stop();
+        stuck.len() + log_stuck.len()
+    }
+}
+
+impl Drop for Simulation {
+    /// a failed test still stops the recorder, so the
+    /// tests after it get the log pipe, its files are
+    /// kept to be looked at
+    fn drop(&mut self) {
+        self.shut_down();
+        if !std::thread::panicking() {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+}
+
+/// this function waits for the clock to move, returning
+/// false once a shutdown is requested instead
+async fn ticked(clock: &mut Setting<Timestamp>) -> bool {
+    let mut stopped = core::pin::pin!(shutdown::stopped());
+    let mut changed = core::pin::pin!(clock.changed());
+    core::future::poll_fn(|cx| {
+        if stopped.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(false);
+        }
+        changed.as_mut().poll(cx).map(|_| true)
+    })
+    .await
+}
+
+/// this function returns once motion detection has taken
+/// every frame sent to it, or false if the recorder stopped
+/// first, as a real camera gives its frames far apart
+async fn taken(since: usize) -> bool {
+    let mut stopped = core::pin::pin!(shutdown::stopped());
+    core::future::poll_fn(|cx| {
+        if stopped.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(false);
+        }
+        if queue::telemetry::pending(since, &FRAME_QUEUES) == 0 {
+            return Poll::Ready(true);
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// this function returns when an update given at
+/// `wall_nanos` was captured, on both clocks
+fn captured_at(wall_nanos: u64) -> Timestamp {
+    let now = crate::time::now();
+    Timestamp {
+        wall_nanos,
+        monotonic_nanos: now.monotonic_nanos - (now.wall_nanos - wall_nanos),
+    }
+}
+
+/// this function makes a microphone that hears a steady
+/// tone, one chunk each PERIOD
+fn microphone(
+    clock: Setting<Timestamp>,
+    produced: Arc<AtomicU64>,
+) -> Source<AudioUpdate> {
+    let name: Arc<str> = Arc::from("simulated microphone");
+    Box::new(move |sender, microphone_log| {
+        let mut clock = clock.clone();
+        let produced = produced.clone();
+        let name = name.clone();
+        Box::pin(async move {
+            crate::info!(
+                microphone_log,
+                Job::AudioInput,
+                "simulated microphone started"
+            );
+            clock.current();
+            let mut next = produced.load(Ordering::SeqCst).max(START);
+            loop {
+                let now = crate::time::now().wall_nanos;
+                while next <= now {
+                    let frames =
+                        (PERIOD.as_secs() * SAMPLE_RATE as u64) as usize;
+                    let data = (0..frames)
+                        .map(|frame| if frame % 10 < 5 { 0.5 } else { -0.5 })
+                        .collect();
+                    let update = AudioUpdate {
+                        data: Buffer::unpooled(data),
+                        timestamp: captured_at(next),
+                        pts: next - START,
+                        sample_rate: SAMPLE_RATE,
+                        channels: 1,
+                        name: name.clone(),
+                    };
+                    if let Err((_, err)) = sender.enqueue(update) {
+                        crate::error!(
+                            microphone_log,
+                            Job::AudioInput,
+                            "could not send simulated audio: {:?}",
+                            err
+                        );
+                    }
+                    next += PERIOD.as_nanos() as u64;
+                }
+                produced.store(now, Ordering::SeqCst);
+                if !ticked(&mut clock).await {
+                    break;
+                }
+            }
+        })
+    })
+}
+
+/// this function makes a camera that sees a still scene,
+/// except while there is movement, each PERIOD a frame,
+/// giving each once the one before it has been taken
+fn camera(
+    clock: Setting<Timestamp>,
+    produced: Arc<AtomicU64>,
+    movement: Arc<Mutex<Vec<Range<u64>>>>,
+    queues_before: usize,
+) -> Source<VideoUpdate> {
+    Box::new(move |sender, camera_log| {
+        let mut clock = clock.clone();
+        let produced = produced.clone();
+        let movement = movement.clone();
+        Box::pin(async move {
+            crate::info!(
+                camera_log,
+                Job::VideoInput,
+                "simulated camera started"
+            );
+            clock.current();
+            let mut next = produced.load(Ordering::SeqCst).max(START);
+            loop {
+                let now = crate::time::now().wall_nanos;
+                while next <= now {
+                    if !taken(queues_before).await {
+                        return;
+                    }
+                    let sequence = (next - START) / PERIOD.as_nanos() as u64;
+                    let moving = movement
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .any(|window| window.contains(&next));
+                    let update = VideoUpdate {
+                        timestamp: captured_at(next),
+                        pts: next - START,
+                        sequence,
+                        data: Buffer::unpooled(frame(sequence, moving)),
+                        keyframe: true,
+                    };
+                    if let Err((_, err)) = sender.enqueue(update) {
+                        crate::error!(
+                            camera_log,
+                            Job::VideoInput,
+                            "could not send a simulated frame: {:?}",
+                            err
+                        );
+                    }
+                    next += PERIOD.as_nanos() as u64;
+                }
+                produced.store(now, Ordering::SeqCst);
+                if !ticked(&mut clock).await {
+                    break;
+                }
+            }
+        })
+    })
+}
+
+/// this function makes a tiny 16x16 JPEG, as far as its
+/// header goes, whose body is blank for a still scene and
+/// different in every frame while something moves
+fn frame(sequence: u64, moving: bool) -> Vec<u8> {
+    let mut data = std::vec![
+        0xff, 0xd8, // start of image
+        0xff, 0xc0, 0x00, 0x0b, 0x08, // baseline, 8 bit
+        0x00, 0x10, 0x00, 0x10, // 16 high, 16 wide
+        0x01, 0x01, 0x11, 0x00, // one component
+    ];
+    data.extend((0..64u64).map(|at| {
+        if moving {
+            (sequence * 37 + at) as u8
+        } else {
+            0
+        }
+    }));
+    data.extend_from_slice(&[0xff, 0xd9]);
+    data
+}
+
+/// the time `minutes` and `seconds` after START
+pub fn after(minutes: u64, seconds: u64) -> Duration {
+    Duration::from_secs(minutes * 60 + seconds)
+}
+
+/// the names of the audio and the video files recorded to
+/// `directory`, in order
+pub fn recorded(directory: &str) -> (Vec<String>, Vec<String>) {
+    let file_name = |recording: crate::recordings::Recording| {
+        std::path::Path::new(&recording.path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut audio: Vec<String> = crate::recordings::scan(directory)
+        .unwrap()
+        .into_iter()
+        .map(file_name)
+        .collect();
+    let mut video: Vec<String> = crate::recordings::scan_video(directory)
+        .unwrap()
+        .into_iter()
+        .map(file_name)
+        .collect();
+    audio.sort();
+    video.sort();
+    (audio, video)
+}
+
+/// the names of the audio and the video files that start
+/// at `starts` after START, as recorded would list them
+pub fn recorded_at(starts: &[Duration]) -> (Vec<String>, Vec<String>) {
+    let timestamps: Vec<Timestamp> = starts
+        .iter()
+        .map(|start| Timestamp {
+            wall_nanos: Simulation::at(*start),
+            monotonic_nanos: 0,
+        })
+        .collect();
+    (
+        timestamps
+            .iter()
+            .map(crate::recordings::audio_file_name)
+            .collect(),
+        timestamps
+            .iter()
+            .map(crate::recordings::video_file_name)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_three_hours_across_removable_storage() {
+        let mut simulation = Simulation::start("three_hours", |config| {
+            config.storage.segment_minutes = 10;
+            config.storage.retention_hours = 1;
+        });
+        simulation.movement(after(20, 0), after(25, 0));
+        simulation.run_for(after(30, 0));
+        let bookmark = simulation.mark("someone at the door");
+        simulation.run_for(after(60, 0));
+        let stick = simulation.insert("STICK");
+        simulation.run_for(after(30, 0));
+        simulation.remove("STICK");
+        simulation.run_for(after(60, 0));
+        simulation.stop();
+
+        // the bookmark kept the two segments around it past
+        // the retention, and everything older went
+        let main = simulation.main.clone();
+        assert_eq!(
+            recorded(&main),
+            recorded_at(&[
+                after(20, 0),
+                after(30, 0),
+                after(120, 5),
+                after(130, 0),
+                after(140, 0),
+                after(150, 0),
+                after(160, 0),
+                after(170, 0),
+                after(180, 0),
+            ])
+        );
+        // the stick has what was recorded while it was in
+        assert_eq!(
+            recorded(&stick),
+            recorded_at(&[
+                after(90, 5),
+                after(100, 0),
+                after(110, 0),
+                after(120, 0)
+            ])
+        );
+        let bookmarks = crate::recordings::catalog::read(&main).unwrap();
+        assert_eq!(bookmarks, std::vec![bookmark.clone()]);
+        assert_eq!(bookmark.at, Simulation::at(after(30, 0)));
+
+        // 240 frames of 5s is 20 minutes, and the first still
+        // frame still differs from the last moving one
+        assert!(simulation.logged("motion started at frame 240,"));
+        assert!(simulation.logged("motion stopped at frame 301,"));
+        assert!(simulation.logged(&format!(
+            "removable storage mounted, recording to {}",
+            stick
+        )));
+        assert!(simulation.logged(&format!(
+            "removable storage removed, recording to {}",
+            main
+        )));
+        assert!(simulation.logged("it is older than 1 hours"));
+        let stored =
+            std::fs::read_to_string(simulation.root.join("main_log.txt"))
+                .unwrap();
+        assert!(stored.contains("event marked"));
+    }
+}
@@ -0,0 +1,262 @@
+/// This is where a panicking task is caught and started
+/// again. Every failure in capture and storage panics, so
+/// without this an unplugged microphone or a bad write
+/// takes its whole thread down, and with it every queue
+/// that thread drains. Each task runs under a Restart
+/// policy: a panic is caught where the task is polled, its
+/// message is logged under the task's Job, and after a
+/// backoff that doubles with every panic the task is made
+/// again from its queues, which the pipeline kept for it.
+/// A task that ran for longer than the longest backoff
+/// starts again from the shortest. The other tasks on the
+/// thread keep running the whole time
+use crate::log::{Job, LogPipe};
+use crate::pipeline::StageFuture;
+
+use core::any::Any;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::string::{String, ToString};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// this decides whether, and how soon, a task that
+/// panicked is started again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Restart {
+    /// the wait before the first restart
+    pub initial: Duration,
+    /// the longest wait, however often it panics
+    pub max: Duration,
+    /// how many restarts in a row are allowed, None
+    /// for as many as it takes
+    pub limit: Option<u32>,
+}
+
+impl Restart {
+    /// the task is never started again
+    pub const NEVER: Restart = Restart {
+        initial: Duration::ZERO,
+        max: Duration::ZERO,
+        limit: Some(0),
+    };
+
+    /// devices come and go, so capture keeps trying, but
+    /// slowly, while a microphone or camera is unplugged
+    pub const CAPTURE: Restart = Restart {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(30),
+        limit: None,
+    };
+
+    /// storage and the UI are retried quickly, as every
+    /// moment they are down their queues fill up
+    pub const QUICKLY: Restart = Restart {
+        initial: Duration::from_millis(100),
+        max: Duration::from_secs(5),
+        limit: None,
+    };
+
+    /// this function returns the wait before restart
+    /// number `restarts`, counting from zero
+    pub fn backoff(&self, restarts: u32) -> Duration {
+        let doubled = self
+            .initial
+            .checked_mul(1u32.checked_shl(restarts).unwrap_or(u32::MAX))
+            .unwrap_or(self.max);
+        doubled.min(self.max)
+    }
+
+    fn allows(&self, restarts: u32) -> bool {
+        match self.limit {
+            Some(limit) => restarts < limit,
+            None => true,
+        }
+    }
+}
+
+/// this function returns the message a panic was given
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "a panic without a message".to_string()
+    }
+}
+
+/// this function runs `task` until it returns, catching
+/// the panics of the future it makes and making another
+/// under `restart`, `make` returns None once it has
+/// nothing left to make a task from
+pub async fn supervise<F>(
+    name: &'static str,
+    job: Job,
+    restart: Restart,
+    log: LogPipe,
+    mut make: F,
+) where
+    F: FnMut(LogPipe) -> Option<StageFuture>,
+{
+    let mut restarts = 0;
+    while let Some(mut task) = make(log.clone()) {
+        let started = Instant::now();
+        let caught = poll_fn(|cx| {
+            match catch_unwind(AssertUnwindSafe(|| task.as_mut().poll(cx))) {
+                Ok(Poll::Ready(())) => Poll::Ready(None),
+                Ok(Poll::Pending) => Poll::Pending,
+                Err(payload) => Poll::Ready(Some(panic_message(&*payload))),
+            }
+        })
+        .await;
+        let message = match caught {
+            Some(message) => message,
+            None => return,
+        };
+        // the panicked task is dropped here, giving its
+        // receiver back to the pipeline
+        drop(task);
+        crate::error!(log, job.clone(), "{} task panicked: {}", name, message);
+        if started.elapsed() > restart.max {
+            restarts = 0;
+        }
+        if crate::shutdown::requested() {
+            return;
+        }
+        if !restart.allows(restarts) {
+            crate::error!(
+                log,
+                job.clone(),
+                "{} task will not be restarted",
+                name
+            );
+            return;
+        }
+        let backoff = restart.backoff(restarts);
+        crate::warn!(
+            log,
+            job.clone(),
+            "restarting {} task in {:?}",
+            name,
+            backoff
+        );
+        delay(backoff).await;
+        restarts += 1;
+    }
+}
+
+/// this function waits out `duration`, or until a
+/// shutdown is requested, without holding up the
+/// other tasks on the thread
+pub async fn delay(duration: Duration) {
+    let done = Arc::new(AtomicBool::new(false));
+    let mut waker_sent = false;
+    poll_fn(|cx| {
+        if done.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        if !waker_sent {
+            waker_sent = true;
+            let done = done.clone();
+            let waker = cx.waker().clone();
+            let deadline = Instant::now() + duration;
+            let spawned = std::thread::Builder::new()
+                .name("restart backoff".into())
+                .spawn(move || {
+                    while Instant::now() < deadline
+                        && !crate::shutdown::requested()
+                    {
+                        std::thread::sleep(
+                            (deadline - Instant::now())
+                                .min(Duration::from_millis(50)),
+                        );
+                    }
+                    done.store(true, Ordering::SeqCst);
+                    waker.wake();
+                });
+            if spawned.is_err() {
+                return Poll::Ready(());
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let restart = Restart {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            limit: None,
+        };
+        let waits: Vec<u128> =
+            (0..6).map(|n| restart.backoff(n).as_millis()).collect();
+        assert_eq!(waits, std::vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(restart.backoff(40), Duration::from_secs(1));
+        assert!(!Restart::NEVER.allows(0));
+    }
+
+    #[test]
+    fn restarts_a_panicked_task_and_logs_it_under_its_job() {
+        let _pipe = crate::log::TEST_PIPE
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        crate::shutdown::reset();
+        let (_out, out_sender) = crate::queue::LOG_OUT.open();
+        let (mut stored, storage_sender) = crate::queue::LOG_STORAGE.open();
+        let log = LogPipe::set_pipe(out_sender, storage_sender);
+
+        let restart = Restart {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(10),
+            limit: Some(5),
+        };
+        let mut attempts = 0;
+        let tasks = pasts::Executor::default();
+        let task_log = log.clone();
+        tasks.clone().block_on(async move {
+            tasks.spawn_boxed(supervise(
+                "flaky",
+                Job::AudioInput,
+                restart,
+                task_log,
+                move |_log| {
+                    attempts += 1;
+                    let attempt = attempts;
+                    Some(std::boxed::Box::pin(async move {
+                        if attempt < 3 {
+                            panic!("device unplugged, attempt {}", attempt);
+                        }
+                    }))
+                },
+            ));
+        });
+        LogPipe::shutdown();
+
+        let mut panics = Vec::new();
+        while let Ok(update) = stored.try_dequeue() {
+            if update.user_string.contains("panicked") {
+                assert_eq!(update.job, Job::AudioInput);
+                panics.push(update.user_string);
+            }
+        }
+        assert_eq!(
+            panics,
+            std::vec![
+                "flaky task panicked: device unplugged, attempt 1",
+                "flaky task panicked: device unplugged, attempt 2",
+            ]
+        );
+    }
+}
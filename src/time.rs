@@ -0,0 +1,473 @@
+/// This is where every update gets its time from.
+/// Each Timestamp carries two clocks: the wall clock, in
+/// nanoseconds since the UNIX_EPOCH, which is what goes
+/// in filenames and log lines, and a monotonic clock, in
+/// nanoseconds since the recorder started, which never
+/// goes backwards and is what we use to order and space
+/// frames. Both are measured against one anchor taken the
+/// first time the time is asked for.
+/// The wall clock on the laptop can jump, when NTP steps
+/// it or when the RTC battery is flat and it comes up in
+/// 1970, so each reading of the system clock is checked
+/// against the wall time the anchor predicts. A reading
+/// that disagrees by more than JUMP_THRESHOLD is counted
+/// as a jump and becomes the new reference, and a reading
+/// that is before MIN_VALID_WALL is ignored in favour of
+/// the prediction, rather than panicking like we used to.
+/// When the anchor itself was taken before the wall clock
+/// was valid, the first valid reading re-anchors the wall
+/// clock instead of being counted as a jump.
+/// Under test the clock can be simulated instead, see
+/// simulated
+pub mod presentation;
+#[cfg(test)]
+pub mod simulated;
+
+use core::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::log::{Job, LogPipe};
+
+use std::format;
+use std::string::String;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime};
+
+/// how far the system clock may drift from the anchor's
+/// prediction before we call it a jump
+pub const JUMP_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// any wall clock earlier than 2023-01-01T00:00:00Z must
+/// come from an RTC that lost its time, so we never trust it
+pub const MIN_VALID_WALL: u64 = 1_672_531_200 * NANOS_PER_SECOND;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// the single point in time that both clocks are measured from
+struct Anchor {
+    /// the monotonic instant the recorder started at
+    instant: Instant,
+    /// the wall clock at that instant, in nanoseconds
+    wall_nanos: u64,
+}
+
+static ANCHOR: OnceLock<Anchor> = OnceLock::new();
+
+/// the correction added to the anchor's wall clock,
+/// moved each time the system clock jumps
+static WALL_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// the number of clock jumps seen since startup
+static JUMPS: AtomicU64 = AtomicU64::new(0);
+
+/// how far the wall clock moved at the last jump,
+/// in nanoseconds
+static LAST_JUMP: AtomicI64 = AtomicI64::new(0);
+
+/// the number of clock jumps report has logged
+static REPORTED: AtomicU64 = AtomicU64::new(0);
+
+/// how reconcile moves the offset to follow the system clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Correction {
+    /// the system clock jumped away from our prediction
+    Jump(i64),
+    /// the system clock became valid for the first time
+    /// since the anchor was taken before it was set
+    Anchor(i64),
+}
+
+/// this is the time an update was captured at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Timestamp {
+    /// nanoseconds since the UNIX_EPOCH (1 Jan 1970)
+    pub wall_nanos: u64,
+    /// nanoseconds since the recorder started,
+    /// this never goes backwards
+    pub monotonic_nanos: u64,
+}
+
+/// this function returns the current time on both clocks
+pub fn now() -> Timestamp {
+    #[cfg(test)]
+    if let Some(simulated) = simulated::current() {
+        return simulated;
+    }
+    let anchor = ANCHOR.get_or_init(|| Anchor {
+        instant: Instant::now(),
+        wall_nanos: system_wall_nanos().unwrap_or(0),
+    });
+    let monotonic_nanos = anchor.instant.elapsed().as_nanos() as u64;
+    let offset = WALL_OFFSET.load(Ordering::SeqCst);
+    let (wall_nanos, new_offset) = reconcile(
+        anchor.wall_nanos,
+        offset,
+        monotonic_nanos,
+        system_wall_nanos(),
+    );
+    match new_offset {
+        Some(Correction::Jump(new_offset)) => {
+            // only the first thread to notice a jump counts it
+            if WALL_OFFSET
+                .compare_exchange(
+                    offset,
+                    new_offset,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_ok()
+            {
+                LAST_JUMP.store(new_offset - offset, Ordering::SeqCst);
+                JUMPS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Some(Correction::Anchor(new_offset)) => {
+            let _ = WALL_OFFSET.compare_exchange(
+                offset,
+                new_offset,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+        }
+        None => {}
+    }
+    Timestamp {
+        wall_nanos,
+        monotonic_nanos,
+    }
+}
+
+/// this function returns how many times the wall
+/// clock has jumped since the recorder started
+pub fn clock_jumps() -> u64 {
+    JUMPS.load(Ordering::SeqCst)
+}
+
+/// this function logs the clock jumps seen since it was
+/// last called, it is called on main's tick since now()
+/// has no log to write to
+pub fn report(log: &LogPipe) {
+    let jumps = clock_jumps();
+    let reported = REPORTED.swap(jumps, Ordering::SeqCst);
+    if jumps > reported {
+        crate::warn!(
+            log,
+            Job::Main,
+            "the wall clock jumped by {:+.3}s, {} jumps since the \
+             recorder started",
+            LAST_JUMP.load(Ordering::SeqCst) as f64 / NANOS_PER_SECOND as f64,
+            jumps
+        );
+    }
+}
+
+/// this function reads the system clock, returning None
+/// when it is set before the UNIX_EPOCH
+fn system_wall_nanos() -> Option<u64> {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since_1970) => Some(since_1970.as_nanos() as u64),
+        Err(_) => None,
+    }
+}
+
+/// this function decides which wall time to report, given
+/// the anchor, the current offset, the monotonic time and
+/// what the system clock says, returning a new offset when
+/// the system clock has jumped away from our prediction or
+/// is valid for the first time since an invalid anchor
+fn reconcile(
+    anchor_wall_nanos: u64,
+    offset: i64,
+    monotonic_nanos: u64,
+    system_nanos: Option<u64>,
+) -> (u64, Option<Correction>) {
+    let predicted =
+        anchor_wall_nanos as i128 + offset as i128 + monotonic_nanos as i128;
+    let predicted = predicted.clamp(0, u64::MAX as i128) as u64;
+    match system_nanos {
+        Some(system) if system >= MIN_VALID_WALL => {
+            let drift = (system as i128 - predicted as i128).unsigned_abs();
+            let new_offset = (system as i128
+                - anchor_wall_nanos as i128
+                - monotonic_nanos as i128) as i64;
+            if predicted < MIN_VALID_WALL {
+                // the anchor was taken before the clock was set
+                (system, Some(Correction::Anchor(new_offset)))
+            } else if drift > JUMP_THRESHOLD.as_nanos() {
+                (system, Some(Correction::Jump(new_offset)))
+            } else {
+                (system, None)
+            }
+        }
+        // the system clock is unset or was reset, keep
+        // counting from the last wall time we believed
+        _ => (predicted, None),
+    }
+}
+
+impl Timestamp {
+    /// whole seconds since the UNIX_EPOCH
+    pub fn as_secs(&self) -> u64 {
+        self.wall_nanos / NANOS_PER_SECOND
+    }
+
+    /// the time between an earlier timestamp and this one,
+    /// measured on the monotonic clock
+    pub fn since(&self, earlier: &Timestamp) -> Duration {
+        Duration::from_nanos(
+            self.monotonic_nanos.saturating_sub(earlier.monotonic_nanos),
+        )
+    }
+
+    /// the wall clock as a UTC calendar date and time,
+    /// with the fraction truncated to milliseconds
+    pub fn datetime(&self) -> iso8601::DateTime {
+        let seconds = self.as_secs();
+        let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+        let seconds_of_day = (seconds % 86_400) as u32;
+        iso8601::DateTime {
+            date: iso8601::Date::YMD { year, month, day },
+            time: iso8601::Time {
+                hour: seconds_of_day / 3600,
+                minute: seconds_of_day / 60 % 60,
+                second: seconds_of_day % 60,
+                millisecond: self.subsec_nanos() / 1_000_000,
+                tz_offset_hours: 0,
+                tz_offset_minutes: 0,
+            },
+        }
+    }
+
+    /// the wall clock formatted as RFC 3339 in UTC with
+    /// nanoseconds, e.g. 2023-07-27T23:31:46.123456789Z
+    pub fn rfc3339(&self) -> String {
+        let datetime = self.datetime();
+        format!(
+            "{}T{:02}:{:02}:{:02}.{:09}Z",
+            datetime.date,
+            datetime.time.hour,
+            datetime.time.minute,
+            datetime.time.second,
+            self.subsec_nanos(),
+        )
+    }
+
+    /// the wall clock in the RFC 3339 basic format, which
+    /// has no colons so it is safe in filenames on every
+    /// filesystem, e.g. 20230727T233146.123Z
+    pub fn for_filename(&self) -> String {
+        let datetime = self.datetime();
+        match datetime.date {
+            iso8601::Date::YMD { year, month, day } => format!(
+                "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+                year,
+                month,
+                day,
+                datetime.time.hour,
+                datetime.time.minute,
+                datetime.time.second,
+                datetime.time.millisecond,
+            ),
+            _ => unreachable!("datetime() always returns a calendar date"),
+        }
+    }
+
+    fn subsec_nanos(&self) -> u32 {
+        (self.wall_nanos % NANOS_PER_SECOND) as u32
+    }
+}
+
+/// this function reads a wall clock time written the way
+/// rfc3339 or for_filename write one, or any other ISO 8601
+/// calendar date and time, into nanoseconds since the
+/// UNIX_EPOCH, a date on its own is its midnight in UTC
+pub fn parse_wall(text: &str) -> anyhow::Result<u64> {
+    let datetime = match iso8601::datetime(text) {
+        Ok(datetime) => datetime,
+        Err(_) => iso8601::DateTime {
+            date: iso8601::date(text).map_err(|_| {
+                anyhow::anyhow!("{} is not a date or a date and time", text)
+            })?,
+            time: iso8601::Time::default(),
+        },
+    };
+    let days = match datetime.date {
+        iso8601::Date::YMD { year, month, day } => {
+            days_from_civil(year, month, day)
+        }
+        _ => anyhow::bail!("{} is not written as year, month and day", text),
+    };
+    let time = datetime.time;
+    let seconds = days * 86_400
+        + time.hour as i64 * 3600
+        + time.minute as i64 * 60
+        + time.second as i64
+        - time.tz_offset_hours as i64 * 3600
+        - time.tz_offset_minutes as i64 * 60;
+    // iso8601 keeps only milliseconds, so the nanoseconds
+    // rfc3339 writes are read again here
+    let nanos = match text.split_once('.') {
+        Some((_, fraction)) => {
+            let digits: String = fraction
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .chain(core::iter::repeat('0'))
+                .take(9)
+                .collect();
+            digits.parse::<u64>().unwrap_or(0)
+        }
+        None => 0,
+    };
+    if seconds < 0 {
+        anyhow::bail!("{} is before 1970", text);
+    }
+    Ok(seconds as u64 * NANOS_PER_SECOND + nanos)
+}
+
+/// this function turns a (year, month, day) back into a
+/// count of days since 1970-01-01, after Howard Hinnant's
+/// days_from_civil
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = year as i64 - if month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_index = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// this function turns a count of days since 1970-01-01
+/// into a (year, month, day) in the proleptic Gregorian
+/// calendar, after Howard Hinnant's civil_from_days
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+        - day_of_era / 146_096)
+        / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = NANOS_PER_SECOND;
+    /// 2023-07-27T23:31:46Z, from main_log.txt
+    const RECORDED: u64 = 1_690_500_706 * SECOND;
+
+    #[test]
+    fn formats_rfc3339_with_nanoseconds() {
+        let timestamp = Timestamp {
+            wall_nanos: RECORDED + 5_000_123,
+            monotonic_nanos: 0,
+        };
+        assert_eq!(timestamp.rfc3339(), "2023-07-27T23:31:46.005000123Z");
+        assert_eq!(timestamp.for_filename(), "20230727T233146.005Z");
+        let parsed = iso8601::datetime(&timestamp.rfc3339()).unwrap();
+        assert_eq!(parsed.date, timestamp.datetime().date);
+        assert_eq!(parsed.time.second, 46);
+    }
+
+    #[test]
+    fn converts_leap_days_and_the_epoch() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn parses_what_it_formats() {
+        let timestamp = Timestamp {
+            wall_nanos: RECORDED + 5_000_123,
+            monotonic_nanos: 0,
+        };
+        assert_eq!(parse_wall(&timestamp.rfc3339()).unwrap(), RECORDED + 5_000_123);
+        assert_eq!(parse_wall(&timestamp.for_filename()).unwrap(), RECORDED + 5_000_000);
+        assert_eq!(
+            parse_wall("2023-07-28T01:31:46+02:00").unwrap(),
+            RECORDED
+        );
+        assert_eq!(parse_wall("2023-07-27").unwrap(), RECORDED - 84_706 * SECOND);
+        for days in [0, 11_016, 19_782, 19_565] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert!(parse_wall("yesterday").is_err());
+    }
+
+    #[test]
+    fn follows_small_drift_without_jumping() {
+        let (wall, offset) =
+            reconcile(RECORDED, 0, 10 * SECOND, Some(RECORDED + 11 * SECOND));
+        assert_eq!(wall, RECORDED + 11 * SECOND);
+        assert_eq!(offset, None);
+    }
+
+    #[test]
+    fn re_anchors_when_ntp_steps_the_clock() {
+        let stepped = RECORDED + 3600 * SECOND;
+        let (wall, offset) =
+            reconcile(RECORDED, 0, 10 * SECOND, Some(stepped));
+        assert_eq!(wall, stepped);
+        let offset = match offset {
+            Some(Correction::Jump(offset)) => offset,
+            other => panic!("a one hour step is a jump, not {:?}", other),
+        };
+        let (wall, again) =
+            reconcile(RECORDED, offset, 11 * SECOND, Some(stepped + SECOND));
+        assert_eq!(wall, stepped + SECOND);
+        assert_eq!(again, None);
+    }
+
+    #[test]
+    fn keeps_counting_through_an_rtc_reset() {
+        let (wall, offset) =
+            reconcile(RECORDED, 0, 10 * SECOND, Some(5 * SECOND));
+        assert_eq!(wall, RECORDED + 10 * SECOND);
+        assert_eq!(offset, None);
+        let (wall, _) = reconcile(RECORDED, 0, 10 * SECOND, None);
+        assert_eq!(wall, RECORDED + 10 * SECOND);
+    }
+
+    #[test]
+    fn re_anchors_once_the_clock_is_first_set() {
+        // the anchor was taken while the RTC still read 1970
+        let anchor = 20 * SECOND;
+        let (wall, offset) = reconcile(anchor, 0, 10 * SECOND, Some(RECORDED));
+        assert_eq!(wall, RECORDED);
+        let offset = match offset {
+            Some(Correction::Anchor(offset)) => offset,
+            other => panic!("setting the clock is not a jump: {:?}", other),
+        };
+        let (wall, again) =
+            reconcile(anchor, offset, 11 * SECOND, Some(RECORDED + SECOND));
+        assert_eq!(wall, RECORDED + SECOND);
+        assert_eq!(again, None);
+        // and the prediction carries on from the new anchor
+        let (wall, _) = reconcile(anchor, offset, 12 * SECOND, None);
+        assert_eq!(wall, RECORDED + 2 * SECOND);
+    }
+
+    #[test]
+    fn monotonic_time_never_goes_backwards() {
+        let first = now();
+        let second = now();
+        assert!(second.monotonic_nanos >= first.monotonic_nanos);
+        assert!(first.wall_nanos >= MIN_VALID_WALL);
+    }
+}
@@ -0,0 +1,248 @@
+/// This is where audio gets presentation timestamps (pts),
+/// the time on the monotonic clock that each chunk of
+/// samples should be played at. Audio pts come from
+/// counting samples rather than from when the callback
+/// happened to run, so they are evenly spaced. They are
+/// started from the capture Timestamp of the first chunk,
+/// which puts them on the same monotonic clock as the
+/// video frames and lets storage line the two up.
+/// The sound card has its own crystal, which never runs
+/// at exactly the nominal rate, so every CORRECTION_WINDOW
+/// we measure the real rate against the monotonic clock
+/// and slew towards it, which keeps hours long recordings
+/// from drifting away from the video
+use super::Timestamp;
+
+use core::time::Duration;
+
+/// a presentation timestamp, in nanoseconds on the
+/// monotonic clock shared by audio and video
+pub type Pts = u64;
+
+/// how much capture time passes between rate corrections
+pub const CORRECTION_WINDOW: Duration = Duration::from_secs(10);
+
+/// when the pts and the capture time disagree by more than
+/// this, units were lost (a dropped frame, an xrun) and we
+/// start counting again from the capture time
+pub const RESYNC_THRESHOLD: Duration = Duration::from_millis(250);
+
+/// the furthest a measured rate may be from the nominal
+/// rate, in parts per million, anything further is a
+/// glitch rather than a slow crystal
+pub const MAX_DRIFT_PPM: f64 = 5_000.0;
+
+const NANOS_PER_SECOND: f64 = 1_000_000_000.0;
+
+/// this turns a count of units (audio samples per
+/// channel) into presentation timestamps
+#[derive(Debug, Clone)]
+pub struct PresentationClock {
+    /// the rate the device says it runs at, in units per second
+    nominal_rate: f64,
+    /// the rate we currently believe it runs at
+    rate: f64,
+    /// the pts of the unit numbered base_units
+    base_pts: Pts,
+    /// the unit our pts are currently counted from
+    base_units: u64,
+    /// how many units have been stamped so far
+    units: u64,
+    /// the capture time and unit count at the
+    /// start of the current correction window
+    window_start: Option<(Timestamp, u64)>,
+    /// how many times we had to start again
+    /// from the capture time
+    resyncs: u64,
+}
+
+impl PresentationClock {
+    /// a clock counting audio samples per channel
+    pub fn audio(sample_rate: u32) -> PresentationClock {
+        PresentationClock::with_rate(sample_rate as f64)
+    }
+
+    fn with_rate(nominal_rate: f64) -> PresentationClock {
+        PresentationClock {
+            nominal_rate,
+            rate: nominal_rate,
+            base_pts: 0,
+            base_units: 0,
+            units: 0,
+            window_start: None,
+            resyncs: 0,
+        }
+    }
+
+    /// this function returns the pts of the first unit of a
+    /// chunk of `count` units that was captured at `captured`,
+    /// and moves the clock past the chunk
+    pub fn stamp(&mut self, count: u64, captured: Timestamp) -> Pts {
+        let (window_time, window_units) = match self.window_start {
+            Some(window_start) => window_start,
+            None => {
+                self.base_pts = captured.monotonic_nanos;
+                self.base_units = self.units;
+                self.window_start = Some((captured, self.units));
+                (captured, self.units)
+            }
+        };
+
+        let mut pts = self.pts_of(self.units);
+        let error = captured.monotonic_nanos as i128 - pts as i128;
+        if error.unsigned_abs() > RESYNC_THRESHOLD.as_nanos() {
+            self.resyncs += 1;
+            self.base_pts = captured.monotonic_nanos;
+            self.base_units = self.units;
+            self.window_start = Some((captured, self.units));
+            pts = captured.monotonic_nanos;
+        } else if captured.since(&window_time) >= CORRECTION_WINDOW {
+            // measure the real rate over the window, then aim to
+            // also remove the error we have built up by the end of
+            // the next window, starting from where we are now so
+            // the pts never jump
+            let elapsed = captured.since(&window_time).as_nanos() as f64;
+            let measured =
+                (self.units - window_units) as f64 * NANOS_PER_SECOND / elapsed;
+            let window = CORRECTION_WINDOW.as_nanos() as f64;
+            let slewed = measured * window / (window + error as f64);
+            let limit = self.nominal_rate * MAX_DRIFT_PPM / 1_000_000.0;
+            self.rate = slewed.clamp(
+                self.nominal_rate - limit,
+                self.nominal_rate + limit,
+            );
+            self.base_pts = pts;
+            self.base_units = self.units;
+            self.window_start = Some((captured, self.units));
+        }
+
+        self.units += count;
+        pts
+    }
+
+    /// the number of units stamped so far
+    #[cfg(test)]
+    pub fn units(&self) -> u64 {
+        self.units
+    }
+
+    /// how far the device runs from its nominal rate,
+    /// in parts per million, positive when fast
+    #[cfg(test)]
+    pub fn drift_ppm(&self) -> f64 {
+        (self.rate - self.nominal_rate) / self.nominal_rate * 1_000_000.0
+    }
+
+    /// how many times units went missing and we had
+    /// to start again from the capture time
+    #[cfg(test)]
+    pub fn resyncs(&self) -> u64 {
+        self.resyncs
+    }
+
+    fn pts_of(&self, unit: u64) -> Pts {
+        let counted = (unit - self.base_units) as f64;
+        self.base_pts + (counted * NANOS_PER_SECOND / self.rate) as u64
+    }
+}
+
+/// this describes how to line the start of an audio
+/// stream up with the start of a video stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// the streams already start together
+    Aligned,
+    /// audio starts after video, so this many frames
+    /// of silence go in front of the audio
+    PadSamples(u64),
+    /// audio starts before video, so this many frames
+    /// are dropped from the front of the audio
+    SkipSamples(u64),
+}
+
+impl Alignment {
+    /// this function works out the alignment from the pts of
+    /// the first audio sample and the first video frame
+    pub fn between(audio_first: Pts, video_first: Pts, sample_rate: u32) -> Alignment {
+        let offset = offset_nanos(audio_first, video_first);
+        let samples = (offset.unsigned_abs() as u128 * sample_rate as u128
+            / NANOS_PER_SECOND as u128) as u64;
+        match offset {
+            _ if samples == 0 => Alignment::Aligned,
+            offset if offset > 0 => Alignment::PadSamples(samples),
+            _ => Alignment::SkipSamples(samples),
+        }
+    }
+}
+
+/// the exact time from the first video frame to the first
+/// audio sample, positive when the audio starts later
+pub fn offset_nanos(audio_first: Pts, video_first: Pts) -> i64 {
+    audio_first as i64 - video_first as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(nanos: u64) -> Timestamp {
+        Timestamp {
+            wall_nanos: 1_690_500_706_000_000_000 + nanos,
+            monotonic_nanos: nanos,
+        }
+    }
+
+    #[test]
+    fn audio_pts_count_samples_not_callbacks() {
+        let mut clock = PresentationClock::audio(48_000);
+        assert_eq!(clock.stamp(480, at(5_000_000)), 5_000_000);
+        // the callback ran late, but the samples are contiguous
+        assert_eq!(clock.stamp(480, at(18_000_000)), 15_000_000);
+        assert_eq!(clock.stamp(480, at(25_000_000)), 25_000_000);
+        assert_eq!(clock.units(), 1440);
+    }
+
+    #[test]
+    fn corrects_a_fast_sound_card_over_an_hour() {
+        // the card really runs at 48_048 samples a second
+        let mut clock = PresentationClock::audio(48_000);
+        let chunk = 480;
+        let mut samples = 0u64;
+        let mut last_pts = 0;
+        while samples < 48_048 * 3600 {
+            let captured = samples * 1_000_000_000 / 48_048;
+            last_pts = clock.stamp(chunk, at(captured));
+            samples += chunk;
+        }
+        let captured = (samples - chunk) * 1_000_000_000 / 48_048;
+        let error = (last_pts as i64 - captured as i64).abs();
+        assert!(error < 5_000_000, "drifted {} ns over an hour", error);
+        assert!((clock.drift_ppm() - 1000.0).abs() < 50.0);
+        assert_eq!(clock.resyncs(), 0);
+    }
+
+    #[test]
+    fn resyncs_after_an_xrun() {
+        let mut clock = PresentationClock::audio(48_000);
+        assert_eq!(clock.stamp(480, at(0)), 0);
+        assert_eq!(clock.stamp(480, at(10_000_000)), 10_000_000);
+        // the sound card lost 400ms of samples
+        assert_eq!(clock.stamp(480, at(420_000_000)), 420_000_000);
+        assert_eq!(clock.resyncs(), 1);
+        assert_eq!(clock.units(), 1440);
+    }
+
+    #[test]
+    fn aligns_audio_to_video() {
+        assert_eq!(Alignment::between(100, 100, 48_000), Alignment::Aligned);
+        assert_eq!(
+            Alignment::between(1_010_000_000, 1_000_000_000, 48_000),
+            Alignment::PadSamples(480)
+        );
+        assert_eq!(
+            Alignment::between(1_000_000_000, 1_020_000_000, 48_000),
+            Alignment::SkipSamples(960)
+        );
+        assert_eq!(offset_nanos(1_000_000_000, 1_020_000_000), -20_000_000);
+    }
+}
@@ -0,0 +1,110 @@
+/// This is where tests take over the clock. While a
+/// simulation runs, now() returns the time it was set to
+/// and only moves when the simulation advances it, so hours
+/// of recording, with their segments, retention and
+/// bookmarks, go by as fast as the pipeline can keep up.
+/// The simulated clock is installed on the thread that
+/// starts it and handed on to the pipeline threads it
+/// spawns, every other thread, and so every other test,
+/// keeps reading the real clock
+use super::Timestamp;
+
+use core::cell::RefCell;
+use core::time::Duration;
+
+use std::sync::{Arc, Mutex};
+
+/// a simulated clock, shared by the threads it is installed on
+pub type Clock = Arc<Mutex<Timestamp>>;
+
+std::thread_local! {
+    /// the simulated clock this thread reads, none while
+    /// the real clock is used
+    static INSTALLED: RefCell<Option<Clock>> = RefCell::new(None);
+}
+
+/// this function returns the simulated time, if the clock
+/// is being simulated on this thread
+pub fn current() -> Option<Timestamp> {
+    INSTALLED.with(|installed| {
+        installed
+            .borrow()
+            .as_ref()
+            .map(|clock| *clock.lock().unwrap_or_else(|err| err.into_inner()))
+    })
+}
+
+/// this function returns the clock installed on this
+/// thread, to be installed on a thread it spawns
+pub fn installed() -> Option<Clock> {
+    INSTALLED.with(|installed| installed.borrow().clone())
+}
+
+/// this function has this thread read `clock`, or the
+/// real clock when it is none
+pub fn install(clock: Option<Clock>) {
+    INSTALLED.with(|installed| *installed.borrow_mut() = clock);
+}
+
+/// this function stops the clock at `wall_nanos` for this
+/// thread, the monotonic clock carries on from where it was,
+/// so it never goes backwards for a queue that is already open
+pub fn start(wall_nanos: u64) -> Timestamp {
+    let monotonic_nanos = super::now().monotonic_nanos;
+    let started = Timestamp {
+        wall_nanos,
+        monotonic_nanos,
+    };
+    install(Some(Arc::new(Mutex::new(started))));
+    started
+}
+
+/// this function moves the simulated clock on by `by`,
+/// returning the new time
+pub fn advance(by: Duration) -> Timestamp {
+    let clock = installed().expect("the clock is not being simulated");
+    let mut at = clock.lock().unwrap_or_else(|err| err.into_inner());
+    *at = Timestamp {
+        wall_nanos: at.wall_nanos + by.as_nanos() as u64,
+        monotonic_nanos: at.monotonic_nanos + by.as_nanos() as u64,
+    };
+    *at
+}
+
+/// this function gives this thread the real clock back
+pub fn stop() {
+    install(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_moves_when_advanced() {
+        let started = start(super::super::MIN_VALID_WALL);
+        assert_eq!(super::super::now(), started);
+        let advanced = advance(Duration::from_secs(3600));
+        assert_eq!(advanced.since(&started), Duration::from_secs(3600));
+        assert_eq!(
+            super::super::now().wall_nanos,
+            super::super::MIN_VALID_WALL + 3600 * 1_000_000_000
+        );
+        stop();
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn other_threads_keep_the_real_clock() {
+        let started = start(super::super::MIN_VALID_WALL);
+        let clock = installed();
+        let real = std::thread::spawn(super::super::now).join().unwrap();
+        assert!(real.wall_nanos > started.wall_nanos + 3600 * 1_000_000_000);
+        let handed_on = std::thread::spawn(move || {
+            install(clock);
+            super::super::now()
+        });
+        assert_eq!(handed_on.join().unwrap(), started);
+        stop();
+    }
+}
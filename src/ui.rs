@@ -3,7 +3,10 @@ use crate::hardware::{
     storage::{MainStorage, RemovableStorage},
 };
 
-use crate::queue::Receiver;
+use crate::config::{StatusColors, StorageConfig, UiConfig};
+use crate::control::{Controls, Setting};
+use crate::queue::telemetry::QueueSample;
+use crate::queue::{Receiver, VideoUpdate};
 /// This is where the ui thread will retrieve
 /// state from the ui queue and display the
 /// state as follows, a full screen blue
@@ -35,11 +38,35 @@ use crate::queue::Receiver;
 /// bar will cause the full screen display of video
 /// frames copied into the ui queue. These frames are
 /// normally discarded to save cycles being used to
-/// display them.
+/// display them. Pressing M marks an event, followed by
+/// an optional note and enter, which protects the
+/// recording around it from retention.
 use winit;
 
-/// mock frame placeholder
-pub struct Frame {}
+use std::io::BufRead;
+use std::string::String;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+/// the key that marks an event
+pub const MARK_KEY: char = 'm';
+
+/// mock frame placeholder, sharing the buffer
+/// of the frame headed for storage
+pub struct Frame {
+    /// the frame, with where it sits in the video stream
+    pub video: Arc<VideoUpdate>,
+}
+
+impl Frame {
+    /// this function shares a frame with the viewfinder
+    pub fn from_video(update: &Arc<VideoUpdate>) -> Frame {
+        Frame {
+            video: update.clone(),
+        }
+    }
+}
 
 /// This struct is retrieved from the queue by the
 /// UI thread, so that it can display the most current
@@ -49,13 +76,46 @@ pub struct ViewUpdate {
     /// this indicates the current status of the system
     status: Status,
     /// this is the most recent frame from the video source
-    frame: Frame,
+    frame: Option<Frame>,
+    /// the latest sample of every queue, so a storage
+    /// deficit can be shown before memory runs out
+    queues: Vec<QueueSample>,
+}
+
+impl ViewUpdate {
+    /// this function wraps a frame for the UI queue, until
+    /// the secondary camera and removable storage are
+    /// detected we are always on the main disk and camera
+    pub fn new(frame: Frame) -> ViewUpdate {
+        ViewUpdate {
+            status: Status::MainDiskAndMainCam,
+            frame: Some(frame),
+            queues: Vec::new(),
+        }
+    }
+
+    /// this function shares a frame headed for storage
+    /// with the UI
+    pub fn from_video(update: &Arc<VideoUpdate>) -> ViewUpdate {
+        ViewUpdate::new(Frame::from_video(update))
+    }
+
+    /// this function wraps the latest queue telemetry
+    /// for the UI queue
+    pub fn from_queues(queues: Vec<QueueSample>) -> ViewUpdate {
+        ViewUpdate {
+            status: Status::MainDiskAndMainCam,
+            frame: None,
+            queues,
+        }
+    }
 }
 
 /// this carries all of the information
 /// the UI thread needs in order to inform
 /// the user of the current system state
-enum Status {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
     /// this indicates that the removable storage
     /// and the secondary camera is disconnected
     MainDiskAndMainCam,
@@ -71,6 +131,73 @@ enum Status {
     /// is connected and being used, and that the
     /// secondary camera is connected and being used
     RemovableDiskAndSecondaryCam,
+    /// this indicates that the main disk will be full in
+    /// less than a day
+    MainNearFull,
+    /// this indicates that the removable storage will be
+    /// full in less than an hour
+    RemovableNearFull,
+    /// this indicates that a disk being recorded to is
+    /// full, so recording has moved off it or is paused
+    StorageFull,
+}
+
+impl Status {
+    /// this function picks the color the screen is
+    /// filled with in this state
+    pub fn color(&self, colors: &StatusColors) -> u32 {
+        match self {
+            Status::MainDiskAndMainCam => colors.main_camera,
+            Status::MainDiskAndSecondaryCam => colors.secondary_camera,
+            Status::RemovableDiskAndMainCam
+            | Status::RemovableDiskAndSecondaryCam => colors.removable,
+            Status::MainNearFull => colors.main_near_full,
+            Status::RemovableNearFull => colors.removable_near_full,
+            Status::StorageFull => colors.full,
+        }
+    }
+
+    /// this function lets what storage knows of the disks
+    /// outrank the status an update gave, a full disk
+    /// first, then the one that fills soonest
+    pub fn with_storage(
+        self,
+        main: MainStorage,
+        removable: RemovableStorage,
+    ) -> Status {
+        match (main, removable) {
+            (MainStorage::Full, _) | (_, RemovableStorage::Full) => {
+                Status::StorageFull
+            }
+            (_, RemovableStorage::NearFull(_)) => Status::RemovableNearFull,
+            (MainStorage::NearFull(_), _) => Status::MainNearFull,
+            (MainStorage::HasCapacity, RemovableStorage::HasCapacity) => self,
+        }
+    }
+
+    /// this function says what the state means, for
+    /// the status page
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Status::MainDiskAndMainCam => {
+                "recording the webcam to the main disk"
+            }
+            Status::MainDiskAndSecondaryCam => {
+                "recording the secondary camera to the main disk"
+            }
+            Status::RemovableDiskAndMainCam => {
+                "recording the webcam to removable storage"
+            }
+            Status::RemovableDiskAndSecondaryCam => {
+                "recording the secondary camera to removable storage"
+            }
+            Status::MainNearFull => "the main disk is full within a day",
+            Status::RemovableNearFull => {
+                "removable storage is full within an hour"
+            }
+            Status::StorageFull => "a disk being recorded to is full",
+        }
+    }
 }
 
 /// this carries specific details
@@ -124,7 +251,146 @@ use crate::log::{Job, LogPipe};
 
 /// in this function we use winit to create our locked
 /// viewfinder and display information to the user via
-/// the LCD screen
-pub async fn start(queue: Receiver<ViewUpdate>, ui_log: LogPipe) {
-    ui_log.info("started UI", Job::UI);
+/// the LCD screen, a changed [ui] section is shown from
+/// the next update. The status and newest frame are also
+/// handed to Controls, for the status page and preview
+pub async fn start(
+    mut queue: Receiver<ViewUpdate>,
+    ui_log: LogPipe,
+    mut settings: Setting<UiConfig>,
+    controls: Controls,
+) {
+    crate::info!(ui_log, Job::UI, "started UI");
+    let mut config = settings.current();
+    // until the viewfinder is drawn, keep the queue empty
+    // so shared frames do not hold their buffers
+    while let Ok(update) = queue.dequeue().await {
+        if let Some(changed) = settings.try_changed() {
+            if changed.viewfinder != config.viewfinder {
+                crate::info!(
+                    ui_log,
+                    Job::UI,
+                    "viewfinder {}",
+                    if changed.viewfinder { "shown" } else { "hidden" }
+                );
+            }
+            if changed.colors != config.colors {
+                crate::info!(ui_log, Job::UI, "status colors changed");
+            }
+            config = changed;
+        }
+        let status = update.status.with_storage(
+            controls.main_storage.get(),
+            controls.removable_storage.get(),
+        );
+        if controls.status.get() != status {
+            controls.status.set(status);
+        }
+        if let Some(frame) = update.frame.as_ref() {
+            controls.preview.set(Some(frame.video.clone()));
+        }
+        if let Some(frame) = update.frame.filter(|_| config.viewfinder) {
+            crate::trace!(
+                ui_log,
+                Job::UI,
+                "viewfinder frame {} at pts {}",
+                frame.video.sequence,
+                frame.video.pts
+            );
+        }
+        for sample in update.queues.iter().filter(|sample| sample.in_deficit()) {
+            crate::trace!(
+                ui_log,
+                Job::UI,
+                "{} queue in deficit, {}/{}",
+                sample.name,
+                sample.depth,
+                sample.capacity
+            );
+        }
+    }
+}
+
+/// this function finds the note of a line typed at the
+/// keyboard, if the line marks an event
+fn mark_note(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let rest = line.strip_prefix(MARK_KEY)?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(rest.trim())
+}
+
+/// this function reads the keyboard on a thread of its
+/// own, until the viewfinder window takes key presses the
+/// terminal stands in for it. The thread blocks reading,
+/// so it is not joined at shutdown
+pub fn read_keys(
+    storage: Setting<StorageConfig>,
+    controls: Controls,
+    keys_log: LogPipe,
+) -> std::io::Result<JoinHandle<()>> {
+    std::thread::Builder::new()
+        .name(String::from("keys"))
+        .spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if crate::shutdown::requested() {
+                    break;
+                }
+                let Some(note) = mark_note(&line) else {
+                    continue;
+                };
+                if let Err(err) =
+                    controls.mark(note, &storage.get(), &keys_log)
+                {
+                    crate::error!(
+                        keys_log,
+                        Job::UI,
+                        "could not mark the event, {}",
+                        err
+                    );
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_storage_state_has_a_color() {
+        let colors = StatusColors::default();
+        let color = |main, removable| {
+            Status::RemovableDiskAndMainCam
+                .with_storage(main, removable)
+                .color(&colors)
+        };
+        use MainStorage as Main;
+        use RemovableStorage as Removable;
+        assert_eq!(
+            color(Main::HasCapacity, Removable::HasCapacity),
+            colors.removable
+        );
+        assert_eq!(
+            color(Main::NearFull(60), Removable::HasCapacity),
+            colors.main_near_full
+        );
+        assert_eq!(
+            color(Main::NearFull(60), Removable::NearFull(60)),
+            colors.removable_near_full
+        );
+        assert_eq!(color(Main::Full, Removable::NearFull(60)), colors.full);
+        assert_eq!(color(Main::HasCapacity, Removable::Full), colors.full);
+    }
+
+    #[test]
+    fn only_the_mark_key_marks() {
+        assert_eq!(mark_note("m"), Some(""));
+        assert_eq!(mark_note(" m  car stopped \n"), Some("car stopped"));
+        assert_eq!(mark_note("mark"), None);
+        assert_eq!(mark_note("x note"), None);
+    }
 }
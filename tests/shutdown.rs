@@ -0,0 +1,80 @@
+/// This is where the built recorder is stopped the way a
+/// service manager stops it, with SIGTERM while it runs.
+/// It records nothing, so it needs no devices, and its log
+/// shows whether it drained and flushed before it exited
+use std::format;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+extern "C" {
+    fn kill(pid: i32, signum: i32) -> i32;
+}
+
+const SIGTERM: i32 = 15;
+
+/// how long the recorder gets to start, and to stop
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// this function waits for `done`, failing the test
+/// after TIMEOUT
+fn wait_for<T>(what: &str, mut done: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(done) = done() {
+            return done;
+        }
+        assert!(Instant::now() < deadline, "gave up waiting for {}", what);
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn sigterm_stops_a_running_recorder_and_flushes_its_log() {
+    let directory = std::env::temp_dir()
+        .join(format!("camera_sigterm_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(directory.join("recordings")).unwrap();
+    let log = directory.join("log.txt");
+    let config = directory.join("camera.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[storage]\ndirectory = \"{}\"\nremovable_root = \"\"\n\n\
+             [log]\nfile = \"{}\"\n\n[control]\nsocket = \"\"\n\n\
+             [ui]\nviewfinder = false\n",
+            directory.join("recordings").display(),
+            log.display()
+        ),
+    )
+    .unwrap();
+
+    let mut recorder = Command::new(env!("CARGO_BIN_EXE_camera"))
+        .arg("--config")
+        .arg(&config)
+        .args(["record", "--no-audio", "--no-video"])
+        .env_remove("CAMERA_LOG")
+        .env_remove("CAMERA_LOG_FORMAT")
+        .current_dir(&directory)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let logged = || std::fs::read_to_string(&log).unwrap_or_default();
+    // it is running once it watches its configuration
+    wait_for("the recorder to start", || {
+        logged().contains("watching").then_some(())
+    });
+    assert_eq!(unsafe { kill(recorder.id() as i32, SIGTERM) }, 0);
+    let status = wait_for("the recorder to stop", || {
+        recorder.try_wait().unwrap()
+    });
+
+    assert!(status.success(), "the recorder exited with {}", status);
+    let logged = logged();
+    assert!(logged.contains("stopping capture and draining the queues"));
+    // every line sent before the log was shut down was written
+    let last = logged.lines().last().unwrap_or_default();
+    assert!(last.contains("flushing the log"), "the log ended {}", last);
+    std::fs::remove_dir_all(&directory).unwrap();
+}